@@ -0,0 +1,150 @@
+//! Harmonic analysis of chords
+
+use crate::pitch::Pitch;
+
+/// The best fit of a chord to a segment of the harmonic or subharmonic series
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeriesFit {
+    /// For each note, its number in the series. For the subharmonic series, note `i` is
+    /// approximated by `1/numbers[i]`.
+    pub numbers: Vec<u32>,
+    /// For each note, the amount in cents by which the note is above its place in the series
+    pub deviations: Vec<f64>,
+}
+
+impl SeriesFit {
+    pub fn max_deviation(&self) -> f64 {
+        self.deviations.iter().fold(0.0, |m, d| m.max(d.abs()))
+    }
+}
+
+/// The result of analyzing a chord. Notes are in the order given.
+pub struct ChordAnalysis {
+    /// For each note, its distance in cents above the lowest note
+    pub cents: Vec<f64>,
+    /// `intervals[i][j]` is the interval in cents from note `i` to note `j`
+    pub intervals: Vec<Vec<f64>>,
+    /// Best fit as harmonics, e.g. 4:5:6
+    pub otonal: Option<SeriesFit>,
+    /// Best fit as subharmonics, e.g. 1/6:1/5:1/4
+    pub utonal: Option<SeriesFit>,
+}
+
+impl ChordAnalysis {
+    /// Analyze a chord. A series fit is the one using the smallest numbers whose deviations are
+    /// all within `tolerance` cents and whose numbers are no larger than `max_number`.
+    pub fn new(pitches: &[Pitch], tolerance: f64, max_number: u32) -> Self {
+        let absolute: Vec<f64> = pitches
+            .iter()
+            .map(|p| p.as_float().log2() * 1200.0)
+            .collect();
+        let lowest = absolute.iter().copied().fold(f64::INFINITY, f64::min);
+        let cents: Vec<f64> = absolute.iter().map(|c| c - lowest).collect();
+        let intervals = cents
+            .iter()
+            .map(|from| cents.iter().map(|to| to - from).collect())
+            .collect();
+        // Both fits try each possible number for the lowest note in turn. A fit with a common
+        // factor would have been found with a smaller number, so fits are always in lowest terms.
+        let fit = |series: &dyn Fn(u32, f64) -> (u32, f64)| -> Option<SeriesFit> {
+            (1..=max_number).find_map(|lowest_number| {
+                let mut numbers = Vec::new();
+                let mut deviations = Vec::new();
+                for &c in &cents {
+                    let (n, series_cents) = series(lowest_number, c);
+                    if n == 0 || n > max_number {
+                        return None;
+                    }
+                    // Treat rounding noise as exact so exact fits don't show as -0.
+                    let deviation = c - series_cents;
+                    let deviation = if deviation.abs() < 1e-9 {
+                        0.0
+                    } else {
+                        deviation
+                    };
+                    if deviation.abs() > tolerance {
+                        return None;
+                    }
+                    numbers.push(n);
+                    deviations.push(deviation);
+                }
+                Some(SeriesFit {
+                    numbers,
+                    deviations,
+                })
+            })
+        };
+        let ratio = |c: f64| (c / 1200.0).exp2();
+        let log_cents = |r: f64| r.log2() * 1200.0;
+        let otonal = fit(&|h, c| {
+            let n = (f64::from(h) * ratio(c)).round() as u32;
+            (n, log_cents(f64::from(n) / f64::from(h)))
+        });
+        let utonal = fit(&|u, c| {
+            let n = (f64::from(u) / ratio(c)).round() as u32;
+            (n, log_cents(f64::from(u) / f64::from(n)))
+        });
+        Self {
+            cents,
+            intervals,
+            otonal,
+            utonal,
+        }
+    }
+
+    /// Whether the chord is more simply read as harmonics (`otonal`) or subharmonics (`utonal`),
+    /// or `None` if neither fits
+    pub fn reading(&self) -> Option<&'static str> {
+        let largest = |f: &SeriesFit| f.numbers.iter().copied().max().unwrap_or_default();
+        match (&self.otonal, &self.utonal) {
+            (None, None) => None,
+            (Some(_), None) => Some("otonal"),
+            (None, Some(_)) => Some("utonal"),
+            (Some(o), Some(u)) => Some(if largest(u) < largest(o) {
+                "utonal"
+            } else {
+                "otonal"
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn analyze(pitches: &[&str], tolerance: f64) -> ChordAnalysis {
+        let pitches: Vec<Pitch> = pitches.iter().map(|p| Pitch::must_parse(p)).collect();
+        ChordAnalysis::new(&pitches, tolerance, 64)
+    }
+
+    #[test]
+    fn test_chord() {
+        // Just major triad
+        let a = analyze(&["1", "5/4", "3/2"], 16.0);
+        assert_eq!(a.otonal.as_ref().unwrap().numbers, [4, 5, 6]);
+        assert!(a.otonal.as_ref().unwrap().max_deviation() < 1e-9);
+        assert_eq!(a.utonal.as_ref().unwrap().numbers, [15, 12, 10]);
+        assert_eq!(a.reading(), Some("otonal"));
+        assert!((a.intervals[1][2] - 315.641).abs() < 1e-3);
+        assert!((a.intervals[2][0] + 701.955).abs() < 1e-3);
+
+        // Just minor triad, given out of order
+        let a = analyze(&["3/2", "1", "6/5"], 16.0);
+        assert_eq!(a.otonal.as_ref().unwrap().numbers, [15, 10, 12]);
+        assert_eq!(a.utonal.as_ref().unwrap().numbers, [4, 6, 5]);
+        assert_eq!(a.reading(), Some("utonal"));
+
+        // 12-EDO dominant seventh: the seventh is 31¢ above 7/4, so 4:5:6:7 needs a larger
+        // tolerance.
+        let seventh = ["1", "^1|3", "^7|12", "^5|6"];
+        let a = analyze(&seventh, 16.0);
+        assert_eq!(a.otonal.unwrap().numbers, [24, 30, 36, 43]);
+        let o = analyze(&seventh, 32.0).otonal.unwrap();
+        assert_eq!(o.numbers, [4, 5, 6, 7]);
+        assert!((o.deviations[1] - 13.686).abs() < 1e-3);
+        assert!((o.deviations[2] + 1.955).abs() < 1e-3);
+        assert!((o.max_deviation() - 31.174).abs() < 1e-3);
+        assert!(analyze(&seventh, 1.0).otonal.is_none());
+    }
+}
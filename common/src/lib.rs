@@ -6,6 +6,7 @@ use std::fmt::Display;
 use std::io;
 use std::sync::LazyLock;
 
+pub mod chord;
 pub mod parsing;
 pub mod pitch;
 
@@ -62,6 +62,7 @@ pub mod pass3;
 pub mod score;
 pub(crate) mod score_helpers;
 mod timeline;
+pub mod timeline_json;
 
 use crate::parsing::score::{Directive, FromRawDirective};
 use crate::parsing::score_helpers::{ArcContext, ToStatic};
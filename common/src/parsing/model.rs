@@ -229,6 +229,8 @@ impl<'s> NoteOrIdentifier<'s> {
 pub enum ParamValue<'s> {
     Zero,
     PitchOrNumber(PitchOrNumber),
+    /// A number written with a leading `-`; this holds its absolute value.
+    Negative(Ratio<u32>),
     String(Cow<'s, str>),
     NoteOrIdentifier(NoteOrIdentifier<'s>),
 }
@@ -237,6 +239,7 @@ impl Display for ParamValue<'_> {
         match self {
             ParamValue::Zero => color!(f, 6, "0"),
             ParamValue::PitchOrNumber(pr) => Display::fmt(pr, f),
+            ParamValue::Negative(r) => color!(f, 6, "-{r}"),
             ParamValue::String(s) => color!(f, 166, "\"{s}\""),
             ParamValue::NoteOrIdentifier(n) => Display::fmt(n, f),
         }
@@ -247,14 +250,36 @@ impl<'s> ParamValue<'s> {
     pub fn try_as_pitch(&self) -> Option<&Pitch> {
         match self {
             ParamValue::PitchOrNumber(pr) => Some(pr.as_pitch()),
-            ParamValue::Zero | ParamValue::String(_) | ParamValue::NoteOrIdentifier(_) => None,
+            ParamValue::Zero
+            | ParamValue::Negative(_)
+            | ParamValue::String(_)
+            | ParamValue::NoteOrIdentifier(_) => None,
         }
     }
 
     pub fn try_as_ratio(&self) -> Option<Ratio<u32>> {
         match self {
             ParamValue::PitchOrNumber(pr) => pr.try_as_ratio(),
-            ParamValue::Zero | ParamValue::String(_) | ParamValue::NoteOrIdentifier(_) => None,
+            ParamValue::Zero
+            | ParamValue::Negative(_)
+            | ParamValue::String(_)
+            | ParamValue::NoteOrIdentifier(_) => None,
+        }
+    }
+
+    /// Return the value as a signed rational number, which may be zero or negative
+    pub fn try_as_signed_ratio(&self) -> Option<Ratio<i32>> {
+        let signed = |r: Ratio<u32>| {
+            Some(Ratio::new(
+                i32::try_from(*r.numer()).ok()?,
+                i32::try_from(*r.denom()).ok()?,
+            ))
+        };
+        match self {
+            ParamValue::Zero => Some(Ratio::ZERO),
+            ParamValue::PitchOrNumber(pr) => signed(pr.try_as_ratio()?),
+            ParamValue::Negative(r) => signed(*r).map(|r| -r),
+            ParamValue::String(_) | ParamValue::NoteOrIdentifier(_) => None,
         }
     }
 
@@ -262,22 +287,28 @@ impl<'s> ParamValue<'s> {
         match self {
             ParamValue::Zero => Some(0),
             ParamValue::PitchOrNumber(pr) => pr.try_as_int(),
-            ParamValue::String(_) | ParamValue::NoteOrIdentifier(_) => None,
+            ParamValue::Negative(_) | ParamValue::String(_) | ParamValue::NoteOrIdentifier(_) => {
+                None
+            }
         }
     }
 
     pub fn try_as_string(&self) -> Option<&Cow<'s, str>> {
         match self {
-            ParamValue::Zero | ParamValue::PitchOrNumber(_) | ParamValue::NoteOrIdentifier(_) => {
-                None
-            }
+            ParamValue::Zero
+            | ParamValue::PitchOrNumber(_)
+            | ParamValue::Negative(_)
+            | ParamValue::NoteOrIdentifier(_) => None,
             ParamValue::String(s) => Some(s),
         }
     }
 
     pub fn try_as_note(&self) -> Option<&NoteOctave<'s>> {
         match self {
-            ParamValue::Zero | ParamValue::PitchOrNumber(_) | ParamValue::String(_) => None,
+            ParamValue::Zero
+            | ParamValue::PitchOrNumber(_)
+            | ParamValue::Negative(_)
+            | ParamValue::String(_) => None,
             ParamValue::NoteOrIdentifier(n) => n.as_note(),
         }
     }
@@ -286,7 +317,10 @@ impl<'s> ParamValue<'s> {
         // An identifier is returned as a string that could be parsed as an identifier. All
         // identifiers are also valid note names.
         match self {
-            ParamValue::Zero | ParamValue::PitchOrNumber(_) | ParamValue::String(_) => None,
+            ParamValue::Zero
+            | ParamValue::PitchOrNumber(_)
+            | ParamValue::Negative(_)
+            | ParamValue::String(_) => None,
             ParamValue::NoteOrIdentifier(n) => n.as_identifier(),
         }
     }
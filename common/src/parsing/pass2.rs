@@ -432,6 +432,7 @@ fn param_value<'s>(
         alt((
             string(diags).map(|x| ParamValue::String(x.value)),
             zero().map(|_| ParamValue::Zero),
+            preceded(character('-'), ratio(diags)).map(|x| ParamValue::Negative(x.value)),
             pitch_or_number(diags).map(|x| ParamValue::PitchOrNumber(x.value)),
             note_or_identifier(diags).map(|x| ParamValue::NoteOrIdentifier(x.value)),
         ))
@@ -235,6 +235,17 @@ fn test_param() -> anyhow::Result<()> {
     );
     assert!(rest.is_empty());
 
+    let (s, rest) = parse_param("a=-1.5").map_err(to_anyhow)?;
+    assert_eq!(
+        s,
+        Param {
+            key: Spanned::new(0..1, ident("a")),
+            value: Spanned::new(2..6, ParamValue::Negative(Ratio::new(3, 2))),
+        }
+    );
+    assert_eq!(s.value.value.try_as_signed_ratio(), Some(Ratio::new(-3, 2)));
+    assert!(rest.is_empty());
+
     let (s, rest) = parse_param("a=^2|19").map_err(to_anyhow)?;
     assert_eq!(
         s,
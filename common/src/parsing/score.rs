@@ -11,28 +11,37 @@ use serde::Serialize;
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::collections::Bound::Excluded;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::{Debug, Formatter};
 use std::ops::Deref;
+use std::rc::Rc;
 use std::sync::atomic::AtomicI32;
 use std::sync::{Arc, LazyLock, RwLock};
 use std::{cmp, mem};
 
+mod derived;
 mod directives;
 mod generator;
+mod mos;
 mod repl;
+mod temperament;
 use crate::parsing::layout::{
     Coordinate, IsomorphicMapping, Layout, LayoutMapping, Layouts, ManualMapping, MappingDetails,
 };
 use crate::parsing::pass2::Pass2;
+use crate::parsing::score::derived::{Derivation, DerivedGenerator};
 use crate::parsing::score::generator::NoteGenerator;
+use crate::parsing::score::temperament::{ScaleError, TemperamentScale};
 use crate::parsing::{
-    CsoundInstrumentId, DynamicEvent, MarkEvent, MidiInstrumentNumber, NoteEvent, NoteValue,
-    Options, PartNote, PitchChange, TempoEvent, TimeBoundaries, Timeline, TimelineData,
-    TimelineEvent, WithTime, pass2, score_helpers, timeline,
+    CsoundInstrumentId, CsoundParamEvent, DynamicEvent, MarkEvent, MidiInstrumentNumber,
+    MidiRoutingSettings, NoteEvent, NoteValue, Options, PartNote, PitchChange, TempoEvent,
+    TimeBoundaries, Timeline, TimelineData, TimelineEvent, WavInstrumentSettings, Waveform,
+    WithTime, pass2, score_helpers, timeline,
 };
 use crate::pitch::Pitch;
 pub use directives::*;
+pub use generator::NoteSpeller;
+pub use mos::Mos;
 pub use repl::{DivisionsAndCycle, PromptCommand, ReplNote};
 use to_static_derive::ToStatic;
 
@@ -68,8 +77,11 @@ pub struct Score<'s> {
     tempo_in_flight_until: Option<Spanned<Ratio<u32>>>,
     line_start_time: Ratio<u32>,
     midi_instruments: HashMap<Cow<'s, str>, Span>,
+    midi_routings: HashMap<Cow<'s, str>, Span>,
     csound_instruments: HashMap<Cow<'s, str>, Span>,
     csound_global_instruments: BTreeMap<CsoundInstrumentId<'s>, Span>,
+    csound_params: HashMap<Cow<'s, str>, Span>,
+    wav_instruments: HashMap<Cow<'s, str>, Span>,
     known_parts: HashSet<Cow<'s, str>>,
     marks: HashMap<Cow<'s, str>, MarkData<'s>>,
     layouts: HashMap<LayoutKey<'s>, LayoutData<'s>>,
@@ -207,7 +219,7 @@ pub struct ScaleBuilder<'s> {
     pub definition: ScaleDefinition<'s>,
     pub notes: HashMap<Cow<'s, str>, Pitch>,
     pub primary_names: HashMap<Pitch, Cow<'s, str>>,
-    pub generator: Option<Box<dyn Generator>>,
+    pub generator: Option<Rc<dyn Generator>>,
 }
 
 #[derive(Serialize, Clone, ToStatic)]
@@ -432,10 +444,13 @@ impl<'a, 's> ScoreBlockValidator<'a, 's> {
         start_time: Ratio<u32>,
         value: &mut NoteValue<'s>,
     ) {
+        // See docs/TODO.md for the origin of these values.
         let mut velocity: u8 = 72;
+        let mut release_velocity: u8 = 64;
         let mut seen = HashSet::new();
         let tied = r_note.is_tie();
         let mut shorten: Ratio<u32> = Ratio::from_integer(0);
+        let mut shorten_count: u32 = 0;
         for m in &r_note.modifiers {
             if !seen.insert(m.value) {
                 if matches!(m.value, NoteModifier::Marcato | NoteModifier::Accent) {
@@ -461,10 +476,12 @@ impl<'a, 's> ScoreBlockValidator<'a, 's> {
                             .err(code::SCORE, m.span, "marcato may not appear with accent");
                     }
                     velocity = cmp::max(velocity, 108);
+                    release_velocity = cmp::max(release_velocity, 96);
                 }
                 NoteModifier::Shorten => {
                     // TODO: Make this amount configurable
                     shorten += Ratio::new(1, 4);
+                    shorten_count += 1;
                     if tied {
                         self.diags.err(
                             code::SCORE,
@@ -476,7 +493,10 @@ impl<'a, 's> ScoreBlockValidator<'a, 's> {
                 NoteModifier::Tie | NoteModifier::Glide => {}
             }
         }
+        // Each shorten mark also makes the release quicker.
+        release_velocity = cmp::min(127, u32::from(release_velocity) + 32 * shorten_count) as u8;
         value.velocity = velocity;
+        value.release_velocity = release_velocity;
         if !tied && let Some(last_pitch) = value.pitches.last_mut() {
             let mut duration = last_pitch.end_time - start_time;
             let min_duration = cmp::min(duration, Ratio::new(1, 4));
@@ -595,6 +615,7 @@ impl<'a, 's> ScoreBlockValidator<'a, 's> {
                             let value = NoteValue {
                                 text: &self.score.src[note.span],
                                 velocity: 0,
+                                release_velocity: 0,
                                 pitches: Default::default(),
                             };
                             PendingNote {
@@ -903,8 +924,11 @@ impl<'a, 's> ScoreBlockValidator<'a, 's> {
             self.validate_dynamic_line(line, beats_per_bar.as_ref());
         }
         if let Some(x) = beats_per_bar {
-            for beats in x {
+            // Every bar check, including the implicit one at the end of the block, is a bar line.
+            for (beats, (_, span)) in x.into_iter().zip(&self.note_line_bar_checks[0]) {
                 self.score.line_start_time += beats;
+                self.score
+                    .insert_event(self.score.line_start_time, *span, TimelineData::BarLine);
             }
         }
     }
@@ -920,9 +944,11 @@ impl<'s> Score<'s> {
             scales: Default::default(),
             events: Default::default(),
             midi_instruments: Default::default(),
+            midi_routings: Default::default(),
             csound_instruments: Default::default(),
             csound_global_instruments: Default::default(),
             csound_template: None,
+            wav_instruments: Default::default(),
             time_lcm: 1,
         };
         let pending_tempo = Some(WithTime::new(
@@ -948,8 +974,11 @@ impl<'s> Score<'s> {
             tempo_in_flight_until: None,
             line_start_time: Ratio::from_integer(0),
             midi_instruments: Default::default(),
+            midi_routings: Default::default(),
             csound_instruments: Default::default(),
             csound_global_instruments: Default::default(),
+            csound_params: Default::default(),
+            wav_instruments: Default::default(),
             known_parts: Default::default(),
             marks: Default::default(),
             layouts: Default::default(),
@@ -1032,6 +1061,9 @@ impl<'s> Score<'s> {
             }
             Directive::DefineScale(x) => self.define_scale(diags, x),
             Directive::DefineGeneratedScale(x) => self.define_generated_scale(diags, x),
+            Directive::DefineTemperamentScale(x) => self.define_temperament_scale(diags, x),
+            Directive::DefineMosScale(x) => self.define_mos_scale(diags, x),
+            Directive::DefineDerivedScale(x) => self.define_derived_scale(diags, x),
             Directive::UseScale(x) => self.use_scale(diags, x),
             Directive::Transpose(x) => self.transpose(diags, x),
             Directive::SetBasePitch(x) => self.set_base_pitch(x),
@@ -1040,9 +1072,12 @@ impl<'s> Score<'s> {
             Directive::CheckPitch(x) => self.check_pitch(diags, x),
             Directive::ResetTuning(x) => self.reset_tuning(x),
             Directive::MidiInstrument(x) => self.midi_instrument(diags, x),
+            Directive::MidiRouting(x) => self.midi_routing(diags, x),
             Directive::CsoundInstrument(x) => self.csound_instrument(diags, x),
             Directive::CsoundGlobalInstrument(x) => self.csound_global_instrument(diags, x),
             Directive::CsoundTemplate(x) => self.csound_template(diags, x),
+            Directive::CsoundParam(x) => self.csound_param(diags, x),
+            Directive::WavInstrument(x) => self.wav_instrument(diags, x),
             Directive::Tempo(x) => self.tempo(diags, x),
             Directive::Mark(x) => self.mark(diags, x),
             Directive::Repeat(x) => self.repeat(diags, x),
@@ -1132,7 +1167,7 @@ impl<'s> Score<'s> {
         let divided_interval = directive
             .divided_interval
             .map_or(definition.cycle, Spanned::value);
-        let generator: Option<Box<dyn Generator>> = Some(Box::new(NoteGenerator {
+        let generator: Option<Rc<dyn Generator>> = Some(Rc::new(NoteGenerator {
             divisions: directive.divisions.map(Spanned::value),
             divided_interval,
             tolerance: directive.tolerance.map(Spanned::value).unwrap_or_default(),
@@ -1146,6 +1181,175 @@ impl<'s> Score<'s> {
         self.add_scale(diags, scale);
     }
 
+    pub fn define_temperament_scale(
+        &mut self,
+        diags: &Diagnostics,
+        directive: DefineTemperamentScale<'s>,
+    ) {
+        let definition = ScaleDefinition {
+            span: directive.scale.span,
+            name: directive.scale.value,
+            cycle: directive
+                .cycle_ratio
+                .map_or(Ratio::from_integer(2), Spanned::value),
+        };
+        let temperament = TemperamentScale {
+            cycle: definition.cycle,
+            period: directive.period.map_or(definition.cycle, Spanned::value),
+            generator: directive.generator.value,
+            commas: directive.comma.into_iter().map(Spanned::value).collect(),
+            val: directive.val.into_iter().map(Spanned::value).collect(),
+            size: directive.size.value,
+            mode: directive.mode.map(Spanned::value).unwrap_or_default(),
+        };
+        let generator = match temperament.into_generator() {
+            Ok(g) => g,
+            Err(ScaleError::Temperament(e)) => {
+                diags.err(code::SCALE, directive.span, e);
+                return;
+            }
+            Err(ScaleError::Size(e)) => {
+                diags.err(code::SCALE, directive.size.span, e);
+                return;
+            }
+        };
+        let scale = ScaleBuilder {
+            definition,
+            notes: Default::default(),
+            primary_names: Default::default(),
+            generator: Some(Rc::new(generator)),
+        };
+        self.add_scale(diags, scale);
+    }
+
+    pub fn define_mos_scale(&mut self, diags: &Diagnostics, directive: DefineMosScale<'s>) {
+        let definition = ScaleDefinition {
+            span: directive.scale.span,
+            name: directive.scale.value,
+            cycle: directive
+                .period
+                .map_or(Ratio::from_integer(2), Spanned::value),
+        };
+        let mos = match Mos::new(
+            definition.cycle,
+            &directive.generator.value,
+            directive.size.value,
+            directive.mode.map(Spanned::value).unwrap_or_default(),
+        ) {
+            Ok(mos) => mos,
+            Err(e) => {
+                diags.err(code::SCALE, directive.span, e);
+                return;
+            }
+        };
+        let mut notes = HashMap::new();
+        let mut primary_names = HashMap::new();
+        let pitches = match mos.pitches(definition.cycle, &directive.generator.value) {
+            Ok(pitches) => pitches,
+            Err(e) => {
+                diags.err(code::SCALE, directive.size.span, e);
+                return;
+            }
+        };
+        for (i, (pitch, name)) in pitches.iter().zip(mos.note_names()).enumerate() {
+            let name: Cow<str> = Cow::Owned(name);
+            notes.insert(name.clone(), pitch.clone());
+            primary_names.insert(pitch.clone(), name);
+            notes.insert(Cow::Owned(format!("A{i}")), pitch.clone());
+        }
+        let scale = ScaleBuilder {
+            definition,
+            notes,
+            primary_names,
+            generator: None,
+        };
+        self.add_scale(diags, scale);
+    }
+
+    pub fn define_derived_scale(&mut self, diags: &Diagnostics, directive: DefineDerivedScale<'s>) {
+        let Some(source) = self.scales.get(&directive.from.value) else {
+            diags.err(
+                code::SCALE,
+                directive.from.span,
+                format!("there is no scale called '{}'", directive.from.value),
+            );
+            return;
+        };
+        let mut source = source.borrow_mut();
+        let cycle = source.definition.cycle;
+        // Look up notes in the source scale, normalized to within its cycle.
+        let mut missing = false;
+        let mut note_pitch = |note: &Spanned<NoteOctave<'s>>| {
+            let pitch = source.get_note(diags, note);
+            if pitch.is_none() {
+                missing = true;
+                diags.err(
+                    code::SCALE,
+                    note.span,
+                    format!(
+                        "note '{}' is not present in scale '{}'",
+                        note.value.name.value, directive.from.value
+                    ),
+                );
+            }
+            pitch.map(|p| p.normalized(cycle).0)
+        };
+        let root = directive.root.as_ref().map(&mut note_pitch);
+        let keep: Option<HashSet<Pitch>> = if directive.note.is_empty() {
+            None
+        } else {
+            Some(directive.note.iter().filter_map(&mut note_pitch).collect())
+        };
+        if missing {
+            return;
+        }
+        let root = root.flatten().unwrap_or_default();
+        if let Some(keep) = &keep
+            && !keep.contains(&root)
+        {
+            let span = directive.root.map_or(directive.span, |r| r.span);
+            diags.err(
+                code::SCALE,
+                span,
+                "the root of the derived scale must be one of the notes that are kept",
+            );
+            return;
+        }
+        let derivation = Derivation {
+            cycle,
+            root,
+            keep,
+            stretched_cycle: directive.cycle_ratio.map(Spanned::value),
+        };
+        let definition = ScaleDefinition {
+            span: directive.scale.span,
+            name: directive.scale.value,
+            cycle: derivation.stretched_cycle.unwrap_or(cycle),
+        };
+        let notes = source
+            .notes
+            .iter()
+            .filter_map(|(name, p)| Some((name.clone(), derivation.apply(p)?)))
+            .collect();
+        let primary_names = source
+            .primary_names
+            .iter()
+            .filter_map(|(p, name)| Some((derivation.apply(p)?, name.clone())))
+            .collect();
+        let generator = source
+            .generator
+            .clone()
+            .map(|source| Rc::new(DerivedGenerator { source, derivation }) as Rc<dyn Generator>);
+        drop(source);
+        let scale = ScaleBuilder {
+            definition,
+            notes,
+            primary_names,
+            generator,
+        };
+        self.add_scale(diags, scale);
+    }
+
     fn current_score_block(&mut self) -> &mut ScoreBlock<'s> {
         if self.pending_score_block.is_none() {
             self.pending_score_block = Some(Default::default());
@@ -1476,6 +1680,29 @@ impl<'s> Score<'s> {
         );
     }
 
+    fn midi_routing(&mut self, diags: &Diagnostics, directive: MidiRouting<'s>) {
+        // Validate has checked the port range.
+        let part = directive.part.value.name;
+        if let Some(&old) = self.midi_routings.get(&part) {
+            diags.push(
+                Diagnostic::new(
+                    code::MIDI,
+                    directive.span,
+                    format!("MIDI routing for part '{part}' has already been specified"),
+                )
+                .with_context(old, "here is the previous occurrence"),
+            );
+            return;
+        }
+        self.midi_routings.insert(part.clone(), directive.span);
+        self.timeline.midi_routings.push(MidiRoutingSettings {
+            span: directive.span,
+            part,
+            port: directive.port.map(|x| x.value as u8),
+            track_name: directive.track_name.map(Spanned::value),
+        });
+    }
+
     fn csound_instrument(&mut self, diags: &Diagnostics, directive: CsoundInstrument<'s>) {
         // Validate has assured that exactly one of `name` or `number` is defined.
         let instrument = directive.name.map_or_else(
@@ -1532,6 +1759,58 @@ impl<'s> Score<'s> {
         self.timeline.csound_template = Some(directive.path.value);
     }
 
+    fn csound_param(&mut self, _diags: &Diagnostics, directive: CsoundParam<'s>) {
+        let part = directive.part.value.name;
+        // Remember the first occurrence for each part so we can check that the part exists.
+        self.csound_params
+            .entry(part.clone())
+            .or_insert(directive.part.span);
+        let offset = directive
+            .start_time
+            .map_or(Ratio::from_integer(0), Spanned::value);
+        let start_time = self.line_start_time + offset;
+        // Validate has verified that end_value and duration are both present or both absent.
+        let end_value = directive
+            .end_value
+            .map(|v| WithTime::new(start_time + directive.duration.unwrap().value, v.value));
+        self.insert_event(
+            start_time,
+            directive.span,
+            TimelineData::CsoundParam(CsoundParamEvent {
+                part,
+                name: directive.name.value.name,
+                value: directive.value.value,
+                end_value,
+            }),
+        );
+    }
+
+    fn wav_instrument(&mut self, diags: &Diagnostics, directive: WavInstrument<'s>) {
+        // Validate has checked the waveform name and ranges. Defaults are kept consistent with
+        // the doc comments for the fields.
+        let defaults = WavInstrumentSettings::default();
+        let settings = WavInstrumentSettings {
+            waveform: directive
+                .waveform
+                .and_then(|x| Waveform::from_name(&x.value))
+                .unwrap_or(defaults.waveform),
+            attack: directive.attack.map_or(defaults.attack, Spanned::value),
+            decay: directive.decay.map_or(defaults.decay, Spanned::value),
+            sustain: directive.sustain.map_or(defaults.sustain, Spanned::value),
+            release: directive.release.map_or(defaults.release, Spanned::value),
+            cutoff: directive.cutoff.map_or(defaults.cutoff, Spanned::value),
+        };
+        score_helpers::check_duplicate_by_part(
+            diags,
+            "WAV instrument",
+            directive.part.as_slice(),
+            directive.span,
+            &mut self.wav_instruments,
+            settings,
+            &mut self.timeline.wav_instruments,
+        );
+    }
+
     pub fn tempo(&mut self, diags: &Diagnostics, directive: Tempo<'s>) {
         let offset = directive
             .start_time
@@ -1946,6 +2225,29 @@ impl<'s> Score<'s> {
                 diags.err(code::MIDI, span, "this part never appeared in the score");
             }
         }
+        for (part, &span) in &self.midi_routings {
+            if !self.known_parts.contains(part) {
+                diags.err(code::MIDI, span, "this part never appeared in the score");
+            }
+        }
+        for (part, &span) in &self.csound_params {
+            if !self.known_parts.contains(part) {
+                diags.err(
+                    code::DIRECTIVE_USAGE,
+                    span,
+                    "this part never appeared in the score",
+                );
+            }
+        }
+        for (part, &span) in &self.wav_instruments {
+            if !part.is_empty() && !self.known_parts.contains(part) {
+                diags.err(
+                    code::DIRECTIVE_USAGE,
+                    span,
+                    "this part never appeared in the score",
+                );
+            }
+        }
         for (part, dynamic) in &self.pending_dynamic_changes {
             diags.err(
                 code::SCORE,
@@ -2055,6 +2357,33 @@ impl<'s> Score<'s> {
         })
     }
 
+    /// Keep only timeline events relevant to the parts selected on the command line.
+    fn select_parts(
+        events: BTreeSet<Arc<TimelineEvent<'s>>>,
+        options: &Options,
+    ) -> BTreeSet<Arc<TimelineEvent<'s>>> {
+        if options.part.is_empty() {
+            return events;
+        }
+        let parts: Vec<&str> = options.part.iter().map(Deref::deref).collect();
+        events
+            .into_iter()
+            .filter(|event| {
+                // Events that aren't specific to a part, such as tempo changes, marks, and bar
+                // lines, are always kept.
+                let Some(part) = event.part() else {
+                    return true;
+                };
+                let matches = parts.contains(&part);
+                if options.omit_parts {
+                    !matches
+                } else {
+                    matches
+                }
+            })
+            .collect()
+    }
+
     pub fn post_process(&mut self, diags: &Diagnostics, options: &Options) {
         for p in options.part.iter().map(Deref::deref) {
             if !self.known_parts.contains(&Cow::Borrowed(p)) {
@@ -2073,34 +2402,7 @@ impl<'s> Score<'s> {
         };
         let tempo_percent = options.tempo_percent.unwrap_or(100);
         let tempo_factor = Ratio::new(tempo_percent, 100);
-        // Keep only timeline events relevant to selected parts.
-        let events = mem::take(&mut self.timeline.events);
-        let events = if options.part.is_empty() {
-            events
-        } else {
-            let parts: Vec<&str> = options.part.iter().map(Deref::deref).collect();
-            events
-                .into_iter()
-                .filter(|event| {
-                    let part = match &event.data {
-                        TimelineData::Dynamic(e) => e.part,
-                        TimelineData::Note(e) => e.part_note.part,
-                        TimelineData::Tempo(_)
-                        | TimelineData::Mark(_)
-                        | TimelineData::RepeatStart(_)
-                        | TimelineData::RepeatEnd(_) => {
-                            return true;
-                        }
-                    };
-                    let matches = parts.contains(&part);
-                    if options.omit_parts {
-                        !matches
-                    } else {
-                        matches
-                    }
-                })
-                .collect()
-        };
+        let events = Self::select_parts(mem::take(&mut self.timeline.events), options);
         let mut current_tempo = None;
         let mut delta: Ratio<u32> = time_boundaries.start_time;
         let mut last_event_time: Ratio<u32> = 0.into();
@@ -2163,7 +2465,16 @@ impl<'s> Score<'s> {
                     current_tempo = Some(Arc::new(new_event.clone()));
                     continue;
                 }
-                TimelineData::Mark(_) | TimelineData::Dynamic(_) | TimelineData::RepeatEnd(_) => {}
+                TimelineData::BarLine => {
+                    // Bar lines don't take up time, so they shouldn't affect how much time is
+                    // skipped.
+                    self.timeline.events.insert(Arc::new(new_event));
+                    continue;
+                }
+                TimelineData::Mark(_)
+                | TimelineData::Dynamic(_)
+                | TimelineData::CsoundParam(_)
+                | TimelineData::RepeatEnd(_) => {}
             }
             last_event_time = new_event.time;
             self.timeline.events.insert(Arc::new(new_event));
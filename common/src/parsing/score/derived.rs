@@ -0,0 +1,141 @@
+use crate::parsing::diagnostics::Diagnostics;
+use crate::parsing::model::Spanned;
+use crate::parsing::score::{Assignments, Generator};
+use crate::pitch::{Factor, Pitch};
+use num_rational::Ratio;
+use num_traits::ToPrimitive;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+/// Exponents of the stretched cycle whose denominators are no larger than this are kept exact;
+/// others are rounded to the nearest 1/1,200,000 of a cycle.
+const MAX_EXACT_DENOMINATOR: i32 = 1200;
+const ROUNDING_DENOMINATOR: i32 = 1_200_000;
+
+/// How a derived scale's pitches are computed from its source scale's pitches
+pub(crate) struct Derivation {
+    /// Cycle of the source scale
+    pub cycle: Ratio<u32>,
+    /// Normalized pitch of the new root in the source scale
+    pub root: Pitch,
+    /// Normalized pitches of the source scale to keep; `None` keeps everything
+    pub keep: Option<HashSet<Pitch>>,
+    /// New cycle ratio, if the cycle is stretched or compressed
+    pub stretched_cycle: Option<Ratio<u32>>,
+}
+
+impl Derivation {
+    /// Map a base-relative pitch in the source scale to the derived scale, or return `None` if
+    /// the pitch is not in the derived scale. Notes keep their cycle offsets, so a note that was
+    /// within the cycle of the source scale is within the cycle of the derived scale.
+    pub fn apply(&self, pitch: &Pitch) -> Option<Pitch> {
+        let (normalized, cycles) = pitch.normalized(self.cycle);
+        if let Some(keep) = &self.keep
+            && !keep.contains(&normalized)
+        {
+            return None;
+        }
+        let (rotated, _) = (&normalized / &self.root).normalized(self.cycle);
+        Some(match self.stretched_cycle {
+            None => &rotated * &Pitch::from(self.cycle.pow(cycles)),
+            Some(new_cycle) => {
+                // Express the pitch as a power of the cycle, and raise the new cycle to that
+                // power.
+                let exp = rotated.as_float().log(self.cycle.to_f64().unwrap());
+                let exp = (1..=MAX_EXACT_DENOMINATOR)
+                    .map(|d| Ratio::new((exp * f64::from(d)).round() as i32, d))
+                    .find(|r| (r.to_f64().unwrap() - exp).abs() < 1e-9)
+                    .unwrap_or_else(|| {
+                        Ratio::new(
+                            (exp * f64::from(ROUNDING_DENOMINATOR)).round() as i32,
+                            ROUNDING_DENOMINATOR,
+                        )
+                    })
+                    + Ratio::from_integer(cycles);
+                Pitch::new(vec![
+                    Factor::new(
+                        *new_cycle.numer(),
+                        *new_cycle.denom(),
+                        *exp.numer(),
+                        *exp.denom(),
+                    )
+                    .unwrap(),
+                ])
+            }
+        })
+    }
+}
+
+/// Generate notes for a scale derived from a generated scale by deriving them from the source
+/// scale's generated notes
+pub(crate) struct DerivedGenerator {
+    pub source: Rc<dyn Generator>,
+    pub derivation: Derivation,
+}
+
+impl Generator for DerivedGenerator {
+    fn get_note(&self, diags: &Diagnostics, name: &Spanned<&str>) -> Option<Pitch> {
+        self.source
+            .get_note(diags, name)
+            .and_then(|p| self.derivation.apply(&p))
+    }
+
+    fn assign_generated_notes(&self) -> Assignments {
+        let source = self.source.assign_generated_notes();
+        let mut result = Assignments::default();
+        for (name, pitch) in source.notes {
+            if let Some(p) = self.derivation.apply(&pitch) {
+                if let Some(delta) = source.deltas.get(&name) {
+                    result.deltas.insert(name.clone(), *delta);
+                }
+                result.notes.insert(name, p);
+            }
+        }
+        for (pitch, name) in source.primary_names {
+            if let Some(p) = self.derivation.apply(&pitch) {
+                result.primary_names.insert(p, name);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derivation() {
+        let two = Ratio::from_integer(2);
+        let edo = |n: i32| Pitch::must_parse(&format!("^{n}|12"));
+        // Dorian from a 12-EDO major scale: D becomes the root, and C is a seventh above it.
+        let d = Derivation {
+            cycle: two,
+            root: edo(2),
+            keep: Some([0, 2, 4, 5, 7, 9, 11].into_iter().map(edo).collect()),
+            stretched_cycle: None,
+        };
+        assert_eq!(d.apply(&edo(2)).unwrap(), Pitch::unit());
+        assert_eq!(d.apply(&edo(0)).unwrap(), edo(10));
+        assert_eq!(d.apply(&edo(12)).unwrap(), edo(22));
+        assert_eq!(d.apply(&edo(-1)).unwrap(), edo(-3));
+        assert!(d.apply(&edo(1)).is_none());
+
+        // Stretched octaves: equal divisions stay exact; other pitches are rounded.
+        let d = Derivation {
+            cycle: two,
+            root: Pitch::unit(),
+            keep: None,
+            stretched_cycle: Some(Ratio::new(201, 100)),
+        };
+        assert_eq!(d.apply(&edo(7)).unwrap(), Pitch::must_parse("201/100^7|12"));
+        assert_eq!(
+            d.apply(&edo(19)).unwrap(),
+            Pitch::must_parse("201/100^19|12")
+        );
+        assert_eq!(
+            d.apply(&Pitch::must_parse("3/2")).unwrap(),
+            Pitch::must_parse("201/100^701955|1200000")
+        );
+    }
+}
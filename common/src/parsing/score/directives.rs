@@ -6,8 +6,10 @@ use crate::parsing::model::{
     DataBlock, Identifier, LayoutBlock, NoteOctave, ScaleBlock, Span, Spanned,
 };
 use crate::parsing::score::HashSet;
+use crate::parsing::score::Mos;
 use crate::parsing::score::RawDirective;
 use crate::parsing::score_helpers;
+use crate::parsing::timeline::Waveform;
 use crate::pitch::Pitch;
 use directive_derive::FromRawDirective;
 use num_rational::Ratio;
@@ -132,6 +134,177 @@ impl DefineGeneratedScale<'_> {
     }
 }
 
+#[derive(FromRawDirective)]
+/// Define a scale from a regular temperament. The temperament is given
+/// either by commas to temper out or by an explicit val, along with the
+/// just intervals approximated by its period and generator.
+/// - With `comma`, the commas, period, and generator must involve exactly two
+///   more primes than there are commas, giving a rank-2 temperament. The
+///   cycle must map to a whole number of periods. Periods are pure, and the
+///   generator is tuned to minimize the weighted error of the primes.
+/// - With `val`, the values give the number of steps for each prime in order
+///   (2, 3, 5, 7, ...). The scale is a subset of the equal division of the
+///   cycle defined by the val.
+///
+/// The scale has `size` notes per cycle. These are formed by stacking the
+/// generator, reducing by the period, and repeating the result in each period
+/// of the cycle. `mode` is the number of generators stacked below the root.
+///
+/// Any generated just intonation note name (see `define_generated_scale`)
+/// whose tempered pitch is in the scale may be used, so `E` and `II` are the
+/// same note in meantone. Every scale degree `n` may also be called `An`.
+///
+/// Example: `define_temperament_scale(scale="meantone" generator=3/2 comma=81/80 size=7 mode=1)`
+pub struct DefineTemperamentScale<'s> {
+    pub span: Span,
+    /// scale name
+    pub scale: Spanned<Cow<'s, str>>,
+    /// ratio to be applied by the octave marker; default is 2 (one octave)
+    pub cycle_ratio: Option<Spanned<Ratio<u32>>>,
+    /// just interval approximated by the period; default is the cycle ratio
+    pub period: Option<Spanned<Ratio<u32>>>,
+    /// just interval approximated by the generator
+    pub generator: Spanned<Ratio<u32>>,
+    /// comma to temper out; may be repeated
+    pub comma: Vec<Spanned<Ratio<u32>>>,
+    /// number of steps for each successive prime; repeat once per prime
+    pub val: Vec<Spanned<u32>>,
+    /// number of notes per cycle; must be a multiple of the number of periods
+    pub size: Spanned<u32>,
+    /// number of generators below the root; default is 0
+    pub mode: Option<Spanned<u32>>,
+}
+impl DefineTemperamentScale<'_> {
+    pub fn validate(&mut self, diags: &Diagnostics) {
+        if self.comma.is_empty() == self.val.is_empty() {
+            diags.err(
+                code::DIRECTIVE_USAGE,
+                self.span,
+                "exactly one of 'comma' or 'val' must be given",
+            );
+        }
+        if let Some(v) = self.val.iter().find(|v| v.value == 0) {
+            diags.err(code::DIRECTIVE_USAGE, v.span, "val entries must be >= 1");
+        }
+        if self.size.value == 0 {
+            diags.err(code::DIRECTIVE_USAGE, self.size.span, "size must be >= 1");
+        }
+        if let Some(mode) = self.mode
+            && mode.value >= self.size.value
+        {
+            diags.err(
+                code::DIRECTIVE_USAGE,
+                mode.span,
+                "mode must be less than size",
+            );
+        }
+    }
+}
+
+#[derive(FromRawDirective)]
+/// Define a moment-of-symmetry (MOS) scale by stacking a generator and reducing
+/// by the period. The result must have exactly two step sizes. The period is
+/// also the scale's cycle ratio, so `'` and `,` move by a period.
+///
+/// Notes are named with the nominals `J`, `K`, `L`, and so on, starting from
+/// the root, for scales with up to 17 notes. Degree `n` may always be called
+/// `An`. Run `syntoniq calc mos` to find the sizes that form a MOS for a given
+/// generator.
+///
+/// Example: `define_mos_scale(scale="diatonic" generator=^7|12 size=7 mode=1)`
+pub struct DefineMosScale<'s> {
+    pub span: Span,
+    /// scale name
+    pub scale: Spanned<Cow<'s, str>>,
+    /// interval by which the generator chain is reduced; default is 2 (one
+    /// octave)
+    pub period: Option<Spanned<Ratio<u32>>>,
+    /// interval to stack
+    pub generator: Spanned<Pitch>,
+    /// number of notes per period
+    pub size: Spanned<u32>,
+    /// number of generators below the root; default is 0, the brightest mode
+    pub mode: Option<Spanned<u32>>,
+}
+impl DefineMosScale<'_> {
+    pub fn validate(&mut self, diags: &Diagnostics) {
+        if self.size.value < 2 {
+            diags.err(code::DIRECTIVE_USAGE, self.size.span, "size must be >= 2");
+        } else if self.size.value > Mos::MAX_SIZE {
+            diags.err(
+                code::DIRECTIVE_USAGE,
+                self.size.span,
+                format!("size must be <= {}", Mos::MAX_SIZE),
+            );
+        }
+        let period = self.period.map_or(Ratio::from_integer(2), Spanned::value);
+        if let Err(e) = Mos::check_period(period) {
+            let span = self.period.map_or(self.span, |p| p.span);
+            diags.err(code::DIRECTIVE_USAGE, span, e);
+        } else if let Err(e) = Mos::check_generator(period, &self.generator.value) {
+            diags.err(code::DIRECTIVE_USAGE, self.generator.span, e);
+        }
+        if let Some(mode) = self.mode
+            && mode.value >= self.size.value
+        {
+            diags.err(
+                code::DIRECTIVE_USAGE,
+                mode.span,
+                "mode must be less than size",
+            );
+        }
+    }
+}
+
+#[derive(FromRawDirective)]
+/// Define a scale derived from an existing scale. Notes keep their names from
+/// the source scale, so music written for the source scale can be used with the
+/// derived scale. Any combination of the following may be given:
+/// * `root`: rotate to a mode by making the given note the root of the scale
+/// * `note`: keep only the given notes, including those with the same pitch
+///   in other cycles
+/// * `cycle_ratio`: stretch or compress the cycle, scaling each note's pitch
+///   so that its position within the cycle is unchanged
+///
+/// Example: `define_derived_scale(scale="dorian" from="major" root=d)`
+pub struct DefineDerivedScale<'s> {
+    pub span: Span,
+    /// scale name
+    pub scale: Spanned<Cow<'s, str>>,
+    /// name of the scale from which to derive this one
+    pub from: Spanned<Cow<'s, str>>,
+    /// note to use as the root of the derived scale; default is the root of
+    /// the source scale
+    pub root: Option<Spanned<NoteOctave<'s>>>,
+    /// note to keep; may be repeated; default is to keep all notes
+    pub note: Vec<Spanned<NoteOctave<'s>>>,
+    /// new ratio for the cycle, such as 201/100 for stretched octaves; default
+    /// is the cycle of the source scale
+    pub cycle_ratio: Option<Spanned<Ratio<u32>>>,
+}
+impl DefineDerivedScale<'_> {
+    pub fn validate(&mut self, diags: &Diagnostics) {
+        for note in self.root.iter().chain(&self.note) {
+            if let Some(octave) = note.value.octave {
+                diags.err(
+                    code::DIRECTIVE_USAGE,
+                    octave.span,
+                    "octave markers may not be used here",
+                );
+            }
+        }
+        if let Some(cycle_ratio) = self.cycle_ratio
+            && cycle_ratio.value <= Ratio::from_integer(1)
+        {
+            diags.err(
+                code::DIRECTIVE_USAGE,
+                cycle_ratio.span,
+                "cycle_ratio must be greater than 1",
+            );
+        }
+    }
+}
+
 #[derive(FromRawDirective)]
 /// Change the scale for the specified parts. If no parts are specified, change
 /// the scale used by parts with no explicit scale. This creates a tuning with
@@ -327,6 +500,35 @@ impl MidiInstrument<'_> {
     }
 }
 
+#[derive(FromRawDirective)]
+/// Control the MIDI port and track name used for a part. Parts with this
+/// directive are placed on the given port, and their tracks come first in the
+/// order of the directives. Other parts are placed on ports automatically.
+/// It is an error to name a part that doesn't appear somewhere in the score.
+pub struct MidiRouting<'s> {
+    pub span: Span,
+    /// Which part to route
+    pub part: Spanned<Identifier<'s>>,
+    /// MIDI port number from 0 to 127, matching device names `d0`, `d1`, etc.;
+    /// if not specified, the port is chosen automatically
+    pub port: Option<Spanned<u32>>,
+    /// Name of the part's tracks
+    pub track_name: Option<Spanned<Cow<'s, str>>>,
+}
+impl MidiRouting<'_> {
+    pub fn validate(&mut self, diags: &Diagnostics) {
+        if let Some(port) = self.port
+            && port.value > 127
+        {
+            diags.err(
+                code::MIDI,
+                port.span,
+                "port numbers must be between 0 and 127",
+            );
+        }
+    }
+}
+
 #[derive(FromRawDirective)]
 /// Set the Csound instrument number or name for zero or more parts. If no part
 /// is specified, this becomes the default instrument for all parts without a
@@ -398,6 +600,118 @@ impl CsoundTemplate<'_> {
     pub fn validate(&mut self, _diags: &Diagnostics) {}
 }
 
+#[derive(FromRawDirective)]
+/// Set a named parameter of a part's Csound instrument at a point in the score,
+/// with a possible gradual change. This sets the Csound channel
+/// `p<n>_<name>`, where `<n>` is the part's Csound part number, so a custom
+/// Csound template can read it with `chnget` to control things like timbre, a
+/// filter, or panning. It is an error to name a part that doesn't appear
+/// somewhere in the score.
+pub struct CsoundParam<'s> {
+    pub span: Span,
+    /// Which part the parameter belongs to
+    pub part: Spanned<Identifier<'s>>,
+    /// Parameter name; the names `amp`, `notes`, and names starting with
+    /// `freq_` or `release_` are used by syntoniq.
+    pub name: Spanned<Identifier<'s>>,
+    /// Value of the parameter, which may be zero or negative
+    pub value: Spanned<Ratio<i32>>,
+    /// Optional effective time relative to the current score time. Defaults
+    /// to 0.
+    pub start_time: Option<Spanned<Ratio<u32>>>,
+    /// Optional end value; if specified, duration is required. Indicates that
+    /// the parameter should change gradually from `value` to `end_value` over
+    /// `duration` beats. The change is exponential, like Csound's `expseg`,
+    /// when both values are non-zero with the same sign, and linear, like
+    /// `linseg`, otherwise.
+    pub end_value: Option<Spanned<Ratio<i32>>>,
+    /// Must appear with `end_value` to indicate the duration of a gradual
+    /// change.
+    pub duration: Option<Spanned<Ratio<u32>>>,
+}
+impl CsoundParam<'_> {
+    pub fn validate(&mut self, diags: &Diagnostics) {
+        let name = &self.name.value.name;
+        if name == "amp"
+            || name == "notes"
+            || name.starts_with("freq_")
+            || name.starts_with("release_")
+        {
+            diags.err(
+                code::DIRECTIVE_USAGE,
+                self.name.span,
+                format!("parameter '{name}' is reserved for use by syntoniq"),
+            );
+        }
+        if self.end_value.is_some() != self.duration.is_some() {
+            diags.err(
+                code::USAGE,
+                self.span,
+                "'end_value' and 'duration' must either both be present or both be absent",
+            );
+        }
+    }
+}
+
+#[derive(FromRawDirective)]
+/// Set the sound used by the built-in WAV renderer and the SuperCollider
+/// output for zero or more parts. If no part is specified, this becomes the
+/// default sound for all parts without a specific sound. It is an error to
+/// name a part that doesn't appear somewhere in the score. Each note is a
+/// waveform shaped by an attack/decay/sustain/release envelope and passed
+/// through a low-pass filter.
+pub struct WavInstrument<'s> {
+    pub span: Span,
+    /// One of `sine`, `triangle`, `square`, or `sawtooth`; default is `triangle`
+    pub waveform: Option<Spanned<Cow<'s, str>>>,
+    /// Seconds for the envelope to rise to full level; default is 0.05
+    pub attack: Option<Spanned<Ratio<u32>>>,
+    /// Seconds for the envelope to fall to the sustain level; default is 0.05
+    pub decay: Option<Spanned<Ratio<u32>>>,
+    /// Sustain level from 0 to 1; default is 0.9
+    pub sustain: Option<Spanned<Ratio<u32>>>,
+    /// Seconds for the envelope to fall to silence after the note ends; default
+    /// is 0.15
+    pub release: Option<Spanned<Ratio<u32>>>,
+    /// Cutoff frequency of the low-pass filter in Hz; default is 2000
+    pub cutoff: Option<Spanned<u32>>,
+    /// Which parts use this sound; if not specified, all unassigned parts use
+    /// it
+    pub part: Vec<Spanned<Identifier<'s>>>,
+}
+impl WavInstrument<'_> {
+    pub fn validate(&mut self, diags: &Diagnostics) {
+        score_helpers::check_part(diags, &self.part);
+        if let Some(waveform) = &self.waveform
+            && Waveform::from_name(&waveform.value).is_none()
+        {
+            diags.err(
+                code::DIRECTIVE_USAGE,
+                waveform.span,
+                "waveform must be one of sine, triangle, square, or sawtooth",
+            );
+        }
+        if let Some(sustain) = self.sustain
+            && sustain.value > Ratio::from_integer(1)
+        {
+            diags.err(
+                code::DIRECTIVE_USAGE,
+                sustain.span,
+                "sustain must be between 0 and 1",
+            );
+        }
+        if let Some(cutoff) = self.cutoff
+            && cutoff.value == 0
+        {
+            diags.err(
+                code::DIRECTIVE_USAGE,
+                cutoff.span,
+                "cutoff must be greater than 0",
+            );
+        }
+    }
+}
+
 #[derive(FromRawDirective)]
 /// Set tempo, with possible accelerando or ritardando (gradual change).
 pub struct Tempo<'s> {
@@ -545,6 +859,9 @@ pub enum Directive<'s> {
     Syntoniq(Syntoniq<'s>),
     DefineScale(DefineScale<'s>),
     DefineGeneratedScale(DefineGeneratedScale<'s>),
+    DefineTemperamentScale(DefineTemperamentScale<'s>),
+    DefineMosScale(DefineMosScale<'s>),
+    DefineDerivedScale(DefineDerivedScale<'s>),
     UseScale(UseScale<'s>),
     Transpose(Transpose<'s>),
     SetBasePitch(SetBasePitch<'s>),
@@ -553,9 +870,12 @@ pub enum Directive<'s> {
     CheckPitch(CheckPitch<'s>),
     ResetTuning(ResetTuning<'s>),
     MidiInstrument(MidiInstrument<'s>),
+    MidiRouting(MidiRouting<'s>),
     CsoundInstrument(CsoundInstrument<'s>),
     CsoundGlobalInstrument(CsoundGlobalInstrument<'s>),
     CsoundTemplate(CsoundTemplate<'s>),
+    CsoundParam(CsoundParam<'s>),
+    WavInstrument(WavInstrument<'s>),
     Tempo(Tempo<'s>),
     Mark(Mark<'s>),
     Repeat(Repeat<'s>),
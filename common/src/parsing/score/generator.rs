@@ -5,7 +5,7 @@ use crate::parsing::pass1::{Parser1Intermediate, number_intermediate};
 use crate::parsing::score::{Assignments, Generator};
 use crate::pitch::{Factor, Pitch};
 use num_rational::Ratio;
-use num_traits::ToPrimitive;
+use num_traits::{CheckedDiv, ToPrimitive};
 use serde::Serialize;
 use std::borrow::Cow;
 use std::cmp::Ordering;
@@ -417,15 +417,7 @@ impl Candidate {
     }
 
     fn name(&self) -> Cow<'static, str> {
-        // Prepend `B` (2/1) for each octave, then append the letter for each step.
-        let mut s = String::new();
-        for _ in 0..self.note_path.octaves {
-            s.push('B');
-        }
-        s.push(Self::step_letter(self.note_path.step1));
-        if self.note_path.step2 != 0 {
-            s.push(Self::step_letter(self.note_path.step2));
-        }
+        let mut s = self.note_path.name();
         // Since we only consider candidates that are closest to a step, it is never necessary to
         // add `%` or `#` to the note name. This serves as a visual indicator that the match is
         // slightly farther away from the note. It seldom appears because we can get quite close
@@ -443,25 +435,65 @@ impl Candidate {
 /// When assigning note names, we limit ourselves to notes with at most two intervals other than
 /// `B` (ratio 2). This is an efficient intermediate representation. Logic is divided between
 /// `NotePath` and Candidate.
-struct NotePath {
+pub(crate) struct NotePath {
     octaves: i32,
     step1: i32,
     step2: i32,
 }
 impl NotePath {
-    fn candidate(
-        octaves: i32,
-        step1: i32,
-        step2: i32,
-        divided_interval: f64,
-        divisions: i32,
-    ) -> Option<Candidate> {
-        let note_path = NotePath {
-            octaves,
-            step1,
-            step2,
-        };
-        note_path.into_candidate(divided_interval, divisions)
+    /// Return single-letter and double-letter note paths in priority order. Paths that fall
+    /// outside of the range of interest are included; callers filter them out.
+    pub(crate) fn all(max_octaves: i32) -> (Vec<NotePath>, Vec<NotePath>) {
+        let mut paths1 = Vec::new();
+        let mut paths2 = Vec::new();
+        // Consider larger intervals (lower letters) first...
+        for step1 in 2..=25 {
+            // ...and consider the "up" direction before the "down" direction.
+            for neg1 in [false, true] {
+                // Never consider `b` (which drops a whole octave). Also ignore `d` and `c` since
+                // `Bd` = `C` and `Bc` = `D`.
+                if neg1 && step1 < 5 {
+                    continue;
+                }
+                // The loops so far give us `B`, `C`, `D`, `E`, `e`, `F`, `f`, `G`, `g`, etc.
+                // Consider as many leading `B` notes as necessary to get us within the interval
+                // range. This allows us to find things like `Bi` as a single-letter note for 8/9
+                // and also allows us to find names for notes that are farther than an octave away
+                // from the root on a scale that divides a larger interval. For example, `BE` would
+                // be step 16 in 19-ED3, which is very close to just extending 12-EDO to an octave
+                // and a fifth.
+                let step1 = if neg1 { -step1 } else { step1 };
+                for octaves in 0..=max_octaves {
+                    paths1.push(NotePath {
+                        octaves,
+                        step1,
+                        step2: 0,
+                    });
+                    // Consider all the second step refinements of the single-letter notes.
+                    if step1 == 2 {
+                        // We don't need to consider two-latter cases starting with `B` -- those are
+                        // automatically handled as single-letter cases since prepending `B` is free.
+                        continue;
+                    }
+                    // Allow step 2 to be the same size or smaller than step 1. Never consider a
+                    // step 2 of `b` (meaningless -- will always drop below the interval) or `c` or
+                    // `d` since we'd prefer to go up by `C` than down by `d` or up by `D` than down
+                    // by `c`. Notes like `II` (81/64) are valid, but any note where the second step
+                    // is larger than the first would be picked up in the other direction. In other
+                    // words, we don't need to look at `eC` -- we will find `Ce` instead.
+                    for step2 in std::cmp::max(step1.abs(), 5)..=25 {
+                        for neg2 in [false, true] {
+                            paths2.push(NotePath {
+                                octaves,
+                                step1,
+                                step2: if neg2 { -step2 } else { step2 },
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        (paths1, paths2)
     }
 
     fn step_to_ratio(step: i32) -> Ratio<i32> {
@@ -478,11 +510,40 @@ impl NotePath {
         }
     }
 
+    /// The root note, `A`
+    fn root() -> Self {
+        NotePath {
+            octaves: 0,
+            step1: 1,
+            step2: 0,
+        }
+    }
+
+    /// The generated note name for this path without any `#` or `%`
+    pub(crate) fn name(&self) -> String {
+        // Prepend `B` (2/1) for each octave, then append the letter for each step.
+        let mut s = String::new();
+        for _ in 0..self.octaves {
+            s.push('B');
+        }
+        s.push(Candidate::step_letter(self.step1));
+        if self.step2 != 0 {
+            s.push(Candidate::step_letter(self.step2));
+        }
+        s
+    }
+
+    /// The just intonation ratio represented by this path
+    pub(crate) fn ratio(&self) -> Ratio<u32> {
+        let r = Self::step_to_ratio(self.step1)
+            * Self::step_to_ratio(self.step2)
+            * Ratio::from_integer(2i32.pow(self.octaves as u32));
+        Ratio::new(*r.numer() as u32, *r.denom() as u32)
+    }
+
     fn into_candidate(self, divided_interval: f64, divisions: i32) -> Option<Candidate> {
         // Find which step the ratio is closest to.
-        let step1 = Self::step_to_ratio(self.step1);
-        let step2 = Self::step_to_ratio(self.step2);
-        let val = (step1 * step2).to_f64().unwrap() * f64::from(2i32.pow(self.octaves as u32));
+        let val = self.ratio().to_f64().unwrap();
         if !(1.0..divided_interval).contains(&val) {
             // If this falls outside the interval, discard it regardless of how close it is.
             return None;
@@ -543,69 +604,20 @@ impl Generator for NoteGenerator {
         let mut candidates1: Vec<Candidate> = vec![Candidate {
             closest_step: 0,
             delta: 0.0,
-            note_path: NotePath {
-                octaves: 0,
-                step1: 1,
-                step2: 0,
-            },
+            note_path: NotePath::root(),
         }];
-        let mut candidates2: Vec<Candidate> = Default::default();
         let divided_interval_f64 = self.divided_interval.to_f64().unwrap();
         let max_octaves = divided_interval_f64.log2().ceil() as i32;
-        // Consider larger intervals (lower letters) first...
-        for step1 in 2..=25 {
-            // ...and consider the "up" direction before the "down" direction.
-            for neg1 in [false, true] {
-                // Never consider `b` (which drops a whole octave). Also ignore `d` and `c` since
-                // `Bd` = `C` and `Bc` = `D`.
-                if neg1 && step1 < 5 {
-                    continue;
-                }
-                // The loops so far give us `B`, `C`, `D`, `E`, `e`, `F`, `f`, `G`, `g`, etc.
-                // Consider as many leading `B` notes as necessary to get us within the interval
-                // range. This allows us to find things like `Bi` as a single-letter note for 8/9
-                // and also allows us to find names for notes that are farther than an octave away
-                // from the root on a scale that divides a larger interval. For example, `BE` would
-                // be step 16 in 19-ED3, which is very close to just extending 12-EDO to an octave
-                // and a fifth.
-                for octaves in 0..=max_octaves {
-                    if let Some(candidate) = NotePath::candidate(
-                        octaves,
-                        if neg1 { -step1 } else { step1 },
-                        0,
-                        divided_interval_f64,
-                        divisions,
-                    ) {
-                        candidates1.push(candidate);
-                    }
-                    // Consider all the second step refinements of the single-letter notes.
-                    if step1 == 2 {
-                        // We don't need to consider two-latter cases starting with `B` -- those are
-                        // automatically handled as single-letter cases since prepending `B` is free.
-                        continue;
-                    }
-                    // Allow step 2 to be the same size or smaller than step 1. Never consider a
-                    // step 2 of `b` (meaningless -- will always drop below the interval) or `c` or
-                    // `d` since we'd prefer to go up by `C` than down by `d` or up by `D` than down
-                    // by `c`. Notes like `II` (81/64) are valid, but any note where the second step
-                    // is larger than the first would be picked up in the other direction. In other
-                    // words, we don't need to look at `eC` -- we will find `Ce` instead.
-                    for step2 in std::cmp::max(step1, 5)..=25 {
-                        for neg2 in [false, true] {
-                            if let Some(candidate) = NotePath::candidate(
-                                octaves,
-                                if neg1 { -step1 } else { step1 },
-                                if neg2 { -step2 } else { step2 },
-                                divided_interval_f64,
-                                divisions,
-                            ) {
-                                candidates2.push(candidate);
-                            }
-                        }
-                    }
-                }
-            }
-        }
+        let (paths1, paths2) = NotePath::all(max_octaves);
+        candidates1.extend(
+            paths1
+                .into_iter()
+                .filter_map(|p| p.into_candidate(divided_interval_f64, divisions)),
+        );
+        let candidates2: Vec<Candidate> = paths2
+            .into_iter()
+            .filter_map(|p| p.into_candidate(divided_interval_f64, divisions))
+            .collect();
         // After we've identified all the candidates, iterate through them, tracking the best pitch
         // we've seen so far. See docs on `Candidate`.
         let mut winners: HashMap<i32, Candidate> = Default::default();
@@ -656,6 +668,77 @@ impl Generator for NoteGenerator {
     }
 }
 
+/// Spells ratios as generated note names in the JI scale. Creating the table of short paths is
+/// relatively expensive, so create one speller and use it for all ratios.
+pub struct NoteSpeller {
+    /// Ratio and name of each path in priority order
+    paths: Vec<(Ratio<u32>, String)>,
+    by_ratio: HashMap<Ratio<u32>, String>,
+    /// Largest numerator and denominator of any path
+    max_terms: (u64, u64),
+}
+impl Default for NoteSpeller {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl NoteSpeller {
+    pub fn new() -> Self {
+        let (paths1, paths2) = NotePath::all(1);
+        let paths: Vec<_> = paths1
+            .iter()
+            .chain(&paths2)
+            .map(|path| (path.ratio(), path.name()))
+            .collect();
+        let mut by_ratio: HashMap<Ratio<u32>, String> = HashMap::new();
+        let mut max_terms = (0, 0);
+        for (ratio, name) in &paths {
+            by_ratio.entry(*ratio).or_insert_with(|| name.clone());
+            max_terms.0 = max_terms.0.max(u64::from(*ratio.numer()));
+            max_terms.1 = max_terms.1.max(u64::from(*ratio.denom()));
+        }
+        Self {
+            paths,
+            by_ratio,
+            max_terms,
+        }
+    }
+
+    /// Spell a ratio as a generated note name with as few letters as possible, preferring
+    /// earlier paths among names of the same length. Names of up to four letters, other than
+    /// leading `B`s, are considered.
+    pub fn spell(&self, ratio: Ratio<u32>) -> Option<String> {
+        if ratio == Ratio::from_integer(1) {
+            return Some(NotePath::root().name());
+        }
+        if let Some(name) = self.by_ratio.get(&ratio) {
+            return Some(name.clone());
+        }
+        // Try splitting the ratio into two paths, each of which has one or two letters. A product
+        // of two paths can't have larger terms than the product of their terms.
+        let (max_numer, max_denom) = self.max_terms;
+        if u64::from(*ratio.numer()) > max_numer * max_numer
+            || u64::from(*ratio.denom()) > max_denom * max_denom
+        {
+            return None;
+        }
+        let mut best: Option<String> = None;
+        for (path_ratio, path_name) in &self.paths {
+            // Large ratios can overflow; they won't have short names anyway.
+            if let Some(rest) = ratio
+                .checked_div(path_ratio)
+                .and_then(|r| self.by_ratio.get(&r))
+            {
+                let name = format!("{path_name}{rest}");
+                if best.as_ref().is_none_or(|b| name.len() < b.len()) {
+                    best = Some(name);
+                }
+            }
+        }
+        best
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -664,6 +747,44 @@ mod tests {
         Spanned::new(0..n.len(), n)
     }
 
+    #[test]
+    fn test_spell() {
+        let speller = NoteSpeller::new();
+        for (ratio, name) in [
+            ((1, 1), "A"),
+            ((3, 2), "C"),
+            ((16, 9), "Bi"),
+            ((27, 16), "CI"),
+            ((81, 80), "Ij"),
+        ] {
+            let ratio = Ratio::new(ratio.0, ratio.1);
+            let spelled = speller.spell(ratio).unwrap();
+            assert_eq!(spelled, name);
+        }
+        // Every 5-limit ratio in a small neighborhood can be spelled, and the spelling has the
+        // right pitch.
+        let g = NoteGenerator {
+            divisions: None,
+            divided_interval: Ratio::from_integer(2),
+            tolerance: Pitch::unit(),
+        };
+        let diags = Diagnostics::new();
+        for e3 in -3..=3 {
+            for e5 in -2..=2 {
+                let mut ratio = Ratio::new(3u32, 1).pow(e3) * Ratio::new(5u32, 1).pow(e5);
+                while ratio < Ratio::from_integer(1) {
+                    ratio *= 2;
+                }
+                while ratio >= Ratio::from_integer(2) {
+                    ratio /= 2;
+                }
+                let pitch = Pitch::from(ratio);
+                let name = speller.spell(ratio).unwrap();
+                assert_eq!(g.get_note(&diags, &make_note(&name)).unwrap(), pitch);
+            }
+        }
+    }
+
     #[test]
     fn test_generator() {
         // EDO Overlay
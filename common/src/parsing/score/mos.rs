@@ -0,0 +1,216 @@
+use crate::pitch::Pitch;
+use num_rational::Ratio;
+use num_traits::ToPrimitive;
+
+/// Pitches closer than this fraction of a period are considered the same.
+const EPSILON: f64 = 1e-9;
+
+/// A moment-of-symmetry scale: a chain of generators, reduced by the period, whose steps come in
+/// exactly two sizes
+pub struct Mos {
+    /// For each scale degree in ascending order, the number of generators above the root
+    pub chain: Vec<i32>,
+    /// Sequence of large (`L`) and small (`s`) steps
+    pub pattern: String,
+    /// Size of the large step in cents
+    pub large: f64,
+    /// Size of the small step in cents
+    pub small: f64,
+}
+
+impl Mos {
+    /// Largest number of notes in a MOS scale
+    pub const MAX_SIZE: u32 = 1000;
+
+    /// Return an error unless the period is greater than 1.
+    pub fn check_period(period: Ratio<u32>) -> Result<(), String> {
+        if period <= Ratio::from_integer(1) {
+            return Err("period must be greater than 1".to_string());
+        }
+        Ok(())
+    }
+
+    /// Return an error if the generator is a unison or a multiple of the period, in which case
+    /// every note of the chain is the root.
+    pub fn check_generator(period: Ratio<u32>, generator: &Pitch) -> Result<(), String> {
+        let generator_periods = generator.as_float().log2() / period.to_f64().unwrap().log2();
+        if (generator_periods - generator_periods.round()).abs() < EPSILON {
+            return Err("generator must not be a unison or a multiple of the period".to_string());
+        }
+        Ok(())
+    }
+
+    /// Stack `size` generators, starting `mode` generators below the root, and reduce by the
+    /// period. Return an error if the result is not a MOS. The analysis is done in terms of
+    /// generator counts rather than exact pitches so that, for example, eight notes of a chain
+    /// of 12-EDO fifths, which happen to have only two step sizes, are not considered a MOS.
+    /// Exact pitches are only computed by [`Mos::pitches`].
+    pub fn new(
+        period: Ratio<u32>,
+        generator: &Pitch,
+        size: u32,
+        mode: u32,
+    ) -> Result<Self, String> {
+        if size < 2 {
+            return Err("size must be at least 2".to_string());
+        }
+        if size > Self::MAX_SIZE {
+            return Err(format!("size must be at most {}", Self::MAX_SIZE));
+        }
+        if mode >= size {
+            return Err("mode must be less than size".to_string());
+        }
+        Self::check_period(period)?;
+        Self::check_generator(period, generator)?;
+        let period_octaves = period.to_f64().unwrap().log2();
+        let generator_periods = generator.as_float().log2() / period_octaves;
+        // Position of each note within the period, as a fraction of the period
+        let mut notes: Vec<(f64, i32)> = Vec::new();
+        // The same positions in ascending order, for finding repeats
+        let mut sorted: Vec<f64> = Vec::new();
+        let mode = mode as i32;
+        for k in -mode..size as i32 - mode {
+            let position = (f64::from(k) * generator_periods).rem_euclid(1.0);
+            let position = if 1.0 - position < EPSILON {
+                0.0
+            } else {
+                position
+            };
+            let idx = sorted.partition_point(|&p| p < position);
+            let repeats = |i: Option<usize>| {
+                i.and_then(|i| sorted.get(i))
+                    .is_some_and(|p| (p - position).abs() < EPSILON)
+            };
+            if repeats(Some(idx)) || repeats(idx.checked_sub(1)) {
+                return Err(format!(
+                    "the generator chain repeats after {} notes",
+                    notes.len()
+                ));
+            }
+            sorted.insert(idx, position);
+            notes.push((position, k));
+        }
+        notes.sort_by(|a, b| a.0.total_cmp(&b.0));
+        // Each step spans a fixed number of generators. A MOS has exactly two kinds of steps.
+        let mut kinds: Vec<(i32, f64)> = Vec::new();
+        let mut steps = Vec::new();
+        for (i, &(position, k)) in notes.iter().enumerate() {
+            let (next_position, next_k) = notes
+                .get(i + 1)
+                .copied()
+                .unwrap_or((notes[0].0 + 1.0, notes[0].1));
+            let generators = next_k - k;
+            if !kinds.iter().any(|&(g, _)| g == generators) {
+                kinds.push((generators, next_position - position));
+            }
+            steps.push(generators);
+        }
+        if kinds.len() != 2 || (kinds[0].1 - kinds[1].1).abs() < EPSILON {
+            let sizes = if kinds.len() == 2 { 1 } else { kinds.len() };
+            return Err(format!(
+                "{size} notes do not form a MOS; there are {sizes} step sizes instead of 2"
+            ));
+        }
+        if kinds[0].1 < kinds[1].1 {
+            kinds.swap(0, 1);
+        }
+        let pattern = steps
+            .iter()
+            .map(|&g| if g == kinds[0].0 { 'L' } else { 's' })
+            .collect();
+        let cents = period_octaves * 1200.0;
+        Ok(Self {
+            chain: notes.into_iter().map(|(_, k)| k).collect(),
+            pattern,
+            large: kinds[0].1 * cents,
+            small: kinds[1].1 * cents,
+        })
+    }
+
+    /// Exact pitches of the scale degrees relative to the root, within the period, in ascending
+    /// order. Return an error if a pitch is too large to represent, which happens with long
+    /// chains of pure intervals.
+    pub fn pitches(&self, period: Ratio<u32>, generator: &Pitch) -> Result<Vec<Pitch>, String> {
+        let inverse = generator.invert();
+        self.chain
+            .iter()
+            .map(|&k| {
+                // Reduce after each step to keep the numbers small.
+                let step = if k < 0 { &inverse } else { generator };
+                let mut pitch = Pitch::unit();
+                for _ in 0..k.abs() {
+                    pitch = pitch
+                        .checked_mul(step)
+                        .and_then(|p| p.checked_normalized(period))
+                        .ok_or_else(|| {
+                            format!(
+                                "a chain of {} generators is too large to represent exactly",
+                                self.chain.len()
+                            )
+                        })?
+                        .0;
+                }
+                Ok(pitch)
+            })
+            .collect()
+    }
+
+    /// Systematic note names for each degree: the nominals `J`, `K`, `L`, etc. when there are
+    /// enough letters, and `An` otherwise
+    pub fn note_names(&self) -> Vec<String> {
+        (0..self.chain.len())
+            .map(|i| match char::from_u32('J' as u32 + i as u32) {
+                Some(ch) if self.chain.len() <= 17 => ch.to_string(),
+                _ => format!("A{i}"),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mos() {
+        let fifth = Pitch::must_parse("^7|12");
+        let two = Ratio::from_integer(2);
+        let m = Mos::new(two, &fifth, 7, 1).unwrap();
+        assert_eq!(m.pattern, "LLsLLLs");
+        assert_eq!(m.chain, [0, 2, 4, -1, 1, 3, 5]);
+        assert_eq!(
+            m.pitches(two, &fifth).unwrap(),
+            ["1", "^1|6", "^1|3", "^5|12", "^7|12", "^3|4", "^11|12"].map(Pitch::must_parse)
+        );
+        assert!((m.large - 200.0).abs() < 1e-6);
+        assert!((m.small - 100.0).abs() < 1e-6);
+        assert_eq!(m.note_names(), ["J", "K", "L", "M", "N", "O", "P"]);
+        assert_eq!(Mos::new(two, &fifth, 7, 0).unwrap().pattern, "LLLsLLs");
+        assert_eq!(Mos::new(two, &fifth, 5, 0).unwrap().pattern, "ssLsL");
+        for (size, sizes) in [(6, 3), (8, 3), (12, 1)] {
+            assert_eq!(
+                Mos::new(two, &fifth, size, 0).err().unwrap(),
+                format!(
+                    "{size} notes do not form a MOS; there are {sizes} step sizes instead of 2"
+                )
+            );
+        }
+        assert_eq!(
+            Mos::new(two, &fifth, 13, 0).err().unwrap(),
+            "the generator chain repeats after 12 notes"
+        );
+        assert_eq!(
+            Mos::new(two, &Pitch::must_parse("3/2"), 1001, 0)
+                .err()
+                .unwrap(),
+            "size must be at most 1000"
+        );
+        // Pythagorean pentatonic
+        let m = Mos::new(two, &Pitch::must_parse("3/2"), 5, 1).unwrap();
+        assert_eq!(m.pattern, "sLssL");
+        assert_eq!(
+            m.pitches(two, &Pitch::must_parse("3/2")).unwrap(),
+            ["1", "9/8", "4/3", "3/2", "27/16"].map(Pitch::must_parse)
+        );
+    }
+}
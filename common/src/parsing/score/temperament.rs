@@ -0,0 +1,515 @@
+use crate::parsing::diagnostics::{Diagnostics, code};
+use crate::parsing::model::Spanned;
+use crate::parsing::score::generator::{NoteGenerator, NotePath};
+use crate::parsing::score::{Assignments, Generator};
+use crate::pitch::{Factor, Pitch};
+use num_rational::Ratio;
+use num_traits::{CheckedMul, ToPrimitive, Zero};
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Number of divisions of the octave used to represent an optimized generator: a thousandth of
+/// a cent
+const GENERATOR_RESOLUTION: i32 = 1_200_000;
+
+/// A regular temperament. Each prime maps to a whole number of each unit. A temperament defined
+/// by a val has a single unit, one step of the equal division. A temperament defined by commas
+/// has two units: the period and the generator.
+struct Temperament {
+    primes: Vec<u32>,
+    /// Tempered size of each unit as a base raised to an exponent
+    units: Vec<(Ratio<u32>, Ratio<i32>)>,
+    /// `mapping[unit][prime]` is the number of `unit`s that `prime` maps to
+    mapping: Vec<Vec<i32>>,
+}
+
+/// Return the exponent of each prime in the factorization of `r`, or `None` if `r` has other
+/// prime factors.
+fn monzo(r: Ratio<u32>, primes: &[u32]) -> Option<Vec<i32>> {
+    let mut result = vec![0; primes.len()];
+    for (n, sign) in [(*r.numer(), 1), (*r.denom(), -1)] {
+        let mut n = n;
+        for (i, &p) in primes.iter().enumerate() {
+            while n.is_multiple_of(p) {
+                n /= p;
+                result[i] += sign;
+            }
+        }
+        if n != 1 {
+            return None;
+        }
+    }
+    Some(result)
+}
+
+fn add_primes(n: u32, primes: &mut Vec<u32>) {
+    let mut n = n;
+    let mut p = 2;
+    while n > 1 {
+        if n.is_multiple_of(p) {
+            if !primes.contains(&p) {
+                primes.push(p);
+            }
+            n /= p;
+        } else {
+            p += 1;
+        }
+    }
+}
+
+/// Solve `matrix·x = rhs` using Gaussian elimination. Return `None` if `matrix` is singular.
+fn solve(mut matrix: Vec<Vec<Ratio<i64>>>, mut rhs: Vec<Ratio<i64>>) -> Option<Vec<Ratio<i64>>> {
+    let size = rhs.len();
+    for col in 0..size {
+        let pivot = (col..size).find(|&row| !matrix[row][col].is_zero())?;
+        matrix.swap(col, pivot);
+        rhs.swap(col, pivot);
+        let pivot_row = matrix[col].clone();
+        for row in 0..size {
+            if row != col && !matrix[row][col].is_zero() {
+                let factor = matrix[row][col] / pivot_row[col];
+                for (x, p) in matrix[row].iter_mut().zip(&pivot_row).skip(col) {
+                    *x -= factor * p;
+                }
+                let v = rhs[col];
+                rhs[row] -= factor * v;
+            }
+        }
+    }
+    Some((0..size).map(|i| rhs[i] / matrix[i][i]).collect())
+}
+
+impl Temperament {
+    fn from_val(cycle: Ratio<u32>, val: &[u32]) -> Result<Self, String> {
+        let primes: Vec<u32> = primal::Primes::all()
+            .take(val.len())
+            .map(|p| p as u32)
+            .collect();
+        let mapping = vec![val.iter().map(|&x| x as i32).collect()];
+        let mut t = Temperament {
+            primes,
+            units: vec![(cycle, Ratio::from_integer(1))],
+            mapping,
+        };
+        let steps = t
+            .map(cycle)
+            .ok_or_else(|| format!("the val does not include all the primes in {cycle}"))?[0];
+        if steps <= 0 {
+            return Err(format!("the val maps {cycle} to {steps} steps"));
+        }
+        t.units[0].1 = Ratio::new(1, steps);
+        Ok(t)
+    }
+
+    fn from_commas(
+        cycle: Ratio<u32>,
+        period: Ratio<u32>,
+        generator: Ratio<u32>,
+        commas: &[Ratio<u32>],
+    ) -> Result<Self, String> {
+        // The period maps to one period, the generator maps to one generator, and each comma
+        // maps to nothing. If these intervals form a basis for the lattice of their primes, this
+        // determines the mapping of every prime.
+        let mut primes = Vec::new();
+        for r in [cycle, period, generator].iter().chain(commas) {
+            add_primes(*r.numer(), &mut primes);
+            add_primes(*r.denom(), &mut primes);
+        }
+        primes.sort_unstable();
+        if primes.len() != commas.len() + 2 {
+            let primes: Vec<String> = primes.iter().map(u32::to_string).collect();
+            return Err(format!(
+                "a rank-2 temperament of primes {} requires {} comma(s), not {}",
+                primes.join(", "),
+                primes.len().saturating_sub(2),
+                commas.len(),
+            ));
+        }
+        let basis: Vec<Vec<Ratio<i64>>> = [period, generator]
+            .iter()
+            .chain(commas)
+            .map(|&r| {
+                monzo(r, &primes)
+                    .unwrap()
+                    .into_iter()
+                    .map(|x| Ratio::from_integer(i64::from(x)))
+                    .collect()
+            })
+            .collect();
+        let mut mapping = Vec::new();
+        for unit in 0..2 {
+            let target = (0..basis.len())
+                .map(|i| Ratio::from_integer(i64::from(i == unit)))
+                .collect();
+            let row = solve(basis.clone(), target)
+                .ok_or("the period, generator, and commas are not independent")?;
+            if row.iter().any(|x| !x.is_integer()) {
+                return Err(
+                    "some primes are not a whole number of periods and generators; \
+                     try a different generator"
+                        .to_string(),
+                );
+            }
+            mapping.push(row.iter().map(|x| x.to_integer() as i32).collect());
+        }
+        let mut t = Temperament {
+            primes,
+            units: vec![
+                (cycle, Ratio::from_integer(1)),
+                (Ratio::from_integer(2), Ratio::from_integer(1)),
+            ],
+            mapping,
+        };
+        let cycle_map = t.map(cycle).unwrap();
+        if cycle_map[0] <= 0 || cycle_map[1] != 0 {
+            return Err(format!(
+                "the cycle ratio {cycle} must map to a whole number of periods"
+            ));
+        }
+        let periods = cycle_map[0];
+        t.units[0].1 = Ratio::new(1, periods);
+        t.units[1].1 = t.optimal_generator();
+        Ok(t)
+    }
+
+    /// Tune the generator to minimize the Tenney-weighted squared error of the primes while
+    /// keeping the period pure. Return the generator as an exponent of 2.
+    fn optimal_generator(&self) -> Ratio<i32> {
+        let (cycle, period_exp) = self.units[0];
+        let period = cycle.to_f64().unwrap().log2() * period_exp.to_f64().unwrap();
+        let mut num = 0.0;
+        let mut den = 0.0;
+        for (i, &p) in self.primes.iter().enumerate() {
+            let size = f64::from(p).log2();
+            let weight = 1.0 / (size * size);
+            let periods = f64::from(self.mapping[0][i]);
+            let generators = f64::from(self.mapping[1][i]);
+            num += weight * generators * (size - periods * period);
+            den += weight * generators * generators;
+        }
+        let octaves = num / den;
+        Ratio::new(
+            (octaves * f64::from(GENERATOR_RESOLUTION)).round() as i32,
+            GENERATOR_RESOLUTION,
+        )
+    }
+
+    /// Return the number of each unit that `r` maps to.
+    fn map(&self, r: Ratio<u32>) -> Option<Vec<i32>> {
+        let m = monzo(r, &self.primes)?;
+        Some(
+            self.mapping
+                .iter()
+                .map(|row| row.iter().zip(&m).map(|(a, b)| a * b).sum())
+                .collect(),
+        )
+    }
+
+    /// Return the pitch of the given number of each unit, or `None` if it is too large to
+    /// represent.
+    fn pitch(&self, counts: &[i32]) -> Option<Pitch> {
+        let factors = self
+            .units
+            .iter()
+            .zip(counts)
+            .map(|((base, exp), &count)| {
+                let exp = exp.checked_mul(&Ratio::from_integer(count))?;
+                Factor::new(*base.numer(), *base.denom(), *exp.numer(), *exp.denom()).ok()
+            })
+            .collect::<Option<_>>()?;
+        Pitch::checked_new(factors)
+    }
+
+    fn temper(&self, r: Ratio<u32>) -> Option<Pitch> {
+        self.pitch(&self.map(r)?)
+    }
+}
+
+/// An error from building a temperament scale
+#[derive(Debug, PartialEq)]
+pub(crate) enum ScaleError {
+    /// The temperament can't be built from the directive's parameters.
+    Temperament(String),
+    /// The scale can't have the requested number of notes.
+    Size(String),
+}
+
+impl From<String> for ScaleError {
+    fn from(e: String) -> Self {
+        ScaleError::Temperament(e)
+    }
+}
+
+impl From<&str> for ScaleError {
+    fn from(e: &str) -> Self {
+        ScaleError::Temperament(e.to_string())
+    }
+}
+
+pub(crate) struct TemperamentScale {
+    pub cycle: Ratio<u32>,
+    pub period: Ratio<u32>,
+    pub generator: Ratio<u32>,
+    pub commas: Vec<Ratio<u32>>,
+    pub val: Vec<u32>,
+    pub size: u32,
+    pub mode: u32,
+}
+
+pub(crate) struct TemperamentGenerator {
+    cycle: Ratio<u32>,
+    temperament: Temperament,
+    ji: NoteGenerator,
+    /// Normalized pitch of each scale degree
+    pitches: Vec<Pitch>,
+    degrees: HashMap<Pitch, usize>,
+    /// Assigned names; includes `An` for each degree
+    names: HashMap<String, Pitch>,
+    primary_names: Vec<String>,
+}
+
+impl TemperamentScale {
+    #[allow(clippy::too_many_lines)]
+    pub fn into_generator(self) -> Result<TemperamentGenerator, ScaleError> {
+        let temperament = if self.val.is_empty() {
+            Temperament::from_commas(self.cycle, self.period, self.generator, &self.commas)?
+        } else {
+            Temperament::from_val(self.cycle, &self.val)?
+        };
+        let period = temperament
+            .map(self.period)
+            .ok_or("the val does not include all the primes in the period")?;
+        let generator = temperament
+            .map(self.generator)
+            .ok_or("the val does not include all the primes in the generator")?;
+        // The number of periods in the cycle is the ratio of the first unit's counts, which is
+        // either the number of steps (for a val) or periods (for commas).
+        let cycle_units = temperament.map(self.cycle).unwrap()[0];
+        if period[0] <= 0 || cycle_units % period[0] != 0 {
+            return Err(format!(
+                "the cycle ratio {} is not a whole number of periods",
+                self.cycle
+            )
+            .into());
+        }
+        let periods = (cycle_units / period[0]) as u32;
+        if !self.size.is_multiple_of(periods) {
+            return Err(ScaleError::Size(format!(
+                "size must be a multiple of the number of periods ({periods})"
+            )));
+        }
+        let per_period = (self.size / periods) as i32;
+        let mode = self.mode as i32;
+        if mode >= per_period {
+            return Err(format!(
+                "mode must be less than the number of notes per period ({per_period})"
+            )
+            .into());
+        }
+        let mut pitches = Vec::new();
+        for j in 0..periods as i32 {
+            for i in -mode..per_period - mode {
+                let counts: Vec<i32> = period
+                    .iter()
+                    .zip(&generator)
+                    .map(|(p, g)| j * p + i * g)
+                    .collect();
+                let Some((pitch, _)) = temperament
+                    .pitch(&counts)
+                    .and_then(|p| p.checked_normalized(self.cycle))
+                else {
+                    return Err(ScaleError::Size(format!(
+                        "size {} is too large; the pitches of the generator chain can't be represented exactly",
+                        self.size
+                    )));
+                };
+                if pitches.contains(&pitch) {
+                    return Err(ScaleError::Size(format!(
+                        "size {} is too large; the generator chain repeats pitch {pitch}",
+                        self.size
+                    )));
+                }
+                pitches.push(pitch);
+            }
+        }
+        pitches.sort();
+        let degrees: HashMap<Pitch, usize> = pitches
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (p.clone(), i))
+            .collect();
+
+        // Name each degree after the simplest generated note whose tempered pitch lands on it,
+        // preferring single-letter names, as with equal divisions. Use `An` for any degree that
+        // doesn't have such a name.
+        let mut names: HashMap<String, Pitch> = pitches
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (format!("A{i}"), p.clone()))
+            .collect();
+        let mut primary_names: Vec<Option<String>> = vec![None; pitches.len()];
+        primary_names[0] = Some("A".to_string());
+        let max_octaves = self.cycle.to_f64().unwrap().log2().ceil() as i32;
+        let (paths1, paths2) = NotePath::all(max_octaves);
+        for path in paths1.into_iter().chain(paths2) {
+            let ratio = path.ratio();
+            if ratio < Ratio::from_integer(1) || ratio >= self.cycle {
+                continue;
+            }
+            let Some(pitch) = temperament.temper(ratio) else {
+                continue;
+            };
+            if let Some(&degree) = degrees.get(&pitch.normalized(self.cycle).0)
+                && primary_names[degree].is_none()
+            {
+                let name = path.name();
+                primary_names[degree] = Some(name.clone());
+                names.insert(name, pitch);
+            }
+        }
+        let primary_names = primary_names
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| name.unwrap_or_else(|| format!("A{i}")))
+            .collect();
+
+        Ok(TemperamentGenerator {
+            cycle: self.cycle,
+            temperament,
+            ji: NoteGenerator {
+                divisions: None,
+                divided_interval: self.cycle,
+                tolerance: Pitch::unit(),
+            },
+            pitches,
+            degrees,
+            names,
+            primary_names,
+        })
+    }
+}
+
+impl Generator for TemperamentGenerator {
+    fn get_note(&self, diags: &Diagnostics, name: &Spanned<&str>) -> Option<Pitch> {
+        if let Some(pitch) = self.names.get(name.value) {
+            return Some(pitch.clone());
+        }
+        let ratio = self.ji.get_note(diags, name)?.as_rational();
+        let Some(ratio) = ratio else {
+            diags.err(
+                code::GENERATED_NOTE,
+                name.span,
+                "only just intonation note names may be used with a temperament scale",
+            );
+            return None;
+        };
+        let pitch = self.temperament.temper(ratio)?;
+        self.degrees
+            .contains_key(&pitch.normalized(self.cycle).0)
+            .then_some(pitch)
+    }
+
+    fn assign_generated_notes(&self) -> Assignments {
+        let notes = self
+            .names
+            .iter()
+            .map(|(name, pitch)| (Cow::Owned(name.clone()), pitch.clone()))
+            .collect();
+        let primary_names = self
+            .pitches
+            .iter()
+            .zip(&self.primary_names)
+            .map(|(pitch, name)| (pitch.clone(), Cow::Owned(name.clone())))
+            .collect();
+        Assignments {
+            notes,
+            primary_names,
+            deltas: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scale(generator: &str, commas: &[&str], val: &[u32], size: u32) -> TemperamentScale {
+        TemperamentScale {
+            cycle: Ratio::from_integer(2),
+            period: Ratio::from_integer(2),
+            generator: generator.parse().unwrap(),
+            commas: commas.iter().map(|c| c.parse().unwrap()).collect(),
+            val: val.to_vec(),
+            size,
+            mode: 1,
+        }
+    }
+
+    fn note(g: &TemperamentGenerator, name: &str) -> Option<Pitch> {
+        let diags = Diagnostics::new();
+        let p = g.get_note(&diags, &Spanned::new(0..name.len(), name));
+        assert!(!diags.has_errors());
+        p
+    }
+
+    #[test]
+    fn test_meantone() {
+        let g = scale("3/2", &["81/80"], &[], 7).into_generator().unwrap();
+        // The optimized fifth falls between 1/4-comma (696.6¢) and 1/6-comma (698.4¢) meantone.
+        let fifth = note(&g, "C").unwrap();
+        assert_eq!(fifth, Pitch::must_parse("^348607|600000"));
+        assert_eq!(note(&g, "E"), note(&g, "II"));
+        assert_eq!(note(&g, "Bf"), note(&g, "A5"));
+        // 7/4 is outside the temperament, and 6/5 is not in the major scale.
+        assert_eq!(note(&g, "Bh"), None);
+        assert_eq!(note(&g, "F"), None);
+        assert_eq!(
+            g.primary_names,
+            ["A", "I", "E", "D", "C", "Bf", "Bp"].map(str::to_string)
+        );
+    }
+
+    #[test]
+    fn test_val() {
+        // Meantone as a subset of 31-EDO
+        let g = scale("3/2", &[], &[31, 49, 72], 7)
+            .into_generator()
+            .unwrap();
+        assert_eq!(note(&g, "C").unwrap(), Pitch::must_parse("^18|31"));
+        assert_eq!(note(&g, "E").unwrap(), Pitch::must_parse("^10|31"));
+        assert_eq!(note(&g, "A6").unwrap(), Pitch::must_parse("^28|31"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = |s: TemperamentScale| match s.into_generator().err().unwrap() {
+            ScaleError::Temperament(e) => e,
+            ScaleError::Size(e) => panic!("unexpected size error: {e}"),
+        };
+        let size_err = |s: TemperamentScale| match s.into_generator().err().unwrap() {
+            ScaleError::Size(e) => e,
+            ScaleError::Temperament(e) => panic!("unexpected temperament error: {e}"),
+        };
+        assert_eq!(
+            err(scale("15/8", &[], &[], 7)),
+            "a rank-2 temperament of primes 2, 3, 5 requires 1 comma(s), not 0"
+        );
+        assert_eq!(
+            err(scale("15/8", &["225/64"], &[], 7)),
+            "the period, generator, and commas are not independent"
+        );
+        assert_eq!(
+            err(scale("9/8", &["81/80"], &[], 7)),
+            "some primes are not a whole number of periods and generators; try a different generator"
+        );
+        assert_eq!(
+            size_err(scale("3/2", &[], &[12, 19, 28], 13)),
+            "size 13 is too large; the generator chain repeats pitch ^5|12"
+        );
+        assert!(scale("3/2", &["81/80"], &[], 50).into_generator().is_ok());
+        assert_eq!(
+            size_err(scale("3/2", &["81/80"], &[], 100)),
+            "size 100 is too large; the pitches of the generator chain can't be represented exactly"
+        );
+    }
+}
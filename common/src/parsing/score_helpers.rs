@@ -218,6 +218,13 @@ impl CheckValue<'_> for Ratio<u32> {
     }
 }
 
+impl CheckValue<'_> for Ratio<i32> {
+    fn check_value(pv: &ParamValue) -> Result<Self, impl AsRef<str>> {
+        pv.try_as_signed_ratio()
+            .ok_or("should be a rational number or decimal, which may be zero or negative")
+    }
+}
+
 impl CheckValue<'_> for Pitch {
     fn check_value(pv: &ParamValue) -> Result<Self, impl AsRef<str>> {
         pv.try_as_pitch().cloned().ok_or("should be a pitch")
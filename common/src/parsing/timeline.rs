@@ -2,7 +2,7 @@ use crate::parsing::model::Span;
 use crate::parsing::score::{ScalesByName, serialize_scales};
 use crate::pitch::Pitch;
 use num_rational::Ratio;
-use num_traits::ToPrimitive;
+use num_traits::{CheckedAdd, CheckedMul, CheckedSub, ToPrimitive};
 use serde::Serialize;
 use std::borrow::Cow;
 use std::collections::{BTreeMap, BTreeSet};
@@ -15,9 +15,12 @@ pub struct Timeline<'s> {
     #[serde(with = "serialize_scales")]
     pub scales: Arc<ScalesByName<'s>>,
     pub midi_instruments: BTreeMap<Cow<'s, str>, MidiInstrumentNumber>,
+    /// MIDI routing for parts in the order given in the score
+    pub midi_routings: Vec<MidiRoutingSettings<'s>>,
     pub csound_instruments: BTreeMap<Cow<'s, str>, CsoundInstrumentId<'s>>,
     pub csound_global_instruments: Vec<CsoundGlobalInstrument<'s>>,
     pub csound_template: Option<Cow<'s, str>>,
+    pub wav_instruments: BTreeMap<Cow<'s, str>, WavInstrumentSettings>,
     /// Least common multiple of time denominators, useful for computing ticks per beat. Callers
     /// should not count on 100% of denominators being a factor, but all denominators of note and
     /// duration values will be. This means you should take the numerator of the floor of the
@@ -36,6 +39,48 @@ pub struct TimeBoundaries {
     pub end_time: Ratio<u32>,
 }
 
+/// A value that changes linearly over the course of an event
+trait Interpolate: Copy {
+    /// Return the value that is `fraction` of the way from `start` to `end`
+    fn between(start: Self, end: Self, fraction: Ratio<u32>) -> Self;
+}
+
+impl Interpolate for Ratio<u32> {
+    fn between(start: Self, end: Self, fraction: Ratio<u32>) -> Self {
+        if start <= end {
+            start + fraction * (end - start)
+        } else {
+            start - fraction * (start - end)
+        }
+    }
+}
+
+impl Interpolate for Ratio<i32> {
+    fn between(start: Self, end: Self, fraction: Ratio<u32>) -> Self {
+        // Compute in 128 bits so that values far apart can't overflow. If the exact result
+        // doesn't fit back into 32 bits, use the closest value that does.
+        let wide = |x: Self| Ratio::new(i128::from(*x.numer()), i128::from(*x.denom()));
+        let fraction = Ratio::new(i128::from(*fraction.numer()), i128::from(*fraction.denom()));
+        let (start_wide, end_wide) = (wide(start), wide(end));
+        end_wide
+            .checked_sub(&start_wide)
+            .and_then(|x| x.checked_mul(&fraction))
+            .and_then(|x| x.checked_add(&start_wide))
+            .and_then(|x| {
+                Some(Ratio::new(
+                    i32::try_from(*x.numer()).ok()?,
+                    i32::try_from(*x.denom()).ok()?,
+                ))
+            })
+            .or_else(|| {
+                let start = start.to_f64()?;
+                let value = start + fraction.to_f64()? * (end.to_f64()? - start);
+                Ratio::approximate_float(value)
+            })
+            .unwrap_or(start)
+    }
+}
+
 enum TimePosition {
     Before,
     After,
@@ -57,13 +102,29 @@ impl TimelineEvent<'_> {
             TimelineData::Note(e) => e.value.pitches.last().map(|x| x.end_time),
             TimelineData::Tempo(e) => e.end_bpm.as_ref().map(|x| x.time),
             TimelineData::Dynamic(e) => e.end_level.as_ref().map(|x| x.time),
-            TimelineData::Mark(_) | TimelineData::RepeatStart(_) | TimelineData::RepeatEnd(_) => {
-                None
-            }
+            TimelineData::CsoundParam(e) => e.end_value.as_ref().map(|x| x.time),
+            TimelineData::Mark(_)
+            | TimelineData::RepeatStart(_)
+            | TimelineData::RepeatEnd(_)
+            | TimelineData::BarLine => None,
         };
         maybe.unwrap_or(self.time)
     }
 
+    /// The part the event belongs to, or `None` for events that apply to the whole score
+    pub fn part(&self) -> Option<&str> {
+        match &self.data {
+            TimelineData::Dynamic(e) => Some(e.part),
+            TimelineData::CsoundParam(e) => Some(&e.part),
+            TimelineData::Note(e) => Some(e.part_note.part),
+            TimelineData::Tempo(_)
+            | TimelineData::Mark(_)
+            | TimelineData::RepeatStart(_)
+            | TimelineData::RepeatEnd(_)
+            | TimelineData::BarLine => None,
+        }
+    }
+
     fn add_or_subtract(v: &mut Ratio<u32>, delta: Ratio<u32>, subtract: bool) {
         if subtract {
             if &delta > v {
@@ -76,9 +137,9 @@ impl TimelineEvent<'_> {
         }
     }
 
-    fn interpolate(
-        value_start: &mut Ratio<u32>,
-        value_end: &mut Ratio<u32>,
+    fn interpolate<T: Interpolate>(
+        value_start: &mut T,
+        value_end: &mut T,
         event_start: &mut Ratio<u32>,
         event_end: &mut Ratio<u32>,
         boundaries: &TimeBoundaries,
@@ -102,21 +163,14 @@ impl TimelineEvent<'_> {
             // This event falls entirely before the start time.
             TimePosition::Before
         } else {
-            fn inner(
-                start: Ratio<u32>,
-                end: Ratio<u32>,
+            fn inner<T: Interpolate>(
+                start: T,
+                end: T,
                 duration: Ratio<u32>,
                 offset: Ratio<u32>,
-                value: &mut Ratio<u32>,
+                value: &mut T,
             ) {
-                let elapsed_fraction = offset / duration;
-                if start <= end {
-                    let value_delta = end - start;
-                    *value = start + elapsed_fraction * value_delta;
-                } else {
-                    let value_delta = start - end;
-                    *value = start - elapsed_fraction * value_delta;
-                }
+                *value = T::between(start, end, offset / duration);
             }
             let duration = *event_end - *event_start;
             let mut new_value_start = *value_start;
@@ -222,6 +276,34 @@ impl TimelineEvent<'_> {
                         end_level.item = end_value.round().to_u8().unwrap();
                     }
                 }
+                TimelineData::CsoundParam(e) => {
+                    // Like tempo, a parameter keeps its value until it is changed, so treat it
+                    // the same way.
+                    match &mut e.end_value {
+                        Some(end_value) => {
+                            let time_pos = Self::interpolate(
+                                &mut e.value,
+                                &mut end_value.item,
+                                &mut event_start,
+                                &mut end_value.time,
+                                b,
+                            );
+                            match time_pos {
+                                TimePosition::Before | TimePosition::EndsAtStart => {
+                                    e.value = end_value.item;
+                                    e.end_value = None;
+                                }
+                                TimePosition::After | TimePosition::StartsAtEnd => return None,
+                                TimePosition::Overlapping => {}
+                            }
+                        }
+                        None => {
+                            if event_start >= b.end_time {
+                                return None;
+                            }
+                        }
+                    }
+                }
                 TimelineData::Note(e) => {
                     let pitches: Vec<PitchChange> = mem::take(&mut e.value.pitches);
                     for mut p in pitches {
@@ -255,9 +337,9 @@ impl TimelineEvent<'_> {
                         return None;
                     }
                 }
-                TimelineData::Mark(_) => {
-                    // Mark events are instantaneous, and we only care about them if they are in
-                    // the time range, not at the boundaries.
+                TimelineData::Mark(_) | TimelineData::BarLine => {
+                    // Mark and bar line events are instantaneous, and we only care about them if
+                    // they are in the time range, not at the boundaries.
                     if event_start <= b.start_time || event_start >= b.end_time {
                         return None;
                     }
@@ -286,13 +368,21 @@ impl TimelineEvent<'_> {
                     Self::add_or_subtract(&mut x.time, delta, subtract);
                 }
             }
+            TimelineData::CsoundParam(e) => {
+                if let Some(x) = e.end_value.as_mut() {
+                    Self::add_or_subtract(&mut x.time, delta, subtract);
+                }
+            }
             TimelineData::Note(e) => {
                 for p in &mut e.value.pitches {
                     Self::add_or_subtract(&mut p.start_time, delta, subtract);
                     Self::add_or_subtract(&mut p.end_time, delta, subtract);
                 }
             }
-            TimelineData::Mark(_) | TimelineData::RepeatStart(_) | TimelineData::RepeatEnd(_) => {}
+            TimelineData::Mark(_)
+            | TimelineData::RepeatStart(_)
+            | TimelineData::RepeatEnd(_)
+            | TimelineData::BarLine => {}
         }
 
         let mut new_time = event_start;
@@ -320,10 +410,13 @@ pub enum TimelineData<'s> {
     // events that happen at the same time and span. (Unusual.)
     Tempo(TempoEvent),
     Dynamic(DynamicEvent<'s>),
+    CsoundParam(CsoundParamEvent<'s>),
     Note(NoteEvent<'s>),
     Mark(MarkEvent<'s>),
     RepeatStart(MarkEvent<'s>),
     RepeatEnd(MarkEvent<'s>),
+    /// The end of a bar, from an explicit bar check or the end of a score block
+    BarLine,
 }
 
 #[derive(Serialize, Clone, Debug, PartialOrd, PartialEq, Ord, Eq)]
@@ -353,7 +446,10 @@ pub struct NoteEvent<'s> {
 #[derive(Serialize, Clone, Debug, PartialOrd, PartialEq, Ord, Eq)]
 pub struct NoteValue<'s> {
     pub text: &'s str,
+    /// Attack velocity from 0 to 127
     pub velocity: u8,
+    /// Release velocity from 0 to 127; higher values release more quickly
+    pub release_velocity: u8,
     pub pitches: Vec<PitchChange<'s>>,
 }
 
@@ -375,12 +471,30 @@ pub struct DynamicEvent<'s> {
     pub end_level: Option<WithTime<u8>>,
 }
 
+/// A value for a Csound channel named after a part and parameter, with a possible gradual change
+#[derive(Serialize, Debug, Clone, PartialOrd, PartialEq, Ord, Eq)]
+pub struct CsoundParamEvent<'s> {
+    pub part: Cow<'s, str>,
+    pub name: Cow<'s, str>,
+    pub value: Ratio<i32>,
+    pub end_value: Option<WithTime<Ratio<i32>>>,
+}
+
 #[derive(Default, Serialize, Clone, Copy, PartialOrd, PartialEq, Ord, Eq)]
 pub struct MidiInstrumentNumber {
     pub bank: u16,
     pub instrument: u8,
 }
 
+/// MIDI port and track name for a part. Port numbers are 0-based.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct MidiRoutingSettings<'s> {
+    pub span: Span,
+    pub part: Cow<'s, str>,
+    pub port: Option<u8>,
+    pub track_name: Option<Cow<'s, str>>,
+}
+
 #[derive(Serialize, Clone, PartialOrd, PartialEq, Ord, Eq, Hash)]
 pub enum CsoundInstrumentId<'s> {
     Number(u32),
@@ -396,6 +510,50 @@ impl CsoundInstrumentId<'_> {
     }
 }
 
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Waveform {
+    Sine,
+    Triangle,
+    Square,
+    Sawtooth,
+}
+impl Waveform {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sine" => Some(Waveform::Sine),
+            "triangle" => Some(Waveform::Triangle),
+            "square" => Some(Waveform::Square),
+            "sawtooth" => Some(Waveform::Sawtooth),
+            _ => None,
+        }
+    }
+}
+
+/// Sound used by the built-in WAV renderer. Envelope times are in seconds. The defaults match
+/// the instrument in the default Csound template.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WavInstrumentSettings {
+    pub waveform: Waveform,
+    pub attack: Ratio<u32>,
+    pub decay: Ratio<u32>,
+    pub sustain: Ratio<u32>,
+    pub release: Ratio<u32>,
+    pub cutoff: u32,
+}
+impl Default for WavInstrumentSettings {
+    fn default() -> Self {
+        Self {
+            waveform: Waveform::Triangle,
+            attack: Ratio::new(1, 20),
+            decay: Ratio::new(1, 20),
+            sustain: Ratio::new(9, 10),
+            release: Ratio::new(3, 20),
+            cutoff: 2000,
+        }
+    }
+}
+
 #[derive(Serialize, Clone, Debug, PartialOrd, PartialEq, Ord, Eq)]
 pub struct TempoEvent {
     pub bpm: Ratio<u32>,
@@ -538,4 +696,26 @@ mod tests {
             TimePosition::After
         ));
     }
+
+    #[test]
+    fn test_interpolate_signed() {
+        // Csound parameters may be zero or negative.
+        let between = |start, end, n, d| {
+            Interpolate::between(
+                Ratio::<i32>::from(start),
+                Ratio::from(end),
+                Ratio::new(n, d),
+            )
+        };
+        assert_eq!(between(-2, 2, 1, 4), Ratio::from(-1));
+        assert_eq!(between(2, -2, 1, 2), Ratio::from(0));
+        assert_eq!(between(0, -3, 2, 3), Ratio::from(-2));
+        assert_eq!(
+            between(2_000_000_000, -2_000_000_000, 1, 4),
+            Ratio::from(1_000_000_000)
+        );
+        // The exact value, -10000000000/7, has a numerator that doesn't fit in 32 bits.
+        let value = between(-2_000_000_000, 2_000_000_000, 1, 7);
+        assert!((value.to_f64().unwrap() + 10_000_000_000.0 / 7.0).abs() < 1.0);
+    }
 }
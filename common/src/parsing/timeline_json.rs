@@ -0,0 +1,710 @@
+//! Versioned JSON representation of a timeline. These types mirror the ones in the timeline
+//! module but own their data so that they can be deserialized, and they generate the published
+//! JSON schema. When the format changes incompatibly, increment [`VERSION`].
+
+use crate::parsing::model::Span;
+use crate::parsing::score::{ScalesByName, serialize_scales};
+use crate::parsing::score_helpers::{ArcContext, ToStatic};
+use crate::parsing::timeline as tl;
+use crate::pitch::Pitch;
+use anyhow::bail;
+use num_integer::Integer;
+use num_rational::Ratio;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Current version of the timeline JSON format
+pub const VERSION: u32 = 1;
+
+/// A rational number, written as `[numerator, denominator]`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(try_from = "[u32; 2]")]
+pub struct Rational(#[schemars(with = "[u32; 2]")] pub Ratio<u32>);
+
+impl TryFrom<[u32; 2]> for Rational {
+    type Error = String;
+
+    fn try_from([numer, denom]: [u32; 2]) -> Result<Self, Self::Error> {
+        if denom == 0 {
+            return Err(format!("rational number {numer}/0 has a zero denominator"));
+        }
+        Ok(Self(Ratio::new(numer, denom)))
+    }
+}
+
+/// A rational number that may be zero or negative, written as `[numerator, denominator]`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(try_from = "[i32; 2]")]
+pub struct SignedRational(#[schemars(with = "[i32; 2]")] pub Ratio<i32>);
+
+impl TryFrom<[i32; 2]> for SignedRational {
+    type Error = String;
+
+    fn try_from([numer, denom]: [i32; 2]) -> Result<Self, Self::Error> {
+        if denom == 0 {
+            return Err(format!("rational number {numer}/0 has a zero denominator"));
+        }
+        Ok(Self(Ratio::new(numer, denom)))
+    }
+}
+
+/// A syntoniq timeline. Times are in beats from the start of the score, and spans are byte
+/// offsets into the score that produced the timeline.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct Timeline {
+    /// Version of the timeline format
+    pub version: u32,
+    #[serde(default)]
+    pub events: Vec<TimelineEvent>,
+    /// Scales used by the score, for information only. These are ignored when a timeline is read.
+    #[serde(serialize_with = "serialize_scales::serialize", skip_deserializing)]
+    #[schemars(with = "Vec<serde_json::Value>")]
+    pub scales: Arc<ScalesByName<'static>>,
+    #[serde(default)]
+    pub midi_instruments: BTreeMap<String, MidiInstrumentNumber>,
+    #[serde(default)]
+    pub midi_routings: Vec<MidiRoutingSettings>,
+    #[serde(default)]
+    pub csound_instruments: BTreeMap<String, CsoundInstrumentId>,
+    #[serde(default)]
+    pub csound_global_instruments: Vec<CsoundGlobalInstrument>,
+    /// Csound template path, relative to the score or timeline file
+    #[serde(default)]
+    pub csound_template: Option<String>,
+    #[serde(default)]
+    pub wav_instruments: BTreeMap<String, WavInstrumentSettings>,
+    /// Least common multiple of time denominators, used for MIDI ticks per beat. When a timeline
+    /// is read, this is combined with the denominators of event start times, so it doesn't have
+    /// to be updated when events are added.
+    #[serde(default = "default_time_lcm")]
+    pub time_lcm: u32,
+}
+
+fn default_release_velocity() -> u8 {
+    64
+}
+
+fn default_time_lcm() -> u32 {
+    1
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct TimelineEvent {
+    pub time: Rational,
+    #[serde(default)]
+    pub repeat_depth: usize,
+    #[serde(default)]
+    pub span: [usize; 2],
+    pub data: TimelineData,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub enum TimelineData {
+    Tempo(TempoEvent),
+    Dynamic(DynamicEvent),
+    CsoundParam(CsoundParamEvent),
+    Note(NoteEvent),
+    Mark(MarkEvent),
+    RepeatStart(MarkEvent),
+    RepeatEnd(MarkEvent),
+    /// The end of a bar, from an explicit bar check or the end of a score block
+    BarLine,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct WithTime<T> {
+    pub time: Rational,
+    pub item: T,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct TempoEvent {
+    pub bpm: Rational,
+    pub end_bpm: Option<WithTime<Rational>>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct DynamicEvent {
+    #[serde(default)]
+    pub text: String,
+    pub part: String,
+    /// Level from 0 to 127
+    pub start_level: u8,
+    pub end_level: Option<WithTime<u8>>,
+}
+
+/// A value for a part's Csound parameter from the `csound_param` directive
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct CsoundParamEvent {
+    pub part: String,
+    pub name: String,
+    pub value: SignedRational,
+    pub end_value: Option<WithTime<SignedRational>>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct NoteEvent {
+    pub part: String,
+    pub note_number: u32,
+    pub value: NoteValue,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct NoteValue {
+    #[serde(default)]
+    pub text: String,
+    /// Velocity from 0 to 127
+    pub velocity: u8,
+    /// Release velocity from 0 to 127; higher values release more quickly
+    #[serde(default = "default_release_velocity")]
+    pub release_velocity: u8,
+    /// Pitches of the note in order. A note that doesn't change pitch has exactly one.
+    pub pitches: Vec<PitchChange>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct PitchChange {
+    #[serde(default)]
+    pub text: String,
+    #[serde(default)]
+    pub span: [usize; 2],
+    /// Pitch in syntoniq's pitch notation, such as `220*^1|12`
+    #[schemars(with = "String")]
+    pub start_pitch: Pitch,
+    pub start_time: Rational,
+    /// If present, the pitch glides to this pitch by the end time
+    #[schemars(with = "Option<String>")]
+    pub end_pitch: Option<Pitch>,
+    pub end_time: Rational,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct MarkEvent {
+    pub label: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug)]
+pub struct MidiInstrumentNumber {
+    /// 0-based bank number
+    pub bank: u16,
+    /// 0-based instrument number
+    pub instrument: u8,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct MidiRoutingSettings {
+    #[serde(default)]
+    pub span: [usize; 2],
+    pub part: String,
+    /// 0-based port number
+    pub port: Option<u8>,
+    pub track_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub enum CsoundInstrumentId {
+    Number(u32),
+    Name(String),
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct CsoundGlobalInstrument {
+    pub instrument: CsoundInstrumentId,
+    /// Beats the instrument continues after the last note
+    pub tail: Rational,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Waveform {
+    Sine,
+    Triangle,
+    Square,
+    Sawtooth,
+}
+
+/// Envelope times are in seconds, and the cutoff is in Hz.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug)]
+pub struct WavInstrumentSettings {
+    pub waveform: Waveform,
+    pub attack: Rational,
+    pub decay: Rational,
+    pub sustain: Rational,
+    pub release: Rational,
+    pub cutoff: u32,
+}
+
+/// Return the JSON schema for the current version of the timeline format
+pub fn schema() -> String {
+    let schema = schemars::schema_for!(Timeline);
+    serde_json::to_string_pretty(&schema).unwrap()
+}
+
+fn span(s: [usize; 2]) -> Span {
+    Span {
+        start: s[0],
+        end: s[1],
+    }
+}
+
+fn with_time<T: Clone + Serialize, U>(w: &tl::WithTime<T>, f: impl Fn(&T) -> U) -> WithTime<U> {
+    WithTime {
+        time: Rational(w.time),
+        item: f(&w.item),
+    }
+}
+
+fn csound_id(id: &tl::CsoundInstrumentId) -> CsoundInstrumentId {
+    match id {
+        tl::CsoundInstrumentId::Number(n) => CsoundInstrumentId::Number(*n),
+        tl::CsoundInstrumentId::Name(s) => CsoundInstrumentId::Name(s.to_string()),
+    }
+}
+
+fn from_csound_id(id: &CsoundInstrumentId) -> tl::CsoundInstrumentId<'_> {
+    match id {
+        CsoundInstrumentId::Number(n) => tl::CsoundInstrumentId::Number(*n),
+        CsoundInstrumentId::Name(s) => tl::CsoundInstrumentId::Name(Cow::Borrowed(s)),
+    }
+}
+
+fn waveform(w: tl::Waveform) -> Waveform {
+    match w {
+        tl::Waveform::Sine => Waveform::Sine,
+        tl::Waveform::Triangle => Waveform::Triangle,
+        tl::Waveform::Square => Waveform::Square,
+        tl::Waveform::Sawtooth => Waveform::Sawtooth,
+    }
+}
+
+fn from_waveform(w: Waveform) -> tl::Waveform {
+    match w {
+        Waveform::Sine => tl::Waveform::Sine,
+        Waveform::Triangle => tl::Waveform::Triangle,
+        Waveform::Square => tl::Waveform::Square,
+        Waveform::Sawtooth => tl::Waveform::Sawtooth,
+    }
+}
+
+fn event_data(data: &tl::TimelineData) -> TimelineData {
+    let mark = |e: &tl::MarkEvent| MarkEvent {
+        label: e.label.to_string(),
+    };
+    match data {
+        tl::TimelineData::Tempo(e) => TimelineData::Tempo(TempoEvent {
+            bpm: Rational(e.bpm),
+            end_bpm: e.end_bpm.as_ref().map(|x| with_time(x, |v| Rational(*v))),
+        }),
+        tl::TimelineData::Dynamic(e) => TimelineData::Dynamic(DynamicEvent {
+            text: e.text.to_string(),
+            part: e.part.to_string(),
+            start_level: e.start_level,
+            end_level: e.end_level.as_ref().map(|x| with_time(x, |v| *v)),
+        }),
+        tl::TimelineData::CsoundParam(e) => TimelineData::CsoundParam(CsoundParamEvent {
+            part: e.part.to_string(),
+            name: e.name.to_string(),
+            value: SignedRational(e.value),
+            end_value: e
+                .end_value
+                .as_ref()
+                .map(|x| with_time(x, |v| SignedRational(*v))),
+        }),
+        tl::TimelineData::Note(e) => TimelineData::Note(NoteEvent {
+            part: e.part_note.part.to_string(),
+            note_number: e.part_note.note_number,
+            value: NoteValue {
+                text: e.value.text.to_string(),
+                velocity: e.value.velocity,
+                release_velocity: e.value.release_velocity,
+                pitches: e
+                    .value
+                    .pitches
+                    .iter()
+                    .map(|p| PitchChange {
+                        text: p.text.to_string(),
+                        span: [p.span.start, p.span.end],
+                        start_pitch: p.start_pitch.clone(),
+                        start_time: Rational(p.start_time),
+                        end_pitch: p.end_pitch.clone(),
+                        end_time: Rational(p.end_time),
+                    })
+                    .collect(),
+            },
+        }),
+        tl::TimelineData::Mark(e) => TimelineData::Mark(mark(e)),
+        tl::TimelineData::RepeatStart(e) => TimelineData::RepeatStart(mark(e)),
+        tl::TimelineData::RepeatEnd(e) => TimelineData::RepeatEnd(mark(e)),
+        tl::TimelineData::BarLine => TimelineData::BarLine,
+    }
+}
+
+fn from_mark(e: &MarkEvent) -> tl::MarkEvent<'_> {
+    tl::MarkEvent {
+        label: Cow::Borrowed(&e.label),
+    }
+}
+
+fn from_event_data(data: &TimelineData) -> tl::TimelineData<'_> {
+    match data {
+        TimelineData::Tempo(e) => tl::TimelineData::Tempo(tl::TempoEvent {
+            bpm: e.bpm.0,
+            end_bpm: e
+                .end_bpm
+                .as_ref()
+                .map(|x| tl::WithTime::new(x.time.0, x.item.0)),
+        }),
+        TimelineData::Dynamic(e) => tl::TimelineData::Dynamic(tl::DynamicEvent {
+            text: &e.text,
+            part: &e.part,
+            start_level: e.start_level,
+            end_level: e
+                .end_level
+                .as_ref()
+                .map(|x| tl::WithTime::new(x.time.0, x.item)),
+        }),
+        TimelineData::CsoundParam(e) => tl::TimelineData::CsoundParam(tl::CsoundParamEvent {
+            part: Cow::Borrowed(&e.part),
+            name: Cow::Borrowed(&e.name),
+            value: e.value.0,
+            end_value: e
+                .end_value
+                .as_ref()
+                .map(|x| tl::WithTime::new(x.time.0, x.item.0)),
+        }),
+        TimelineData::Note(e) => tl::TimelineData::Note(tl::NoteEvent {
+            part_note: tl::PartNote {
+                part: &e.part,
+                note_number: e.note_number,
+            },
+            value: tl::NoteValue {
+                text: &e.value.text,
+                velocity: e.value.velocity,
+                release_velocity: e.value.release_velocity,
+                pitches: e
+                    .value
+                    .pitches
+                    .iter()
+                    .map(|p| tl::PitchChange {
+                        text: &p.text,
+                        span: span(p.span),
+                        start_pitch: p.start_pitch.clone(),
+                        start_time: p.start_time.0,
+                        end_pitch: p.end_pitch.clone(),
+                        end_time: p.end_time.0,
+                    })
+                    .collect(),
+            },
+        }),
+        TimelineData::Mark(e) => tl::TimelineData::Mark(from_mark(e)),
+        TimelineData::RepeatStart(e) => tl::TimelineData::RepeatStart(from_mark(e)),
+        TimelineData::RepeatEnd(e) => tl::TimelineData::RepeatEnd(from_mark(e)),
+        TimelineData::BarLine => tl::TimelineData::BarLine,
+    }
+}
+
+/// Check the things about an event that the rest of the program relies on and that
+/// deserialization alone doesn't guarantee
+fn validate_event(e: &TimelineEvent) -> anyhow::Result<()> {
+    let time = e.time.0;
+    let check_end = |what: &str, end: Rational| {
+        if end.0 <= time {
+            bail!(
+                "{what} at time {time} ends at time {}, which is not after its start",
+                end.0
+            );
+        }
+        Ok(())
+    };
+    let check_level = |what: &str, level: u8| {
+        if level > 127 {
+            bail!("{what} at time {time} is {level}, which is more than 127");
+        }
+        Ok(())
+    };
+    match &e.data {
+        TimelineData::Tempo(t) => {
+            if t.bpm.0 == Ratio::ZERO {
+                bail!("tempo at time {time} is zero");
+            }
+            if let Some(end) = &t.end_bpm {
+                check_end("tempo change", end.time)?;
+                if end.item.0 == Ratio::ZERO {
+                    bail!("tempo change at time {time} ends at a tempo of zero");
+                }
+            }
+        }
+        TimelineData::Dynamic(d) => {
+            check_level("dynamic level", d.start_level)?;
+            if let Some(end) = &d.end_level {
+                check_end("dynamic change", end.time)?;
+                check_level("dynamic end level", end.item)?;
+            }
+        }
+        TimelineData::CsoundParam(p) => {
+            if let Some(end) = &p.end_value {
+                check_end("csound parameter change", end.time)?;
+            }
+        }
+        TimelineData::Note(n) => {
+            let what = format!("note {} of part {}", n.note_number, n.part);
+            if n.value.pitches.is_empty() {
+                bail!("{what} at time {time} has no pitches");
+            }
+            check_level(&format!("velocity of {what}"), n.value.velocity)?;
+            check_level(
+                &format!("release velocity of {what}"),
+                n.value.release_velocity,
+            )?;
+            let mut expected_start = time;
+            for p in &n.value.pitches {
+                if p.start_time.0 != expected_start {
+                    bail!(
+                        "{what} at time {time} has a pitch that starts at time {} instead of {}",
+                        p.start_time.0,
+                        expected_start
+                    );
+                }
+                expected_start = p.end_time.0;
+                if p.end_time.0 < p.start_time.0 {
+                    bail!(
+                        "{what} at time {time} has a pitch that ends at time {} before its start \
+                         time {}",
+                        p.end_time.0,
+                        p.start_time.0
+                    );
+                }
+            }
+        }
+        TimelineData::Mark(_)
+        | TimelineData::RepeatStart(_)
+        | TimelineData::RepeatEnd(_)
+        | TimelineData::BarLine => {}
+    }
+    Ok(())
+}
+
+impl Timeline {
+    pub fn new(timeline: &tl::Timeline) -> Self {
+        let scales = timeline.scales.to_static(&mut ArcContext::default());
+        Self {
+            version: VERSION,
+            events: timeline
+                .events
+                .iter()
+                .map(|e| TimelineEvent {
+                    time: Rational(e.time),
+                    repeat_depth: e.repeat_depth,
+                    span: [e.span.start, e.span.end],
+                    data: event_data(&e.data),
+                })
+                .collect(),
+            scales,
+            midi_instruments: timeline
+                .midi_instruments
+                .iter()
+                .map(|(part, i)| {
+                    let i = MidiInstrumentNumber {
+                        bank: i.bank,
+                        instrument: i.instrument,
+                    };
+                    (part.to_string(), i)
+                })
+                .collect(),
+            midi_routings: timeline
+                .midi_routings
+                .iter()
+                .map(|r| MidiRoutingSettings {
+                    span: [r.span.start, r.span.end],
+                    part: r.part.to_string(),
+                    port: r.port,
+                    track_name: r.track_name.as_ref().map(Cow::to_string),
+                })
+                .collect(),
+            csound_instruments: timeline
+                .csound_instruments
+                .iter()
+                .map(|(part, id)| (part.to_string(), csound_id(id)))
+                .collect(),
+            csound_global_instruments: timeline
+                .csound_global_instruments
+                .iter()
+                .map(|g| CsoundGlobalInstrument {
+                    instrument: csound_id(&g.instrument),
+                    tail: Rational(g.tail),
+                })
+                .collect(),
+            csound_template: timeline.csound_template.as_ref().map(Cow::to_string),
+            wav_instruments: timeline
+                .wav_instruments
+                .iter()
+                .map(|(part, w)| {
+                    let w = WavInstrumentSettings {
+                        waveform: waveform(w.waveform),
+                        attack: Rational(w.attack),
+                        decay: Rational(w.decay),
+                        sustain: Rational(w.sustain),
+                        release: Rational(w.release),
+                        cutoff: w.cutoff,
+                    };
+                    (part.to_string(), w)
+                })
+                .collect(),
+            time_lcm: timeline.time_lcm,
+        }
+    }
+
+    /// Create a timeline that borrows from this one. Scales are not included.
+    pub fn timeline(&self) -> anyhow::Result<tl::Timeline<'_>> {
+        if self.version != VERSION {
+            bail!(
+                "timeline version {} is not supported; the supported version is {VERSION}",
+                self.version
+            );
+        }
+        let mut time_lcm = self.time_lcm.max(1);
+        let mut events = Vec::new();
+        for e in &self.events {
+            time_lcm = time_lcm.lcm(e.time.0.denom());
+            validate_event(e)?;
+            events.push(Arc::new(tl::TimelineEvent {
+                time: e.time.0,
+                repeat_depth: e.repeat_depth,
+                span: span(e.span),
+                data: from_event_data(&e.data),
+            }));
+        }
+        Ok(tl::Timeline {
+            events: events.into_iter().collect(),
+            scales: Default::default(),
+            midi_instruments: self
+                .midi_instruments
+                .iter()
+                .map(|(part, i)| {
+                    let i = tl::MidiInstrumentNumber {
+                        bank: i.bank,
+                        instrument: i.instrument,
+                    };
+                    (Cow::Borrowed(part.as_str()), i)
+                })
+                .collect(),
+            midi_routings: self
+                .midi_routings
+                .iter()
+                .map(|r| tl::MidiRoutingSettings {
+                    span: span(r.span),
+                    part: Cow::Borrowed(&r.part),
+                    port: r.port,
+                    track_name: r.track_name.as_deref().map(Cow::Borrowed),
+                })
+                .collect(),
+            csound_instruments: self
+                .csound_instruments
+                .iter()
+                .map(|(part, id)| (Cow::Borrowed(part.as_str()), from_csound_id(id)))
+                .collect(),
+            csound_global_instruments: self
+                .csound_global_instruments
+                .iter()
+                .map(|g| tl::CsoundGlobalInstrument {
+                    instrument: from_csound_id(&g.instrument),
+                    tail: g.tail.0,
+                })
+                .collect(),
+            csound_template: self.csound_template.as_deref().map(Cow::Borrowed),
+            wav_instruments: self
+                .wav_instruments
+                .iter()
+                .map(|(part, w)| {
+                    let w = tl::WavInstrumentSettings {
+                        waveform: from_waveform(w.waveform),
+                        attack: w.attack.0,
+                        decay: w.decay.0,
+                        sustain: w.sustain.0,
+                        release: w.release.0,
+                        cutoff: w.cutoff,
+                    };
+                    (Cow::Borrowed(part.as_str()), w)
+                })
+                .collect(),
+            time_lcm,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(events: &str) -> anyhow::Result<()> {
+        let json = format!(r#"{{"version": {VERSION}, "events": [{events}]}}"#);
+        let timeline: Timeline = serde_json::from_str(&json)?;
+        timeline.timeline().map(|_| ())
+    }
+
+    fn note(velocity: u8, start: u32, end: u32) -> String {
+        format!(
+            r#"{{"time": [{start}, 1], "data": {{"Note": {{"part": "p", "note_number": 1,
+            "value": {{"velocity": {velocity}, "pitches": [{{"start_pitch": "440",
+            "start_time": [{start}, 1], "end_time": [{end}, 1]}}]}}}}}}}}"#
+        )
+    }
+
+    fn error(events: &str) -> String {
+        check(events).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_validate() {
+        check(&note(127, 1, 2)).unwrap();
+        // Times that are too late for MIDI are only an error when generating MIDI.
+        check(&note(64, 9000, 9001)).unwrap();
+        assert!(error(&note(128, 1, 2)).contains("velocity of note 1 of part p at time 1 is 128"));
+        assert!(error(&note(64, 2, 1)).contains("ends at time 1 before its start time 2"));
+        assert!(error(r#"{"time": [1, 0], "data": "BarLine"}"#).contains("zero denominator"));
+        let dynamic = |level, end_time, end_level| {
+            format!(
+                r#"{{"time": [2, 1], "data": {{"Dynamic": {{"part": "p", "start_level": {level},
+                "end_level": {{"time": [{end_time}, 1], "item": {end_level}}}}}}}}}"#
+            )
+        };
+        check(&dynamic(10, 3, 127)).unwrap();
+        assert!(error(&dynamic(128, 3, 127)).contains("dynamic level at time 2 is 128"));
+        assert!(error(&dynamic(10, 3, 200)).contains("dynamic end level at time 2 is 200"));
+        assert!(error(&dynamic(10, 2, 127)).contains("ends at time 2, which is not after"));
+        let tempo = |bpm, end_time| {
+            format!(
+                r#"{{"time": [2, 1], "data": {{"Tempo": {{"bpm": [{bpm}, 1],
+                "end_bpm": {{"time": [{end_time}, 1], "item": [90, 1]}}}}}}}}"#
+            )
+        };
+        check(&tempo(60, 4)).unwrap();
+        assert!(error(&tempo(0, 4)).contains("tempo at time 2 is zero"));
+        assert!(error(&tempo(60, 1)).contains("tempo change at time 2 ends at time 1"));
+    }
+
+    #[test]
+    fn test_validate_pitches() {
+        let pitches = |first: [u32; 2], second: [u32; 2]| {
+            format!(
+                r#"{{"time": [2, 1], "data": {{"Note": {{"part": "p", "note_number": 1,
+                "value": {{"velocity": 64, "pitches": [
+                {{"start_pitch": "440", "start_time": [{}, 1], "end_time": [{}, 1]}},
+                {{"start_pitch": "440", "start_time": [{}, 1], "end_time": [{}, 1]}}]}}}}}}}}"#,
+                first[0], first[1], second[0], second[1]
+            )
+        };
+        check(&pitches([2, 3], [3, 4])).unwrap();
+        assert!(
+            error(&pitches([1, 3], [3, 4]))
+                .contains("has a pitch that starts at time 1 instead of 2")
+        );
+        assert!(
+            error(&pitches([2, 3], [4, 5]))
+                .contains("has a pitch that starts at time 4 instead of 3")
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use crate::parsing::pass2;
 use anyhow::bail;
 use num_rational::Ratio;
-use num_traits::{CheckedDiv, CheckedMul, FromPrimitive, ToPrimitive};
+use num_traits::{CheckedAdd, CheckedDiv, CheckedMul, FromPrimitive, ToPrimitive};
 use serde::de::Visitor;
 use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
 use std::cmp::Ordering;
@@ -180,15 +180,25 @@ impl From<Ratio<u32>> for Pitch {
 
 /// Compute a fractional MIDI note to a midi note number and a pitch bend value using ±48 semitones.
 pub fn mpe_bend(semitones: f64) -> u16 {
-    // Pitch bend is 8192 + 8192 * (semitones/bend range). MPE pitch bend range is typically 48
-    // semitones and is explicitly configured as this value. This method is never called with
-    // a value outside the range. Although it's a public function, this isn't a proper library
-    // and is used only by other crates in the workspace.
+    // MPE pitch bend range is typically 48 semitones and is explicitly configured as this value.
+    // This method is never called with a value outside the range. Although it's a public
+    // function, this isn't a proper library and is used only by other crates in the workspace.
     assert!(
         semitones.abs() < 48.0,
         "mpe_bend was called with >= 48 semitones"
     );
-    let bend = 8192.0 + (8192.0 / 48.0 * semitones).round();
+    bend(semitones, 48.0)
+}
+
+/// Compute a pitch bend value for the given number of semitones when the pitch bend range is
+/// `range` semitones in either direction.
+pub fn bend(semitones: f64, range: f64) -> u16 {
+    // Pitch bend is 8192 + 8192 * (semitones/bend range).
+    assert!(
+        semitones.abs() < range,
+        "bend was called with a value outside the bend range"
+    );
+    let bend = 8192.0 + (8192.0 / range * semitones).round();
     bend.clamp(0.0, 16383.0) as u16
 }
 
@@ -202,6 +212,11 @@ pub fn note_bend(fractional_note: f64) -> (u8, u16) {
 
 impl Pitch {
     pub fn new(factors: Vec<Factor>) -> Self {
+        Self::checked_new(factors).expect("TODO: handle overflow")
+    }
+
+    /// Like [`Pitch::new`], but return `None` if the rational part or an exponent overflows.
+    pub fn checked_new(factors: Vec<Factor>) -> Option<Self> {
         // Algorithm:
         // - Rewrite as the product of primes raised to rational powers
         // - Add exponents of like bases
@@ -214,9 +229,8 @@ impl Pitch {
         // the exponent denominator is non-negative. Since these are rationals, that just means
         // we have positive bases.
 
-        //TODO: This code doesn't really handle overflow properly. If it happens in practice,
-        // consider switching to bigint (easy with performance penalty), u64 or u128, or handling
-        // gracefully.
+        //TODO: Overflow is detected but not avoided. If it happens in practice, consider switching
+        // to bigint (easy with performance penalty), u64 or u128.
 
         // Split (a/b)^x to a^x * b^(-x)
         let factors = {
@@ -249,20 +263,15 @@ impl Pitch {
         let mut rational_accumulator = Ratio::from_integer(1);
         for f in factors {
             if f.exp == Ratio::from_integer(1) {
-                rational_accumulator = rational_accumulator
-                    .checked_mul(&f.base)
-                    .expect("TODO: handle overflow");
+                rational_accumulator = rational_accumulator.checked_mul(&f.base)?;
             } else {
                 let base_n = *f.base.numer();
                 for (prime, prime_exp) in sieve.factor(base_n as usize).unwrap() {
-                    let exp = f
-                        .exp
-                        .checked_mul(&Ratio::from_integer(prime_exp as i32))
-                        .expect("TODO: handle overflow");
-                    exp_accumulators
+                    let exp = f.exp.checked_mul(&Ratio::from_integer(prime_exp as i32))?;
+                    let accumulator = exp_accumulators
                         .entry(prime as u32)
-                        .and_modify(|e| *e += exp)
-                        .or_insert(exp);
+                        .or_insert(Ratio::from_integer(0));
+                    *accumulator = accumulator.checked_add(&exp)?;
                 }
             }
         }
@@ -276,16 +285,10 @@ impl Pitch {
                 let qn = *q.numer();
                 if qn > 0 {
                     rational_accumulator = rational_accumulator
-                        .checked_mul(&Ratio::from_integer(
-                            base.checked_pow(qn as u32).expect("TODO: overflow"),
-                        ))
-                        .expect("TODO: handle overflow");
+                        .checked_mul(&Ratio::from_integer(base.checked_pow(qn as u32)?))?;
                 } else {
                     rational_accumulator = rational_accumulator
-                        .checked_div(&Ratio::from_integer(
-                            base.checked_pow((-qn) as u32).expect("TODO: overflow"),
-                        ))
-                        .expect("TODO: handle overflow");
+                        .checked_div(&Ratio::from_integer(base.checked_pow((-qn) as u32)?))?;
                 }
                 exp -= q;
             }
@@ -312,7 +315,7 @@ impl Pitch {
             .collect();
         }
 
-        Self { factors: result }
+        Some(Self { factors: result })
     }
 
     pub fn as_rational(&self) -> Option<Ratio<u32>> {
@@ -335,6 +338,11 @@ impl Pitch {
         Self::new(self.factors.iter().chain(&other.factors).cloned().collect())
     }
 
+    /// Like [`Pitch::concat`], but return `None` on overflow
+    pub fn checked_mul(&self, other: &Self) -> Option<Self> {
+        Self::checked_new(self.factors.iter().chain(&other.factors).cloned().collect())
+    }
+
     #[must_use]
     pub fn invert(&self) -> Self {
         let factors = self
@@ -362,6 +370,12 @@ impl Pitch {
     /// Returns a normalized pitch (1 <= `pitch` < `cycle`) and the cycle offset (`normalized` *
     /// `cycle^offset` = `orig`)
     pub fn normalized(&self, cycle: Ratio<u32>) -> (Pitch, i32) {
+        self.checked_normalized(cycle)
+            .expect("TODO: handle overflow")
+    }
+
+    /// Like [`Pitch::normalized`], but return `None` on overflow
+    pub fn checked_normalized(&self, cycle: Ratio<u32>) -> Option<(Pitch, i32)> {
         // This may not be the most efficient way to calculate this, but it's probably the
         // clearest. Calculate the cycle offset to normalize this to within a cycle.
         let one = Pitch::unit();
@@ -370,14 +384,14 @@ impl Pitch {
         let mut normalized = self.clone();
         let mut cycle_offset = 0;
         while normalized < one {
-            normalized *= &cycle_as_pitch;
+            normalized = normalized.checked_mul(&cycle_as_pitch)?;
             cycle_offset -= 1;
         }
         while normalized >= cycle_as_pitch {
-            normalized *= &inverted_cycle_as_pitch;
+            normalized = normalized.checked_mul(&inverted_cycle_as_pitch)?;
             cycle_offset += 1;
         }
-        (normalized, cycle_offset)
+        Some((normalized, cycle_offset))
     }
 
     /// Parse a pitch from a string.
@@ -669,6 +683,13 @@ mod tests {
         assert_eq!(mpe_bend(47.9999), 16383);
     }
 
+    #[test]
+    fn test_bend_range() {
+        assert_eq!(bend(1.0, 2.0), 12288);
+        assert_eq!(bend(-0.5, 2.0), 6144);
+        assert_eq!(bend(6.0, 12.0), mpe_bend(24.0));
+    }
+
     #[test]
     fn test_is_rational() {
         assert_eq!(Pitch::unit().as_rational().unwrap(), Ratio::new(1, 1));
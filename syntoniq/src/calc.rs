@@ -1,8 +1,14 @@
 use anyhow::{anyhow, bail};
-use clap::Subcommand;
+use clap::{Subcommand, ValueEnum};
 use num_rational::Ratio;
 use num_traits::ToPrimitive;
+use serde::{Serialize, Serializer};
+use serde_json::{Value, json};
 use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use syntoniq_common::chord::{ChordAnalysis, SeriesFit};
+use syntoniq_common::parsing;
 use syntoniq_common::parsing::score;
 use syntoniq_common::pitch::{Factor, Pitch};
 
@@ -58,6 +64,205 @@ pub enum Commands {
     /// Compute the pitch resulting from multiplying all the arguments together. Arguments can be
     /// pitches in Syntoniq pitch notation or note names in the generated JI scale.
     Pitch { values: Vec<String> },
+    /// List the scale sizes for which stacking a generator and reducing by the period forms a
+    /// moment-of-symmetry (MOS) scale, which has exactly two step sizes. Columns:
+    /// size: number of notes per period;
+    /// pattern: large (L) and small (s) steps in the brightest mode;
+    /// L: large step in cents;
+    /// s: small step in cents;
+    /// L/s: ratio of the large step to the small step
+    Mos {
+        /// Interval by which to reduce the generator chain as a rational number in Syntoniq pitch
+        /// notation; defaults to 2 (octave)
+        #[arg(long)]
+        period: Option<String>,
+        /// Generator in Syntoniq pitch notation
+        #[arg(long)]
+        generator: String,
+        /// Largest scale size to consider, up to 1000; defaults to 24
+        #[arg(long)]
+        max_size: Option<u32>,
+    },
+    /// Rank equal divisions of an interval by how well they approximate a set of ratios. Columns:
+    /// divisions: number of divisions of the interval;
+    /// max ¢, rms ¢: maximum and root-mean-square error in cents;
+    /// max %, rms %: maximum and root-mean-square error as a percentage of a step;
+    /// consistent: whether the closest step to each ratio is the sum of the closest steps to its
+    /// prime factors
+    EdoFit {
+        /// Ratio to approximate in Syntoniq pitch notation or as a note name in the generated JI
+        /// scale; may be repeated
+        #[arg(long)]
+        ratio: Vec<String>,
+        /// Approximate all ratios between odd numbers up to this limit, reduced to within an
+        /// octave
+        #[arg(long)]
+        odd_limit: Option<u32>,
+        /// Interval to divide as a rational number in Syntoniq pitch notation;
+        /// defaults to 2 (octave)
+        #[arg(long)]
+        interval: Option<String>,
+        /// Smallest number of divisions to consider; defaults to 5
+        #[arg(long)]
+        min_divisions: Option<u32>,
+        /// Largest number of divisions to consider; defaults to 72
+        #[arg(long)]
+        max_divisions: Option<u32>,
+        /// Number of results to show; defaults to 10
+        #[arg(long)]
+        count: Option<usize>,
+    },
+    /// Find small just intervals (commas) whose prime factors are within a prime limit. Columns:
+    /// ratio: the comma as a ratio;
+    /// cents: the size of the comma in cents;
+    /// monzo: exponents of each prime from 2 up to the prime limit;
+    /// note: a note name for the comma in the generated JI scale, if a short one exists
+    Comma {
+        /// Largest prime factor to consider; defaults to 5
+        #[arg(long)]
+        prime_limit: Option<u32>,
+        /// Largest comma to show in cents; defaults to 30
+        #[arg(long)]
+        max_cents: Option<f64>,
+        /// Largest absolute exponent of each odd prime; defaults to 12
+        #[arg(long)]
+        max_exponent: Option<u32>,
+    },
+    /// Show the just intonation lattice around a pitch, with each pitch reduced to within an
+    /// octave and labeled with a note name in the generated JI scale. The first prime runs across
+    /// the columns and the second down the rows. A third prime produces one grid per exponent.
+    Lattice {
+        /// Center of the lattice in Syntoniq pitch notation or as a note name in the generated JI
+        /// scale; defaults to 1
+        #[arg(long)]
+        pitch: Option<String>,
+        /// Odd prime to use as a lattice dimension; may be repeated up to three times; defaults to
+        /// 3 and 5
+        #[arg(long)]
+        prime: Vec<u32>,
+        /// Largest exponent of each prime on either side of the center; defaults to 2
+        #[arg(long)]
+        radius: Option<u32>,
+    },
+    /// Analyze a chord given as pitches in Syntoniq pitch notation or note names in the generated
+    /// JI scale. Shows each note's place in the closest harmonic (otonal) and subharmonic
+    /// (utonal) series with its deviation in cents, followed by the interval in cents from each
+    /// note (row) to each other note (column).
+    Chord {
+        values: Vec<String>,
+        /// How close each note must be to its place in a series. The default is 1/75th of an
+        /// octave, which is 16¢, the same as for `near`.
+        #[arg(long)]
+        tolerance: Option<String>,
+        /// Largest harmonic or subharmonic number to consider; defaults to 64
+        #[arg(long)]
+        max_harmonic: Option<u32>,
+    },
+    /// Compare two scales degree by degree. Each degree of the first scale is aligned with the
+    /// nearest degree of the second. Columns:
+    /// degree, note, cents: degree of the first scale with its primary note name and pitch;
+    /// nearest, note, cents: the nearest degree of the second scale;
+    /// Δ cents: how far the second scale's degree is above the first's in cents;
+    /// Δ steps: the same difference in average steps of the second scale
+    Compare {
+        /// Score from which to take scale definitions; if omitted, only built-in scales and
+        /// inline equal divisions are available
+        #[arg(long)]
+        score: Option<PathBuf>,
+        /// Name of a scale, or N-EDO for N equal divisions of the octave if no scale has that
+        /// name; must be given exactly twice
+        #[arg(long)]
+        scale: Vec<String>,
+        /// How close degrees must be to count as counterparts. The default is 1/75th of an
+        /// octave, which is 16¢, the same as for `near`.
+        #[arg(long)]
+        tolerance: Option<String>,
+    },
+}
+
+/// How to write calculator results
+#[derive(Copy, Clone, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// Aligned columns for reading
+    #[default]
+    Text,
+    /// A JSON array with one object per row
+    Json,
+    /// Comma-separated values with a header row
+    Csv,
+}
+
+/// One row of machine-readable output as (column, value) pairs in column order. Every record
+/// produced by a command has the same columns.
+type Record = Vec<(String, Value)>;
+
+/// The result of a calculation: text for reading and records for JSON and CSV.
+struct Output {
+    text: Vec<String>,
+    /// Names of the fields of each record, in order; CSV headers are written even when there are
+    /// no records.
+    columns: Vec<String>,
+    records: Vec<Record>,
+}
+
+/// Serializes a record as a JSON object with its fields in order
+struct JsonRecord<'a>(&'a Record);
+
+impl Serialize for JsonRecord<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|(k, v)| (k, v)))
+    }
+}
+
+fn column_names(names: &[&str]) -> Vec<String> {
+    names.iter().map(ToString::to_string).collect()
+}
+
+fn record<const N: usize>(fields: [(&str, Value); N]) -> Record {
+    fields
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect()
+}
+
+impl Output {
+    fn render(self, format: OutputFormat) -> Vec<String> {
+        match format {
+            OutputFormat::Text => self.text,
+            OutputFormat::Json => {
+                let records: Vec<_> = self.records.iter().map(JsonRecord).collect();
+                let json = serde_json::to_string_pretty(&records).unwrap();
+                json.lines().map(str::to_string).collect()
+            }
+            OutputFormat::Csv => {
+                fn csv_field(v: &Value) -> String {
+                    let s = match v {
+                        Value::Null => String::new(),
+                        Value::String(s) => s.clone(),
+                        Value::Array(a) => a.iter().map(csv_field).collect::<Vec<_>>().join(" "),
+                        _ => v.to_string(),
+                    };
+                    if s.contains([',', '"', '\n']) {
+                        format!("\"{}\"", s.replace('"', "\"\""))
+                    } else {
+                        s
+                    }
+                }
+                let headers: Vec<String> = self
+                    .columns
+                    .iter()
+                    .map(|k| csv_field(&Value::String(k.clone())))
+                    .collect();
+                let mut lines = vec![headers.join(",")];
+                for r in &self.records {
+                    debug_assert!(r.iter().map(|(k, _)| k).eq(&self.columns));
+                    let fields: Vec<String> = r.iter().map(|(_, v)| csv_field(v)).collect();
+                    lines.push(fields.join(","));
+                }
+                lines
+            }
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -210,10 +415,11 @@ fn format_tabular(
     result
 }
 
-fn equal_scale(interval_ratio: Ratio<u32>, divisions: u32) -> Vec<String> {
+fn equal_scale(interval_ratio: Ratio<u32>, divisions: u32) -> Output {
     let octaves = interval_ratio.to_f64().unwrap().log(2.0);
     let cents_per_step: f64 = 1200.0 * octaves / divisions as f64;
     let mut rows = Vec::new();
+    let mut records = Vec::new();
     let mut names = score::generated_note_names(interval_ratio, divisions);
     names.push(("A'".to_string(), 0.0));
     for i in 0..=divisions {
@@ -232,6 +438,17 @@ fn equal_scale(interval_ratio: Ratio<u32>, divisions: u32) -> Vec<String> {
         let note_clean = note.replace("#", "").replace("%", "") + "!";
         let offset_deg = format!("{note_clean} {} {:.3}°", sign(delta), delta.abs());
         let offset_cents = delta * cents_per_step;
+        records.push(record([
+            ("step", json!(i)),
+            ("pitch", json!(p_str)),
+            ("simplified", json!(p.to_string())),
+            ("value", json!(p.as_float())),
+            ("cents", json!(cents_per_step * f64::from(i))),
+            ("note", json!(note)),
+            // Positive when the scale degree is above the note's pure ratio
+            ("delta_degrees", json!(0.0 - delta)),
+            ("delta_cents", json!(0.0 - offset_cents)),
+        ]));
         let offset_cents = format!(
             "{note_clean} {} {:6.3}¢",
             sign(offset_cents),
@@ -247,7 +464,7 @@ fn equal_scale(interval_ratio: Ratio<u32>, divisions: u32) -> Vec<String> {
             offset_cents,
         ]);
     }
-    format_tabular(
+    let text = format_tabular(
         &[
             "pitch",
             "simplified",
@@ -268,7 +485,21 @@ fn equal_scale(interval_ratio: Ratio<u32>, divisions: u32) -> Vec<String> {
         ],
         3,
         &rows,
-    )
+    );
+    Output {
+        text,
+        columns: column_names(&[
+            "step",
+            "pitch",
+            "simplified",
+            "value",
+            "cents",
+            "note",
+            "delta_degrees",
+            "delta_cents",
+        ]),
+        records,
+    }
 }
 
 fn find_nearest(
@@ -277,7 +508,7 @@ fn find_nearest(
     tolerance: Pitch,
     given_max_denom: Option<u32>,
     interval: Option<Ratio<u32>>,
-) -> Vec<String> {
+) -> Output {
     let as_float = pitch.as_float();
     let max_denom;
     let target;
@@ -353,8 +584,14 @@ fn find_nearest(
         })
         .collect();
     let mut rows = Vec::new();
+    let mut records = Vec::new();
     for m in best_matches {
         let f_str = m.factor.to_string();
+        records.push(record([
+            ("pitch", json!(f_str)),
+            ("value", json!(m.freq)),
+            ("delta_cents", json!(m.cents)),
+        ]));
         let freq = format!("{:.3}", m.freq);
         let cents = format!("{:.3}¢", m.cents);
         rows.push(vec![f_str.clone(), freq.clone(), cents.clone()]);
@@ -374,42 +611,67 @@ fn find_nearest(
             }
         }
     }
-    let mut output = vec![format!("== {orig_pitch} ≈ {as_float:.3} ==")];
-    output.append(&mut format_tabular(
+    let mut text = vec![format!("== {orig_pitch} ≈ {as_float:.3} ==")];
+    text.append(&mut format_tabular(
         &["pitch", "value", "Δ cents"],
         &[Format::Char("|/"), Format::Char("."), Format::Char(".")],
         3,
         &rows,
     ));
-    output
+    Output {
+        text,
+        columns: column_names(&["pitch", "value", "delta_cents"]),
+        records,
+    }
+}
+
+/// Parse a value as a pitch or, failing that, as a note in the generated JI scale.
+fn pitch_or_note(value: &str) -> Option<Pitch> {
+    Pitch::parse(value)
+        .ok()
+        .or_else(|| score::generated_note_pitch(value))
 }
 
-fn calculate_pitch(values: Vec<String>) -> Vec<String> {
+fn calculate_pitch(values: Vec<String>) -> Output {
     fn fmt(rows: &[Vec<String>]) -> Vec<String> {
         format_tabular(&[], &[Format::Left, Format::Left], 2, rows)
     }
+    const COLUMNS: [&str; 4] = ["input", "pitch", "value", "cents"];
+    fn pitch_record(input: &str, p: Option<&Pitch>) -> Record {
+        record([
+            ("input", json!(input)),
+            ("pitch", json!(p.map(Pitch::to_string))),
+            ("value", json!(p.map(Pitch::as_float))),
+            ("cents", json!(p.map(|p| p.as_float().log2() * 1200.0))),
+        ])
+    }
 
     let mut pitch = Pitch::unit();
     let mut rows = Vec::new();
+    let mut records = Vec::new();
     let mut errors = false;
     for v in values {
-        match Pitch::parse(&v)
-            .ok()
-            .or_else(|| score::generated_note_pitch(&v))
-        {
+        match pitch_or_note(&v) {
             Some(p) => {
+                records.push(pitch_record(&v, Some(&p)));
                 rows.push(vec![v, p.to_string()]);
                 pitch *= &p;
             }
             None => {
                 errors = true;
+                records.push(pitch_record(&v, None));
                 rows.push(vec![v, "unable to parse as pitch or note".to_string()]);
             }
         }
     }
     if errors {
-        return fmt(&rows);
+        return Output {
+            text: fmt(&rows),
+            columns: column_names(&COLUMNS),
+            records,
+        };
     }
+    records.push(pitch_record("final pitch", Some(&pitch)));
     rows.push(vec!["final pitch".to_string(), pitch.to_string()]);
     let as_float = pitch.as_float();
     rows.push(vec!["frequency".to_string(), format!("{as_float:.3}")]);
@@ -426,10 +688,672 @@ fn calculate_pitch(values: Vec<String>) -> Vec<String> {
         let cents = octaves * 1200.0;
         rows.push(vec!["cents".to_string(), format!("{cents:.3}¢")]);
     }
-    fmt(&rows)
+    Output {
+        text: fmt(&rows),
+        columns: column_names(&COLUMNS),
+        records,
+    }
+}
+
+/// Return all ratios a/b, reduced to within an octave, where a and b are odd and no larger than
+/// `limit`
+fn odd_limit_ratios(limit: u32) -> Vec<Ratio<u32>> {
+    let mut result = Vec::new();
+    for a in (1..=limit).step_by(2) {
+        for b in (1..=limit).step_by(2) {
+            let mut r = Ratio::new(a, b);
+            while r < Ratio::from_integer(1) {
+                r *= 2;
+            }
+            while r >= Ratio::from_integer(2) {
+                r /= 2;
+            }
+            if r != Ratio::from_integer(1) && !result.contains(&r) {
+                result.push(r);
+            }
+        }
+    }
+    result.sort();
+    result
+}
+
+/// Reduce a ratio to within an octave
+fn octave_reduce(mut r: Ratio<u64>) -> Ratio<u64> {
+    while r < Ratio::from_integer(1) {
+        r *= 2;
+    }
+    while r >= Ratio::from_integer(2) {
+        r /= 2;
+    }
+    r
 }
 
-fn generate_output(command: Commands) -> anyhow::Result<Vec<String>> {
+/// Return the ratio with the given prime exponents or `None` if it doesn't fit in 32 bits
+fn monzo_ratio(start: Ratio<u64>, exponents: &[(u32, i32)]) -> Option<Ratio<u32>> {
+    let mut numer = *start.numer();
+    let mut denom = *start.denom();
+    for &(p, e) in exponents {
+        let factor = u64::from(p).checked_pow(e.unsigned_abs())?;
+        if e > 0 {
+            numer = numer.checked_mul(factor)?;
+        } else {
+            denom = denom.checked_mul(factor)?;
+        }
+    }
+    let r = octave_reduce(Ratio::new(numer, denom));
+    Some(Ratio::new(
+        u32::try_from(*r.numer()).ok()?,
+        u32::try_from(*r.denom()).ok()?,
+    ))
+}
+
+fn prime_factors(mut n: u32) -> Vec<u32> {
+    let mut result = Vec::new();
+    let mut p = 2;
+    while n > 1 {
+        if n.is_multiple_of(p) {
+            result.push(p);
+            n /= p;
+        } else {
+            p += 1;
+        }
+    }
+    result
+}
+
+fn edo_fit(
+    ratios: &[Ratio<u32>],
+    interval: Ratio<u32>,
+    min_divisions: u32,
+    max_divisions: u32,
+    count: usize,
+) -> Output {
+    struct Fit {
+        divisions: u32,
+        max_cents: f64,
+        rms_cents: f64,
+        max_relative: f64,
+        rms_relative: f64,
+        consistent: bool,
+    }
+    let log_interval = interval.to_f64().unwrap().ln();
+    let steps = |divisions: u32, x: f64| f64::from(divisions) * x.ln() / log_interval;
+    let mut fits = Vec::new();
+    for divisions in min_divisions..=max_divisions {
+        let step_cents = 1200.0 * interval.to_f64().unwrap().log2() / f64::from(divisions);
+        // The number of steps for a ratio's prime factors, each rounded to the nearest step
+        let factor_steps = |n: u32| -> f64 {
+            prime_factors(n)
+                .into_iter()
+                .map(|p| steps(divisions, f64::from(p)).round())
+                .sum()
+        };
+        let mut max_relative = 0.0f64;
+        let mut sum_squares = 0.0;
+        let mut consistent = true;
+        for r in ratios {
+            let exact = steps(divisions, r.to_f64().unwrap());
+            let nearest = exact.round();
+            let error = nearest - exact;
+            max_relative = max_relative.max(error.abs());
+            sum_squares += error * error;
+            if factor_steps(*r.numer()) - factor_steps(*r.denom()) != nearest {
+                consistent = false;
+            }
+        }
+        let rms_relative = (sum_squares / ratios.len() as f64).sqrt();
+        fits.push(Fit {
+            divisions,
+            max_cents: max_relative * step_cents,
+            rms_cents: rms_relative * step_cents,
+            max_relative,
+            rms_relative,
+            consistent,
+        });
+    }
+    // Rank by error relative to the step size so that small divisions aren't automatically
+    // penalized; break ties in favor of fewer divisions.
+    fits.sort_by(|a, b| {
+        a.max_relative
+            .total_cmp(&b.max_relative)
+            .then(a.divisions.cmp(&b.divisions))
+    });
+    fits.truncate(count);
+    let records = fits
+        .iter()
+        .map(|f| {
+            record([
+                ("divisions", json!(f.divisions)),
+                ("max_cents", json!(f.max_cents)),
+                ("rms_cents", json!(f.rms_cents)),
+                ("max_percent", json!(f.max_relative * 100.0)),
+                ("rms_percent", json!(f.rms_relative * 100.0)),
+                ("consistent", json!(f.consistent)),
+            ])
+        })
+        .collect();
+    let rows: Vec<Vec<String>> = fits
+        .into_iter()
+        .map(|f| {
+            vec![
+                f.divisions.to_string(),
+                format!("{:.3}¢", f.max_cents),
+                format!("{:.3}¢", f.rms_cents),
+                format!("{:.1}%", f.max_relative * 100.0),
+                format!("{:.1}%", f.rms_relative * 100.0),
+                if f.consistent { "yes" } else { "no" }.to_string(),
+            ]
+        })
+        .collect();
+    let targets: Vec<String> = ratios.iter().map(Ratio::to_string).collect();
+    let mut text = vec![format!("== {} ==", targets.join(" "))];
+    text.append(&mut format_tabular(
+        &[
+            "divisions",
+            "max ¢",
+            "rms ¢",
+            "max %",
+            "rms %",
+            "consistent",
+        ],
+        &[
+            Format::Right,
+            Format::Char("."),
+            Format::Char("."),
+            Format::Char("."),
+            Format::Char("."),
+            Format::Left,
+        ],
+        3,
+        &rows,
+    ));
+    Output {
+        text,
+        columns: column_names(&[
+            "divisions",
+            "max_cents",
+            "rms_cents",
+            "max_percent",
+            "rms_percent",
+            "consistent",
+        ]),
+        records,
+    }
+}
+
+/// Largest number of combinations of prime exponents to check when searching for commas
+const MAX_COMMA_SEARCH: u64 = 10_000_000;
+
+fn find_commas(prime_limit: u32, max_cents: f64, max_exponent: i32) -> anyhow::Result<Output> {
+    let primes: Vec<u32> = (3..=prime_limit)
+        .filter(|&n| prime_factors(n).len() == 1)
+        .collect();
+    let combinations = (2 * u64::from(max_exponent.unsigned_abs()) + 1)
+        .checked_pow(primes.len() as u32)
+        .filter(|&n| n <= MAX_COMMA_SEARCH);
+    if combinations.is_none() {
+        bail!("too many combinations to search; use a smaller prime-limit or max-exponent");
+    }
+    let mut commas = Vec::new();
+    // Visit every combination of exponents of the odd primes, and choose the power of 2 that
+    // brings each one closest to unison. Each comma is found once going up and once going down;
+    // keep the one going up.
+    let mut exponents = vec![-max_exponent; primes.len()];
+    loop {
+        let odd_cents: f64 = primes
+            .iter()
+            .zip(&exponents)
+            .map(|(&p, &e)| f64::from(e) * 1200.0 * f64::from(p).log2())
+            .sum();
+        let octaves = -(odd_cents / 1200.0).round();
+        let cents = odd_cents + 1200.0 * octaves;
+        if cents > 0.0 && cents <= max_cents {
+            let mut monzo = vec![(2, octaves as i32)];
+            monzo.extend(primes.iter().copied().zip(exponents.iter().copied()));
+            // Skip commas too large to represent.
+            if let Some(ratio) = monzo_ratio(Ratio::from_integer(1), &monzo) {
+                commas.push((cents, ratio, monzo));
+            }
+        }
+        // Advance to the next combination.
+        let Some(i) = exponents.iter().position(|&e| e < max_exponent) else {
+            break;
+        };
+        for e in &mut exponents[..i] {
+            *e = -max_exponent;
+        }
+        exponents[i] += 1;
+    }
+    commas.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let speller = score::NoteSpeller::new();
+    let mut rows = Vec::new();
+    let mut records = Vec::new();
+    for (cents, ratio, monzo) in commas {
+        let exponents: Vec<i32> = monzo.iter().map(|&(_, e)| e).collect();
+        let note = speller.spell(ratio);
+        records.push(record([
+            ("ratio", json!(ratio.to_string())),
+            ("cents", json!(cents)),
+            ("monzo", json!(exponents)),
+            ("note", json!(note)),
+        ]));
+        let monzo: Vec<String> = exponents.iter().map(i32::to_string).collect();
+        rows.push(vec![
+            ratio.to_string(),
+            format!("{cents:.3}¢"),
+            format!("[{}⟩", monzo.join(" ")),
+            note.unwrap_or_default(),
+        ]);
+    }
+    let text = format_tabular(
+        &["ratio", "cents", "monzo", "note"],
+        &[
+            Format::Char("/"),
+            Format::Char("."),
+            Format::Left,
+            Format::Left,
+        ],
+        3,
+        &rows,
+    );
+    Ok(Output {
+        text,
+        columns: column_names(&["ratio", "cents", "monzo", "note"]),
+        records,
+    })
+}
+
+fn lattice(center: Ratio<u32>, primes: &[u32], radius: i32) -> anyhow::Result<Output> {
+    let center = Ratio::new(u64::from(*center.numer()), u64::from(*center.denom()));
+    let speller = score::NoteSpeller::new();
+    let mut records = Vec::new();
+    let mut cell = |exponents: &[(u32, i32)]| -> anyhow::Result<String> {
+        let ratio = monzo_ratio(center, exponents)
+            .ok_or_else(|| anyhow!("lattice pitches are too large; try a smaller radius"))?;
+        let note = speller.spell(ratio);
+        let mut r: Record = exponents
+            .iter()
+            .map(|&(p, e)| (format!("exponent_{p}"), json!(e)))
+            .collect();
+        r.extend(record([
+            ("ratio", json!(ratio.to_string())),
+            ("cents", json!(ratio.to_f64().unwrap().log2() * 1200.0)),
+            ("note", json!(note)),
+        ]));
+        records.push(r);
+        Ok(match note {
+            Some(name) => format!("{name} {ratio}"),
+            None => ratio.to_string(),
+        })
+    };
+    // The first prime runs across, the second runs down with positive exponents at the top,
+    // and the third, if any, selects the grid.
+    let across = primes[0];
+    let rows_for = |p: Option<&u32>| -> Vec<Option<(u32, i32)>> {
+        match p {
+            None => vec![None],
+            Some(&p) => (-radius..=radius).rev().map(|e| Some((p, e))).collect(),
+        }
+    };
+    let mut layers = rows_for(primes.get(2));
+    layers.reverse();
+    let down = rows_for(primes.get(1));
+    // Only label rows when there is a second prime.
+    let labeled = primes.len() > 1;
+    let mut headers = Vec::new();
+    let mut formats = Vec::new();
+    if labeled {
+        headers.push(String::new());
+        formats.push(Format::Right);
+    }
+    for e in -radius..=radius {
+        headers.push(format!("{across}^{e}"));
+        formats.push(Format::Left);
+    }
+    let headers: Vec<&str> = headers.iter().map(String::as_str).collect();
+    let mut text = Vec::new();
+    for layer in layers {
+        if let Some((p, e)) = layer {
+            text.push(format!("== {p}^{e} =="));
+        }
+        let mut rows = Vec::new();
+        for &row_factor in &down {
+            let mut row = Vec::new();
+            if let Some((p, e)) = row_factor {
+                row.push(format!("{p}^{e}"));
+            }
+            for e in -radius..=radius {
+                let mut exponents = vec![(across, e)];
+                exponents.extend(row_factor);
+                exponents.extend(layer);
+                row.push(cell(&exponents)?);
+            }
+            rows.push(row);
+        }
+        text.append(&mut format_tabular(&headers, &formats, 3, &rows));
+    }
+    let mut columns: Vec<String> = primes.iter().map(|p| format!("exponent_{p}")).collect();
+    columns.extend(column_names(&["ratio", "cents", "note"]));
+    Ok(Output {
+        text,
+        columns,
+        records,
+    })
+}
+
+fn mos_sizes(period: Ratio<u32>, generator: &Pitch, max_size: u32) -> Output {
+    let mut rows = Vec::new();
+    let mut records = Vec::new();
+    for size in 2..=max_size {
+        let Ok(mos) = score::Mos::new(period, generator, size, 0) else {
+            continue;
+        };
+        records.push(record([
+            ("size", json!(size)),
+            ("pattern", json!(mos.pattern)),
+            ("large_cents", json!(mos.large)),
+            ("small_cents", json!(mos.small)),
+            ("ratio", json!(mos.large / mos.small)),
+        ]));
+        rows.push(vec![
+            size.to_string(),
+            mos.pattern,
+            format!("{:.3}¢", mos.large),
+            format!("{:.3}¢", mos.small),
+            format!("{:.3}", mos.large / mos.small),
+        ]);
+    }
+    let text = format_tabular(
+        &["size", "pattern", "L", "s", "L/s"],
+        &[
+            Format::Right,
+            Format::Left,
+            Format::Char("."),
+            Format::Char("."),
+            Format::Char("."),
+        ],
+        3,
+        &rows,
+    );
+    Output {
+        text,
+        columns: column_names(&["size", "pattern", "large_cents", "small_cents", "ratio"]),
+        records,
+    }
+}
+
+fn chord(names: &[String], pitches: &[Pitch], tolerance: f64, max_harmonic: u32) -> Output {
+    let analysis = ChordAnalysis::new(pitches, tolerance, max_harmonic);
+    let number = |fit: &Option<SeriesFit>, i: usize| fit.as_ref().map(|f| f.numbers[i]);
+    let deviation = |fit: &Option<SeriesFit>, i: usize| fit.as_ref().map(|f| f.deviations[i]);
+    let or_dash = |v: Option<String>| v.unwrap_or_else(|| "-".to_string());
+    let mut rows = Vec::new();
+    let mut records = Vec::new();
+    for (i, (name, pitch)) in names.iter().zip(pitches).enumerate() {
+        let (otonal, utonal) = (&analysis.otonal, &analysis.utonal);
+        records.push(record([
+            ("note", json!(name)),
+            ("pitch", json!(pitch.to_string())),
+            ("cents", json!(analysis.cents[i])),
+            ("harmonic", json!(number(otonal, i))),
+            ("harmonic_delta_cents", json!(deviation(otonal, i))),
+            ("subharmonic", json!(number(utonal, i))),
+            ("subharmonic_delta_cents", json!(deviation(utonal, i))),
+            ("intervals", json!(analysis.intervals[i])),
+        ]));
+        rows.push(vec![
+            name.clone(),
+            pitch.to_string(),
+            format!("{:.3}¢", analysis.cents[i]),
+            or_dash(number(otonal, i).map(|n| n.to_string())),
+            or_dash(deviation(otonal, i).map(|d| format!("{d:.3}¢"))),
+            or_dash(number(utonal, i).map(|n| format!("1/{n}"))),
+            or_dash(deviation(utonal, i).map(|d| format!("{d:.3}¢"))),
+        ]);
+    }
+    let mut text = format_tabular(
+        &[
+            "note",
+            "pitch",
+            "cents",
+            "harmonic",
+            "Δ cents",
+            "subharmonic",
+            "Δ cents",
+        ],
+        &[
+            Format::Left,
+            Format::Left,
+            Format::Char("."),
+            Format::Right,
+            Format::Char("."),
+            Format::Right,
+            Format::Char("."),
+        ],
+        3,
+        &rows,
+    );
+
+    text.push("== intervals ==".to_string());
+    let mut headers = vec![""];
+    headers.extend(names.iter().map(String::as_str));
+    let mut formats = vec![Format::Left];
+    formats.extend(names.iter().map(|_| Format::Char(".")));
+    let rows: Vec<Vec<String>> = names
+        .iter()
+        .zip(&analysis.intervals)
+        .map(|(name, intervals)| {
+            let mut row = vec![name.clone()];
+            row.extend(intervals.iter().map(|c| format!("{c:.3}")));
+            row
+        })
+        .collect();
+    text.append(&mut format_tabular(&headers, &formats, 3, &rows));
+
+    text.push("== summary ==".to_string());
+    let summarize = |fit: &Option<SeriesFit>, prefix: &str| match fit {
+        None => "none within tolerance".to_string(),
+        Some(f) => {
+            let numbers: Vec<String> = f.numbers.iter().map(|n| format!("{prefix}{n}")).collect();
+            format!("{} (max Δ {:.3}¢)", numbers.join(":"), f.max_deviation())
+        }
+    };
+    text.push(format!("otonal: {}", summarize(&analysis.otonal, "")));
+    text.push(format!("utonal: {}", summarize(&analysis.utonal, "1/")));
+    text.push(format!("reading: {}", analysis.reading().unwrap_or("none")));
+    Output {
+        text,
+        columns: column_names(&[
+            "note",
+            "pitch",
+            "cents",
+            "harmonic",
+            "harmonic_delta_cents",
+            "subharmonic",
+            "subharmonic_delta_cents",
+            "intervals",
+        ]),
+        records,
+    }
+}
+
+/// The degrees of a scale within its cycle
+pub(crate) struct ScaleDegrees {
+    pub(crate) name: String,
+    pub(crate) cycle_cents: f64,
+    pub(crate) notes: Vec<String>,
+    pub(crate) cents: Vec<f64>,
+}
+
+impl ScaleDegrees {
+    pub(crate) fn from_scale(scale: &score::Scale) -> Self {
+        Self {
+            name: scale.definition.name.to_string(),
+            cycle_cents: scale.definition.cycle.to_f64().unwrap().log2() * 1200.0,
+            notes: scale
+                .primary_names
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            cents: scale
+                .pitches
+                .iter()
+                .map(|p| p.as_float().log2() * 1200.0)
+                .collect(),
+        }
+    }
+
+    /// Parse N-EDO, naming notes as in a generated scale with N divisions.
+    pub(crate) fn inline_edo(name: &str) -> Option<Self> {
+        let divisions: u32 = name.strip_suffix("-EDO")?.parse().ok()?;
+        if divisions == 0 {
+            return None;
+        }
+        let notes = score::generated_note_names(Ratio::from_integer(2), divisions);
+        Some(Self {
+            name: name.to_string(),
+            cycle_cents: 1200.0,
+            notes: notes.into_iter().map(|(note, _)| note).collect(),
+            cents: (0..divisions)
+                .map(|i| 1200.0 * f64::from(i) / f64::from(divisions))
+                .collect(),
+        })
+    }
+
+    /// Return the index of the degree nearest to `cents` and how far above `cents` it is,
+    /// considering degrees in adjacent cycles.
+    pub(crate) fn nearest(&self, cents: f64) -> (usize, f64) {
+        let mut best = (0, f64::INFINITY);
+        for (i, c) in self.cents.iter().enumerate() {
+            for cycle in [-1.0, 0.0, 1.0] {
+                let delta = c + cycle * self.cycle_cents - cents;
+                if delta.abs() < best.1.abs() {
+                    best = (i, delta);
+                }
+            }
+        }
+        // Treat rounding noise as an exact match.
+        if best.1.abs() < 1e-9 {
+            best.1 = 0.0;
+        }
+        best
+    }
+}
+
+/// Load the named scales from the score, or from the built-in scales if there is no score.
+fn load_scales(score: Option<&PathBuf>, names: &[String]) -> anyhow::Result<Vec<ScaleDegrees>> {
+    let (filename, src) = match score {
+        Some(path) => (path.display().to_string(), fs::read_to_string(path)?),
+        None => (
+            "built-in scales".to_string(),
+            "syntoniq(version=1)\n".to_string(),
+        ),
+    };
+    let timeline = parsing::timeline(&filename, &src, &parsing::Options::default())?;
+    let mut result = Vec::new();
+    for name in names {
+        let scale = match timeline.scales.get(name.as_str()) {
+            Some(scale) => ScaleDegrees::from_scale(scale),
+            None => ScaleDegrees::inline_edo(name)
+                .ok_or_else(|| anyhow!("{name} is not a known scale or N-EDO"))?,
+        };
+        if scale.cents.is_empty() {
+            bail!("scale {name} has no notes");
+        }
+        result.push(scale);
+    }
+    Ok(result)
+}
+
+fn compare_scales(a: &ScaleDegrees, b: &ScaleDegrees, tolerance: f64) -> Output {
+    let mut records = Vec::new();
+    let mut rows = Vec::new();
+    let mut unmatched = Vec::new();
+    for (from, to, first) in [(a, b, true), (b, a, false)] {
+        let step = to.cycle_cents / to.cents.len() as f64;
+        let mut missing = Vec::new();
+        for (degree, (note, &cents)) in from.notes.iter().zip(&from.cents).enumerate() {
+            let (nearest, delta) = to.nearest(cents);
+            let close = delta.abs() <= tolerance;
+            if !close {
+                missing.push(note.as_str());
+            }
+            records.push(record([
+                ("scale", json!(from.name)),
+                ("degree", json!(degree)),
+                ("note", json!(note)),
+                ("cents", json!(cents)),
+                ("other_scale", json!(to.name)),
+                ("nearest_degree", json!(nearest)),
+                ("nearest_note", json!(to.notes[nearest])),
+                ("nearest_cents", json!(to.cents[nearest])),
+                ("delta_cents", json!(delta)),
+                ("delta_steps", json!(delta / step)),
+                ("close", json!(close)),
+            ]));
+            // The table shows only the first scale's degrees.
+            if first {
+                rows.push(vec![
+                    degree.to_string(),
+                    note.clone(),
+                    format!("{cents:.3}¢"),
+                    nearest.to_string(),
+                    to.notes[nearest].clone(),
+                    format!("{:.3}¢", to.cents[nearest]),
+                    format!("{delta:.3}¢"),
+                    format!("{:.3}", delta / step),
+                ]);
+            }
+        }
+        let missing = if missing.is_empty() {
+            "none".to_string()
+        } else {
+            missing.join(" ")
+        };
+        unmatched.push(format!(
+            "{} notes with no counterpart in {} within {tolerance:.3}¢: {missing}",
+            from.name, to.name
+        ));
+    }
+    let mut text = vec![format!("== {} → {} ==", a.name, b.name)];
+    text.append(&mut format_tabular(
+        &[
+            "degree", "note", "cents", "nearest", "note", "cents", "Δ cents", "Δ steps",
+        ],
+        &[
+            Format::Right,
+            Format::Left,
+            Format::Char("."),
+            Format::Right,
+            Format::Left,
+            Format::Char("."),
+            Format::Char("."),
+            Format::Char("."),
+        ],
+        3,
+        &rows,
+    ));
+    text.append(&mut unmatched);
+    Output {
+        text,
+        columns: column_names(&[
+            "scale",
+            "degree",
+            "note",
+            "cents",
+            "other_scale",
+            "nearest_degree",
+            "nearest_note",
+            "nearest_cents",
+            "delta_cents",
+            "delta_steps",
+            "close",
+        ]),
+        records,
+    }
+}
+
+fn generate_output(command: Commands, format: OutputFormat) -> anyhow::Result<Vec<String>> {
     let output = match command {
         Commands::EqualScale {
             interval,
@@ -465,12 +1389,154 @@ fn generate_output(command: Commands) -> anyhow::Result<Vec<String>> {
             find_nearest(&pitch, as_pitch, tolerance, max_denom, interval)
         }
         Commands::Pitch { values } => calculate_pitch(values),
+        Commands::EdoFit {
+            ratio,
+            odd_limit,
+            interval,
+            min_divisions,
+            max_divisions,
+            count,
+        } => {
+            let mut ratios = Vec::new();
+            for r in &ratio {
+                let r = pitch_or_note(r)
+                    .and_then(|p| p.as_rational())
+                    .ok_or_else(|| anyhow!("{r} is not a ratio or generated JI note"))?;
+                if !ratios.contains(&r) {
+                    ratios.push(r);
+                }
+            }
+            if let Some(limit) = odd_limit {
+                for r in odd_limit_ratios(limit) {
+                    if !ratios.contains(&r) {
+                        ratios.push(r);
+                    }
+                }
+            }
+            if ratios.is_empty() {
+                bail!("at least one ratio or an odd limit of at least 3 must be given");
+            }
+            let interval = match interval {
+                None => Ratio::from_integer(2),
+                Some(i) => Pitch::parse(&i)?
+                    .as_rational()
+                    .ok_or_else(|| anyhow!("interval must be rational"))?,
+            };
+            if interval <= Ratio::from_integer(1) {
+                bail!("interval must be greater than 1");
+            }
+            let min_divisions = min_divisions.unwrap_or(5).max(1);
+            let max_divisions = max_divisions.unwrap_or(72);
+            if max_divisions < min_divisions {
+                bail!("max-divisions must be at least min-divisions");
+            }
+            edo_fit(
+                &ratios,
+                interval,
+                min_divisions,
+                max_divisions,
+                count.unwrap_or(10),
+            )
+        }
+        Commands::Mos {
+            period,
+            generator,
+            max_size,
+        } => {
+            let period = match period {
+                None => Ratio::from_integer(2),
+                Some(p) => Pitch::parse(&p)?
+                    .as_rational()
+                    .ok_or_else(|| anyhow!("period must be rational"))?,
+            };
+            let generator = Pitch::parse(&generator)?;
+            score::Mos::check_period(period).map_err(|e| anyhow!(e))?;
+            score::Mos::check_generator(period, &generator).map_err(|e| anyhow!(e))?;
+            let max_size = max_size.unwrap_or(24);
+            if max_size > score::Mos::MAX_SIZE {
+                bail!("--max-size must be at most {}", score::Mos::MAX_SIZE);
+            }
+            mos_sizes(period, &generator, max_size)
+        }
+        Commands::Comma {
+            prime_limit,
+            max_cents,
+            max_exponent,
+        } => {
+            let prime_limit = prime_limit.unwrap_or(5);
+            if prime_limit < 3 {
+                bail!("prime-limit must be at least 3");
+            }
+            let max_exponent = i32::try_from(max_exponent.unwrap_or(12))?;
+            find_commas(prime_limit, max_cents.unwrap_or(30.0), max_exponent)?
+        }
+        Commands::Lattice {
+            pitch,
+            prime,
+            radius,
+        } => {
+            let center = match pitch {
+                None => Ratio::from_integer(1),
+                Some(p) => pitch_or_note(&p)
+                    .and_then(|p| p.as_rational())
+                    .ok_or_else(|| anyhow!("{p} is not a ratio or generated JI note"))?,
+            };
+            let primes = if prime.is_empty() { vec![3, 5] } else { prime };
+            if primes.len() > 3 {
+                bail!("at most three primes may be given");
+            }
+            for &p in &primes {
+                if p < 3 || prime_factors(p).len() != 1 {
+                    bail!("{p} is not an odd prime");
+                }
+            }
+            lattice(center, &primes, i32::try_from(radius.unwrap_or(2))?)?
+        }
+        Commands::Chord {
+            values,
+            tolerance,
+            max_harmonic,
+        } => {
+            if values.len() < 2 {
+                bail!("a chord must have at least two notes");
+            }
+            let mut pitches = Vec::new();
+            for v in &values {
+                pitches.push(
+                    pitch_or_note(v)
+                        .ok_or_else(|| anyhow!("unable to parse {v} as pitch or note"))?,
+                );
+            }
+            let tolerance = tolerance
+                .map(|s| Pitch::parse(&s))
+                .unwrap_or(Ok(Pitch::must_parse("^1|75")))?;
+            chord(
+                &values,
+                &pitches,
+                tolerance.as_float().log2() * 1200.0,
+                max_harmonic.unwrap_or(64),
+            )
+        }
+        Commands::Compare {
+            score,
+            scale,
+            tolerance,
+        } => {
+            if scale.len() != 2 {
+                bail!("--scale must be given exactly twice");
+            }
+            let scales = load_scales(score.as_ref(), &scale)?;
+            let tolerance = tolerance
+                .map(|s| Pitch::parse(&s))
+                .unwrap_or(Ok(Pitch::must_parse("^1|75")))?;
+            compare_scales(&scales[0], &scales[1], tolerance.as_float().log2() * 1200.0)
+        }
     };
-    Ok(output)
+    Ok(output.render(format))
 }
 
-pub fn run(command: Commands) -> anyhow::Result<()> {
-    for line in generate_output(command)? {
+pub fn run(command: Commands, format: OutputFormat) -> anyhow::Result<()> {
+    for line in generate_output(command, format)? {
         println!("{line}");
     }
     Ok(())
@@ -482,10 +1548,13 @@ mod tests {
 
     #[test]
     fn test_equal_scale() {
-        let out = generate_output(Commands::EqualScale {
-            interval: Some("3".to_string()),
-            divisions: 13,
-        })
+        let out = generate_output(
+            Commands::EqualScale {
+                interval: Some("3".to_string()),
+                divisions: 13,
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(
             out,
@@ -508,10 +1577,13 @@ mod tests {
             ]
         );
 
-        let out = generate_output(Commands::EqualScale {
-            interval: None,
-            divisions: 19,
-        })
+        let out = generate_output(
+            Commands::EqualScale {
+                interval: None,
+                divisions: 19,
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(
             out,
@@ -543,12 +1615,15 @@ mod tests {
 
     #[test]
     fn test_near() {
-        let out = generate_output(Commands::Near {
-            pitch: "5/4".to_string(),
-            interval: None,
-            max_denom: Some(19),
-            tolerance: None,
-        })
+        let out = generate_output(
+            Commands::Near {
+                pitch: "5/4".to_string(),
+                interval: None,
+                max_denom: Some(19),
+                tolerance: None,
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(
             out,
@@ -566,12 +1641,15 @@ mod tests {
             ]
         );
 
-        let out = generate_output(Commands::Near {
-            pitch: "^6|17".to_string(),
-            interval: None,
-            max_denom: None,
-            tolerance: None,
-        })
+        let out = generate_output(
+            Commands::Near {
+                pitch: "^6|17".to_string(),
+                interval: None,
+                max_denom: None,
+                tolerance: None,
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(
             out,
@@ -589,12 +1667,15 @@ mod tests {
             ]
         );
 
-        let out = generate_output(Commands::Near {
-            pitch: "^7|12".to_string(),
-            interval: Some("3".to_string()),
-            max_denom: None,
-            tolerance: None,
-        })
+        let out = generate_output(
+            Commands::Near {
+                pitch: "^7|12".to_string(),
+                interval: Some("3".to_string()),
+                max_denom: None,
+                tolerance: None,
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(
             out,
@@ -640,12 +1721,15 @@ mod tests {
             ]
         );
 
-        let out = generate_output(Commands::Near {
-            pitch: "^7|12".to_string(),
-            interval: Some("3".to_string()),
-            max_denom: Some(47),
-            tolerance: Some("^1|150".to_string()),
-        })
+        let out = generate_output(
+            Commands::Near {
+                pitch: "^7|12".to_string(),
+                interval: Some("3".to_string()),
+                max_denom: Some(47),
+                tolerance: Some("^1|150".to_string()),
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(
             out,
@@ -671,9 +1755,12 @@ mod tests {
 
     #[test]
     fn test_pitch() {
-        let out = generate_output(Commands::Pitch {
-            values: vec!["C!17".to_string()],
-        })
+        let out = generate_output(
+            Commands::Pitch {
+                values: vec!["C!17".to_string()],
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(
             out,
@@ -686,9 +1773,12 @@ mod tests {
             ]
         );
 
-        let out = generate_output(Commands::Pitch {
-            values: vec!["C!2/17".to_string()],
-        })
+        let out = generate_output(
+            Commands::Pitch {
+                values: vec!["C!2/17".to_string()],
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(
             out,
@@ -701,9 +1791,12 @@ mod tests {
             ]
         );
 
-        let out = generate_output(Commands::Pitch {
-            values: vec!["C!3/17".to_string()],
-        })
+        let out = generate_output(
+            Commands::Pitch {
+                values: vec!["C!3/17".to_string()],
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(
             out,
@@ -716,9 +1809,12 @@ mod tests {
             ]
         );
 
-        let out = generate_output(Commands::Pitch {
-            values: vec!["D!3/2/17".to_string()],
-        })
+        let out = generate_output(
+            Commands::Pitch {
+                values: vec!["D!3/2/17".to_string()],
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(
             out,
@@ -731,15 +1827,21 @@ mod tests {
             ]
         );
 
-        let out = generate_output(Commands::Pitch {
-            values: vec!["D!3/2/17/3".to_string()],
-        })
+        let out = generate_output(
+            Commands::Pitch {
+                values: vec!["D!3/2/17/3".to_string()],
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(out, ["D!3/2/17/3  unable to parse as pitch or note",]);
 
-        let out = generate_output(Commands::Pitch {
-            values: vec!["E!41".to_string()],
-        })
+        let out = generate_output(
+            Commands::Pitch {
+                values: vec!["E!41".to_string()],
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(
             out,
@@ -752,9 +1854,12 @@ mod tests {
             ]
         );
 
-        let out = generate_output(Commands::Pitch {
-            values: vec!["^4|17".to_string()],
-        })
+        let out = generate_output(
+            Commands::Pitch {
+                values: vec!["^4|17".to_string()],
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(
             out,
@@ -767,9 +1872,12 @@ mod tests {
             ]
         );
 
-        let out = generate_output(Commands::Pitch {
-            values: vec!["264".to_string(), "E!41".to_string()],
-        })
+        let out = generate_output(
+            Commands::Pitch {
+                values: vec!["264".to_string(), "E!41".to_string()],
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(
             out,
@@ -783,9 +1891,12 @@ mod tests {
             ]
         );
 
-        let out = generate_output(Commands::Pitch {
-            values: vec!["220*^1|4".to_string()],
-        })
+        let out = generate_output(
+            Commands::Pitch {
+                values: vec!["220*^1|4".to_string()],
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(
             out,
@@ -798,9 +1909,12 @@ mod tests {
             ]
         );
 
-        let out = generate_output(Commands::Pitch {
-            values: vec!["220*^4|17".to_string()],
-        })
+        let out = generate_output(
+            Commands::Pitch {
+                values: vec!["220*^4|17".to_string()],
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(
             out,
@@ -813,9 +1927,12 @@ mod tests {
             ]
         );
 
-        let out = generate_output(Commands::Pitch {
-            values: vec!["440".to_string(), "E!41".to_string()],
-        })
+        let out = generate_output(
+            Commands::Pitch {
+                values: vec!["440".to_string(), "E!41".to_string()],
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(
             out,
@@ -829,15 +1946,21 @@ mod tests {
             ]
         );
 
-        let out = generate_output(Commands::Pitch {
-            values: vec!["440*E!41".to_string()],
-        })
+        let out = generate_output(
+            Commands::Pitch {
+                values: vec!["440*E!41".to_string()],
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(out, ["440*E!41  unable to parse as pitch or note",]);
 
-        let out = generate_output(Commands::Pitch {
-            values: vec!["440*^-9|12".to_string()],
-        })
+        let out = generate_output(
+            Commands::Pitch {
+                values: vec!["440*^-9|12".to_string()],
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(
             out,
@@ -850,9 +1973,12 @@ mod tests {
             ]
         );
 
-        let out = generate_output(Commands::Pitch {
-            values: vec!["440*^-9|12".to_string(), "C!12".to_string()],
-        })
+        let out = generate_output(
+            Commands::Pitch {
+                values: vec!["440*^-9|12".to_string(), "C!12".to_string()],
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(
             out,
@@ -866,9 +1992,12 @@ mod tests {
             ]
         );
 
-        let out = generate_output(Commands::Pitch {
-            values: vec!["440*^-9|12".to_string(), "C!19".to_string()],
-        })
+        let out = generate_output(
+            Commands::Pitch {
+                values: vec!["440*^-9|12".to_string(), "C!19".to_string()],
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(
             out,
@@ -882,9 +2011,12 @@ mod tests {
             ]
         );
 
-        let out = generate_output(Commands::Pitch {
-            values: vec!["440*^-9|12".to_string(), "C".to_string()],
-        })
+        let out = generate_output(
+            Commands::Pitch {
+                values: vec!["440*^-9|12".to_string(), "C".to_string()],
+            },
+            OutputFormat::Text,
+        )
         .unwrap();
         assert_eq!(
             out,
@@ -898,4 +2030,331 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_mos() {
+        let out = generate_output(
+            Commands::Mos {
+                period: Some("3".to_string()),
+                generator: "3^6|13".to_string(),
+                max_size: None,
+            },
+            OutputFormat::Text,
+        )
+        .unwrap();
+        assert_eq!(
+            out,
+            [
+                "size     pattern         L          s        L/s",
+                "  2    sL            1024.130¢   877.825¢   1.167",
+                "  3    LLs            877.825¢   146.304¢   6.000",
+                "  5    LsLss          731.521¢   146.304¢   5.000",
+                "  7    LssLsss        585.217¢   146.304¢   4.000",
+                "  9    LsssLssss      438.913¢   146.304¢   3.000",
+                " 11    LssssLsssss    292.608¢   146.304¢   2.000",
+            ]
+        );
+        for (period, generator) in [("1", "3/2"), ("2", "1"), ("2", "4")] {
+            assert!(
+                generate_output(
+                    Commands::Mos {
+                        period: Some(period.to_string()),
+                        generator: generator.to_string(),
+                        max_size: None,
+                    },
+                    OutputFormat::Text,
+                )
+                .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_edo_fit() {
+        let out = generate_output(
+            Commands::EdoFit {
+                ratio: vec!["E".to_string(), "3/2".to_string()],
+                odd_limit: None,
+                interval: None,
+                min_divisions: None,
+                max_divisions: Some(31),
+                count: Some(3),
+            },
+            OutputFormat::Text,
+        )
+        .unwrap();
+        assert_eq!(
+            out,
+            [
+                "== 5/4 3/2 ==",
+                "divisions   max ¢    rms ¢    max %   rms %   consistent",
+                "   19       7.366¢   7.293¢   11.7%   11.5%      yes",
+                "   22       7.136¢   5.964¢   13.1%   10.9%      yes",
+                "   31       5.181¢   3.705¢   13.4%    9.6%      yes",
+            ]
+        );
+        assert!(
+            generate_output(
+                Commands::EdoFit {
+                    ratio: vec![],
+                    odd_limit: None,
+                    interval: None,
+                    min_divisions: None,
+                    max_divisions: None,
+                    count: None,
+                },
+                OutputFormat::Text,
+            )
+            .is_err()
+        );
+        assert!(
+            generate_output(
+                Commands::EdoFit {
+                    ratio: vec!["3/2".to_string(), "5/4".to_string()],
+                    odd_limit: None,
+                    interval: Some("1".to_string()),
+                    min_divisions: None,
+                    max_divisions: None,
+                    count: None,
+                },
+                OutputFormat::Text,
+            )
+            .is_err()
+        );
+        assert_eq!(
+            odd_limit_ratios(5),
+            [(6, 5), (5, 4), (4, 3), (3, 2), (8, 5), (5, 3)].map(|(n, d)| Ratio::new(n, d))
+        );
+    }
+
+    #[test]
+    fn test_comma() {
+        let out = generate_output(
+            Commands::Comma {
+                prime_limit: None,
+                max_cents: Some(22.0),
+                max_exponent: Some(4),
+            },
+            OutputFormat::Text,
+        )
+        .unwrap();
+        assert_eq!(
+            out,
+            [
+                "  ratio      cents      monzo      note",
+                "2048/2025   19.553¢   [11 -4 -2⟩   iPP",
+                "  81/80     21.506¢   [-4 4 -1⟩    Ij",
+            ]
+        );
+        assert!(
+            generate_output(
+                Commands::Comma {
+                    prime_limit: Some(23),
+                    max_cents: None,
+                    max_exponent: None,
+                },
+                OutputFormat::Text,
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_lattice() {
+        let out = generate_output(
+            Commands::Lattice {
+                pitch: None,
+                prime: vec![],
+                radius: Some(1),
+            },
+            OutputFormat::Text,
+        )
+        .unwrap();
+        assert_eq!(
+            out,
+            [
+                "        3^-1      3^0       3^1",
+                " 5^1   Bf 5/3    E 5/4    Bp 15/8",
+                " 5^0   D 4/3     A 1      C 3/2",
+                "5^-1   P 16/15   Be 8/5   F 6/5",
+            ]
+        );
+        let out = generate_output(
+            Commands::Lattice {
+                pitch: Some("E".to_string()),
+                prime: vec![7],
+                radius: Some(1),
+            },
+            OutputFormat::Text,
+        )
+        .unwrap();
+        assert_eq!(
+            out,
+            [" 7^-1      7^0      7^1", "Cu 10/7   E 5/4   Eh 35/32"]
+        );
+        assert!(
+            generate_output(
+                Commands::Lattice {
+                    pitch: None,
+                    prime: vec![9],
+                    radius: None,
+                },
+                OutputFormat::Text,
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_chord() {
+        let chord = |values: &[&str], tolerance: Option<&str>| {
+            generate_output(
+                Commands::Chord {
+                    values: values.iter().map(ToString::to_string).collect(),
+                    tolerance: tolerance.map(ToString::to_string),
+                    max_harmonic: None,
+                },
+                OutputFormat::Text,
+            )
+        };
+        assert_eq!(
+            chord(&["A", "E", "C"], None).unwrap(),
+            [
+                "note   pitch    cents     harmonic   Δ cents   subharmonic   Δ cents",
+                " A      1        0.000¢      4       0.000¢       1/15       0.000¢",
+                " E      5/4    386.314¢      5       0.000¢       1/12       0.000¢",
+                " C      3/2    701.955¢      6       0.000¢       1/10       0.000¢",
+                "== intervals ==",
+                "       A          E          C",
+                "A      0.000    386.314   701.955",
+                "E   -386.314      0.000   315.641",
+                "C   -701.955   -315.641     0.000",
+                "== summary ==",
+                "otonal: 4:5:6 (max Δ 0.000¢)",
+                "utonal: 1/15:1/12:1/10 (max Δ 0.000¢)",
+                "reading: otonal",
+            ]
+        );
+        let out = chord(&["1", "^1|3", "^7|12", "^5|6"], Some("^1|38")).unwrap();
+        assert_eq!(out[out.len() - 3], "otonal: 4:5:6:7 (max Δ 31.174¢)");
+        // Tritone: 41/29 is within 1¢, but not with smaller harmonics.
+        let out = generate_output(
+            Commands::Chord {
+                values: vec!["1".to_string(), "^1|2".to_string()],
+                tolerance: Some("^1|1200".to_string()),
+                max_harmonic: Some(16),
+            },
+            OutputFormat::Text,
+        )
+        .unwrap();
+        assert_eq!(
+            out[out.len() - 3..],
+            [
+                "otonal: none within tolerance",
+                "utonal: none within tolerance",
+                "reading: none",
+            ]
+        );
+        assert!(chord(&["1", "Xyz!"], None).is_err());
+        assert!(chord(&["1"], None).is_err());
+    }
+
+    #[test]
+    fn test_compare() {
+        let compare = |score: Option<&str>, scales: &[&str]| {
+            generate_output(
+                Commands::Compare {
+                    score: score.map(PathBuf::from),
+                    scale: scales.iter().map(ToString::to_string).collect(),
+                    tolerance: None,
+                },
+                OutputFormat::Text,
+            )
+        };
+        assert_eq!(
+            compare(
+                Some("test-data/test23-temperament.stq"),
+                &["meantone", "31-EDO"]
+            )
+            .unwrap(),
+            [
+                "== meantone → 31-EDO ==",
+                "degree   note     cents     nearest   note     cents     Δ cents   Δ steps",
+                "  0       A        0.000¢      0       c        0.000¢    0.000¢    0.000",
+                "  1       I      194.428¢      5       d      193.548¢   -0.880¢   -0.023",
+                "  2       E      388.856¢     10       e      387.097¢   -1.759¢   -0.045",
+                "  3       D      502.786¢     13       f      503.226¢    0.440¢    0.011",
+                "  4       C      697.214¢     18       g      696.774¢   -0.440¢   -0.011",
+                "  5       Bf     891.642¢     23       a      890.323¢   -1.319¢   -0.034",
+                "  6       Bp    1086.070¢     28       b     1083.871¢   -2.199¢   -0.057",
+                "meantone notes with no counterpart in 31-EDO within 16.000¢: none",
+                "31-EDO notes with no counterpart in meantone within 16.000¢: \
+                 c+ c# d% d- d+ d# e% e- f% e# f+ f# g% g- g+ g# a% a- a+ a# b% b- b+ b#",
+            ]
+        );
+        // Inline equal divisions are named as in a generated scale.
+        let out = compare(None, &["12-EDO", "22-EDO"]).unwrap();
+        assert_eq!(
+            out[4],
+            "   2      d      200.000¢      4      H%      218.182¢    18.182¢    0.333"
+        );
+        assert_eq!(
+            out[14],
+            "12-EDO notes with no counterpart in 22-EDO within 16.000¢: d e% e a% a b%"
+        );
+        assert!(compare(None, &["12-EDO"]).is_err());
+        assert!(compare(None, &["12-EDO", "nope"]).is_err());
+        assert!(compare(Some("test-data/nope.stq"), &["12-EDO", "19-EDO"]).is_err());
+    }
+
+    #[test]
+    fn test_output_formats() {
+        let mos = || Commands::Mos {
+            period: None,
+            generator: "^7|12".to_string(),
+            max_size: Some(3),
+        };
+        // Floating point values may differ in the last place, so check JSON structurally.
+        let out = generate_output(mos(), OutputFormat::Json).unwrap();
+        let v: Value = serde_json::from_str(&out.join("\n")).unwrap();
+        assert_eq!(v.as_array().unwrap().len(), 2);
+        assert_eq!(v[0]["size"], 2);
+        assert_eq!(v[1]["pattern"], "sLL");
+        assert!((v[1]["large_cents"].as_f64().unwrap() - 500.0).abs() < 1e-6);
+        let out = generate_output(mos(), OutputFormat::Csv).unwrap();
+        assert_eq!(out[0], "size,pattern,large_cents,small_cents,ratio");
+        assert!(out[2].starts_with("3,sLL,"));
+        let too_large = Commands::Mos {
+            period: None,
+            generator: "3/2".to_string(),
+            max_size: Some(1001),
+        };
+        let err = generate_output(too_large, OutputFormat::Text).unwrap_err();
+        assert_eq!(err.to_string(), "--max-size must be at most 1000");
+        // Headers are written even when there are no results.
+        let commas = || Commands::Comma {
+            prime_limit: None,
+            max_cents: Some(0.001),
+            max_exponent: None,
+        };
+        let out = generate_output(commas(), OutputFormat::Csv).unwrap();
+        assert_eq!(out, ["ratio,cents,monzo,note"]);
+        let out = generate_output(commas(), OutputFormat::Json).unwrap();
+        assert_eq!(out, ["[]"]);
+        let out = generate_output(
+            Commands::Pitch {
+                values: vec!["E".to_string(), "\"a,b\"".to_string()],
+            },
+            OutputFormat::Csv,
+        )
+        .unwrap();
+        assert_eq!(
+            out,
+            [
+                "input,pitch,value,cents",
+                "E,5/4,1.25,386.3137138648348",
+                r#""""a,b""",,,"#,
+            ]
+        );
+    }
 }
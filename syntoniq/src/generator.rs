@@ -1,86 +1,319 @@
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 use clap::Parser;
-use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use std::{fs, thread};
 use syntoniq_common::parsing;
-use syntoniq_common::parsing::Timeline;
+use syntoniq_common::parsing::{Timeline, timeline_json};
 
 mod csound;
+mod labels;
+mod lilypond;
 mod midi;
+mod musicxml;
+mod notation;
+mod reaper;
+mod supercollider;
+pub(crate) mod tempo;
 mod text;
+mod wav;
 
 pub const CSOUND_TEMPLATE: &str = csound::DEFAULT_TEMPLATE;
 
+/// Return the orchestra section of the built-in Csound template
+pub fn csound_orchestra() -> anyhow::Result<&'static str> {
+    csound::orchestra(CSOUND_TEMPLATE)
+}
+
 #[derive(Parser)]
 pub struct GenerateOptions {
-    #[arg(long)]
-    score: PathBuf,
-    /// Output a JSON dump of the timeline.
+    #[arg(long, required_unless_present = "from_json")]
+    score: Option<PathBuf>,
+    /// Read the timeline from a JSON file written by `--json` instead of parsing a score. Run
+    /// `syntoniq timeline-schema` for the format. Options that control parsing don't apply.
+    #[arg(long, conflicts_with = "score")]
+    from_json: Option<PathBuf>,
+    /// Output a JSON dump of the timeline. Run `syntoniq timeline-schema` for the format.
     #[arg(long)]
     json: Option<PathBuf>,
     /// Output MIDI file with MPE data. This can be played with FluidSynth or Surge-XT and will load
     /// properly into most Digital Audio Workstations.
     #[arg(long)]
     midi: Option<PathBuf>,
+    #[command(flatten)]
+    midi_options: midi::MidiOptions,
+    /// Output a Reaper project with a track for each part containing its MIDI data, the score's
+    /// tempo changes, and a region for each mark. MIDI options apply to the project's MIDI data.
+    #[arg(long)]
+    reaper: Option<PathBuf>,
     /// Output Csound file. Use the `--csound-template` option to use a template other than the
     /// built-in one.
     #[arg(long)]
     csound: Option<PathBuf>,
+    /// Output a standalone Csound score file containing the template's score section, including
+    /// the tempo statement and all events. Use it with a separate orchestra file.
+    #[arg(long)]
+    csound_sco: Option<PathBuf>,
+    /// Write Csound output that includes this orchestra file in place of the template's
+    /// instruments. The path is written as given, so it should be relative to where Csound will
+    /// be run or absolute. Run `syntoniq csound-template --orc` for the built-in orchestra.
+    #[arg(long, requires = "csound")]
+    csound_orc: Option<PathBuf>,
     /// Output a human-readable text dump of the timeline.
     #[arg(long)]
     text: Option<PathBuf>,
+    /// Output MusicXML for reading in notation software. Pitches are written as the nearest
+    /// 12-EDO note with the deviation in cents as an alteration and the syntoniq note name as
+    /// notehead text.
+    #[arg(long)]
+    musicxml: Option<PathBuf>,
+    /// Output a LilyPond file for engraving a printed score. Pitches are written as the nearest
+    /// 12-EDO note altered by the deviation in cents, with the syntoniq note name above each note.
+    #[arg(long)]
+    lilypond: Option<PathBuf>,
+    /// Write MusicXML and LilyPond output in a fixed meter such as `3/4` or `6/8`, where a quarter
+    /// note is one beat. By default, bar lines are placed at bar checks and at the end of each
+    /// score block. The meter is also used as the time signature of a Reaper project, which is
+    /// otherwise 4/4.
+    #[arg(long)]
+    meter: Option<notation::Meter>,
+    /// Output a SuperCollider file that defines a SynthDef for each part and a Score. The sound of
+    /// each part comes from its `wav_instrument` directive.
+    #[arg(long)]
+    supercollider: Option<PathBuf>,
+    /// Output a binary OSC score for non-realtime rendering with `scsynth -N`. The SynthDefs are
+    /// stored by running the file written by `--supercollider`.
+    #[arg(long)]
+    osc: Option<PathBuf>,
+    /// Output a WAV file rendered with a simple built-in synthesizer. Use this to listen to a
+    /// score without Csound or a MIDI synthesizer. Use the `wav_instrument` directive to change
+    /// the sound of each part.
+    #[arg(long)]
+    wav: Option<PathBuf>,
+    /// Sample rate of the WAV output in Hz
+    #[arg(long, default_value_t = 44100, value_parser = clap::value_parser!(u32).range(8000..))]
+    wav_sample_rate: u32,
+    /// Output a label file listing marks and repeats with their times in seconds. The file uses
+    /// Audacity's label format.
+    #[arg(long)]
+    labels: Option<PathBuf>,
     /// Override the built-in Csound template. The template has to conform to a certain structure
     /// to be usable. Run `syntoniq csound-template` to print the contents of the built-in template.
     /// You can also use a previous output as a template to just replace the generated portion.
     #[arg(long)]
     csound_template: Option<PathBuf>,
+    /// Keep running, and generate outputs again whenever the score or Csound template changes.
+    /// Errors are reported without exiting. Exit with CTRL-C.
+    #[arg(long)]
+    watch: bool,
     #[command(flatten)]
     parse_options: parsing::Options,
 }
 
-fn generate_json(timeline: &Timeline, json_file: PathBuf) -> anyhow::Result<()> {
-    fs::write(&json_file, serde_json::to_string_pretty(&timeline)? + "\n")?;
+impl GenerateOptions {
+    fn output_count(&self) -> usize {
+        [
+            &self.json,
+            &self.midi,
+            &self.reaper,
+            &self.csound,
+            &self.csound_sco,
+            &self.text,
+            &self.musicxml,
+            &self.lilypond,
+            &self.supercollider,
+            &self.osc,
+            &self.wav,
+            &self.labels,
+        ]
+        .into_iter()
+        .filter(|x| x.is_some())
+        .count()
+    }
+}
+
+/// How often to check watched files for changes
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+fn generate_json(timeline: &Timeline, json_file: &Path) -> anyhow::Result<()> {
+    let timeline = timeline_json::Timeline::new(timeline);
+    fs::write(json_file, serde_json::to_string_pretty(&timeline)? + "\n")?;
     println!("JSON output written to {}", json_file.display());
     Ok(())
 }
 
 pub fn run(options: GenerateOptions) -> anyhow::Result<()> {
-    let data = fs::read(&options.score)?;
-    let score_file = options.score.display();
+    if options.watch {
+        watch(&options)
+    } else {
+        run_once(&options, &mut Vec::new())
+    }
+}
+
+/// Parse the score or read the JSON timeline, and write all requested outputs. Each file that the
+/// outputs were generated from is added to `inputs`.
+fn run_once(options: &GenerateOptions, inputs: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    if let Some(json_file) = &options.from_json {
+        inputs.push(json_file.clone());
+        let data = fs::read(json_file)?;
+        let file: timeline_json::Timeline =
+            serde_json::from_slice(&data).map_err(|e| anyhow!("{}: {e}", json_file.display()))?;
+        let timeline = file
+            .timeline()
+            .map_err(|e| anyhow!("{}: {e}", json_file.display()))?;
+        println!("timeline '{}' is valid", json_file.display());
+        return generate(options, json_file, &timeline, inputs);
+    }
+    let Some(score) = &options.score else {
+        bail!("either --score or --from-json is required");
+    };
+    inputs.push(score.clone());
+    let data = fs::read(score)?;
     let src = str::from_utf8(&data)?;
-    let timeline = parsing::timeline(
-        &options.score.display().to_string(),
-        src,
-        &options.parse_options,
-    )?;
-    let csound_template = options.csound_template.or_else(|| {
+    let timeline = parsing::timeline(&score.display().to_string(), src, &options.parse_options)?;
+    println!("syntoniq score '{}' is valid", score.display());
+    generate(options, score, &timeline, inputs)
+}
+
+fn modification_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Return the files to watch after a run that used `new` as inputs. After a failed run, files
+/// from `previous` are still watched since the run may have stopped before finding them.
+fn watched_inputs(previous: Vec<PathBuf>, new: Vec<PathBuf>, succeeded: bool) -> Vec<PathBuf> {
+    if succeeded {
+        return new;
+    }
+    let mut inputs = previous;
+    for p in new {
+        if !inputs.contains(&p) {
+            inputs.push(p);
+        }
+    }
+    inputs
+}
+
+/// Generate outputs, then generate them again each time one of the input files changes. Errors
+/// are reported, and watching continues so they can be fixed in place. If the score can't be
+/// parsed, the files from the last successful run are still watched since the score's
+/// `csound_template` directive isn't known.
+fn watch(options: &GenerateOptions) -> anyhow::Result<()> {
+    let mut inputs: Vec<PathBuf> = Vec::new();
+    loop {
+        let mut new_inputs = Vec::new();
+        let result = run_once(options, &mut new_inputs);
+        match &result {
+            Ok(()) => {
+                let n = options.output_count();
+                let s = if n == 1 { "" } else { "s" };
+                println!("regenerated {n} output{s}");
+            }
+            Err(e) => eprintln!("error: {e}"),
+        }
+        inputs = watched_inputs(inputs, new_inputs, result.is_ok());
+        let names: Vec<_> = inputs.iter().map(|p| p.display().to_string()).collect();
+        println!("watching {}; press CTRL-C to exit", names.join(", "));
+        let times = modification_times(&inputs);
+        while modification_times(&inputs) == times {
+            thread::sleep(WATCH_INTERVAL);
+        }
+        // Editors often write a file in more than one step, so give them a chance to finish.
+        thread::sleep(WATCH_INTERVAL);
+    }
+}
+
+/// Write all requested outputs. Relative paths in the timeline are relative to `source`, the
+/// score or JSON file that the timeline came from. The Csound template, if any, is added to
+/// `inputs`.
+fn generate(
+    options: &GenerateOptions,
+    source: &Path,
+    timeline: &Timeline,
+    inputs: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    let score_file = source.display();
+    let csound_template = options.csound_template.clone().or_else(|| {
         timeline
             .csound_template
             .as_ref()
-            .map(|rel_path| options.score.parent().unwrap().join(rel_path.as_ref()))
+            .map(|rel_path| source.parent().unwrap().join(rel_path.as_ref()))
     });
-    println!("syntoniq score '{}' is valid", options.score.display());
+    if (options.csound.is_some() || options.csound_sco.is_some())
+        && let Some(template) = &csound_template
+    {
+        inputs.push(template.clone());
+    }
     let mut errors = Vec::new();
-    if let Some(json_file) = options.json
-        && let Err(e) = generate_json(&timeline, json_file)
+    if let Some(json_file) = &options.json
+        && let Err(e) = generate_json(timeline, json_file)
     {
         errors.push(format!("{score_file} -> JSON: {e}"));
     }
-    if let Some(midi_file) = options.midi
-        && let Err(e) = midi::generate(&timeline, midi_file)
+    if let Some(midi_file) = &options.midi
+        && let Err(e) = midi::generate(timeline, &options.midi_options, midi_file)
     {
         errors.push(format!("{score_file} -> MIDI: {e}"));
     }
-    if let Some(csound_file) = options.csound
-        && let Err(e) = csound::generate(&timeline, csound_file, csound_template)
+    if let Some(reaper_file) = &options.reaper
+        && let Err(e) =
+            reaper::generate(timeline, &options.midi_options, options.meter, reaper_file)
+    {
+        errors.push(format!("{score_file} -> Reaper: {e}"));
+    }
+    if let Some(csound_file) = &options.csound
+        && let Err(e) = csound::generate(
+            timeline,
+            csound_file,
+            csound_template.as_deref(),
+            options.csound_orc.as_deref(),
+        )
     {
         errors.push(format!("{score_file} -> Csound: {e}"));
     }
-    if let Some(text_file) = options.text
-        && let Err(e) = text::generate(&timeline, text_file)
+    if let Some(sco_file) = &options.csound_sco
+        && let Err(e) = csound::generate_sco(timeline, sco_file, csound_template.as_deref())
+    {
+        errors.push(format!("{score_file} -> Csound score: {e}"));
+    }
+    if let Some(text_file) = &options.text
+        && let Err(e) = text::generate(timeline, text_file)
     {
         errors.push(format!("{score_file} -> Csound: {e}"));
     }
+    if let Some(musicxml_file) = &options.musicxml
+        && let Err(e) = musicxml::generate(timeline, options.meter, musicxml_file)
+    {
+        errors.push(format!("{score_file} -> MusicXML: {e}"));
+    }
+    if let Some(lilypond_file) = &options.lilypond
+        && let Err(e) = lilypond::generate(timeline, options.meter, lilypond_file)
+    {
+        errors.push(format!("{score_file} -> LilyPond: {e}"));
+    }
+    if let Some(scd_file) = &options.supercollider
+        && let Err(e) = supercollider::generate_scd(timeline, scd_file)
+    {
+        errors.push(format!("{score_file} -> SuperCollider: {e}"));
+    }
+    if let Some(osc_file) = &options.osc
+        && let Err(e) = supercollider::generate_osc(timeline, osc_file)
+    {
+        errors.push(format!("{score_file} -> OSC: {e}"));
+    }
+    if let Some(wav_file) = &options.wav
+        && let Err(e) = wav::generate(timeline, options.wav_sample_rate, wav_file)
+    {
+        errors.push(format!("{score_file} -> WAV: {e}"));
+    }
+    if let Some(labels_file) = &options.labels
+        && let Err(e) = labels::generate(timeline, labels_file)
+    {
+        errors.push(format!("{score_file} -> labels: {e}"));
+    }
     if !errors.is_empty() {
         bail!("{}", errors.join("\n"))
     }
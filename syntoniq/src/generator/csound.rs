@@ -1,5 +1,6 @@
 use anyhow::{anyhow, bail};
 use num_rational::Ratio;
+use std::borrow::Cow;
 use std::collections::btree_map::Entry;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::Path;
@@ -27,8 +28,8 @@ pub fn rounded_float(val: impl Into<f64>, max_decimals: usize) -> String {
     s
 }
 
-pub fn ratio_to_rounded_float(r: Ratio<u32>, max_decimals: usize) -> String {
-    let val = (*r.numer() as f64) / (*r.denom() as f64);
+pub fn ratio_to_rounded_float<T: Copy + Into<f64>>(r: Ratio<T>, max_decimals: usize) -> String {
+    let val = (*r.numer()).into() / (*r.denom()).into();
     rounded_float(val, max_decimals)
 }
 
@@ -217,6 +218,39 @@ impl<'s> CsoundGenerator<'s> {
                         }
                     }
                 }
+                TimelineData::CsoundParam(e) => {
+                    let Some(part_data) = &self.part_data.get(e.part.as_ref()) else {
+                        // As with dynamics, the part may have no notes within the region.
+                        continue;
+                    };
+                    let part_number = part_data.part_number;
+                    let name = &e.name;
+                    let value = ratio_to_rounded_float(e.value, 3);
+                    let comment = format!("; csound_param @{offset}");
+                    match &e.end_value {
+                        None => {
+                            self.content.push_str(&format!(
+                                "i \"SetPartParam\" {time} 0.01 {part_number} \"{name}\" {value} {comment}\n",
+                            ));
+                        }
+                        Some(end_value) => {
+                            let end = ratio_to_rounded_float(end_value.item, 3);
+                            let duration = ratio_to_rounded_float(end_value.time - event.time, 3);
+                            // `expseg` only works between non-zero values with the same sign.
+                            let instr = if e.value.numer().signum()
+                                * end_value.item.numer().signum()
+                                == 1
+                            {
+                                "SetPartParamRamp"
+                            } else {
+                                "SetPartParamLinearRamp"
+                            };
+                            self.content.push_str(&format!(
+                                "i \"{instr}\" {time} {duration} {part_number} \"{name}\" {value} {end} {comment}\n",
+                            ));
+                        }
+                    }
+                }
                 TimelineData::Note(e) => {
                     let instrument = self.instrument_for_part(e.part_note.part);
                     let &part_data = &self
@@ -232,6 +266,10 @@ impl<'s> CsoundGenerator<'s> {
                         Ratio::new(cmp::min(127, e.value.velocity as u32), 127),
                         3,
                     );
+                    let release = ratio_to_rounded_float(
+                        Ratio::new(cmp::min(127, e.value.release_velocity as u32), 127),
+                        3,
+                    );
                     // instrument.note is a decimal number, so we need to use leading zeroes based
                     // on the number of note numbers.
                     let instr_note_number = pad_number(*note_number, part_data.note_numbers.len());
@@ -265,6 +303,9 @@ impl<'s> CsoundGenerator<'s> {
                     max_time = cmp::max(max_time, end_time);
                     let duration = ratio_to_rounded_float(end_time - event.time, 3);
                     self.content.push_str(&format!("; {note_text} @{offset}\n"));
+                    self.content.push_str(&format!(
+                        "i \"SetPartParam\" {time} 0.01 {part_number} \"release_{note_number}\" {release}\n"
+                    ));
                     self.content.push_str(&format!(
                         "i {instr} {time} {duration} {part_number} {note_number} {velocity}\n"
                     ));
@@ -281,6 +322,7 @@ impl<'s> CsoundGenerator<'s> {
                     self.content
                         .push_str(&format!("; repeat end '{}' @'{}\n", e.label, event.span));
                 }
+                TimelineData::BarLine => {}
             }
         }
         if !self.timeline.csound_global_instruments.is_empty() {
@@ -299,41 +341,102 @@ impl<'s> CsoundGenerator<'s> {
     }
 }
 
+const BEGIN_MARK: &str = ";; BEGIN SYNTONIQ";
+const END_MARK: &str = ";; END SYNTONIQ";
+const BEGIN_SCORE: &str = "<CsScore>";
+const END_SCORE: &str = "</CsScore>";
+const BEGIN_INSTRUMENTS: &str = "<CsInstruments>";
+const END_INSTRUMENTS: &str = "</CsInstruments>";
+
+fn load_template(template_override: Option<impl AsRef<Path>>) -> anyhow::Result<Cow<'static, str>> {
+    Ok(match template_override {
+        Some(path) => Cow::Owned(String::from_utf8(fs::read(path)?)?),
+        None => Cow::Borrowed(DEFAULT_TEMPLATE),
+    })
+}
+
+/// Return the positions of the start and end of the text between `begin` and `end`
+fn find_section(text: &str, begin: &str, end: &str) -> anyhow::Result<(usize, usize)> {
+    let begin_pos = text
+        .find(begin)
+        .ok_or_else(|| anyhow!("csound template doesn't contain '{begin}'"))?;
+    let end_pos = text
+        .find(end)
+        .ok_or_else(|| anyhow!("csound template doesn't contain '{end}'"))?;
+    if end_pos < begin_pos {
+        bail!("csound template contains '{end}' before '{begin}'");
+    }
+    Ok((begin_pos + begin.len(), end_pos))
+}
+
+/// Replace everything between the begin and end marks with generated content
+fn splice(template: &str, content: &str) -> anyhow::Result<String> {
+    let (begin_pos, end_pos) = find_section(template, BEGIN_MARK, END_MARK)?;
+    Ok(format!(
+        "{}\n{content}{}",
+        &template[..begin_pos],
+        &template[end_pos..]
+    ))
+}
+
+/// Replace the orchestra with an `#include` of an external orchestra file
+fn include_orchestra(csd: &str, orchestra: &Path) -> anyhow::Result<String> {
+    let (begin_pos, end_pos) = find_section(csd, BEGIN_INSTRUMENTS, END_INSTRUMENTS)?;
+    Ok(format!(
+        "{}\n\n#include \"{}\"\n\n{}",
+        &csd[..begin_pos],
+        orchestra.display(),
+        &csd[end_pos..]
+    ))
+}
+
+/// Return the contents of the template's score section with generated content spliced in
+fn standalone_score(template: &str, content: &str) -> anyhow::Result<String> {
+    let (begin_pos, end_pos) = find_section(template, BEGIN_SCORE, END_SCORE)?;
+    let score = splice(&template[begin_pos..end_pos], content)?;
+    Ok(score.trim_start_matches('\n').to_string())
+}
+
+/// Return the orchestra section of a template, suitable for use as a standalone `.orc` file
+pub(crate) fn orchestra(template: &str) -> anyhow::Result<&str> {
+    let (begin_pos, end_pos) = find_section(template, BEGIN_INSTRUMENTS, END_INSTRUMENTS)?;
+    Ok(template[begin_pos..end_pos].trim_start_matches('\n'))
+}
+
+/// Write a `.csd` file. If `orchestra` is given, the file includes it in place of the template's
+/// orchestra.
 pub(crate) fn generate(
     timeline: &Timeline,
     out: impl AsRef<Path>,
     template_override: Option<impl AsRef<Path>>,
+    orchestra: Option<&Path>,
 ) -> anyhow::Result<()> {
     let g = CsoundGenerator::new(timeline)?;
     let content = g.generate()?;
-    let mut _loaded: Option<String> = None;
-    let template = match template_override {
-        Some(path) => {
-            _loaded = Some(String::from_utf8(fs::read(path)?)?);
-            _loaded.as_ref().unwrap().as_str()
-        }
-        None => DEFAULT_TEMPLATE,
-    };
-    const BEGIN_MARK: &str = ";; BEGIN SYNTONIQ";
-    const END_MARK: &str = ";; END SYNTONIQ";
-    let begin_pos = template
-        .find(BEGIN_MARK)
-        .ok_or_else(|| anyhow!("csound template doesn't contain '{BEGIN_MARK}'"))?;
-    let end_pos = template
-        .find(END_MARK)
-        .ok_or_else(|| anyhow!("csound template doesn't contain '{END_MARK}'"))?;
-    fs::write(
-        &out,
-        format!(
-            "{}{BEGIN_MARK}\n{content}{}",
-            &template[..begin_pos],
-            &template[end_pos..]
-        ),
-    )?;
+    let template = load_template(template_override)?;
+    let mut csd = splice(&template, &content)?;
+    if let Some(orchestra) = orchestra {
+        csd = include_orchestra(&csd, orchestra)?;
+    }
+    fs::write(&out, csd)?;
     println!("Csound output written to {}", out.as_ref().display());
     Ok(())
 }
 
+/// Write a standalone `.sco` file from the score section of the template
+pub(crate) fn generate_sco(
+    timeline: &Timeline,
+    out: impl AsRef<Path>,
+    template_override: Option<impl AsRef<Path>>,
+) -> anyhow::Result<()> {
+    let g = CsoundGenerator::new(timeline)?;
+    let content = g.generate()?;
+    let template = load_template(template_override)?;
+    fs::write(&out, standalone_score(&template, &content)?)?;
+    println!("Csound score written to {}", out.as_ref().display());
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -349,6 +452,52 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_sections() {
+        let template = "\
+<CsoundSynthesizer>
+<CsInstruments>
+instr 1
+endin
+</CsInstruments>
+<CsScore>
+f 1 0 8 10 1
+;; BEGIN SYNTONIQ
+old
+;; END SYNTONIQ
+e
+</CsScore>
+</CsoundSynthesizer>
+";
+        let csd = splice(template, "i 1 0 1\n").unwrap();
+        assert!(csd.contains(";; BEGIN SYNTONIQ\ni 1 0 1\n;; END SYNTONIQ"));
+        assert_eq!(
+            include_orchestra(&csd, Path::new("lib/main.orc")).unwrap(),
+            "\
+<CsoundSynthesizer>
+<CsInstruments>
+
+#include \"lib/main.orc\"
+
+</CsInstruments>
+<CsScore>
+f 1 0 8 10 1
+;; BEGIN SYNTONIQ
+i 1 0 1
+;; END SYNTONIQ
+e
+</CsScore>
+</CsoundSynthesizer>
+"
+        );
+        assert_eq!(
+            standalone_score(template, "i 1 0 1\n").unwrap(),
+            "f 1 0 8 10 1\n;; BEGIN SYNTONIQ\ni 1 0 1\n;; END SYNTONIQ\ne\n"
+        );
+        assert_eq!(orchestra(template).unwrap(), "instr 1\nendin\n");
+        assert!(standalone_score("no score", "").is_err());
+    }
+
     #[test]
     fn test_pad_number() {
         assert_eq!(pad_number(12u8, 100), "012");
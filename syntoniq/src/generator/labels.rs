@@ -0,0 +1,43 @@
+use crate::generator::tempo::{TempoMap, to_f64};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use syntoniq_common::parsing::{Timeline, TimelineData};
+
+// A label file lists marks and repeats with their times in seconds so that a rendered score can
+// be navigated by rehearsal mark. It uses Audacity's label format: a start time, an end time, and
+// a label, separated by tabs. These are point labels, so the start and end times are the same.
+// Times come from the tempo map, so they include gradual tempo changes.
+
+/// Text describing a mark or the start or end of a repeat, or `None` for other events
+pub(super) fn label_text(data: &TimelineData) -> Option<String> {
+    match data {
+        TimelineData::Mark(e) => Some(e.label.to_string()),
+        TimelineData::RepeatStart(e) => Some(format!("repeat start {}", e.label)),
+        TimelineData::RepeatEnd(e) => Some(format!("repeat end {}", e.label)),
+        TimelineData::Tempo(_)
+        | TimelineData::Dynamic(_)
+        | TimelineData::CsoundParam(_)
+        | TimelineData::Note(_)
+        | TimelineData::BarLine => None,
+    }
+}
+
+fn labels(timeline: &Timeline) -> String {
+    let tempo = TempoMap::new(timeline);
+    let mut out = String::new();
+    for event in &timeline.events {
+        let Some(text) = label_text(&event.data) else {
+            continue;
+        };
+        let seconds = tempo.seconds(to_f64(event.time));
+        writeln!(out, "{seconds:.6}\t{seconds:.6}\t{text}").unwrap();
+    }
+    out
+}
+
+pub(crate) fn generate(timeline: &Timeline, out: impl AsRef<Path>) -> anyhow::Result<()> {
+    fs::write(&out, labels(timeline))?;
+    println!("Labels written to {}", out.as_ref().display());
+    Ok(())
+}
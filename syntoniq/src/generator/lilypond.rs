@@ -0,0 +1,285 @@
+use crate::generator::notation::{
+    DirectionKind, Measure, Meter, NOTE_TYPES, NotatedPitch, Notation, Part, Piece,
+};
+use num_rational::Ratio;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use syntoniq_common::parsing::Timeline;
+
+// LilyPond output is intended for players who read from paper. See the notation module for how
+// the timeline is converted to written notes.
+//
+// Each pitch is written with `ly:make-pitch` using the nearest 12-EDO step and an alteration
+// rounded to the nearest cent, so the file keeps the actual pitch. LilyPond has no glyphs for
+// arbitrary alterations, so the layout block maps each alteration that appears in the score to
+// the accidental for the nearest quarter tone. The syntoniq note name is written above each note.
+// Time signatures, tempo and rehearsal marks are written in the first staff; LilyPond applies
+// time signatures to every staff.
+
+/// Accidentals for each number of quarter tones from a flat to a double sharp
+const ACCIDENTALS: [&str; 7] = [
+    "accidentals.flat",
+    "accidentals.mirroredflat",
+    "accidentals.natural",
+    "accidentals.sharp.slashslash.stem",
+    "accidentals.sharp",
+    "accidentals.sharp.slashslash.stemstemstem",
+    "accidentals.doublesharp",
+];
+
+const VOICES: [&str; 4] = ["\\voiceOne", "\\voiceTwo", "\\voiceThree", "\\voiceFour"];
+
+/// Return an alteration in LilyPond's units of whole tones, rounded to the nearest cent.
+fn alteration(p: &NotatedPitch) -> Ratio<i32> {
+    Ratio::new((p.alter * 100.0).round() as i32, 200)
+}
+
+fn format_ratio<T: num_integer::Integer + std::fmt::Display + Clone>(r: &Ratio<T>) -> String {
+    if r.is_integer() {
+        r.numer().to_string()
+    } else {
+        format!("{}/{}", r.numer(), r.denom())
+    }
+}
+
+fn pitch(p: &NotatedPitch) -> String {
+    let step = "CDEFGAB".find(p.step).unwrap_or_default();
+    format!(
+        "$(ly:make-pitch {} {step} {})",
+        p.octave - 4,
+        format_ratio(&alteration(p))
+    )
+}
+
+/// Return a note, rest, or spacer that lasts `beats` beats using a scaled quarter note.
+fn scaled(prefix: &str, beats: Ratio<u32>) -> String {
+    if beats == Ratio::from_integer(1) {
+        format!("{prefix}4")
+    } else {
+        format!("{prefix}4*{}", format_ratio(&beats))
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+struct LilyPondGenerator<'s> {
+    notation: Notation<'s>,
+}
+
+impl<'s> LilyPondGenerator<'s> {
+    fn new(timeline: &'s Timeline<'s>, meter: Option<Meter>) -> Self {
+        Self {
+            notation: Notation::new(timeline, meter),
+        }
+    }
+
+    fn write_directions(&self, out: &mut String, measure: &Measure, m: usize) {
+        // Group directions by time within the measure. A tempo change and its words are written
+        // as a single tempo mark.
+        #[derive(Default)]
+        struct Group<'a> {
+            metronome: Option<Ratio<u32>>,
+            words: Option<&'static str>,
+            rehearsals: Vec<&'a str>,
+        }
+        let mut groups: BTreeMap<Ratio<u32>, Group> = BTreeMap::new();
+        for d in &self.notation.directions {
+            if self.notation.measure_index(d.time) != m {
+                continue;
+            }
+            let offset = (d.time.max(measure.start) - measure.start).min(measure.length);
+            let group = groups.entry(offset).or_default();
+            match &d.kind {
+                DirectionKind::Metronome(bpm) => group.metronome = Some(*bpm),
+                DirectionKind::Words(words) => group.words = Some(words),
+                DirectionKind::Rehearsal(label) => group.rehearsals.push(label),
+            }
+        }
+        let mut items = Vec::new();
+        let mut cursor = Ratio::from_integer(0);
+        for (offset, group) in groups {
+            if offset > cursor {
+                items.push(scaled("s", offset - cursor));
+                cursor = offset;
+            }
+            for label in group.rehearsals {
+                items.push(format!("\\mark \"{}\"", escape(label)));
+            }
+            let words = group.words.map(|w| format!(" \"{w}\""));
+            let metronome = group
+                .metronome
+                .map(|bpm| format!(" 4 = {}", bpm.round().to_integer()));
+            if words.is_some() || metronome.is_some() {
+                items.push(format!(
+                    "\\tempo{}{}",
+                    words.unwrap_or_default(),
+                    metronome.unwrap_or_default()
+                ));
+            }
+        }
+        if cursor < measure.length {
+            items.push(scaled("s", measure.length - cursor));
+        }
+        let _ = writeln!(out, "  {} |", items.join(" "));
+    }
+
+    fn write_global(&self, out: &mut String) {
+        let _ = writeln!(out, "global = {{");
+        let measures = &self.notation.measures;
+        for (m, measure) in measures.iter().enumerate() {
+            if m == 0 || measures[m - 1].time_signature != measure.time_signature {
+                let (beats, beat_type) = measure.time_signature;
+                let _ = writeln!(out, "  \\time {beats}/{beat_type}");
+            }
+            self.write_directions(out, measure, m);
+        }
+        let _ = writeln!(out, "  \\bar \"|.\"");
+        let _ = writeln!(out, "}}");
+    }
+
+    fn piece(part: &Part, piece: &Piece) -> String {
+        let mut result = String::new();
+        if piece.tuplet_start
+            && let Some((actual, normal)) = piece.time_modification
+        {
+            let _ = write!(result, "\\tuplet {actual}/{normal} {{ ");
+        }
+        let Some(note_type) = piece.note_type else {
+            // This is a whole-measure rest.
+            result.push_str(&scaled("R", piece.duration));
+            return result;
+        };
+        let duration = NOTE_TYPES
+            .iter()
+            .find(|t| t.0 == note_type)
+            .map(|(_, num, den)| 4 * den / num)
+            .unwrap_or_default();
+        let dot = if piece.dotted { "." } else { "" };
+        match piece.pitch.map(|p| &part.pitches[p]) {
+            Some(p) => {
+                let _ = write!(result, "{}{duration}{dot}", pitch(p));
+                if piece.tie_start {
+                    result.push('~');
+                }
+                if piece.show_name {
+                    let _ = write!(result, "^\\markup {{ \"{}\" }}", escape(&p.name));
+                }
+            }
+            None => {
+                let _ = write!(result, "r{duration}{dot}");
+            }
+        }
+        if piece.tuplet_stop && piece.time_modification.is_some() {
+            result.push_str(" }");
+        }
+        result
+    }
+
+    fn write_staff(&self, out: &mut String, idx: usize, part: &Part) {
+        let i = "    ";
+        if part.name.is_empty() {
+            let _ = writeln!(out, "{i}\\new Staff <<");
+        } else {
+            let _ = writeln!(
+                out,
+                "{i}\\new Staff \\with {{ instrumentName = \"{}\" }} <<",
+                escape(part.name)
+            );
+        }
+        if idx == 0 {
+            let _ = writeln!(out, "{i}  \\global");
+        }
+        for (v, voice_measures) in part.voices.iter().enumerate() {
+            let _ = writeln!(out, "{i}  \\new Voice {{");
+            if part.voices.len() > 1
+                && let Some(voice) = VOICES.get(v)
+            {
+                let _ = writeln!(out, "{i}    {voice}");
+            }
+            if v == 0 {
+                let clef = if part.is_bass() { "bass" } else { "treble" };
+                let _ = writeln!(out, "{i}    \\clef \"{clef}\"");
+            }
+            for (pieces, measure) in voice_measures.iter().zip(&self.notation.measures) {
+                let items: Vec<String> = if pieces.is_empty() {
+                    // Other voices are silent for this measure.
+                    vec![scaled("s", measure.length)]
+                } else {
+                    pieces.iter().map(|p| Self::piece(part, p)).collect()
+                };
+                let _ = writeln!(out, "{i}    {} |", items.join(" "));
+            }
+            let _ = writeln!(out, "{i}  }}");
+        }
+        let _ = writeln!(out, "{i}>>");
+    }
+
+    fn generate(&self) -> String {
+        let mut out = String::new();
+        out.push_str("\\version \"2.24.0\"\n");
+        out.push_str("% Generated by Syntoniq\n\n");
+        self.write_global(&mut out);
+        out.push_str("\n\\score {\n");
+        out.push_str("  <<\n");
+        for (idx, part) in self.notation.parts.iter().enumerate() {
+            self.write_staff(&mut out, idx, part);
+        }
+        out.push_str("  >>\n");
+        // Map each alteration to the accidental for the nearest quarter tone.
+        let mut alterations = BTreeMap::new();
+        alterations.insert(Ratio::from_integer(0), ACCIDENTALS[2]);
+        for p in self.notation.parts.iter().flat_map(|part| &part.pitches) {
+            let a = alteration(p);
+            let quarter_tones = (a * 4).round().to_integer() + 2;
+            let glyph = ACCIDENTALS[quarter_tones.clamp(0, ACCIDENTALS.len() as i32 - 1) as usize];
+            alterations.insert(a, glyph);
+        }
+        out.push_str("  \\layout {\n");
+        out.push_str("    \\context {\n");
+        out.push_str("      \\Score\n");
+        out.push_str("      \\override Accidental.alteration-glyph-name-alist = #'(\n");
+        for (a, glyph) in alterations {
+            let _ = writeln!(out, "        ({} . \"{glyph}\")", format_ratio(&a));
+        }
+        out.push_str("      )\n");
+        out.push_str("    }\n");
+        out.push_str("  }\n");
+        out.push_str("}\n");
+        out
+    }
+}
+
+pub(crate) fn generate(
+    timeline: &Timeline,
+    meter: Option<Meter>,
+    out: impl AsRef<Path>,
+) -> anyhow::Result<()> {
+    let ly = LilyPondGenerator::new(timeline, meter).generate();
+    fs::write(&out, ly)?;
+    println!("LilyPond output written to {}", out.as_ref().display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syntoniq_common::pitch::Pitch;
+
+    #[test]
+    fn test_pitch() {
+        let p = NotatedPitch::new(&Pitch::must_parse("220*^1|4"), "c");
+        assert_eq!(pitch(&p), "$(ly:make-pitch 0 0 0)");
+        // 5/4 above middle C is 14¢ flat of E.
+        let p = NotatedPitch::new(&Pitch::must_parse("220*^1|4*5/4"), "e");
+        assert_eq!(pitch(&p), "$(ly:make-pitch 0 2 -7/100)");
+        // One step of 17-EDO above the C below middle C is 29¢ flat of C sharp.
+        let p = NotatedPitch::new(&Pitch::must_parse("110*^1|4*^1|17"), "c#");
+        assert_eq!(pitch(&p), "$(ly:make-pitch -1 0 71/200)");
+        assert_eq!(scaled("s", Ratio::new(7, 2)), "s4*7/2");
+        assert_eq!(scaled("R", Ratio::from_integer(1)), "R4");
+    }
+}
@@ -1,4 +1,6 @@
+use crate::generator::labels;
 use anyhow::{anyhow, bail};
+use clap::{Args, ValueEnum};
 use midly::MetaMessage::{EndOfTrack, Tempo};
 use midly::PitchBend;
 use midly::num::{u4, u7, u14, u15, u24, u28};
@@ -8,17 +10,20 @@ use midly::{
 use num_rational::Ratio;
 use num_traits::Num;
 use num_traits::cast::ToPrimitive;
+use serde_json::{Value, json};
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::cmp::Ordering;
 use std::collections::btree_map::{Entry, VacantEntry};
 use std::collections::{BTreeMap, BTreeSet, VecDeque};
-use std::path::Path;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use syntoniq_common::parsing::model::Span;
 use syntoniq_common::parsing::{
-    DynamicEvent, MidiInstrumentNumber, NoteEvent, TempoEvent, Timeline, TimelineData,
-    TimelineEvent,
+    DynamicEvent, MidiInstrumentNumber, MidiRoutingSettings, NoteEvent, TempoEvent, Timeline,
+    TimelineData, TimelineEvent,
 };
 use syntoniq_common::pitch;
 // Key concepts:
@@ -52,6 +57,10 @@ use syntoniq_common::pitch;
 //     drums. When MPE is enabled, channel 9 loses that meaning, but some software, such as
 //     FluidSynth (at least as of version 2.4) doesn't seem to pay attention to that. To avoid this
 //     headache, we use channels 1-8 and 10-15, numbered from 0 for notes.
+//   - The above describes the default MPE layout. Command-line options can select the upper zone
+//     (with channel 15 as the manager channel), fewer member channels, or the use of channel 9,
+//     in which case the number of channel pairs per port changes accordingly. The pitch bend range
+//     can also be changed for synthesizers that ignore the pitch bend sensitivity parameter.
 //   - Each part has exactly one instrument.
 //   - When possible, we want to avoid splitting a part across ports.
 //   - We want a dedicated track for each group of (part, port) for optimal DAW convenience.
@@ -75,7 +84,9 @@ use syntoniq_common::pitch;
 //   - See if we can bin-pack using a naive algorithm (the general problem is NP-complete) to
 //     combine some parts (or remainders) into a single port if they have a combined total of 7 or
 //     fewer note numbers
-//   - Allocate tracks based on (part, port)
+//   - Parts with a port in a `midi_routing` directive are placed on that port and are left out
+//     of bin-packing. The bins take the remaining ports.
+//   - Allocate tracks based on (part, port). Tracks for parts with `midi_routing` come first.
 //
 // Then, when we have a note:
 //   - Use the part and note number to find a note's dedicated channel/port
@@ -85,10 +96,124 @@ use syntoniq_common::pitch;
 //   - Play the note in the track using the given channel.
 //   - The note will have the correct instrument and MIDI port because of the track and the correct
 //     pitch because of the channel.
+//
+// Channel-per-note mode is for older hardware that mishandles MPE. It uses ordinary channels with
+// a pitch bend range chosen by the user and doesn't configure an MPE zone. Parts and note numbers
+// are packed into ports as above, but with up to 15 note numbers per port since channels aren't
+// used in pairs. When a note starts, it takes whichever free channel on its port has been free the
+// longest, which gives release tails the most time before the channel's pitch bend changes. Since
+// a channel may move between parts, a program change and channel volume are sent whenever a
+// channel is given to a part whose instrument or volume differs from the channel's. With
+// `--midi-single-port`, all parts share the channels of a single port, which works as long as no
+// more than 15 notes sound at once.
 
 // These values are given by the MPE specification.
 const MPE_RANGE: u8 = 48;
-const MPE_RANGE_F: f64 = 48.0;
+const MPE_MEMBER_CHANNELS: u8 = 15;
+
+/// Default pitch bend range for channel-per-note mode, which is the General MIDI default
+const DEFAULT_BEND_RANGE: u8 = 2;
+
+/// The smallest bend range for which any glide can be split across two notes; see
+/// `handle_note_event`
+const SPLIT_BEND_RANGE: f64 = 48.0;
+
+/// Channels used for notes in channel-per-note mode. Channel 9 is for drums in General MIDI.
+const NOTE_CHANNELS: [u8; 15] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 11, 12, 13, 14, 15];
+
+/// General MIDI System On, without the leading 0xF0
+const GM_SYSTEM_ON: &[u8] = &[0x7E, 0x7F, 0x09, 0x01, 0xF7];
+
+#[derive(Copy, Clone, Default, PartialEq, Eq, ValueEnum)]
+pub enum MidiMode {
+    /// Use MIDI Polyphonic Expression with a pair of channels for each note number
+    #[default]
+    Mpe,
+    /// Give each sounding note its own ordinary channel, for hardware that doesn't support MPE
+    ChannelPerNote,
+}
+
+#[derive(Copy, Clone, Default, PartialEq, Eq, ValueEnum)]
+pub enum MpeZone {
+    /// Channel 1 is the manager channel, and member channels count up from channel 2
+    #[default]
+    Lower,
+    /// Channel 16 is the manager channel, and member channels count down from channel 15
+    Upper,
+}
+
+#[derive(Args, Clone, Default)]
+pub struct MidiOptions {
+    /// How notes are assigned to channels in MIDI output
+    #[arg(long, value_enum, default_value_t)]
+    pub midi_mode: MidiMode,
+    /// Pitch bend range in semitones; default is 48 for MPE and 2 for channel-per-note. Use this
+    /// for synthesizers that ignore pitch bend sensitivity messages.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..128))]
+    pub midi_bend_range: Option<u8>,
+    /// In channel-per-note mode, put all parts on a single port with 16 channels. This fails if
+    /// more than 15 notes sound at once.
+    #[arg(long)]
+    pub midi_single_port: bool,
+    /// MPE zone to use; default is `lower`
+    #[arg(long, value_enum)]
+    pub mpe_zone: Option<MpeZone>,
+    /// Number of member channels in the MPE zone; default is 15. Notes use member channels in
+    /// pairs, so fewer channels means fewer note numbers per port.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=15))]
+    pub mpe_channels: Option<u8>,
+    /// Use channel 10 for notes in MPE mode. By default, it is skipped because some software
+    /// treats it as a drum channel even when MPE is enabled.
+    #[arg(long)]
+    pub mpe_use_channel_10: bool,
+    /// Write a report of the part, port, channels, and instrument of each MIDI track. The report
+    /// is JSON if the file name ends with `.json` and text otherwise.
+    #[arg(long, requires = "midi")]
+    pub midi_report: Option<PathBuf>,
+}
+impl MidiOptions {
+    fn bend_range(&self) -> u8 {
+        self.midi_bend_range.unwrap_or(match self.midi_mode {
+            MidiMode::Mpe => MPE_RANGE,
+            MidiMode::ChannelPerNote => DEFAULT_BEND_RANGE,
+        })
+    }
+}
+
+/// Channel layout of an MPE zone
+struct MpeLayout {
+    manager: u4,
+    members: Vec<u4>,
+    /// Member channels used for notes, taken in pairs
+    note_channels: Vec<u4>,
+}
+impl MpeLayout {
+    fn new(options: &MidiOptions) -> anyhow::Result<Self> {
+        let count = options.mpe_channels.unwrap_or(MPE_MEMBER_CHANNELS);
+        let (manager, members): (u8, Vec<u8>) = match options.mpe_zone.unwrap_or_default() {
+            MpeZone::Lower => (0, (1..=count).collect()),
+            MpeZone::Upper => (15, (15 - count..15).collect()),
+        };
+        let mut note_channels: Vec<u4> = members
+            .iter()
+            .filter(|&&ch| options.mpe_use_channel_10 || ch != 9)
+            .map(|&ch| ch.into())
+            .collect();
+        note_channels.truncate(note_channels.len() / 2 * 2);
+        if note_channels.is_empty() {
+            bail!("the MPE zone must have at least two channels for notes");
+        }
+        Ok(Self {
+            manager: manager.into(),
+            members: members.into_iter().map(u4::from).collect(),
+            note_channels,
+        })
+    }
+
+    fn pairs(&self) -> usize {
+        self.note_channels.len() / 2
+    }
+}
 
 #[derive(PartialEq, Eq)]
 enum MidiEvent<'s> {
@@ -165,19 +290,20 @@ struct MidiNoteData {
     bend: Option<u14>,
 }
 
-#[derive(Default)]
 struct MpeChannelTracker {
+    layout: MpeLayout,
     mappings: RefCell<BTreeMap<PortChannel, bool>>,
 }
 impl MpeChannelTracker {
-    fn idx_to_ch(idx: u8, alt: bool) -> u4 {
-        // MIDI channel 0 is reserved, and we skip channel 9.
-        let ch_low = match idx {
-            0..4 => u4::from(2 * idx + 1),
-            4..7 => u4::from(2 * idx + 2),
-            _ => panic!("idx_to_ch called with idx > 7"),
-        };
-        if alt { ch_low + u4::from(1) } else { ch_low }
+    fn new(layout: MpeLayout) -> Self {
+        Self {
+            layout,
+            mappings: Default::default(),
+        }
+    }
+
+    fn idx_to_ch(&self, idx: u8, alt: bool) -> u4 {
+        self.layout.note_channels[2 * usize::from(idx) + usize::from(alt)]
     }
 
     fn get<T: Copy + Into<PortChannel>>(&self, key: T, toggle: bool) -> u4 {
@@ -187,14 +313,86 @@ impl MpeChannelTracker {
         if toggle {
             *entry = !*entry;
         }
-        Self::idx_to_ch(port_channel.channel_idx, *entry)
+        self.idx_to_ch(port_channel.channel_idx, *entry)
+    }
+
+    fn get_both(&self, idx: u8) -> [u4; 2] {
+        [self.idx_to_ch(idx, false), self.idx_to_ch(idx, true)]
+    }
+}
+
+/// What was assigned to a track, for `--midi-report` and the track's text events
+struct TrackReport<'s> {
+    track: usize,
+    track_name: Option<&'s str>,
+    score_part: &'s str,
+    midi_port: u7,
+    instrument: MidiInstrumentNumber,
+    /// Each note number with its channels. In channel-per-note mode, channels are allocated as
+    /// notes start, so the list is empty.
+    notes: Vec<(u32, Vec<u4>)>,
+}
+impl TrackReport<'_> {
+    fn lines(&self) -> Vec<String> {
+        // User-facing instrument, bank, and channel numbers are 1-based.
+        let name = self
+            .track_name
+            .map(|x| format!(" \"{x}\""))
+            .unwrap_or_default();
+        let mut lines = vec![format!(
+            "track {}{name}: part {}, port {}, instrument {}, bank {}",
+            self.track,
+            self.score_part,
+            self.midi_port,
+            u32::from(self.instrument.instrument) + 1,
+            u32::from(self.instrument.bank) + 1,
+        )];
+        for (note_number, channels) in &self.notes {
+            let channels = if channels.is_empty() {
+                "any free channel".to_string()
+            } else {
+                let channels: Vec<_> = channels
+                    .iter()
+                    .map(|ch| (u8::from(*ch) + 1).to_string())
+                    .collect();
+                format!("channels {}", channels.join(", "))
+            };
+            lines.push(format!("  note {note_number}: {channels}"));
+        }
+        lines
     }
 
-    fn get_both(idx: u8) -> [u4; 2] {
-        [Self::idx_to_ch(idx, false), Self::idx_to_ch(idx, true)]
+    fn to_json(&self) -> Value {
+        let notes: Vec<_> = self
+            .notes
+            .iter()
+            .map(|(note_number, channels)| {
+                let channels: Vec<_> = channels.iter().map(|ch| u8::from(*ch) + 1).collect();
+                json!({"note": note_number, "channels": channels})
+            })
+            .collect();
+        json!({
+            "track": self.track,
+            "name": self.track_name,
+            "part": self.score_part,
+            "port": u8::from(self.midi_port),
+            "instrument": u32::from(self.instrument.instrument) + 1,
+            "bank": u32::from(self.instrument.bank) + 1,
+            "notes": notes,
+        })
     }
 }
 
+/// A channel in channel-per-note mode
+struct ChannelState<'s> {
+    /// Part and track the channel was last given to
+    owner: Option<(&'s str, usize)>,
+    instrument: Option<MidiInstrumentNumber>,
+    volume: u7,
+    /// Time when the channel's last note ends
+    free_at: Ratio<u32>,
+}
+
 struct MidiGenerator<'s> {
     arena: &'s Arena,
     timeline: &'s Timeline<'s>,
@@ -204,25 +402,40 @@ struct MidiGenerator<'s> {
     micros_per_beat: u24,
     part_channels: BTreeMap<&'s str, BTreeSet<TrackPortChannel>>,
     mpe_channel_tracker: MpeChannelTracker,
+    options: MidiOptions,
+    /// Pitch bend range in semitones in either direction
+    bend_range: f64,
+    channel_states: BTreeMap<(u7, u4), ChannelState<'s>>,
+    part_volumes: BTreeMap<&'s str, u7>,
     tracks: Vec<Vec<TrackEvent<'s>>>,
+    track_reports: Vec<TrackReport<'s>>,
     pitch_data: MpeData<'s>,
     smf: Option<Smf<'s>>,
 }
 
+fn part_instrument(
+    midi_instruments: &BTreeMap<Cow<str>, MidiInstrumentNumber>,
+    score_part: &str,
+) -> MidiInstrumentNumber {
+    midi_instruments
+        .get(score_part)
+        .or_else(|| midi_instruments.get(""))
+        .cloned()
+        .unwrap_or_default()
+}
+
 fn set_channel_instrument(
     midi_instruments: &BTreeMap<Cow<str>, MidiInstrumentNumber>,
     track: &mut Vec<TrackEvent>,
     score_part: &str,
     channel: u4,
+    delta: u28,
 ) -> anyhow::Result<()> {
-    let instrument = midi_instruments
-        .get(score_part)
-        .or_else(|| midi_instruments.get(""))
-        .cloned()
-        .unwrap_or_default();
+    let instrument = part_instrument(midi_instruments, score_part);
+    let mut events = Vec::new();
     if instrument.bank > 0 {
         let (bank_msb, bank_lsb) = split_u14(instrument.bank)?;
-        track.push(TrackEvent {
+        events.push(TrackEvent {
             delta: 0.into(),
             kind: TrackEventKind::Midi {
                 channel,
@@ -232,7 +445,7 @@ fn set_channel_instrument(
                 },
             },
         });
-        track.push(TrackEvent {
+        events.push(TrackEvent {
             delta: 0.into(),
             kind: TrackEventKind::Midi {
                 channel,
@@ -245,27 +458,34 @@ fn set_channel_instrument(
     }
     let program = u7::try_from(instrument.instrument)
         .ok_or_else(|| anyhow!("overflow getting instrument number"))?;
-    track.push(TrackEvent {
+    events.push(TrackEvent {
         delta: 0.into(),
         kind: TrackEventKind::Midi {
             channel,
             message: MidiMessage::ProgramChange { program },
         },
     });
+    events[0].delta = delta;
+    track.append(&mut events);
     Ok(())
 }
 
 struct MpeData<'s> {
     channel_data: BTreeMap<MpeChannelKey<'s>, PortChannel>,
     track_data: BTreeMap<MpeTrackKey<'s>, usize>,
+    /// Routing for each part from `midi_routing` directives, with the order of the directive
+    routings: BTreeMap<&'s str, (usize, &'s MidiRoutingSettings<'s>)>,
 }
 impl<'s> MpeData<'s> {
     fn get_channel_mappings(
         &mut self,
         events: &'s BTreeSet<Arc<TimelineEvent<'s>>>,
+        options: &MidiOptions,
+        mpe: &MpeChannelTracker,
     ) -> anyhow::Result<()> {
         // Assign a separate channel for each note for MPE by first creating bins of parts and
-        // notes and then assigning a port to each bin.
+        // notes and then assigning a port to each bin. Parts routed to a specific port make up
+        // that port's bin, and the remaining parts are bin-packed into the other ports.
         let mut channels_for_part: BTreeMap<&str, BTreeSet<u32>> = BTreeMap::new();
         for event in events {
             let TimelineData::Note(note_event) = &event.data else {
@@ -276,13 +496,58 @@ impl<'s> MpeData<'s> {
                 .or_default()
                 .insert(note_event.part_note.note_number);
         }
+        let capacity = match options.midi_mode {
+            MidiMode::Mpe => mpe.layout.pairs(),
+            MidiMode::ChannelPerNote => NOTE_CHANNELS.len(),
+        };
+        let mut pinned: BTreeMap<u8, Vec<(&str, u32)>> = BTreeMap::new();
+        let mut routings: Vec<_> = self.routings.values().collect();
+        routings.sort_by_key(|(order, _)| *order);
+        for (_, routing) in routings {
+            let Some(port) = routing.port else {
+                continue;
+            };
+            if options.midi_single_port {
+                if port != 0 {
+                    bail!(
+                        "midi_routing for part '{}' uses port {port}, but --midi-single-port puts all parts on port 0",
+                        routing.part
+                    );
+                }
+                continue;
+            }
+            let Some(notes) = channels_for_part.remove(routing.part.as_ref()) else {
+                continue;
+            };
+            let bin = pinned.entry(port).or_default();
+            bin.extend(notes.into_iter().map(|n| (routing.part.as_ref(), n)));
+            if bin.len() > capacity {
+                bail!(
+                    "midi_routing for part '{}' puts {} note numbers on port {port}, which only has room for {capacity}",
+                    routing.part,
+                    bin.len()
+                );
+            }
+        }
         let mut all_items: Vec<(&str, VecDeque<u32>)> = Default::default();
         for (score_part, channels_set) in channels_for_part {
             all_items.push((score_part, channels_set.into_iter().collect()));
         }
-        let bins = bin_pack(7, all_items);
-        for (i, bin) in bins.into_iter().enumerate() {
-            let midi_port = u7::from(i as u8);
+        let bins = match options.midi_mode {
+            MidiMode::ChannelPerNote if options.midi_single_port => vec![
+                all_items
+                    .into_iter()
+                    .flat_map(|(score_part, notes)| notes.into_iter().map(move |n| (score_part, n)))
+                    .collect(),
+            ],
+            _ => bin_pack(capacity, all_items),
+        };
+        // Automatically packed bins take the ports that weren't requested.
+        let free_ports = (0u8..).filter(|port| !pinned.contains_key(port));
+        let mut all_bins: Vec<_> = bins.into_iter().zip(free_ports).collect();
+        all_bins.extend(pinned.into_iter().map(|(port, bin)| (bin, port)));
+        for (bin, port) in all_bins {
+            let midi_port = u7::try_from(port).ok_or_else(|| anyhow!("too many MIDI ports"))?;
             for (ch, (score_part, note_number)) in bin.into_iter().enumerate() {
                 let key = MpeChannelKey {
                     score_part,
@@ -303,29 +568,53 @@ impl<'s> MpeData<'s> {
         arena: &'s Arena,
         midi_instruments: &BTreeMap<Cow<str>, MidiInstrumentNumber>,
         tracks: &mut Vec<Vec<TrackEvent<'s>>>,
+        options: &MidiOptions,
+        mpe: &MpeChannelTracker,
     ) -> anyhow::Result<()> {
         let mut cur_track = 1usize;
         let mut channels_seen = BTreeSet::new();
         let mut ports_seen = BTreeSet::new();
-        for (k, port_channel) in &self.channel_data {
+        // Tracks for routed parts come first in the order of their directives.
+        let mut channel_data: Vec<_> = self.channel_data.iter().collect();
+        channel_data.sort_by_key(|(k, _)| {
+            self.routings
+                .get(k.score_part)
+                .map_or(usize::MAX, |(order, _)| *order)
+        });
+        for (k, port_channel) in channel_data {
             let track_key = MpeTrackKey {
                 score_part: k.score_part,
                 midi_port: port_channel.midi_port,
             };
             if let Entry::Vacant(v) = self.track_data.entry(track_key) {
-                add_track(v, tracks, &mut cur_track, arena, port_channel.midi_port);
+                let track_name = self
+                    .routings
+                    .get(k.score_part)
+                    .and_then(|(_, routing)| routing.track_name.as_deref());
+                add_track(
+                    v,
+                    tracks,
+                    &mut cur_track,
+                    arena,
+                    port_channel.midi_port,
+                    track_name,
+                );
             }
-            if channels_seen.insert(port_channel) {
+            // In channel-per-note mode, instruments are set when channels are allocated.
+            if options.midi_mode == MidiMode::Mpe && channels_seen.insert(port_channel) {
                 let track = tracks.last_mut().unwrap();
-                for ch in MpeChannelTracker::get_both(port_channel.channel_idx) {
-                    set_channel_instrument(midi_instruments, track, k.score_part, ch)?;
+                for ch in mpe.get_both(port_channel.channel_idx) {
+                    set_channel_instrument(midi_instruments, track, k.score_part, ch, 0.into())?;
                 }
             }
             if ports_seen.insert(port_channel.midi_port) {
                 // This is the first time we've seen this port, so use this track to initialize
-                // MPE for the port.
+                // the port.
                 let track = tracks.last_mut().unwrap();
-                init_mpe(track);
+                match options.midi_mode {
+                    MidiMode::Mpe => init_mpe(track, &mpe.layout, options.bend_range()),
+                    MidiMode::ChannelPerNote => init_channels(track, options.bend_range()),
+                }
             }
         }
         Ok(())
@@ -556,7 +845,26 @@ fn bpm_to_micros_per_beat(bpm: Ratio<u32>) -> anyhow::Result<u24> {
 }
 
 impl<'s> MidiGenerator<'s> {
-    fn new(timeline: &'s Timeline, arena: &'s Arena) -> anyhow::Result<Self> {
+    fn new(
+        timeline: &'s Timeline,
+        arena: &'s Arena,
+        options: &MidiOptions,
+    ) -> anyhow::Result<Self> {
+        match options.midi_mode {
+            MidiMode::Mpe => {
+                if options.midi_single_port {
+                    bail!("--midi-single-port requires --midi-mode=channel-per-note");
+                }
+            }
+            MidiMode::ChannelPerNote => {
+                if options.mpe_zone.is_some()
+                    || options.mpe_channels.is_some()
+                    || options.mpe_use_channel_10
+                {
+                    bail!("MPE options can't be used with --midi-mode=channel-per-note");
+                }
+            }
+        }
         // Pick a timing that accommodates 2, 3, 5, and 7 as well as anything used by the score.
         let ticks_per_beat = u16::try_from(num_integer::lcm(timeline.time_lcm, 210))
             .ok()
@@ -566,6 +874,12 @@ impl<'s> MidiGenerator<'s> {
         let pitch_data = MpeData {
             channel_data: Default::default(),
             track_data: Default::default(),
+            routings: timeline
+                .midi_routings
+                .iter()
+                .enumerate()
+                .map(|(order, routing)| (routing.part.as_ref(), (order, routing)))
+                .collect(),
         };
         Ok(Self {
             arena,
@@ -576,19 +890,26 @@ impl<'s> MidiGenerator<'s> {
             micros_per_beat,
             pitch_data,
             part_channels: Default::default(),
-            mpe_channel_tracker: Default::default(),
+            mpe_channel_tracker: MpeChannelTracker::new(MpeLayout::new(options)?),
+            options: options.clone(),
+            bend_range: f64::from(options.bend_range()),
+            channel_states: Default::default(),
+            part_volumes: Default::default(),
             tracks: Default::default(),
+            track_reports: Default::default(),
             smf: None,
         })
     }
 
     fn get_delta(&self, track: usize, event_time: Ratio<u32>) -> anyhow::Result<u28> {
-        let time = u28::try_from(
-            *(event_time * (u16::from(self.ticks_per_beat) as u32))
-                .floor()
-                .numer(),
-        )
-        .ok_or_else(|| anyhow!("time overflow"))?;
+        // Compute in 64 bits so that a time with a large numerator doesn't overflow before it is
+        // reduced to ticks.
+        let ticks = u64::from(*event_time.numer()) * u64::from(u16::from(self.ticks_per_beat))
+            / u64::from(*event_time.denom());
+        let time = u32::try_from(ticks)
+            .ok()
+            .and_then(u28::try_from)
+            .ok_or_else(|| anyhow!("time overflow"))?;
         let mut track_last_time = self.track_last_time.borrow_mut();
         let result = match track_last_time.entry(track) {
             Entry::Occupied(mut v) => {
@@ -632,15 +953,150 @@ impl<'s> MidiGenerator<'s> {
 
     fn analyze(&mut self) -> anyhow::Result<()> {
         let mut tracks = self.init_tracks();
-        self.pitch_data
-            .get_channel_mappings(&self.timeline.events)?;
+        self.pitch_data.get_channel_mappings(
+            &self.timeline.events,
+            &self.options,
+            &self.mpe_channel_tracker,
+        )?;
         self.pitch_data.get_track_assignments(
             self.arena,
             &self.timeline.midi_instruments,
             &mut tracks,
+            &self.options,
+            &self.mpe_channel_tracker,
         )?;
         self.pitch_data.get_part_channels(&mut self.part_channels)?;
         self.tracks = tracks;
+        self.track_reports = self.get_track_reports()?;
+        self.add_report_text();
+        Ok(())
+    }
+
+    fn get_track_reports(&self) -> anyhow::Result<Vec<TrackReport<'s>>> {
+        let mut reports: BTreeMap<usize, TrackReport> = BTreeMap::new();
+        for (channel_key, port_channel) in &self.pitch_data.channel_data {
+            let track_key = MpeTrackKey {
+                score_part: channel_key.score_part,
+                midi_port: port_channel.midi_port,
+            };
+            let &track = self
+                .pitch_data
+                .track_data
+                .get(&track_key)
+                .ok_or_else(|| anyhow!("get_track_reports: unable to get track for note"))?;
+            let channels = match self.options.midi_mode {
+                MidiMode::Mpe => self
+                    .mpe_channel_tracker
+                    .get_both(port_channel.channel_idx)
+                    .to_vec(),
+                MidiMode::ChannelPerNote => Vec::new(),
+            };
+            reports
+                .entry(track)
+                .or_insert_with(|| TrackReport {
+                    track,
+                    track_name: self
+                        .pitch_data
+                        .routings
+                        .get(channel_key.score_part)
+                        .and_then(|(_, routing)| routing.track_name.as_deref()),
+                    score_part: channel_key.score_part,
+                    midi_port: port_channel.midi_port,
+                    instrument: part_instrument(
+                        &self.timeline.midi_instruments,
+                        channel_key.score_part,
+                    ),
+                    notes: Vec::new(),
+                })
+                .notes
+                .push((channel_key.note_number, channels));
+        }
+        Ok(reports.into_values().collect())
+    }
+
+    fn zone_name(&self) -> &'static str {
+        match self.options.mpe_zone.unwrap_or_default() {
+            MpeZone::Lower => "lower",
+            MpeZone::Upper => "upper",
+        }
+    }
+
+    fn report_header(&self) -> String {
+        let bend_range = self.options.bend_range();
+        match self.options.midi_mode {
+            MidiMode::Mpe => {
+                let zone = self.zone_name();
+                format!(
+                    "MPE {zone} zone, manager channel {}, pitch bend range {bend_range}",
+                    u8::from(self.mpe_channel_tracker.layout.manager) + 1
+                )
+            }
+            MidiMode::ChannelPerNote => {
+                format!("channel-per-note mode, pitch bend range {bend_range}")
+            }
+        }
+    }
+
+    fn add_report_text(&mut self) {
+        // Describe each track with text events so the assignments can be seen after the file
+        // is imported into a DAW. The global track gets the header. Other tracks start with their
+        // name, port, and device name, so insert after those. All these events are at time 0.
+        let text_events = |lines: Vec<String>| -> Vec<TrackEvent<'s>> {
+            lines
+                .into_iter()
+                .map(|line| TrackEvent {
+                    delta: 0.into(),
+                    kind: TrackEventKind::Meta(MetaMessage::Text(self.arena.add(line.as_bytes()))),
+                })
+                .collect()
+        };
+        let header = text_events(vec![self.report_header()]);
+        let per_track: Vec<_> = self
+            .track_reports
+            .iter()
+            .map(|report| (report.track, text_events(report.lines())))
+            .collect();
+        self.tracks[0].splice(0..0, header);
+        for (track, events) in per_track {
+            let track = &mut self.tracks[track];
+            let pos = track
+                .iter()
+                .take_while(|x| matches!(x.kind, TrackEventKind::Meta(_)))
+                .count();
+            track.splice(pos..pos, events);
+        }
+    }
+
+    fn write_report(&self, path: &Path) -> anyhow::Result<()> {
+        let is_json = path.extension().is_some_and(|x| x == "json");
+        let report = if is_json {
+            let mut v = json!({
+                "mode": match self.options.midi_mode {
+                    MidiMode::Mpe => "mpe",
+                    MidiMode::ChannelPerNote => "channel-per-note",
+                },
+                "bend_range": self.options.bend_range(),
+            });
+            if self.options.midi_mode == MidiMode::Mpe {
+                v["zone"] = json!(self.zone_name());
+                v["manager_channel"] = json!(u8::from(self.mpe_channel_tracker.layout.manager) + 1);
+            }
+            let tracks: Vec<_> = self
+                .track_reports
+                .iter()
+                .map(TrackReport::to_json)
+                .collect();
+            v["tracks"] = json!(tracks);
+            serde_json::to_string_pretty(&v)? + "\n"
+        } else {
+            let mut lines = vec![self.report_header()];
+            for report in &self.track_reports {
+                lines.append(&mut report.lines());
+            }
+            lines.join("\n") + "\n"
+        };
+        fs::write(path, report)?;
+        println!("MIDI report written to {}", path.display());
         Ok(())
     }
 
@@ -650,20 +1106,30 @@ impl<'s> MidiGenerator<'s> {
         Ok(())
     }
 
-    fn volume_events(tpc: TrackPortChannel, mut delta: u28, value: u7) -> Vec<TrackEvent<'s>> {
-        MpeChannelTracker::get_both(tpc.channel_idx)
+    fn volume_event(channel: u4, delta: u28, value: u7) -> TrackEvent<'s> {
+        TrackEvent {
+            delta,
+            kind: TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::Controller {
+                    controller: 7.into(),
+                    value,
+                },
+            },
+        }
+    }
+
+    fn volume_events(
+        &self,
+        tpc: TrackPortChannel,
+        mut delta: u28,
+        value: u7,
+    ) -> Vec<TrackEvent<'s>> {
+        self.mpe_channel_tracker
+            .get_both(tpc.channel_idx)
             .into_iter()
             .map(|channel| {
-                let t = TrackEvent {
-                    delta,
-                    kind: TrackEventKind::Midi {
-                        channel,
-                        message: MidiMessage::Controller {
-                            controller: 7.into(),
-                            value,
-                        },
-                    },
-                };
+                let t = Self::volume_event(channel, delta, value);
                 delta = 0.into();
                 t
             })
@@ -699,51 +1165,161 @@ impl<'s> MidiGenerator<'s> {
         Ok(())
     }
 
+    fn handle_mark_event(&mut self, event: &TimelineEvent<'s>) -> anyhow::Result<()> {
+        // Marks become markers and the starts and ends of repeats become cue points so they can be
+        // used for navigation in a DAW. Like tempo events, these go in track 0.
+        let Some(text) = labels::label_text(&event.data) else {
+            return Ok(());
+        };
+        let text = self.arena.add(text.as_bytes());
+        let message = match event.data {
+            TimelineData::Mark(_) => MetaMessage::Marker(text),
+            TimelineData::RepeatStart(_) | TimelineData::RepeatEnd(_) => {
+                MetaMessage::CuePoint(text)
+            }
+            _ => return Ok(()),
+        };
+        let delta = self.get_delta(0, event.time)?;
+        self.tracks[0].push(TrackEvent {
+            delta,
+            kind: TrackEventKind::Meta(message),
+        });
+        Ok(())
+    }
+
     fn handle_dynamic_event(
         &mut self,
         events: &mut BTreeSet<MidiEvent<'s>>,
         event: &TimelineEvent<'s>,
         dynamic_event: &DynamicEvent<'s>,
     ) -> anyhow::Result<()> {
-        let part_channels = self
-            .part_channels
-            .get(dynamic_event.part)
-            .ok_or_else(|| anyhow!("unable to get part channels"))?;
-        for &tpc in part_channels {
-            let delta = self.get_delta(tpc.track, event.time)?;
-            let value = u7::try_from(dynamic_event.start_level)
-                .ok_or_else(|| anyhow!("volume out of range"))?;
-            self.tracks[tpc.track].append(&mut Self::volume_events(tpc, delta, value));
-            if let Some(end_level) = &dynamic_event.end_level {
-                let total_time = end_level.time - event.time;
-                let total_ticks = *(total_time * u16::from(self.ticks_per_beat) as u32)
-                    .floor()
-                    .numer();
-                let steps = 10;
-                for (ticks, level) in ramp(
-                    dynamic_event.start_level,
-                    end_level.item,
-                    total_ticks,
-                    steps,
-                ) {
-                    let time = event.time + (Ratio::new(ticks, total_ticks) * total_time);
-                    events.insert(MidiEvent::Timeline(Arc::new(TimelineEvent {
-                        time,
-                        repeat_depth: event.repeat_depth,
-                        span: event.span,
-                        data: TimelineData::Dynamic(DynamicEvent {
-                            text: dynamic_event.text,
-                            part: dynamic_event.part,
-                            start_level: level,
-                            end_level: None,
-                        }),
-                    })));
+        let value = u7::try_from(dynamic_event.start_level)
+            .ok_or_else(|| anyhow!("volume out of range"))?;
+        match self.options.midi_mode {
+            MidiMode::Mpe => {
+                let part_channels = self
+                    .part_channels
+                    .get(dynamic_event.part)
+                    .ok_or_else(|| anyhow!("unable to get part channels"))?;
+                for &tpc in part_channels {
+                    let delta = self.get_delta(tpc.track, event.time)?;
+                    let mut volume_events = self.volume_events(tpc, delta, value);
+                    self.tracks[tpc.track].append(&mut volume_events);
                 }
             }
+            MidiMode::ChannelPerNote => {
+                // Set the volume of the channels last given to the part. Other channels get the
+                // part's volume when they are allocated to it.
+                self.part_volumes.insert(dynamic_event.part, value);
+                let mut owned = Vec::new();
+                for (&(_, channel), state) in &mut self.channel_states {
+                    if let Some((part, track)) = state.owner
+                        && part == dynamic_event.part
+                    {
+                        state.volume = value;
+                        owned.push((channel, track));
+                    }
+                }
+                for (channel, track) in owned {
+                    let delta = self.get_delta(track, event.time)?;
+                    self.tracks[track].push(Self::volume_event(channel, delta, value));
+                }
+            }
+        }
+        if let Some(end_level) = &dynamic_event.end_level {
+            let total_time = end_level.time - event.time;
+            let total_ticks = *(total_time * u16::from(self.ticks_per_beat) as u32)
+                .floor()
+                .numer();
+            let steps = 10;
+            for (ticks, level) in ramp(
+                dynamic_event.start_level,
+                end_level.item,
+                total_ticks,
+                steps,
+            ) {
+                let time = event.time + (Ratio::new(ticks, total_ticks) * total_time);
+                events.insert(MidiEvent::Timeline(Arc::new(TimelineEvent {
+                    time,
+                    repeat_depth: event.repeat_depth,
+                    span: event.span,
+                    data: TimelineData::Dynamic(DynamicEvent {
+                        text: dynamic_event.text,
+                        part: dynamic_event.part,
+                        start_level: level,
+                        end_level: None,
+                    }),
+                })));
+            }
         }
         Ok(())
     }
 
+    /// In channel-per-note mode, give a note the free channel on its port that has been free the
+    /// longest. If the channel was last used with a different instrument or volume, send a program
+    /// change or channel volume.
+    fn allocate_channel(
+        &mut self,
+        tpc: TrackPortChannel,
+        score_part: &'s str,
+        time: Ratio<u32>,
+        end_time: Ratio<u32>,
+    ) -> anyhow::Result<u4> {
+        let port = tpc.midi_port;
+        for ch in NOTE_CHANNELS {
+            self.channel_states
+                .entry((port, ch.into()))
+                .or_insert_with(|| ChannelState {
+                    owner: None,
+                    instrument: None,
+                    volume: 127.into(),
+                    free_at: Ratio::from_integer(0),
+                });
+        }
+        let Some(channel) = self
+            .channel_states
+            .range((port, u4::from(0))..=(port, u4::from(15)))
+            .filter(|(_, state)| state.free_at <= time)
+            .min_by_key(|&(&(_, ch), state)| (state.free_at, ch))
+            .map(|(&(_, ch), _)| ch)
+        else {
+            bail!(
+                "more than {} notes sound at once on MIDI port {port}",
+                NOTE_CHANNELS.len()
+            );
+        };
+        let instrument = part_instrument(&self.timeline.midi_instruments, score_part);
+        let volume = self
+            .part_volumes
+            .get(score_part)
+            .copied()
+            .unwrap_or(127.into());
+        let state = self.channel_states.get_mut(&(port, channel)).unwrap();
+        let send_instrument = state.instrument != Some(instrument);
+        let send_volume = state.volume != volume;
+        *state = ChannelState {
+            owner: Some((score_part, tpc.track)),
+            instrument: Some(instrument),
+            volume,
+            free_at: end_time,
+        };
+        if send_instrument {
+            let delta = self.get_delta(tpc.track, time)?;
+            set_channel_instrument(
+                &self.timeline.midi_instruments,
+                &mut self.tracks[tpc.track],
+                score_part,
+                channel,
+                delta,
+            )?;
+        }
+        if send_volume {
+            let delta = self.get_delta(tpc.track, time)?;
+            self.tracks[tpc.track].push(Self::volume_event(channel, delta, volume));
+        }
+        Ok(channel)
+    }
+
     fn handle_synthetic_event(&mut self, event: &SyntheticEvent) -> anyhow::Result<()> {
         let velocity = event.velocity;
         let mut delta = self.get_delta(event.midi_note.track, event.time)?;
@@ -797,6 +1373,17 @@ impl<'s> MidiGenerator<'s> {
             .ok_or_else(|| anyhow!("overflow getting velocity"))?;
         let score_part = note_event.part_note.part;
         let track_port_channel = self.pitch_data.track_port_channel(score_part, note_event)?;
+        let final_end_time = note_event.value.pitches.last().unwrap().end_time;
+        let allocated_channel = match self.options.midi_mode {
+            MidiMode::Mpe => None,
+            MidiMode::ChannelPerNote => Some(self.allocate_channel(
+                track_port_channel,
+                score_part,
+                event.time,
+                final_end_time,
+            )?),
+        };
+        let range = self.bend_range;
         // Generate a list of all the pitches we need, with times. Pitches are represented as
         // fractional MIDI note numbers in the range [0.0, 128.0). fractional_midi_note always
         // return values in that range.
@@ -837,16 +1424,15 @@ impl<'s> MidiGenerator<'s> {
         let max_rounded = max_note.round();
         let middle_rounded = ((min_note + max_note) / 2.0).round();
         // Compute pitch bends from as few note as possible since changing notes creates a brief
-        // discontinuity in pitch. MPE pitch bend is 48 semitones in either direction, so we have
-        // a total range of 8 octaves, which is enough for all practical purposes. Since MIDI spans
-        // 128 semitones, there's a chance we could have to split into at most two ranges. First,
-        // see if we can pivot from a single note, which will be a rounded f64 in the range
-        // [0.0, 128.0).
-        let pivot = if max_rounded - min_note < MPE_RANGE_F {
+        // discontinuity in pitch. MPE pitch bend is 48 semitones in either direction by default,
+        // so we have a total range of 8 octaves, which is enough for all practical purposes. Since
+        // MIDI spans 128 semitones, there's a chance we could have to split into at most two
+        // ranges. First, see if we can pivot from a single note, which will be a rounded f64 in
+        // the range [0.0, 128.0).
+        let pivot = if max_rounded - min_note < range {
             // We can express the entire range as bend from the top pitch.
             Some(max_rounded)
-        } else if middle_rounded - min_note < MPE_RANGE_F && max_note - middle_rounded < MPE_RANGE_F
-        {
+        } else if middle_rounded - min_note < range && max_note - middle_rounded < range {
             // We can express the entire range as bend from a single note that lies between the
             // top and bottom note.
             Some(middle_rounded)
@@ -861,29 +1447,59 @@ impl<'s> MidiGenerator<'s> {
                 pitches
                     .into_iter()
                     .map(|(time, fractional_note)| {
-                        (time, (mpe_note, pitch::mpe_bend(fractional_note - pivot)))
+                        (
+                            time,
+                            (mpe_note, pitch::bend(fractional_note - pivot, range)),
+                        )
                     })
                     .collect()
             }
-            None => {
+            None if range >= SPLIT_BEND_RANGE => {
                 // There will be a discontinuity since we have to switch notes. Make that as low
                 // as possible because it will be hardest to perceive. Find the lowest note that
                 // can be bent to the top pitch.
-                let high_pivot = (max_note - MPE_RANGE_F).ceil();
+                let high_pivot = (max_note - range).ceil();
                 // The highest high pivot possible would be 80, which can bend all the way to 32,
                 // so anything <= 32 will work for the low pivot.
                 let low_pivot = 16.0;
                 pitches
                     .into_iter()
                     .map(|(time, fractional_note)| {
-                        let pivot = if (fractional_note - high_pivot).abs() < MPE_RANGE_F {
+                        let pivot = if (fractional_note - high_pivot).abs() < range {
                             high_pivot
                         } else {
                             low_pivot
                         };
                         (
                             time,
-                            (pivot as u8, pitch::mpe_bend(fractional_note - pivot)),
+                            (pivot as u8, pitch::bend(fractional_note - pivot, range)),
+                        )
+                    })
+                    .collect()
+            }
+            None if self.options.midi_mode == MidiMode::Mpe => {
+                bail!(
+                    "this note glides over {:.2} semitones, which is too far for a pitch bend range \
+                     of {range} semitones; use a larger --midi-bend-range",
+                    max_note - min_note
+                );
+            }
+            None => {
+                // With a small bend range in channel-per-note mode, two notes may not be enough.
+                // Keep bending from the same note until the pitch goes out of range, and then
+                // switch to the nearest note.
+                let mut current: Option<f64> = None;
+                pitches
+                    .into_iter()
+                    .map(|(time, fractional_note)| {
+                        let pivot = match current {
+                            Some(p) if (fractional_note - p).abs() < range => p,
+                            _ => fractional_note.round().min(127.0),
+                        };
+                        current = Some(pivot);
+                        (
+                            time,
+                            (pivot as u8, pitch::bend(fractional_note - pivot, range)),
                         )
                     })
                     .collect()
@@ -891,7 +1507,6 @@ impl<'s> MidiGenerator<'s> {
         };
         note_bend.dedup_by_key(|(_, note)| *note);
         let mut note_bend: VecDeque<_> = note_bend.into_iter().collect();
-        let final_end_time = note_event.value.pitches.last().unwrap().end_time;
         // Generate an initial NoteOn event, a final NoteOff event, and intervening pitch bend
         // events. If we have to switch notes, generate intermediate off/on notes. This will be
         // audible but only happens if the entire range covers more than 8 octaves.
@@ -903,9 +1518,12 @@ impl<'s> MidiGenerator<'s> {
                 .unwrap_or(final_end_time);
             // Generate synthetic events for turning notes on and off at the right times.
             let need_note_on = last_note.map(|x| x != mpe_note).unwrap_or(true);
-            let channel = self
-                .mpe_channel_tracker
-                .get(track_port_channel, need_note_on);
+            let channel = match allocated_channel {
+                Some(channel) => channel,
+                None => self
+                    .mpe_channel_tracker
+                    .get(track_port_channel, need_note_on),
+            };
             let midi_note = {
                 MidiNoteData {
                     track: track_port_channel.track,
@@ -958,7 +1576,8 @@ impl<'s> MidiGenerator<'s> {
                 TimelineData::Note(e) => self.handle_note_event(events, event, e)?,
                 TimelineData::Mark(_)
                 | TimelineData::RepeatStart(_)
-                | TimelineData::RepeatEnd(_) => {}
+                | TimelineData::RepeatEnd(_) => self.handle_mark_event(event)?,
+                TimelineData::CsoundParam(_) | TimelineData::BarLine => {}
             },
             MidiEvent::Synthetic(e) => {
                 self.handle_synthetic_event(e)?;
@@ -968,13 +1587,15 @@ impl<'s> MidiGenerator<'s> {
         Ok(())
     }
 
-    fn generate(mut self) -> anyhow::Result<Smf<'s>> {
+    fn generate(mut self) -> anyhow::Result<(Smf<'s>, Vec<TrackReport<'s>>)> {
         self.analyze()?;
         self.init_output()?;
         let mut events: BTreeSet<_> = self
             .timeline
             .events
             .iter()
+            // Bar lines don't produce MIDI events, so keep them from extending the tracks.
+            .filter(|x| !matches!(x.data, TimelineData::BarLine))
             .map(|x| MidiEvent::Timeline(x.clone()))
             .collect();
         while let Some(event) = events.pop_first() {
@@ -999,9 +1620,13 @@ impl<'s> MidiGenerator<'s> {
             });
         }
 
+        if let Some(path) = &self.options.midi_report {
+            self.write_report(path)?;
+        }
+        let track_reports = mem::take(&mut self.track_reports);
         let mut smf = self.smf.take().unwrap();
         smf.tracks = self.tracks;
-        Ok(smf)
+        Ok((smf, track_reports))
     }
 }
 
@@ -1011,12 +1636,20 @@ fn add_track<'s, T: Ord>(
     cur_track: &mut usize,
     arena: &'s Arena,
     midi_port: u7,
+    track_name: Option<&str>,
 ) {
     v.insert(*cur_track);
     *cur_track += 1;
     let device_name = format!("d{midi_port}");
     let device_name = arena.add(device_name.as_bytes());
-    tracks.push(vec![
+    let mut track = Vec::new();
+    if let Some(track_name) = track_name {
+        track.push(TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::TrackName(arena.add(track_name.as_bytes()))),
+        });
+    }
+    track.append(&mut vec![
         TrackEvent {
             delta: 0.into(),
             kind: TrackEventKind::Meta(MetaMessage::MidiPort(midi_port)),
@@ -1026,6 +1659,7 @@ fn add_track<'s, T: Ord>(
             kind: TrackEventKind::Meta(MetaMessage::DeviceName(device_name)),
         },
     ]);
+    tracks.push(track);
 }
 
 fn end_rpn(track: &mut Vec<TrackEvent>, channel: u4) {
@@ -1092,21 +1726,20 @@ fn set_midi_parameter(
     }
 }
 
-fn init_mpe(track: &mut Vec<TrackEvent>) {
-    // Initialize MPE for a single "low" zone with 15 channels.
-    set_midi_parameter(track, 0.into(), 0.into(), 6.into(), Some(15.into()), None);
-    // Set pitch bend for channel 0 to 2 semitones.
-    set_midi_parameter(track, 0.into(), 0.into(), 0.into(), Some(2.into()), None);
-    end_rpn(track, 0.into());
-    for ch in 1..=15 {
-        // Explicitly set pitch bend sensitivity to 48 semitones for all the other channels. This
-        // can help with instruments that support pitch bend but are not necessarily MPE-aware.
+fn init_channels(track: &mut Vec<TrackEvent>, bend_range: u8) {
+    // Reset the device to General MIDI defaults, and then set the pitch bend range and volume
+    // of each channel used for notes.
+    track.push(TrackEvent {
+        delta: 0.into(),
+        kind: TrackEventKind::SysEx(GM_SYSTEM_ON),
+    });
+    for ch in NOTE_CHANNELS {
         set_midi_parameter(
             track,
             0.into(),
             ch.into(),
             0.into(),
-            Some(MPE_RANGE.into()),
+            Some(bend_range.into()),
             Some(0.into()),
         );
         end_rpn(track, ch.into());
@@ -1123,15 +1756,102 @@ fn init_mpe(track: &mut Vec<TrackEvent>) {
     }
 }
 
-pub(crate) fn generate(timeline: &Timeline, out: impl AsRef<Path>) -> anyhow::Result<()> {
+fn init_mpe(track: &mut Vec<TrackEvent>, layout: &MpeLayout, bend_range: u8) {
+    // Initialize MPE for a single zone. By default, this is the "low" zone with 15 channels.
+    let manager = layout.manager;
+    let members = u8::try_from(layout.members.len()).unwrap();
+    set_midi_parameter(
+        track,
+        0.into(),
+        manager,
+        6.into(),
+        Some(members.into()),
+        None,
+    );
+    // Set pitch bend for the manager channel to 2 semitones.
+    set_midi_parameter(track, 0.into(), manager, 0.into(), Some(2.into()), None);
+    end_rpn(track, manager);
+    for &ch in &layout.members {
+        // Explicitly set pitch bend sensitivity for all the member channels. This can help with
+        // instruments that support pitch bend but are not necessarily MPE-aware.
+        set_midi_parameter(
+            track,
+            0.into(),
+            ch,
+            0.into(),
+            Some(bend_range.into()),
+            Some(0.into()),
+        );
+        end_rpn(track, ch);
+        track.push(TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Midi {
+                channel: ch,
+                message: MidiMessage::Controller {
+                    controller: 7.into(),
+                    value: 127.into(),
+                },
+            },
+        });
+    }
+}
+
+pub(crate) fn generate(
+    timeline: &Timeline,
+    options: &MidiOptions,
+    out: impl AsRef<Path>,
+) -> anyhow::Result<()> {
     let arena = Arena::new();
-    let g = MidiGenerator::new(timeline, &arena)?;
-    let smf = g.generate()?;
+    let g = MidiGenerator::new(timeline, &arena, options)?;
+    let (smf, _) = g.generate()?;
     smf.save(&out)?;
     println!("MIDI output written to {}", out.as_ref().display());
     Ok(())
 }
 
+/// A MIDI track for a part on one port, for generators that embed MIDI data
+pub(super) struct PartTrack<'s> {
+    pub(super) name: String,
+    pub(super) events: Vec<TrackEvent<'s>>,
+}
+
+/// Generate MIDI data without writing a file, and return the ticks per beat and the track for
+/// each part and port. The global track is omitted since its tempo changes and marks have to be
+/// represented differently by other generators. Tracks are named from `midi_routing` or after
+/// their parts, with the port added for parts that span ports.
+pub(super) fn part_tracks<'s>(
+    timeline: &'s Timeline,
+    arena: &'s Arena,
+    options: &MidiOptions,
+) -> anyhow::Result<(u16, Vec<PartTrack<'s>>)> {
+    let options = MidiOptions {
+        midi_report: None,
+        ..options.clone()
+    };
+    let g = MidiGenerator::new(timeline, arena, &options)?;
+    let (smf, track_reports) = g.generate()?;
+    let Timing::Metrical(ticks_per_beat) = smf.header.timing else {
+        bail!("MIDI timing is not metrical");
+    };
+    let mut tracks_per_part: BTreeMap<&str, usize> = BTreeMap::new();
+    for report in &track_reports {
+        *tracks_per_part.entry(report.score_part).or_default() += 1;
+    }
+    let mut tracks = smf.tracks;
+    let mut result = Vec::new();
+    for report in track_reports {
+        let mut name = report.track_name.unwrap_or(report.score_part).to_string();
+        if tracks_per_part[report.score_part] > 1 {
+            name = format!("{name} d{}", report.midi_port);
+        }
+        result.push(PartTrack {
+            name,
+            events: mem::take(&mut tracks[report.track]),
+        });
+    }
+    Ok((u16::from(ticks_per_beat), result))
+}
+
 #[cfg(test)]
 mod tests {
     #![allow(clippy::single_range_in_vec_init)]
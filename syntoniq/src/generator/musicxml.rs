@@ -0,0 +1,268 @@
+use crate::generator::notation::{Direction, DirectionKind, Meter, Notation, Part, Piece, decimal};
+use num_integer::Integer;
+use num_rational::Ratio;
+use num_traits::ToPrimitive;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use syntoniq_common::parsing::Timeline;
+
+// MusicXML output is intended to give players a rough score that can be read in notation
+// software such as MuseScore. See the notation module for how the timeline is converted to
+// written notes. Tempo and rehearsal marks are written in the first part.
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+struct MusicXmlGenerator<'s> {
+    notation: Notation<'s>,
+    divisions: u32,
+}
+
+impl<'s> MusicXmlGenerator<'s> {
+    fn new(timeline: &'s Timeline<'s>, meter: Option<Meter>) -> Self {
+        let notation = Notation::new(timeline, meter);
+        // Use enough divisions per beat to write every duration and offset exactly.
+        let mut divisions = 1u32;
+        for part in &notation.parts {
+            for measure in part.voices.iter().flatten() {
+                for piece in measure {
+                    divisions = divisions.lcm(piece.duration.denom());
+                }
+            }
+        }
+        for d in &notation.directions {
+            divisions = divisions.lcm(d.time.denom());
+        }
+        for m in &notation.measures {
+            divisions = divisions.lcm(m.length.denom());
+        }
+        Self {
+            notation,
+            divisions,
+        }
+    }
+
+    fn duration(&self, r: Ratio<u32>) -> u32 {
+        (r * self.divisions).to_integer()
+    }
+
+    fn write_direction(&self, out: &mut String, d: &Direction, measure_start: Ratio<u32>) {
+        let i = "      ";
+        let _ = writeln!(out, "{i}<direction placement=\"above\">");
+        let _ = writeln!(out, "{i}  <direction-type>");
+        let mut tempo = None;
+        match &d.kind {
+            DirectionKind::Metronome(bpm) => {
+                let bpm = decimal(bpm.to_f64().unwrap());
+                let _ = writeln!(out, "{i}    <metronome>");
+                let _ = writeln!(out, "{i}      <beat-unit>quarter</beat-unit>");
+                let _ = writeln!(out, "{i}      <per-minute>{bpm}</per-minute>");
+                let _ = writeln!(out, "{i}    </metronome>");
+                tempo = Some(bpm);
+            }
+            DirectionKind::Words(words) => {
+                let _ = writeln!(out, "{i}    <words>{words}</words>");
+            }
+            DirectionKind::Rehearsal(label) => {
+                let _ = writeln!(out, "{i}    <rehearsal>{}</rehearsal>", escape(label));
+            }
+        }
+        let _ = writeln!(out, "{i}  </direction-type>");
+        let offset = self.duration(d.time - measure_start);
+        if offset > 0 {
+            let _ = writeln!(out, "{i}  <offset>{offset}</offset>");
+        }
+        if let Some(tempo) = tempo {
+            let _ = writeln!(out, "{i}  <sound tempo=\"{tempo}\"/>");
+        }
+        let _ = writeln!(out, "{i}</direction>");
+    }
+
+    fn write_piece(&self, out: &mut String, part: &Part, voice: usize, piece: &Piece) {
+        let i = "      ";
+        let _ = writeln!(out, "{i}<note>");
+        let pitch = piece.pitch.map(|p| &part.pitches[p]);
+        match (pitch, piece.note_type) {
+            (Some(p), _) => {
+                let _ = writeln!(out, "{i}  <pitch>");
+                let _ = writeln!(out, "{i}    <step>{}</step>", p.step);
+                let alter = decimal(p.alter);
+                if alter != "0" {
+                    let _ = writeln!(out, "{i}    <alter>{alter}</alter>");
+                }
+                let _ = writeln!(out, "{i}    <octave>{}</octave>", p.octave);
+                let _ = writeln!(out, "{i}  </pitch>");
+            }
+            (None, None) => {
+                let _ = writeln!(out, "{i}  <rest measure=\"yes\"/>");
+            }
+            (None, Some(_)) => {
+                let _ = writeln!(out, "{i}  <rest/>");
+            }
+        }
+        let _ = writeln!(
+            out,
+            "{i}  <duration>{}</duration>",
+            self.duration(piece.duration)
+        );
+        if piece.tie_stop {
+            let _ = writeln!(out, "{i}  <tie type=\"stop\"/>");
+        }
+        if piece.tie_start {
+            let _ = writeln!(out, "{i}  <tie type=\"start\"/>");
+        }
+        let _ = writeln!(out, "{i}  <voice>{}</voice>", voice + 1);
+        if let Some(note_type) = piece.note_type {
+            let _ = writeln!(out, "{i}  <type>{note_type}</type>");
+        }
+        if piece.dotted {
+            let _ = writeln!(out, "{i}  <dot/>");
+        }
+        if let Some((actual, normal)) = piece.time_modification {
+            let _ = writeln!(out, "{i}  <time-modification>");
+            let _ = writeln!(out, "{i}    <actual-notes>{actual}</actual-notes>");
+            let _ = writeln!(out, "{i}    <normal-notes>{normal}</normal-notes>");
+            let _ = writeln!(out, "{i}  </time-modification>");
+        }
+        if let Some(p) = pitch
+            && piece.show_name
+        {
+            let _ = writeln!(out, "{i}  <notehead-text>");
+            let _ = writeln!(
+                out,
+                "{i}    <display-text>{}</display-text>",
+                escape(&p.name)
+            );
+            let _ = writeln!(out, "{i}  </notehead-text>");
+        }
+        let mut notations = Vec::new();
+        if piece.tie_stop {
+            notations.push("<tied type=\"stop\"/>");
+        }
+        if piece.tie_start {
+            notations.push("<tied type=\"start\"/>");
+        }
+        if piece.tuplet_start {
+            notations.push("<tuplet type=\"start\"/>");
+        }
+        if piece.tuplet_stop {
+            notations.push("<tuplet type=\"stop\"/>");
+        }
+        if !notations.is_empty() {
+            let _ = writeln!(out, "{i}  <notations>");
+            for n in notations {
+                let _ = writeln!(out, "{i}    {n}");
+            }
+            let _ = writeln!(out, "{i}  </notations>");
+        }
+        let _ = writeln!(out, "{i}</note>");
+    }
+
+    fn write_part(&self, out: &mut String, idx: usize, part: &Part) {
+        let measures = &self.notation.measures;
+        let _ = writeln!(out, "  <part id=\"P{}\">", idx + 1);
+        for (m, measure) in measures.iter().enumerate() {
+            let _ = writeln!(out, "    <measure number=\"{}\">", m + 1);
+            let time_changed = m == 0 || measures[m - 1].time_signature != measure.time_signature;
+            if time_changed {
+                let _ = writeln!(out, "      <attributes>");
+                if m == 0 {
+                    let _ = writeln!(out, "        <divisions>{}</divisions>", self.divisions);
+                    let _ = writeln!(out, "        <key>");
+                    let _ = writeln!(out, "          <fifths>0</fifths>");
+                    let _ = writeln!(out, "        </key>");
+                }
+                let (beats, beat_type) = measure.time_signature;
+                let _ = writeln!(out, "        <time>");
+                let _ = writeln!(out, "          <beats>{beats}</beats>");
+                let _ = writeln!(out, "          <beat-type>{beat_type}</beat-type>");
+                let _ = writeln!(out, "        </time>");
+                if m == 0 {
+                    let (sign, line) = if part.is_bass() { ("F", 4) } else { ("G", 2) };
+                    let _ = writeln!(out, "        <clef>");
+                    let _ = writeln!(out, "          <sign>{sign}</sign>");
+                    let _ = writeln!(out, "          <line>{line}</line>");
+                    let _ = writeln!(out, "        </clef>");
+                }
+                let _ = writeln!(out, "      </attributes>");
+            }
+            if idx == 0 {
+                for d in &self.notation.directions {
+                    if self.notation.measure_index(d.time) == m {
+                        self.write_direction(out, d, measure.start);
+                    }
+                }
+            }
+            let mut first = true;
+            for (v, voice_measures) in part.voices.iter().enumerate() {
+                let pieces = &voice_measures[m];
+                if pieces.is_empty() {
+                    continue;
+                }
+                if !first {
+                    let _ = writeln!(out, "      <backup>");
+                    let _ = writeln!(
+                        out,
+                        "        <duration>{}</duration>",
+                        self.duration(measure.length)
+                    );
+                    let _ = writeln!(out, "      </backup>");
+                }
+                first = false;
+                for piece in pieces {
+                    self.write_piece(out, part, v, piece);
+                }
+            }
+            if m + 1 == measures.len() {
+                let _ = writeln!(out, "      <barline location=\"right\">");
+                let _ = writeln!(out, "        <bar-style>light-heavy</bar-style>");
+                let _ = writeln!(out, "      </barline>");
+            }
+            let _ = writeln!(out, "    </measure>");
+        }
+        let _ = writeln!(out, "  </part>");
+    }
+
+    fn generate(&self) -> String {
+        let mut out = String::new();
+        out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n");
+        out.push_str(
+            "<!DOCTYPE score-partwise PUBLIC \"-//Recordare//DTD MusicXML 4.0 Partwise//EN\" \
+             \"http://www.musicxml.org/dtds/partwise.dtd\">\n",
+        );
+        out.push_str("<score-partwise version=\"4.0\">\n");
+        out.push_str("  <identification>\n");
+        out.push_str("    <encoding>\n");
+        out.push_str("      <software>Syntoniq</software>\n");
+        out.push_str("    </encoding>\n");
+        out.push_str("  </identification>\n");
+        out.push_str("  <part-list>\n");
+        for (idx, part) in self.notation.parts.iter().enumerate() {
+            let _ = writeln!(out, "    <score-part id=\"P{}\">", idx + 1);
+            let _ = writeln!(out, "      <part-name>{}</part-name>", escape(part.name));
+            let _ = writeln!(out, "    </score-part>");
+        }
+        out.push_str("  </part-list>\n");
+        for (idx, part) in self.notation.parts.iter().enumerate() {
+            self.write_part(&mut out, idx, part);
+        }
+        out.push_str("</score-partwise>\n");
+        out
+    }
+}
+
+pub(crate) fn generate(
+    timeline: &Timeline,
+    meter: Option<Meter>,
+    out: impl AsRef<Path>,
+) -> anyhow::Result<()> {
+    let xml = MusicXmlGenerator::new(timeline, meter).generate();
+    fs::write(&out, xml)?;
+    println!("MusicXML output written to {}", out.as_ref().display());
+    Ok(())
+}
@@ -0,0 +1,663 @@
+use anyhow::bail;
+use num_integer::Integer;
+use num_rational::Ratio;
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+use syntoniq_common::parsing::{Timeline, TimelineData};
+use syntoniq_common::pitch::Pitch;
+
+// This module converts a timeline into measures of written notes and rests for the printed
+// notation outputs. It is not intended to be a faithful representation of everything that can be
+// expressed in a syntoniq score.
+//
+// Conventions:
+//   - Each syntoniq part becomes a part with a single staff, and each note number within the part
+//     becomes a voice.
+//   - The syntoniq beat is the quarter note. Bar lines come from bar checks, where the end of each
+//     score block is also a bar line, unless a meter is given. Bar lines that don't fall on a
+//     binary subdivision of the beat can't start a measure with a normal time signature, so they
+//     are dropped.
+//   - Durations are split at bar lines and written as (possibly dotted) note values joined by
+//     ties. Notes that start or end between the binary subdivisions of a beat are split at beats
+//     and written as tuplets.
+//   - Each pitch is written as the nearest 12-EDO pitch spelled with sharps. The deviation in cents
+//     is included in the alteration so that notation software plays approximately the right
+//     pitch, and the syntoniq note name is attached to the note.
+//   - A note whose pitch changes is written as a separate note for each pitch. Glides are written
+//     using their starting pitch.
+//   - Marks become rehearsal marks, and tempo events become metronome marks. Repeats have already
+//     been expanded in the timeline, so they are not marked.
+
+/// Note types with their durations in beats from longest to shortest
+pub(crate) const NOTE_TYPES: [(&str, u32, u32); 11] = [
+    ("whole", 4, 1),
+    ("half", 2, 1),
+    ("quarter", 1, 1),
+    ("eighth", 1, 2),
+    ("16th", 1, 4),
+    ("32nd", 1, 8),
+    ("64th", 1, 16),
+    ("128th", 1, 32),
+    ("256th", 1, 64),
+    ("512th", 1, 128),
+    ("1024th", 1, 256),
+];
+
+/// Length of measures when there are no bar lines
+const DEFAULT_MEASURE_BEATS: u32 = 4;
+
+/// A fixed time signature, given as `beats/beat-type`, e.g. `3/4` or `6/8`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Meter {
+    beats: u32,
+    beat_type: u32,
+}
+
+impl Meter {
+    /// Length of a measure in syntoniq beats
+    fn length(self) -> Ratio<u32> {
+        Ratio::new(self.beats * 4, self.beat_type)
+    }
+
+    /// Beats per measure and beat type, as written in a time signature
+    pub(crate) fn time_signature(self) -> (u32, u32) {
+        (self.beats, self.beat_type)
+    }
+}
+
+impl FromStr for Meter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((beats, beat_type)) = s.split_once('/') else {
+            bail!("a meter must be given as beats/beat-type, e.g. 3/4");
+        };
+        let beats: u32 = beats.parse()?;
+        let beat_type: u32 = beat_type.parse()?;
+        if beats == 0 || !beat_type.is_power_of_two() {
+            bail!(
+                "a meter must have a positive number of beats and a power of two as its beat type"
+            );
+        }
+        Ok(Self { beats, beat_type })
+    }
+}
+
+/// A measure of the printed score
+pub(crate) struct Measure {
+    pub start: Ratio<u32>,
+    /// Length in syntoniq beats, which always has a power of 2 as its denominator
+    pub length: Ratio<u32>,
+    /// Numerator and denominator of the time signature
+    pub time_signature: (u32, u32),
+}
+
+impl Measure {
+    pub fn end(&self) -> Ratio<u32> {
+        self.start + self.length
+    }
+}
+
+/// A pitch as written in the score
+pub(crate) struct NotatedPitch {
+    pub step: &'static str,
+    /// Alteration in semitones
+    pub alter: f64,
+    pub octave: i32,
+    /// Nearest 12-EDO MIDI note number, used to choose a clef
+    midi_note: i32,
+    pub name: String,
+}
+
+impl NotatedPitch {
+    pub fn new(pitch: &Pitch, text: &str) -> Self {
+        const STEPS: [(&str, f64); 12] = [
+            ("C", 0.0),
+            ("C", 1.0),
+            ("D", 0.0),
+            ("D", 1.0),
+            ("E", 0.0),
+            ("F", 0.0),
+            ("F", 1.0),
+            ("G", 0.0),
+            ("G", 1.0),
+            ("A", 0.0),
+            ("A", 1.0),
+            ("B", 0.0),
+        ];
+        let fractional = 12.0 * (pitch.as_float() / 440.0).log2() + 69.0;
+        let midi_note = fractional.round() as i32;
+        let (step, sharp) = STEPS[midi_note.rem_euclid(12) as usize];
+        Self {
+            step,
+            alter: sharp + fractional - f64::from(midi_note),
+            octave: midi_note.div_euclid(12) - 1,
+            midi_note,
+            name: note_name(text).to_string(),
+        }
+    }
+}
+
+/// Return the note name with octave marks from the text of a note, which may include a duration
+/// before the note and modifiers after it.
+fn note_name(text: &str) -> &str {
+    let mut parts = text.split(':');
+    let first = parts.next().unwrap_or_default();
+    if first.starts_with(|c: char| c.is_ascii_digit()) {
+        parts.next().unwrap_or(first)
+    } else {
+        first
+    }
+}
+
+/// A note or rest in a single voice
+struct Segment {
+    start: Ratio<u32>,
+    end: Ratio<u32>,
+    /// `None` for a rest
+    pitch: Option<usize>,
+    tie_to_next: bool,
+}
+
+/// A single written note or rest
+pub(crate) struct Piece {
+    pub start: Ratio<u32>,
+    pub duration: Ratio<u32>,
+    /// `None` for a whole-measure rest
+    pub note_type: Option<&'static str>,
+    pub dotted: bool,
+    pub time_modification: Option<(u32, u32)>,
+    pub pitch: Option<usize>,
+    pub show_name: bool,
+    pub tie_start: bool,
+    pub tie_stop: bool,
+    pub tuplet_start: bool,
+    pub tuplet_stop: bool,
+}
+
+pub(crate) enum DirectionKind<'s> {
+    Metronome(Ratio<u32>),
+    Words(&'static str),
+    Rehearsal(&'s str),
+}
+
+pub(crate) struct Direction<'s> {
+    pub time: Ratio<u32>,
+    pub kind: DirectionKind<'s>,
+}
+
+pub(crate) struct Part<'s> {
+    pub name: &'s str,
+    pub pitches: Vec<NotatedPitch>,
+    /// For each voice, the pieces in each measure
+    pub voices: Vec<Vec<Vec<Piece>>>,
+}
+
+impl Part<'_> {
+    /// Whether the part is mostly below middle C and should use bass clef
+    pub fn is_bass(&self) -> bool {
+        let total: i64 = self.pitches.iter().map(|p| i64::from(p.midi_note)).sum();
+        total < 60 * self.pitches.len() as i64
+    }
+}
+
+fn is_binary(r: Ratio<u32>) -> bool {
+    r.denom().is_power_of_two()
+}
+
+fn odd_part(n: u32) -> u32 {
+    n >> n.trailing_zeros()
+}
+
+/// Split a duration whose denominator is a power of 2 into note values from longest to shortest,
+/// returning the type, whether it is dotted, and the duration for each. If the duration can't be
+/// represented exactly, the remainder is added to the last duration.
+fn note_values(duration: Ratio<u32>) -> Vec<(&'static str, bool, Ratio<u32>)> {
+    let mut result: Vec<(&'static str, bool, Ratio<u32>)> = Vec::new();
+    let mut remaining = duration;
+    while remaining > Ratio::from_integer(0) {
+        let found = NOTE_TYPES.iter().find_map(|&(name, num, den)| {
+            let value = Ratio::new(num, den);
+            let dotted = value * Ratio::new(3, 2);
+            if dotted <= remaining {
+                Some((name, true, dotted))
+            } else if value <= remaining {
+                Some((name, false, value))
+            } else {
+                None
+            }
+        });
+        match found {
+            Some(v) => {
+                remaining -= v.2;
+                result.push(v);
+            }
+            None => {
+                match result.last_mut() {
+                    Some(last) => last.2 += remaining,
+                    None => result.push((NOTE_TYPES[NOTE_TYPES.len() - 1].0, false, remaining)),
+                }
+                break;
+            }
+        }
+    }
+    result
+}
+
+/// Write the time from `start` to `end` as a sequence of pieces, all of which have the given pitch
+/// and are not yet tied.
+fn notate(start: Ratio<u32>, end: Ratio<u32>, pitch: Option<usize>) -> Vec<Piece> {
+    let piece = |start, duration, note_type, dotted, time_modification| Piece {
+        start,
+        duration,
+        note_type: Some(note_type),
+        dotted,
+        time_modification,
+        pitch,
+        show_name: false,
+        tie_start: false,
+        tie_stop: false,
+        tuplet_start: false,
+        tuplet_stop: false,
+    };
+    let mut ranges = Vec::new();
+    if is_binary(start) && is_binary(end) {
+        ranges.push((start, end));
+    } else {
+        // Split at beats so each tuplet falls within a single beat.
+        let mut t = start;
+        while t < end {
+            let next = (t.floor() + Ratio::from_integer(1)).min(end);
+            ranges.push((t, next));
+            t = next;
+        }
+    }
+    let mut result = Vec::new();
+    for (start, end) in ranges {
+        let mut t = start;
+        if is_binary(start) && is_binary(end) {
+            for (note_type, dotted, duration) in note_values(end - start) {
+                result.push(piece(t, duration, note_type, dotted, None));
+                t += duration;
+            }
+        } else {
+            // Write `actual` notes in the time of `normal` notes, where `actual` is the odd part
+            // of the denominators, so that the written durations are binary.
+            let actual = odd_part(start.denom().lcm(end.denom()));
+            let normal = 1 << actual.ilog2();
+            let scale = Ratio::new(actual, normal);
+            for (note_type, dotted, written) in note_values((end - start) * scale) {
+                let duration = written / scale;
+                result.push(piece(
+                    t,
+                    duration,
+                    note_type,
+                    dotted,
+                    Some((actual, normal)),
+                ));
+                t += duration;
+            }
+        }
+    }
+    result
+}
+
+/// Group consecutive notes in the same beat with the same time modification into tuplets.
+fn mark_tuplets(pieces: &mut [Piece]) {
+    let same_group = |a: &Piece, b: &Piece| {
+        a.time_modification == b.time_modification && a.start.floor() == b.start.floor()
+    };
+    for i in 0..pieces.len() {
+        if pieces[i].time_modification.is_none() {
+            continue;
+        }
+        pieces[i].tuplet_start = i == 0 || !same_group(&pieces[i - 1], &pieces[i]);
+        pieces[i].tuplet_stop = i + 1 == pieces.len() || !same_group(&pieces[i], &pieces[i + 1]);
+    }
+}
+
+/// Convert a voice's segments into pieces for each measure.
+fn voice_measures(
+    segments: &[Segment],
+    measures: &[Measure],
+    first_voice: bool,
+) -> Vec<Vec<Piece>> {
+    let total = measures.last().map(Measure::end).unwrap_or_default();
+    // Fill gaps with rests.
+    let mut filled = Vec::new();
+    let mut cursor = Ratio::from_integer(0);
+    for s in segments {
+        if s.start > cursor {
+            filled.push(Segment {
+                start: cursor,
+                end: s.start,
+                pitch: None,
+                tie_to_next: false,
+            });
+        }
+        filled.push(Segment { ..*s });
+        cursor = s.end;
+    }
+    if cursor < total {
+        filled.push(Segment {
+            start: cursor,
+            end: total,
+            pitch: None,
+            tie_to_next: false,
+        });
+    }
+
+    let mut result: Vec<Vec<Piece>> = measures.iter().map(|_| Vec::new()).collect();
+    let mut tied_from_previous = false;
+    for s in filled {
+        let first_measure = measures.partition_point(|m| m.end() <= s.start);
+        let mut pieces = Vec::new();
+        for (m, measure) in measures.iter().enumerate().skip(first_measure) {
+            if measure.start >= s.end {
+                break;
+            }
+            let start = s.start.max(measure.start);
+            let end = s.end.min(measure.end());
+            pieces.extend(notate(start, end, s.pitch).into_iter().map(|p| (m, p)));
+        }
+        if s.pitch.is_some() {
+            let n = pieces.len();
+            for (i, (_, p)) in pieces.iter_mut().enumerate() {
+                p.show_name = i == 0;
+                p.tie_stop = i > 0 || tied_from_previous;
+                p.tie_start = i + 1 < n || s.tie_to_next;
+            }
+        }
+        tied_from_previous = s.tie_to_next;
+        for (m, p) in pieces {
+            result[m].push(p);
+        }
+    }
+    for (pieces, measure) in result.iter_mut().zip(measures) {
+        if pieces.iter().all(|p| p.pitch.is_none()) {
+            pieces.clear();
+            // Only the first voice needs a rest in an empty measure.
+            if first_voice {
+                pieces.push(Piece {
+                    start: measure.start,
+                    duration: measure.length,
+                    note_type: None,
+                    dotted: false,
+                    time_modification: None,
+                    pitch: None,
+                    show_name: false,
+                    tie_start: false,
+                    tie_stop: false,
+                    tuplet_start: false,
+                    tuplet_stop: false,
+                });
+            }
+        } else {
+            mark_tuplets(pieces);
+        }
+    }
+    result
+}
+
+/// Divide the time up to `end_time` into measures, using bar lines at the given times unless a
+/// meter is given.
+fn measures(
+    bar_lines: &BTreeSet<Ratio<u32>>,
+    end_time: Ratio<u32>,
+    meter: Option<Meter>,
+) -> Vec<Measure> {
+    if let Some(meter) = meter {
+        let length = meter.length();
+        let count = (end_time / length).ceil().to_integer().max(1);
+        return (0..count)
+            .map(|i| Measure {
+                start: length * i,
+                length,
+                time_signature: meter.time_signature(),
+            })
+            .collect();
+    }
+    let zero = Ratio::from_integer(0);
+    let mut boundaries: Vec<Ratio<u32>> = vec![zero];
+    boundaries.extend(
+        bar_lines
+            .iter()
+            .copied()
+            .filter(|&t| t > zero && is_binary(t)),
+    );
+    let last = *boundaries.last().unwrap();
+    if end_time > last {
+        boundaries.push(if is_binary(end_time) {
+            end_time
+        } else {
+            end_time.ceil()
+        });
+    } else if boundaries.len() == 1 {
+        boundaries.push(Ratio::from_integer(DEFAULT_MEASURE_BEATS));
+    }
+    boundaries
+        .windows(2)
+        .map(|w| {
+            let length = w[1] - w[0];
+            Measure {
+                start: w[0],
+                length,
+                time_signature: (*length.numer(), 4 * length.denom()),
+            }
+        })
+        .collect()
+}
+
+/// A timeline converted to written notes and rests, ready to be written out in a notation format
+pub(crate) struct Notation<'s> {
+    pub parts: Vec<Part<'s>>,
+    pub directions: Vec<Direction<'s>>,
+    pub measures: Vec<Measure>,
+}
+
+impl<'s> Notation<'s> {
+    pub fn new(timeline: &'s Timeline<'s>, meter: Option<Meter>) -> Self {
+        let mut pitches: BTreeMap<&str, Vec<NotatedPitch>> = BTreeMap::new();
+        let mut segments: BTreeMap<&str, BTreeMap<u32, Vec<Segment>>> = BTreeMap::new();
+        let mut directions = Vec::new();
+        let mut bar_lines = BTreeSet::new();
+        let mut end_time = Ratio::from_integer(0);
+        for event in &timeline.events {
+            end_time = end_time.max(event.end_time());
+            match &event.data {
+                TimelineData::Note(e) => {
+                    let part_pitches = pitches.entry(e.part_note.part).or_default();
+                    let voice = segments
+                        .entry(e.part_note.part)
+                        .or_default()
+                        .entry(e.part_note.note_number)
+                        .or_default();
+                    let mut previous: Option<&Pitch> = None;
+                    for p in &e.value.pitches {
+                        // Cut off any earlier note that overlaps this one.
+                        if let Some(last) = voice.last_mut()
+                            && last.end > p.start_time
+                        {
+                            last.end = p.start_time;
+                            if last.end <= last.start {
+                                voice.pop();
+                            }
+                        }
+                        // A pitch that continues the previous steady pitch is a tied note.
+                        if let (Some(prev), Some(last)) = (previous, voice.last_mut())
+                            && *prev == p.start_pitch
+                        {
+                            last.tie_to_next = true;
+                        }
+                        previous = match p.end_pitch {
+                            None => Some(&p.start_pitch),
+                            Some(_) => None,
+                        };
+                        part_pitches.push(NotatedPitch::new(&p.start_pitch, p.text));
+                        voice.push(Segment {
+                            start: p.start_time,
+                            end: p.end_time,
+                            pitch: Some(part_pitches.len() - 1),
+                            tie_to_next: false,
+                        });
+                    }
+                }
+                TimelineData::Tempo(e) => {
+                    directions.push(Direction {
+                        time: event.time,
+                        kind: DirectionKind::Metronome(e.bpm),
+                    });
+                    if let Some(end_bpm) = &e.end_bpm {
+                        directions.push(Direction {
+                            time: event.time,
+                            kind: DirectionKind::Words(if end_bpm.item > e.bpm {
+                                "accel."
+                            } else {
+                                "rit."
+                            }),
+                        });
+                        directions.push(Direction {
+                            time: end_bpm.time,
+                            kind: DirectionKind::Metronome(end_bpm.item),
+                        });
+                    }
+                }
+                TimelineData::Mark(e) => directions.push(Direction {
+                    time: event.time,
+                    kind: DirectionKind::Rehearsal(&e.label),
+                }),
+                TimelineData::BarLine => {
+                    bar_lines.insert(event.time);
+                }
+                TimelineData::Dynamic(_)
+                | TimelineData::CsoundParam(_)
+                | TimelineData::RepeatStart(_)
+                | TimelineData::RepeatEnd(_) => {}
+            }
+        }
+        let measures = measures(&bar_lines, end_time, meter);
+        // Notation formats need at least one part, so write an unnamed one for a score with no
+        // notes.
+        if segments.is_empty() {
+            segments.entry("").or_default().insert(0, Vec::new());
+        }
+        let parts: Vec<Part> = segments
+            .into_iter()
+            .map(|(name, voices)| Part {
+                name,
+                pitches: pitches.remove(name).unwrap_or_default(),
+                voices: voices
+                    .values()
+                    .enumerate()
+                    .map(|(i, segments)| voice_measures(segments, &measures, i == 0))
+                    .collect(),
+            })
+            .collect();
+        Self {
+            parts,
+            directions,
+            measures,
+        }
+    }
+
+    /// Return the index of the measure containing `time`. Anything after the end is in the last
+    /// measure.
+    pub fn measure_index(&self, time: Ratio<u32>) -> usize {
+        self.measures
+            .partition_point(|m| m.end() <= time)
+            .min(self.measures.len() - 1)
+    }
+}
+
+/// Format a number with at most two decimal places.
+pub(crate) fn decimal(v: f64) -> String {
+    let s = format!("{v:.2}");
+    let s = s.trim_end_matches('0').trim_end_matches('.');
+    if s == "-0" { "0" } else { s }.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn r(n: u32, d: u32) -> Ratio<u32> {
+        Ratio::new(n, d)
+    }
+
+    #[test]
+    fn test_note_name() {
+        assert_eq!(note_name("c"), "c");
+        assert_eq!(note_name("1:c'"), "c'");
+        assert_eq!(note_name("3/2:E!17,2:~"), "E!17,2");
+        assert_eq!(note_name("A1:&~"), "A1");
+    }
+
+    #[test]
+    fn test_notate() {
+        // Summarize each piece as duration, type, dot, and tuplet ratio.
+        let summary = |start, end| -> Vec<String> {
+            notate(start, end, None)
+                .into_iter()
+                .map(|p| {
+                    let dot = if p.dotted { "." } else { "" };
+                    let tuplet = p
+                        .time_modification
+                        .map(|(a, n)| format!(" {a}:{n}"))
+                        .unwrap_or_default();
+                    format!("{} {}{dot}{tuplet}", p.duration, p.note_type.unwrap())
+                })
+                .collect()
+        };
+        assert_eq!(summary(r(0, 1), r(5, 2)), ["2 half", "1/2 eighth"]);
+        assert_eq!(summary(r(1, 1), r(4, 1)), ["3 half."]);
+        // A note starting on the second triplet of a beat is a triplet quarter followed by a
+        // quarter.
+        assert_eq!(summary(r(1, 3), r(2, 1)), ["2/3 quarter 3:2", "1 quarter"]);
+        assert_eq!(summary(r(0, 1), r(1, 5)), ["1/5 16th 5:4"]);
+    }
+
+    #[test]
+    fn test_measures() {
+        // Summarize measures as time signatures.
+        let summary = |bar_lines: &[Ratio<u32>], end_time, meter: Option<&str>| -> Vec<String> {
+            let bar_lines = bar_lines.iter().copied().collect();
+            measures(&bar_lines, end_time, meter.map(|m| m.parse().unwrap()))
+                .iter()
+                .map(|m| format!("{}/{}", m.time_signature.0, m.time_signature.1))
+                .collect()
+        };
+        let bars = [r(4, 1), r(7, 1), r(15, 2), r(23, 3), r(10, 1)];
+        // The bar line in the middle of a triplet is dropped.
+        assert_eq!(summary(&bars, r(10, 1), None), ["4/4", "3/4", "1/8", "5/8"]);
+        // Notes past the last bar line make a final measure, which is extended to the end of the
+        // beat if needed.
+        assert_eq!(summary(&bars[..2], r(17, 2), None), ["4/4", "3/4", "3/8"]);
+        assert_eq!(summary(&bars[..2], r(25, 3), None), ["4/4", "3/4", "2/4"]);
+        assert_eq!(summary(&[], r(0, 1), None), ["4/4"]);
+        assert_eq!(
+            summary(&bars, r(10, 1), Some("6/8")),
+            ["6/8", "6/8", "6/8", "6/8"]
+        );
+        assert_eq!(summary(&[], r(0, 1), Some("3/4")), ["3/4"]);
+        assert!("3".parse::<Meter>().is_err());
+        assert!("3/5".parse::<Meter>().is_err());
+        assert!("0/4".parse::<Meter>().is_err());
+    }
+
+    #[test]
+    fn test_notated_pitch() {
+        let p = NotatedPitch::new(&Pitch::must_parse("440"), "a");
+        assert_eq!((p.step, decimal(p.alter).as_str(), p.octave), ("A", "0", 4));
+        // 5/4 above middle C is 14¢ flat of E.
+        let p = NotatedPitch::new(&Pitch::must_parse("220*^1|4*5/4"), "e");
+        assert_eq!(
+            (p.step, decimal(p.alter).as_str(), p.octave),
+            ("E", "-0.14", 4)
+        );
+        let p = NotatedPitch::new(&Pitch::must_parse("220*^1|4*^1|17"), "c#");
+        assert_eq!(
+            (p.step, decimal(p.alter).as_str(), p.octave),
+            ("C", "0.71", 4)
+        );
+    }
+}
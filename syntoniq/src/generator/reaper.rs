@@ -0,0 +1,230 @@
+use crate::generator::labels;
+use crate::generator::midi::{self, MidiOptions};
+use crate::generator::notation::Meter;
+use crate::generator::tempo::{TempoMap, to_f64};
+use midly::live::LiveEvent;
+use midly::{Arena, TrackEventKind};
+use num_rational::Ratio;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use syntoniq_common::parsing::{Timeline, TimelineData};
+
+// A Reaper project is a text file of nested blocks in angle brackets. The project has a tempo
+// envelope built from the score's tempo events, a region for each mark, a marker for the start
+// and end of each repeat, and a track for each part on each MIDI port. Each track contains a
+// single item with the track's MIDI events embedded in it. These are the same events that are
+// written to the MIDI file, including the MPE configuration, so the tracks play the same way.
+// Tracks are set up to record from all MIDI inputs on all channels, which is what an MPE
+// controller needs, and have input monitoring turned on.
+//
+// Embedded MIDI data uses lines of the form `E delta status data1 data2`, where the delta is in
+// ticks from the previous event and the rest are hexadecimal bytes. Messages with one data byte
+// are padded with a zero, as Reaper does. Meta events and system exclusive messages are omitted.
+// Marks and tempo changes have their own representation, and the General MIDI reset isn't needed
+// within a DAW. Like Reaper, we end the data with an "all notes off" controller message at the end
+// of the track.
+
+/// Tempo used before the first tempo event, matching the MIDI generator
+const DEFAULT_BPM: u32 = 72;
+
+/// Record input for all MIDI devices on all channels
+const ALL_MIDI_INPUTS: u32 = 4096 + (63 << 5);
+
+fn quote(s: &str) -> String {
+    // Reaper strings have no escape mechanism. Instead, the string is quoted with a character it
+    // doesn't contain.
+    if !s.contains('"') {
+        format!("\"{s}\"")
+    } else if !s.contains('\'') {
+        format!("'{s}'")
+    } else {
+        format!("`{}`", s.replace('`', "'"))
+    }
+}
+
+fn seconds_str(seconds: f64) -> String {
+    let s = format!("{seconds:.6}");
+    let s = s.trim_end_matches('0');
+    s.trim_end_matches('.').to_string()
+}
+
+struct ReaperGenerator<'s> {
+    timeline: &'s Timeline<'s>,
+    tempo: TempoMap,
+    content: String,
+}
+
+impl<'s> ReaperGenerator<'s> {
+    fn new(timeline: &'s Timeline<'s>) -> Self {
+        Self {
+            timeline,
+            tempo: TempoMap::new(timeline),
+            content: String::new(),
+        }
+    }
+
+    fn seconds(&self, time: Ratio<u32>) -> String {
+        seconds_str(self.tempo.seconds(to_f64(time)))
+    }
+
+    fn line(&mut self, indent: usize, text: impl AsRef<str>) {
+        writeln!(self.content, "{}{}", "  ".repeat(indent), text.as_ref()).unwrap();
+    }
+
+    fn tempo_envelope(&mut self, meter: Option<Meter>) {
+        // Points have a time in seconds, a tempo, and a shape, which is 0 for a linear change to
+        // the next point and 1 for a constant tempo until the next point. The envelope always
+        // starts at time 0, using the default tempo if the score doesn't set one there.
+        let mut points: Vec<(f64, f64, u8)> = Vec::new();
+        for event in &self.timeline.events {
+            let TimelineData::Tempo(e) = &event.data else {
+                continue;
+            };
+            let start = self.tempo.seconds(to_f64(event.time));
+            let bpm = to_f64(e.bpm);
+            match &e.end_bpm {
+                None => points.push((start, bpm, 1)),
+                Some(end) => {
+                    points.push((start, bpm, 0));
+                    let end_time = self.tempo.seconds(to_f64(end.time));
+                    points.push((end_time, to_f64(end.item), 1));
+                }
+            }
+        }
+        if !points.first().is_some_and(|&(start, _, _)| start == 0.0) {
+            points.insert(0, (0.0, f64::from(DEFAULT_BPM), 1));
+        }
+        let first_bpm = points[0].1;
+        let (beats, beat_type) = meter.map_or((4, 4), Meter::time_signature);
+        self.line(1, format!("TEMPO {first_bpm} {beats} {beat_type}"));
+        self.line(1, "<TEMPOENVEX");
+        self.line(2, "ACT 1 -1");
+        self.line(2, "VIS 1 0 1");
+        self.line(2, "DEFSHAPE 1 -1 -1");
+        for (start, bpm, shape) in points {
+            self.line(2, format!("PT {} {bpm} {shape}", seconds_str(start)));
+        }
+        self.line(1, ">");
+    }
+
+    fn markers(&mut self, end_time: Ratio<u32>) {
+        // Each mark starts a region that ends at the next mark or the end of the score. A region
+        // is written as two markers with the region flag and the same number. Repeats, and marks
+        // that would make empty regions, become ordinary markers.
+        let mut marks = Vec::new();
+        let mut markers = Vec::new();
+        for event in &self.timeline.events {
+            let Some(text) = labels::label_text(&event.data) else {
+                continue;
+            };
+            match event.data {
+                TimelineData::Mark(_) => marks.push((event.time, text)),
+                _ => markers.push((event.time, text)),
+            }
+        }
+        let mut number = 1;
+        for (i, (time, text)) in marks.iter().enumerate() {
+            let region_end = marks.get(i + 1).map_or(end_time, |(t, _)| *t);
+            if region_end <= *time {
+                markers.push((*time, text.clone()));
+                continue;
+            }
+            let start = self.seconds(*time);
+            let end = self.seconds(region_end);
+            self.line(1, format!("MARKER {number} {start} {} 1", quote(text)));
+            self.line(1, format!("MARKER {number} {end} \"\" 1"));
+            number += 1;
+        }
+        markers.sort_by_key(|(time, _)| *time);
+        for (i, (time, text)) in markers.iter().enumerate() {
+            let start = self.seconds(*time);
+            self.line(1, format!("MARKER {} {start} {} 0", i + 1, quote(text)));
+        }
+    }
+
+    fn track(&mut self, track: &midi::PartTrack, ticks_per_beat: u16, length: &str) {
+        let name = quote(&track.name);
+        self.line(1, "<TRACK");
+        self.line(2, format!("NAME {name}"));
+        self.line(2, format!("REC 0 {ALL_MIDI_INPUTS} 1 0 0 0 0 0"));
+        self.line(2, "<ITEM");
+        self.line(3, "POSITION 0");
+        self.line(3, format!("LENGTH {length}"));
+        self.line(3, format!("NAME {name}"));
+        self.line(3, "<SOURCE MIDI");
+        self.line(4, format!("HASDATA 1 {ticks_per_beat} QN"));
+        let mut delta = 0u32;
+        let mut bytes = Vec::new();
+        for event in &track.events {
+            delta += u32::from(event.delta);
+            let TrackEventKind::Midi { channel, message } = event.kind else {
+                continue;
+            };
+            bytes.clear();
+            LiveEvent::Midi { channel, message }
+                .write_std(&mut bytes)
+                .unwrap();
+            bytes.resize(3, 0);
+            let data: Vec<_> = bytes.iter().map(|b| format!("{b:02x}")).collect();
+            self.line(4, format!("E {delta} {}", data.join(" ")));
+            delta = 0;
+        }
+        self.line(4, format!("E {delta} b0 7b 00"));
+        self.line(3, ">");
+        self.line(2, ">");
+        self.line(1, ">");
+    }
+
+    fn generate(mut self, options: &MidiOptions, meter: Option<Meter>) -> anyhow::Result<String> {
+        let arena = Arena::new();
+        let (ticks_per_beat, tracks) = midi::part_tracks(self.timeline, &arena, options)?;
+        let end_time = self
+            .timeline
+            .events
+            .iter()
+            .map(|x| x.end_time())
+            .max()
+            .unwrap_or_default();
+        let length = self.seconds(end_time);
+        self.line(0, "<REAPER_PROJECT 0.1 \"6.0\" 0");
+        self.tempo_envelope(meter);
+        self.markers(end_time);
+        for track in &tracks {
+            self.track(track, ticks_per_beat, &length);
+        }
+        self.line(0, ">");
+        Ok(self.content)
+    }
+}
+
+pub(crate) fn generate(
+    timeline: &Timeline,
+    options: &MidiOptions,
+    meter: Option<Meter>,
+    out: impl AsRef<Path>,
+) -> anyhow::Result<()> {
+    let content = ReaperGenerator::new(timeline).generate(options, meter)?;
+    fs::write(&out, content)?;
+    println!("Reaper project written to {}", out.as_ref().display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("Lead"), "\"Lead\"");
+        assert_eq!(quote("the \"A\" part"), "'the \"A\" part'");
+        assert_eq!(quote("\"A\" isn't `B`"), "`\"A\" isn't 'B'`");
+    }
+
+    #[test]
+    fn test_seconds_str() {
+        assert_eq!(seconds_str(0.0), "0");
+        assert_eq!(seconds_str(2.5), "2.5");
+        assert_eq!(seconds_str(10.9484449), "10.948445");
+    }
+}
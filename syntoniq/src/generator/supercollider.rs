@@ -0,0 +1,529 @@
+use crate::generator::csound::{ratio_to_rounded_float, rounded_float};
+use crate::generator::tempo::{TempoMap, to_f64};
+use anyhow::bail;
+use num_rational::Ratio;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use syntoniq_common::parsing::{Timeline, TimelineData, WavInstrumentSettings, Waveform};
+
+// The SuperCollider generator produces a Score: a list of timed server commands. It follows the
+// structure of the Csound output. Each part has a control bus for its amplitude, one for the
+// maximum number of notes in the part, and one for the frequency of each note number. These are
+// set with `/c_set`, which corresponds to Csound's `SetPartParam`, or by a short-lived
+// `syntoniq_ramp` synth, which corresponds to `SetPartParamRamp`. Each note is a synth using its
+// part's SynthDef that reads the buses and is released with its gate at the end of the note.
+//
+// Unlike Csound, SuperCollider scores are in seconds, so times are converted using the tempo
+// map. The score can be written as an `.scd` file, which defines the SynthDefs and the Score in
+// sclang, or as a binary OSC file for non-realtime rendering with `scsynth -N`.
+
+/// Name of the SynthDef that ramps a control bus exponentially
+const RAMP_SYNTH: &str = "syntoniq_ramp";
+
+/// First node ID used for synths
+const FIRST_NODE: i32 = 1000;
+
+/// Amplitude of each part before its first dynamic, matching the Csound template
+const DEFAULT_AMP: f64 = 0.5;
+
+/// OSC `s_new` add actions
+const ADD_TO_HEAD: i32 = 0;
+const ADD_TO_TAIL: i32 = 1;
+
+enum Arg {
+    Int(i32),
+    Float(f64),
+    Symbol(String),
+}
+
+struct Message {
+    address: &'static str,
+    args: Vec<Arg>,
+}
+
+/// A message at a time in seconds with the comments that precede it. Comments without a message
+/// appear only in the `.scd` output.
+struct Command {
+    time: f64,
+    comments: Vec<String>,
+    message: Option<Message>,
+}
+
+struct PartData {
+    /// Parts are numbered from 1 in order of their first notes.
+    part_number: usize,
+    amp_bus: i32,
+    notes_bus: i32,
+    freq_buses: BTreeMap<u32, i32>,
+}
+
+fn scale_dynamic(v: u8, non_zero: bool) -> f64 {
+    let v = if non_zero && v == 0 { 1 } else { v };
+    f64::from(v) / 127.0
+}
+
+fn synth_name(part: &str) -> String {
+    format!("syntoniq_{part}")
+}
+
+fn symbol(s: &str) -> String {
+    if s.chars().next().is_some_and(|c| c.is_ascii_alphabetic())
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        format!("\\{s}")
+    } else {
+        format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+    }
+}
+
+struct ScGenerator<'s> {
+    timeline: &'s Timeline<'s>,
+    tempo: TempoMap,
+    part_data: BTreeMap<&'s str, PartData>,
+    commands: Vec<Command>,
+    next_node: i32,
+    end_time: f64,
+}
+
+impl<'s> ScGenerator<'s> {
+    fn new(timeline: &'s Timeline<'s>) -> anyhow::Result<Self> {
+        let mut g = Self {
+            timeline,
+            tempo: TempoMap::new(timeline),
+            part_data: Default::default(),
+            commands: Default::default(),
+            next_node: FIRST_NODE,
+            end_time: 0.0,
+        };
+        g.assign_buses();
+        g.generate()?;
+        Ok(g)
+    }
+
+    fn seconds(&self, time: Ratio<u32>) -> f64 {
+        self.tempo.seconds(to_f64(time))
+    }
+
+    fn instrument_for_part(&self, part: &str) -> WavInstrumentSettings {
+        self.timeline
+            .wav_instruments
+            .get(part)
+            .or_else(|| self.timeline.wav_instruments.get(""))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn assign_buses(&mut self) {
+        let mut parts: Vec<&str> = Vec::new();
+        let mut note_numbers: BTreeMap<&str, BTreeSet<u32>> = BTreeMap::new();
+        for event in &self.timeline.events {
+            let TimelineData::Note(e) = &event.data else {
+                continue;
+            };
+            let part = e.part_note.part;
+            if !parts.contains(&part) {
+                parts.push(part);
+            }
+            note_numbers
+                .entry(part)
+                .or_default()
+                .insert(e.part_note.note_number);
+        }
+        let mut next_bus = 0;
+        let mut bus = || {
+            let b = next_bus;
+            next_bus += 1;
+            b
+        };
+        for (idx, part) in parts.into_iter().enumerate() {
+            let amp_bus = bus();
+            let notes_bus = bus();
+            let freq_buses = note_numbers[part].iter().map(|&n| (n, bus())).collect();
+            self.part_data.insert(
+                part,
+                PartData {
+                    part_number: idx + 1,
+                    amp_bus,
+                    notes_bus,
+                    freq_buses,
+                },
+            );
+        }
+    }
+
+    fn push(&mut self, time: f64, comment: Option<String>, message: Option<Message>) {
+        self.commands.push(Command {
+            time,
+            comments: comment.into_iter().collect(),
+            message,
+        });
+    }
+
+    fn set_bus(&mut self, time: f64, comment: Option<String>, bus: i32, value: f64) {
+        let message = Message {
+            address: "/c_set",
+            args: vec![Arg::Int(bus), Arg::Float(value)],
+        };
+        self.push(time, comment, Some(message));
+    }
+
+    fn ramp_bus(
+        &mut self,
+        time: f64,
+        comment: Option<String>,
+        bus: i32,
+        (start, end, duration): (f64, f64, f64),
+    ) {
+        let node = self.next_node;
+        self.next_node += 1;
+        // Ramps are added to the head so they write their buses before notes read them.
+        let message = Message {
+            address: "/s_new",
+            args: vec![
+                Arg::Symbol(RAMP_SYNTH.to_string()),
+                Arg::Int(node),
+                Arg::Int(ADD_TO_HEAD),
+                Arg::Int(0),
+                Arg::Symbol("bus".to_string()),
+                Arg::Int(bus),
+                Arg::Symbol("start".to_string()),
+                Arg::Float(start),
+                Arg::Symbol("end".to_string()),
+                Arg::Float(end),
+                Arg::Symbol("dur".to_string()),
+                Arg::Float(duration),
+            ],
+        };
+        self.push(time, comment, Some(message));
+    }
+
+    fn generate(&mut self) -> anyhow::Result<()> {
+        let initial: Vec<(i32, i32, usize)> = self
+            .part_data
+            .values()
+            .map(|data| (data.amp_bus, data.notes_bus, data.freq_buses.len()))
+            .collect();
+        for (amp_bus, notes_bus, notes) in initial {
+            self.set_bus(0.0, None, amp_bus, DEFAULT_AMP);
+            self.set_bus(0.0, None, notes_bus, notes as f64);
+        }
+        let mut max_time: f64 = 0.0;
+        for event in &self.timeline.events {
+            let time = self.seconds(event.time);
+            let offset = event.span.start;
+            match &event.data {
+                TimelineData::Tempo(_) | TimelineData::CsoundParam(_) | TimelineData::BarLine => {}
+                TimelineData::Dynamic(e) => {
+                    let Some(part_data) = self.part_data.get(e.part) else {
+                        // When using start and end mark, it's possible for a dynamic line to appear
+                        // for a part that has no note events within the region.
+                        continue;
+                    };
+                    let amp_bus = part_data.amp_bus;
+                    let comment = Some(format!("{} @{offset}", e.text));
+                    match &e.end_level {
+                        None => {
+                            let start = scale_dynamic(e.start_level, false);
+                            self.set_bus(time, comment, amp_bus, start);
+                        }
+                        Some(end_level) => {
+                            // Scale dynamics excluding 0 since `XLine` doesn't support 0.
+                            let start = scale_dynamic(e.start_level, true);
+                            let end = scale_dynamic(end_level.item, true);
+                            let end_time = self.seconds(end_level.time);
+                            self.ramp_bus(time, comment, amp_bus, (start, end, end_time - time));
+                            if end_level.item == 0 {
+                                // Silence the part at the end if we're ramping to silence.
+                                self.set_bus(end_time, None, amp_bus, 0.0);
+                            }
+                        }
+                    }
+                }
+                TimelineData::Note(e) => {
+                    let Some(part_data) = self.part_data.get(e.part_note.part) else {
+                        bail!("unknown part");
+                    };
+                    let (amp_bus, notes_bus) = (part_data.amp_bus, part_data.notes_bus);
+                    let Some(&freq_bus) = part_data.freq_buses.get(&e.part_note.note_number) else {
+                        bail!("unknown note number");
+                    };
+                    for pc in &e.value.pitches {
+                        let start_time = self.seconds(pc.start_time);
+                        let end_time = self.seconds(pc.end_time);
+                        let freq = pc.start_pitch.as_float();
+                        let comment = Some(format!("{}@{}", pc.text, pc.span.start));
+                        match pc.end_pitch.as_ref() {
+                            None => self.set_bus(start_time, comment, freq_bus, freq),
+                            Some(end_pitch) => {
+                                let end_freq = end_pitch.as_float();
+                                let ramp = (freq, end_freq, end_time - start_time);
+                                self.ramp_bus(start_time, comment, freq_bus, ramp);
+                            }
+                        }
+                    }
+                    let end_time = self.seconds(e.value.pitches.last().unwrap().end_time);
+                    if time > end_time {
+                        bail!("end time is in the past")
+                    }
+                    let release = to_f64(self.instrument_for_part(e.part_note.part).release);
+                    max_time = max_time.max(end_time + release);
+                    let node = self.next_node;
+                    self.next_node += 1;
+                    let velocity = f64::from(e.value.velocity.min(127)) / 127.0;
+                    let message = Message {
+                        address: "/s_new",
+                        args: vec![
+                            Arg::Symbol(synth_name(e.part_note.part)),
+                            Arg::Int(node),
+                            Arg::Int(ADD_TO_TAIL),
+                            Arg::Int(0),
+                            Arg::Symbol("freqBus".to_string()),
+                            Arg::Int(freq_bus),
+                            Arg::Symbol("ampBus".to_string()),
+                            Arg::Int(amp_bus),
+                            Arg::Symbol("notesBus".to_string()),
+                            Arg::Int(notes_bus),
+                            Arg::Symbol("velocity".to_string()),
+                            Arg::Float(velocity),
+                        ],
+                    };
+                    let comment = Some(format!("{} @{offset}", e.value.text));
+                    self.push(time, comment, Some(message));
+                    let release = Message {
+                        address: "/n_set",
+                        args: vec![Arg::Int(node), Arg::Symbol("gate".to_string()), Arg::Int(0)],
+                    };
+                    self.push(end_time, None, Some(release));
+                }
+                TimelineData::Mark(e) => {
+                    let comment = format!("mark '{}' @'{}", e.label, event.span);
+                    self.push(time, Some(comment), None);
+                }
+                TimelineData::RepeatStart(e) => {
+                    let comment = format!("repeat start '{}' @'{}", e.label, event.span);
+                    self.push(time, Some(comment), None);
+                }
+                TimelineData::RepeatEnd(e) => {
+                    let comment = format!("repeat end '{}' @'{}", e.label, event.span);
+                    self.push(time, Some(comment), None);
+                }
+            }
+        }
+        // Commands are generated in order of events, but pitch changes and note releases happen
+        // later. The sort is stable, so commands at the same time stay in the order generated.
+        self.commands.sort_by(|a, b| a.time.total_cmp(&b.time));
+        // Non-realtime rendering stops at the last command, so add a final command that leaves
+        // time for the last notes to be released. `/status` does nothing to the score, unlike
+        // setting a bus, since every bus belongs to a part.
+        self.end_time = self.commands.last().map_or(0.0, |c| c.time).max(max_time);
+        let message = Message {
+            address: "/status",
+            args: Vec::new(),
+        };
+        self.push(
+            self.end_time,
+            Some("end of score".to_string()),
+            Some(message),
+        );
+        Ok(())
+    }
+
+    fn synth_def(&self, part: &str) -> String {
+        let settings = self.instrument_for_part(part);
+        let r = |v| ratio_to_rounded_float(v, 4);
+        let osc = match settings.waveform {
+            Waveform::Sine => "SinOsc",
+            Waveform::Triangle => "LFTri",
+            Waveform::Square => "Pulse",
+            Waveform::Sawtooth => "Saw",
+        };
+        format!(
+            "SynthDef({}, {{ |freqBus = 0, ampBus = 0, notesBus = 0, velocity = 1, gate = 1, out = 0|
+    var freq = In.kr(freqBus);
+    // Attenuate based on polyphony
+    var amp = In.kr(ampBus) * velocity / In.kr(notesBus).max(1).sqrt;
+    var env = EnvGen.kr(Env.adsr({}, {}, {}, {}), gate, doneAction: Done.freeSelf);
+    var sig = LPF.ar({osc}.ar(freq), {});
+    Out.ar(out, (sig * env * amp) ! 2);
+}}).store;
+",
+            symbol(&synth_name(part)),
+            r(settings.attack),
+            r(settings.decay),
+            r(settings.sustain),
+            r(settings.release),
+            settings.cutoff,
+        )
+    }
+
+    fn scd(&self) -> String {
+        let mut out = String::new();
+        out.push_str(
+            "// SuperCollider score generated by syntoniq
+//
+// Running this file stores a SynthDef for each part and sets ~syntoniq to a Score. Play it with
+// `~syntoniq.play`, or render it to a sound file with `~syntoniq.recordNRT`.
+//
+// Each part has control buses for its amplitude, the maximum number of notes in the part, and
+// the frequency of each note number.
+(
+",
+        );
+        out.push_str("// [part] => amp bus, notes bus\n");
+        let mut parts: Vec<(&&str, &PartData)> = self.part_data.iter().collect();
+        parts.sort_by_key(|(_, data)| data.part_number);
+        for (part, data) in &parts {
+            writeln!(out, "// [{part}] => {}, {}", data.amp_bus, data.notes_bus).unwrap();
+        }
+        out.push_str("// [part.note] => freq bus\n");
+        for (part, data) in &parts {
+            for (note, bus) in &data.freq_buses {
+                writeln!(out, "// [{part}.{note}] => {bus}").unwrap();
+            }
+        }
+        writeln!(
+            out,
+            "
+SynthDef({}, {{ |bus = 0, start = 1, end = 1, dur = 1|
+    ReplaceOut.kr(bus, XLine.kr(start, end, dur, doneAction: Done.freeSelf));
+}}).store;
+",
+            symbol(RAMP_SYNTH)
+        )
+        .unwrap();
+        for (part, _) in &parts {
+            out.push_str(&self.synth_def(part));
+            out.push('\n');
+        }
+        out.push_str("~syntoniq = Score([\n");
+        for command in &self.commands {
+            for comment in &command.comments {
+                writeln!(out, "    // {comment}").unwrap();
+            }
+            let Some(message) = &command.message else {
+                continue;
+            };
+            let mut items = vec![symbol(message.address.trim_start_matches('/'))];
+            items.extend(message.args.iter().map(|arg| match arg {
+                Arg::Int(i) => i.to_string(),
+                Arg::Float(f) => rounded_float(*f, 4),
+                Arg::Symbol(s) => symbol(s),
+            }));
+            writeln!(
+                out,
+                "    [{}, [{}]],",
+                rounded_float(command.time, 4),
+                items.join(", ")
+            )
+            .unwrap();
+        }
+        out.push_str("]);\n");
+        writeln!(
+            out,
+            "// ~syntoniq.recordNRT(outputFilePath: \"score.wav\".standardizePath, headerFormat: \"wav\", sampleFormat: \"int16\", options: ServerOptions.new.numOutputBusChannels_(2), duration: {});",
+            rounded_float(self.end_time, 4)
+        )
+        .unwrap();
+        out.push_str(")\n");
+        out
+    }
+
+    fn osc(&self) -> Vec<u8> {
+        // A non-realtime score file is a sequence of OSC bundles, each preceded by its size.
+        let mut out = Vec::new();
+        let messages: Vec<(f64, &Message)> = self
+            .commands
+            .iter()
+            .filter_map(|c| c.message.as_ref().map(|m| (c.time, m)))
+            .collect();
+        for bundle in messages.chunk_by(|a, b| a.0 == b.0) {
+            let time = bundle[0].0;
+            let mut data = b"#bundle\0".to_vec();
+            let seconds = time.trunc();
+            let fraction = ((time - seconds) * 4_294_967_296.0) as u32;
+            data.extend_from_slice(&(seconds as u32).to_be_bytes());
+            data.extend_from_slice(&fraction.to_be_bytes());
+            for (_, message) in bundle {
+                let encoded = encode_message(message);
+                data.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+                data.extend_from_slice(&encoded);
+            }
+            out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            out.extend_from_slice(&data);
+        }
+        out
+    }
+}
+
+fn encode_string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(s.as_bytes());
+    // Strings are null-terminated and padded to a multiple of four bytes.
+    out.resize((out.len() / 4 + 1) * 4, 0);
+}
+
+fn encode_message(message: &Message) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_string(&mut out, message.address);
+    let tags: String = message
+        .args
+        .iter()
+        .map(|arg| match arg {
+            Arg::Int(_) => 'i',
+            Arg::Float(_) => 'f',
+            Arg::Symbol(_) => 's',
+        })
+        .collect();
+    encode_string(&mut out, &format!(",{tags}"));
+    for arg in &message.args {
+        match arg {
+            Arg::Int(i) => out.extend_from_slice(&i.to_be_bytes()),
+            Arg::Float(f) => out.extend_from_slice(&(*f as f32).to_be_bytes()),
+            Arg::Symbol(s) => encode_string(&mut out, s),
+        }
+    }
+    out
+}
+
+pub(crate) fn generate_scd(timeline: &Timeline, out: impl AsRef<Path>) -> anyhow::Result<()> {
+    let g = ScGenerator::new(timeline)?;
+    fs::write(&out, g.scd())?;
+    println!("SuperCollider output written to {}", out.as_ref().display());
+    Ok(())
+}
+
+pub(crate) fn generate_osc(timeline: &Timeline, out: impl AsRef<Path>) -> anyhow::Result<()> {
+    let g = ScGenerator::new(timeline)?;
+    fs::write(&out, g.osc())?;
+    println!("OSC score written to {}", out.as_ref().display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_message() {
+        let message = Message {
+            address: "/n_set",
+            args: vec![
+                Arg::Int(1000),
+                Arg::Symbol("gate".to_string()),
+                Arg::Float(0.5),
+            ],
+        };
+        let mut exp = b"/n_set\0\0,isf\0\0\0\0".to_vec();
+        exp.extend_from_slice(&1000i32.to_be_bytes());
+        exp.extend_from_slice(b"gate\0\0\0\0");
+        exp.extend_from_slice(&0.5f32.to_be_bytes());
+        assert_eq!(encode_message(&message), exp);
+    }
+
+    #[test]
+    fn test_symbol() {
+        assert_eq!(symbol("syntoniq_p1"), "\\syntoniq_p1");
+        assert_eq!(symbol("syntoniq_é"), "'syntoniq_é'");
+    }
+}
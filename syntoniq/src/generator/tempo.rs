@@ -0,0 +1,113 @@
+use num_rational::Ratio;
+use num_traits::ToPrimitive;
+use syntoniq_common::parsing::{Timeline, TimelineData};
+
+// Outputs that are written in seconds rather than beats, such as WAV, SuperCollider, and label
+// files, convert times with a tempo map built from the score's tempo events. Gradual tempo
+// changes are linear in beats, so the time spent in a ramp is computed exactly rather than by
+// sampling.
+
+pub(crate) fn to_f64(r: Ratio<u32>) -> f64 {
+    r.to_f64().unwrap()
+}
+
+/// A stretch of the score with a constant or linearly changing tempo
+struct TempoSegment {
+    beat: f64,
+    seconds: f64,
+    bpm: f64,
+    /// End beat and tempo of a gradual tempo change
+    ramp: Option<(f64, f64)>,
+}
+
+/// Maps beats to seconds
+pub(crate) struct TempoMap {
+    segments: Vec<TempoSegment>,
+}
+
+impl TempoMap {
+    pub(crate) fn new(timeline: &Timeline) -> Self {
+        let mut map = TempoMap {
+            segments: vec![TempoSegment {
+                beat: 0.0,
+                seconds: 0.0,
+                bpm: 72.0,
+                ramp: None,
+            }],
+        };
+        for event in &timeline.events {
+            let TimelineData::Tempo(e) = &event.data else {
+                continue;
+            };
+            let beat = to_f64(event.time);
+            let seconds = map.seconds(beat);
+            let segment = TempoSegment {
+                beat,
+                seconds,
+                bpm: to_f64(e.bpm),
+                ramp: e
+                    .end_bpm
+                    .as_ref()
+                    .map(|end| (to_f64(end.time), to_f64(end.item))),
+            };
+            if map.segments.last().is_some_and(|s| s.beat == beat) {
+                map.segments.pop();
+            }
+            map.segments.push(segment);
+        }
+        map
+    }
+
+    pub(crate) fn seconds(&self, beat: f64) -> f64 {
+        let idx = self
+            .segments
+            .partition_point(|s| s.beat <= beat)
+            .saturating_sub(1);
+        let s = &self.segments[idx];
+        let Some((end_beat, end_bpm)) = s.ramp else {
+            return s.seconds + (beat - s.beat) * 60.0 / s.bpm;
+        };
+        let length = end_beat - s.beat;
+        let in_ramp = (beat - s.beat).min(length);
+        // Tempo changes linearly in beats, so the time spent in the ramp is the integral of
+        // 60/bpm over the beats covered.
+        let ramp_seconds = if end_bpm == s.bpm || length <= 0.0 {
+            in_ramp * 60.0 / s.bpm
+        } else {
+            let slope = (end_bpm - s.bpm) / length;
+            60.0 / slope * ((s.bpm + slope * in_ramp) / s.bpm).ln()
+        };
+        let after_ramp = (beat - end_beat).max(0.0) * 60.0 / end_bpm;
+        s.seconds + ramp_seconds + after_ramp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tempo_ramp() {
+        let tempo = TempoMap {
+            segments: vec![
+                TempoSegment {
+                    beat: 0.0,
+                    seconds: 0.0,
+                    bpm: 60.0,
+                    ramp: None,
+                },
+                TempoSegment {
+                    beat: 2.0,
+                    seconds: 2.0,
+                    bpm: 60.0,
+                    ramp: Some((4.0, 120.0)),
+                },
+            ],
+        };
+        assert!((tempo.seconds(1.0) - 1.0).abs() < 1e-9);
+        // Two beats going from 60 to 120 bpm take 2 ln 2 seconds.
+        let ramp_end = 2.0 + 2.0 * 2f64.ln();
+        assert!((tempo.seconds(4.0) - ramp_end).abs() < 1e-9);
+        assert!((tempo.seconds(5.0) - (ramp_end + 0.5)).abs() < 1e-9);
+    }
+}
@@ -1,10 +1,79 @@
 use crate::generator;
 use crate::generator::GenerateOptions;
+use crate::generator::midi::{MidiMode, MidiOptions, MpeZone};
 use std::fs;
 use std::path::{Path, PathBuf};
-use syntoniq_common::parsing::Options;
+use syntoniq_common::parsing::{Options, pass3};
 use syntoniq_common::test_helpers;
 
+/// Return options that read the given test score and write no output. Tests set the outputs and
+/// options they need with struct update syntax.
+fn score_options(base: &str) -> GenerateOptions {
+    GenerateOptions {
+        score: Some(format!("test-data/{base}.stq").into()),
+        from_json: None,
+        json: None,
+        midi: None,
+        midi_options: Default::default(),
+        reaper: None,
+        csound: None,
+        csound_sco: None,
+        csound_orc: None,
+        text: None,
+        musicxml: None,
+        lilypond: None,
+        meter: None,
+        supercollider: None,
+        osc: None,
+        wav: None,
+        wav_sample_rate: 8000,
+        labels: None,
+        csound_template: None,
+        watch: false,
+        parse_options: Default::default(),
+    }
+}
+
+/// Run the generator and return its error message, or panic if it succeeds
+fn expect_error(options: GenerateOptions) -> String {
+    match generator::run(options) {
+        Ok(()) => panic!("expected error was not seen"),
+        Err(e) => e.to_string(),
+    }
+}
+
+/// Compare a generated file with the expected file. If they differ, save the generated file in
+/// `save` for comparison and record the failure in `errors`.
+fn check_output(actual: &Path, exp: &str, save: &str, errors: &mut Vec<String>) {
+    let data = match fs::read(actual) {
+        Ok(x) => x,
+        Err(e) => {
+            errors.push(format!("read {}: {e}", actual.display()));
+            return;
+        }
+    };
+    if data == fs::read(exp).unwrap_or_default() {
+        println!("{exp} PASSED");
+        return;
+    }
+    let result =
+        fs::create_dir_all(Path::new(save).parent().unwrap()).and_then(|()| fs::write(save, data));
+    match result {
+        Ok(()) => errors.push(format!("{exp} FAILED; saved in {save}")),
+        Err(e) => errors.push(format!("{exp} FAILED; write {save}: {e}")),
+    }
+}
+
+/// Print the errors collected by a test and fail if there were any
+fn check_errors(errors: Vec<String>) {
+    if !errors.is_empty() {
+        for e in errors {
+            eprintln!("ERROR: {e}");
+        }
+        panic!("there were errors");
+    }
+}
+
 #[test]
 fn test_generator() -> anyhow::Result<()> {
     // This is designed to fail if anything failed but to run all the tests and produce useful
@@ -40,18 +109,45 @@ fn test_generator() -> anyhow::Result<()> {
             }
         });
         let options = GenerateOptions {
-            score: input_file("stq").into(),
             json: Some(outfile("json")),
             midi: Some(outfile("midi")),
+            reaper: Some(outfile("rpp")),
             csound: Some(outfile("csd")),
+            csound_sco: Some(outfile("sco")),
             text: Some(outfile("txt")),
+            musicxml: Some(outfile("musicxml")),
+            lilypond: Some(outfile("ly")),
+            supercollider: Some(outfile("scd")),
+            osc: Some(outfile("osc")),
+            wav: Some(outfile("wav")),
+            labels: Some(outfile("labels.txt")),
             csound_template,
-            parse_options: Default::default(),
+            ..score_options(&base)
         };
         if let Err(e) = generator::run(options) {
             errors.push(format!("{base}: {e}"));
         }
-        for suf in ["json", "midi", "csd", "txt"] {
+        for suf in [
+            "json",
+            "midi",
+            "csd",
+            "txt",
+            "musicxml",
+            "ly",
+            "scd",
+            "osc",
+            "wav",
+            "labels.txt",
+            "rpp",
+            "sco",
+        ] {
+            // WAV files and Reaper projects are large, most scores have no labels, and Csound
+            // scores are the same as the score section of Csound output, so only compare these
+            // for scores that have them.
+            if matches!(suf, "wav" | "labels.txt" | "rpp" | "sco") && !fs::exists(input_file(suf))?
+            {
+                continue;
+            }
             let out = outfile(suf);
             let actual = match fs::read(&out) {
                 Ok(x) => x,
@@ -442,17 +538,15 @@ fn test_options() -> anyhow::Result<()> {
 
     for (base, name, parse_options) in test_cases {
         let outfile = |suf: &str| Path::join(&temp_dir, format!("{base}.{name}.{suf}"));
-        let orig_file = |suf: &str| format!("test-data/{base}.{suf}");
         let exp_file = |suf: &str| format!("test-data/{base}.{name}.{suf}");
         let savefile = |suf: &str| format!("test-data/actual/{base}.{name}.{suf}");
         let options = GenerateOptions {
-            score: orig_file("stq").into(),
             json: Some(outfile("json")),
             midi: Some(outfile("midi")),
             csound: Some(outfile("csd")),
             text: Some(outfile("txt")),
-            csound_template: None,
             parse_options,
+            ..score_options(base)
         };
         if let Err(e) = generator::run(options) {
             if name.contains("error") {
@@ -487,5 +581,376 @@ fn test_options() -> anyhow::Result<()> {
         }
         panic!("there were errors");
     }
+
+    // The error cases above only check that generation fails. Parsing errors are printed, so
+    // check the diagnostic and the returned error here.
+    let parse_options = Options {
+        part: vec!["potato".to_string()],
+        omit_parts: true,
+        ..Default::default()
+    };
+    let src = fs::read_to_string("test-data/test05-dynamics.stq")?;
+    let diags = pass3::parse3(&src, &parse_options).err().unwrap();
+    assert!(
+        diags
+            .render("test05-dynamics.stq", &src)
+            .contains("part 'potato', specified on the command line, is not known")
+    );
+    let err = expect_error(GenerateOptions {
+        parse_options,
+        ..score_options("test05-dynamics")
+    });
+    assert_eq!(err, "test-data/test05-dynamics.stq contains errors");
+    Ok(())
+}
+
+#[test]
+fn test_midi_options() -> anyhow::Result<()> {
+    let mut errors = Vec::<String>::new();
+    let tmp = tempfile::tempdir()?;
+    let temp_dir = Path::join(tmp.path(), "stq");
+    fs::create_dir_all(&temp_dir)?;
+    let channel_per_note = MidiOptions {
+        midi_mode: MidiMode::ChannelPerNote,
+        ..Default::default()
+    };
+    let test_cases = [
+        (
+            "test05-dynamics",
+            "channel-per-note",
+            channel_per_note.clone(),
+        ),
+        (
+            "test05-dynamics",
+            "single-port",
+            MidiOptions {
+                midi_single_port: true,
+                ..channel_per_note.clone()
+            },
+        ),
+        (
+            "test20-glide-wide",
+            "channel-per-note",
+            channel_per_note.clone(),
+        ),
+        (
+            "test18-glide",
+            "bend-range-12",
+            MidiOptions {
+                midi_bend_range: Some(12),
+                ..channel_per_note.clone()
+            },
+        ),
+        (
+            "test20-glide-wide",
+            "bend-range-32",
+            MidiOptions {
+                midi_bend_range: Some(32),
+                ..channel_per_note.clone()
+            },
+        ),
+        (
+            "test21-lots-of-notes",
+            "channel-per-note",
+            channel_per_note.clone(),
+        ),
+        (
+            "test05-dynamics",
+            "mpe-upper-zone",
+            MidiOptions {
+                mpe_zone: Some(MpeZone::Upper),
+                mpe_channels: Some(8),
+                ..Default::default()
+            },
+        ),
+        (
+            "test05-dynamics",
+            "mpe-bend-range-12",
+            MidiOptions {
+                midi_bend_range: Some(12),
+                ..Default::default()
+            },
+        ),
+        (
+            "test21-lots-of-notes",
+            "mpe-channel-10",
+            MidiOptions {
+                mpe_use_channel_10: true,
+                ..Default::default()
+            },
+        ),
+    ];
+
+    for (base, name, midi_options) in test_cases {
+        let outfile = Path::join(&temp_dir, format!("{base}.{name}.midi"));
+        let exp_file = format!("test-data/{base}.{name}.midi");
+        let savefile = format!("test-data/actual/{base}.{name}.midi");
+        let options = GenerateOptions {
+            midi: Some(outfile.clone()),
+            midi_options,
+            ..score_options(base)
+        };
+        if let Err(e) = generator::run(options) {
+            errors.push(format!("{base}: {name}: {e}"));
+            continue;
+        }
+        check_output(&outfile, &exp_file, &savefile, &mut errors);
+    }
+    check_errors(errors);
+
+    let error_cases = [
+        (
+            "test21-lots-of-notes",
+            MidiOptions {
+                midi_single_port: true,
+                ..channel_per_note.clone()
+            },
+            "more than 15 notes sound at once on MIDI port 0",
+        ),
+        (
+            "test18-glide",
+            MidiOptions {
+                midi_bend_range: Some(12),
+                ..Default::default()
+            },
+            "this note glides over 71.72 semitones, which is too far for a pitch bend range of 12 semitones",
+        ),
+        (
+            "test28-midi-routing",
+            MidiOptions {
+                midi_single_port: true,
+                ..channel_per_note.clone()
+            },
+            "midi_routing for part 'strings' uses port 1, but --midi-single-port puts all parts on port 0",
+        ),
+        (
+            "test28-midi-routing",
+            MidiOptions {
+                mpe_channels: Some(4),
+                ..Default::default()
+            },
+            "midi_routing for part 'strings' puts 3 note numbers on port 1, which only has room for 2",
+        ),
+        (
+            "test01-note-on-off",
+            MidiOptions {
+                mpe_zone: Some(MpeZone::Upper),
+                ..channel_per_note.clone()
+            },
+            "MPE options can't be used with --midi-mode=channel-per-note",
+        ),
+    ];
+    for (base, midi_options, expected) in error_cases {
+        let err = expect_error(GenerateOptions {
+            midi: Some(Path::join(&temp_dir, format!("{base}.error.midi"))),
+            midi_options,
+            ..score_options(base)
+        });
+        assert!(err.contains(expected), "{base}: {err}");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_midi_report() -> anyhow::Result<()> {
+    let mut errors = Vec::<String>::new();
+    let tmp = tempfile::tempdir()?;
+    let temp_dir = Path::join(tmp.path(), "stq");
+    fs::create_dir_all(&temp_dir)?;
+    let test_cases = [
+        ("test05-dynamics", "midi-report.txt", MidiMode::Mpe),
+        ("test05-dynamics", "midi-report.json", MidiMode::Mpe),
+        (
+            "test05-dynamics",
+            "channel-per-note.midi-report.txt",
+            MidiMode::ChannelPerNote,
+        ),
+        ("test28-midi-routing", "midi-report.txt", MidiMode::Mpe),
+    ];
+    for (base, name, midi_mode) in test_cases {
+        let outfile = Path::join(&temp_dir, format!("{base}.{name}"));
+        let exp_file = format!("test-data/{base}.{name}");
+        let savefile = format!("test-data/actual/{base}.{name}");
+        let options = GenerateOptions {
+            midi: Some(Path::join(&temp_dir, format!("{base}.midi"))),
+            midi_options: MidiOptions {
+                midi_mode,
+                midi_report: Some(outfile.clone()),
+                ..Default::default()
+            },
+            ..score_options(base)
+        };
+        if let Err(e) = generator::run(options) {
+            errors.push(format!("{base}: {name}: {e}"));
+            continue;
+        }
+        check_output(&outfile, &exp_file, &savefile, &mut errors);
+    }
+    check_errors(errors);
+    Ok(())
+}
+
+#[test]
+fn test_reaper_meter_late_tempo() -> anyhow::Result<()> {
+    // A timeline read from JSON need not have a tempo event at time 0. Drop the initial tempo from
+    // test07's timeline so the first tempo comes later, and write the project in 3/4.
+    let tmp = tempfile::tempdir()?;
+    let temp_dir = Path::join(tmp.path(), "stq");
+    fs::create_dir_all(&temp_dir)?;
+    let base = "test07-tempo";
+    let name = "late-tempo-3-4.rpp";
+    let mut json: serde_json::Value =
+        serde_json::from_slice(&fs::read(format!("test-data/{base}.json"))?)?;
+    let events = json["events"].as_array_mut().unwrap();
+    let first_tempo = events
+        .iter()
+        .position(|e| e["data"].get("Tempo").is_some())
+        .unwrap();
+    events.remove(first_tempo);
+    let json_file = Path::join(&temp_dir, format!("{base}.json"));
+    fs::write(&json_file, serde_json::to_vec(&json)?)?;
+    let outfile = Path::join(&temp_dir, format!("{base}.{name}"));
+    let exp_file = format!("test-data/{base}.{name}");
+    let savefile = format!("test-data/actual/{base}.{name}");
+    let options = GenerateOptions {
+        score: None,
+        from_json: Some(json_file),
+        reaper: Some(outfile.clone()),
+        meter: Some("3/4".parse()?),
+        ..score_options(base)
+    };
+    generator::run(options)?;
+    let mut errors = Vec::<String>::new();
+    check_output(&outfile, &exp_file, &savefile, &mut errors);
+    check_errors(errors);
+    Ok(())
+}
+
+#[test]
+fn test_from_json() -> anyhow::Result<()> {
+    // Regenerate outputs from each JSON timeline and make sure they match the outputs generated
+    // from the score.
+    let paths = test_helpers::get_stq_files("test-data")?;
+    let mut errors = Vec::<String>::new();
+    let tmp = tempfile::tempdir()?;
+    let temp_dir = Path::join(tmp.path(), "stq");
+    fs::create_dir_all(&temp_dir)?;
+    for p in paths {
+        let base = p.file_name().unwrap().to_string_lossy().replace(".stq", "");
+        let outfile = |suf: &str| Path::join(&temp_dir, format!("{base}.{suf}"));
+        let input_file = |suf: &str| format!("test-data/{base}.{suf}");
+        let savefile = |suf: &str| format!("test-data/actual/{base}.from-json.{suf}");
+        let csound_template =
+            Some(PathBuf::from(input_file("template.csd"))).filter(|p| fs::exists(p).unwrap());
+        let options = GenerateOptions {
+            score: None,
+            from_json: Some(input_file("json").into()),
+            midi: Some(outfile("midi")),
+            csound: Some(outfile("csd")),
+            text: Some(outfile("txt")),
+            csound_template,
+            ..score_options(&base)
+        };
+        if let Err(e) = generator::run(options) {
+            errors.push(format!("{base}: {e}"));
+            continue;
+        }
+        for suf in ["midi", "csd", "txt"] {
+            check_output(&outfile(suf), &input_file(suf), &savefile(suf), &mut errors);
+        }
+    }
+    check_errors(errors);
+    Ok(())
+}
+
+#[test]
+fn test_watch_inputs() -> anyhow::Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let outfile = |suf: &str| Path::join(tmp.path(), format!("test18-glide.{suf}"));
+    let inputs = |options: GenerateOptions| {
+        let mut inputs = Vec::new();
+        let result = generator::run_once(&options, &mut inputs);
+        (result.is_ok(), inputs)
+    };
+    let score = PathBuf::from("test-data/test18-glide.stq");
+    let json = PathBuf::from("test-data/test18-glide.json");
+    // This is the template from the score's csound_template directive.
+    let template = PathBuf::from("test-data/test04-17-edo.template.csd");
+    let other_template = PathBuf::from("test-data/test29-csound-param.template.csd");
+
+    // The Csound template is only watched when Csound output is generated.
+    assert_eq!(
+        inputs(GenerateOptions {
+            csound: Some(outfile("csd")),
+            ..score_options("test18-glide")
+        }),
+        (true, vec![score.clone(), template.clone()])
+    );
+    assert_eq!(
+        inputs(GenerateOptions {
+            csound_sco: Some(outfile("sco")),
+            ..score_options("test18-glide")
+        }),
+        (true, vec![score.clone(), template.clone()])
+    );
+    assert_eq!(
+        inputs(GenerateOptions {
+            midi: Some(outfile("midi")),
+            ..score_options("test18-glide")
+        }),
+        (true, vec![score.clone()])
+    );
+    // --csound-template overrides the directive.
+    assert_eq!(
+        inputs(GenerateOptions {
+            csound: Some(outfile("csd")),
+            csound_template: Some(other_template.clone()),
+            ..score_options("test18-glide")
+        }),
+        (true, vec![score.clone(), other_template.clone()])
+    );
+
+    // With --from-json, the JSON file is watched instead of the score, and the template is
+    // relative to it.
+    assert_eq!(
+        inputs(GenerateOptions {
+            score: None,
+            from_json: Some(json.clone()),
+            csound: Some(outfile("csd")),
+            ..score_options("test18-glide")
+        }),
+        (true, vec![json.clone(), template.clone()])
+    );
+
+    // A score that can't be parsed is watched, but its template isn't known.
+    let bad_score = Path::join(tmp.path(), "bad.stq");
+    fs::write(
+        &bad_score,
+        "syntoniq(version=1)\ncsound_template(path=\"x.csd\")\n[p1.0] 1:potato\n",
+    )?;
+    assert_eq!(
+        inputs(GenerateOptions {
+            score: Some(bad_score.clone()),
+            csound: Some(outfile("csd")),
+            ..score_options("test18-glide")
+        }),
+        (false, vec![bad_score.clone()])
+    );
+
+    // After a failure, files from the previous run are still watched. After a success, only the
+    // files from that run are watched.
+    let previous = vec![score.clone(), template.clone()];
+    assert_eq!(
+        generator::watched_inputs(previous.clone(), vec![score.clone()], false),
+        previous
+    );
+    assert_eq!(
+        generator::watched_inputs(previous.clone(), vec![other_template.clone()], false),
+        [score.clone(), template.clone(), other_template.clone()]
+    );
+    assert_eq!(
+        generator::watched_inputs(previous, vec![score.clone()], true),
+        [score]
+    );
     Ok(())
 }
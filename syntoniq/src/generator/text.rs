@@ -37,13 +37,31 @@ pub(crate) fn generate(timeline: &Timeline, out: impl AsRef<Path>) -> anyhow::Re
                     )?;
                 }
             }
+            TimelineData::CsoundParam(e) => {
+                write!(
+                    f,
+                    "[{part}] csound {name}={value}",
+                    part = e.part,
+                    name = e.name,
+                    value = e.value
+                )?;
+                if let Some(end_value) = &e.end_value {
+                    write!(
+                        f,
+                        " .. {end} at {time}",
+                        end = end_value.item,
+                        time = end_value.time
+                    )?;
+                }
+            }
             TimelineData::Note(e) => {
                 write!(
                     f,
-                    "[{part}.{note}] v={vel}",
+                    "[{part}.{note}] v={vel} r={rel}",
                     part = e.part_note.part,
                     note = e.part_note.note_number,
-                    vel = e.value.velocity
+                    vel = e.value.velocity,
+                    rel = e.value.release_velocity
                 )?;
                 let mut first = true;
                 for p in &e.value.pitches {
@@ -78,6 +96,9 @@ pub(crate) fn generate(timeline: &Timeline, out: impl AsRef<Path>) -> anyhow::Re
             TimelineData::RepeatEnd(e) => {
                 write!(f, "end repeat at {}", e.label)?;
             }
+            TimelineData::BarLine => {
+                write!(f, "bar line")?;
+            }
         }
         if !matches!(data, TimelineData::Note(_)) {
             write!(f, " {span}")?;
@@ -0,0 +1,385 @@
+use crate::generator::tempo::{TempoMap, to_f64};
+use anyhow::bail;
+use num_rational::Ratio;
+use std::collections::{BTreeMap, BTreeSet};
+use std::f64::consts::PI;
+use std::fs;
+use std::path::Path;
+use syntoniq_common::parsing::{Timeline, TimelineData, WavInstrumentSettings, Waveform};
+
+// The WAV renderer is a small synthesizer that lets a score be heard without Csound or a MIDI
+// synthesizer. It is modeled after the instrument in the default Csound template: each part has
+// an amplitude from its dynamics, each note is scaled by its velocity and attenuated by the
+// square root of the number of notes in the part, and the result is shaped by an ADSR envelope
+// and a low-pass filter. The sound for each part is set with the `wav_instrument` directive.
+//
+// Rendering is deterministic: every time is converted to seconds up front, and each sample is
+// computed directly from the score rather than from any state that depends on the environment.
+// This allows WAV output to be compared in tests.
+
+/// Maximum number of harmonics used to build each waveform
+const MAX_HARMONICS: u32 = 16;
+
+/// Amplitude of each part before its first dynamic, matching the Csound template
+const DEFAULT_AMP: f64 = 0.5;
+
+/// A constant or linearly changing value over a period of time in seconds
+#[derive(Clone, Copy)]
+struct Ramp {
+    start: f64,
+    start_value: f64,
+    end: f64,
+    end_value: f64,
+}
+
+impl Ramp {
+    fn value(&self, t: f64) -> f64 {
+        if t >= self.end {
+            self.end_value
+        } else if t <= self.start {
+            self.start_value
+        } else {
+            let frac = (t - self.start) / (self.end - self.start);
+            self.start_value + (self.end_value - self.start_value) * frac
+        }
+    }
+}
+
+/// The amplitude of a part over time
+#[derive(Default)]
+struct Dynamics {
+    ramps: Vec<Ramp>,
+}
+
+impl Dynamics {
+    fn value(&self, t: f64) -> f64 {
+        let idx = self.ramps.partition_point(|r| r.start <= t);
+        if idx == 0 {
+            DEFAULT_AMP
+        } else {
+            self.ramps[idx - 1].value(t)
+        }
+    }
+}
+
+/// A segment of a note's pitch. The pitch changes geometrically for glides.
+struct PitchSegment {
+    start: f64,
+    end: f64,
+    start_freq: f64,
+    end_freq: f64,
+}
+
+struct Note<'s> {
+    part: &'s str,
+    start: f64,
+    end: f64,
+    velocity: f64,
+    pitches: Vec<PitchSegment>,
+}
+
+impl Note<'_> {
+    fn frequency(&self, t: f64, idx: &mut usize) -> f64 {
+        while *idx + 1 < self.pitches.len() && t >= self.pitches[*idx + 1].start {
+            *idx += 1;
+        }
+        let p = &self.pitches[*idx];
+        if t >= p.end || p.end <= p.start {
+            p.end_freq
+        } else if t <= p.start {
+            p.start_freq
+        } else {
+            let frac = (t - p.start) / (p.end - p.start);
+            p.start_freq * (p.end_freq / p.start_freq).powf(frac)
+        }
+    }
+}
+
+/// Return the level of an ADSR envelope at `t` seconds after the note started for a note that
+/// lasts `duration` seconds.
+fn envelope(settings: &WavInstrumentSettings, t: f64, duration: f64) -> f64 {
+    let attack = to_f64(settings.attack);
+    let decay = to_f64(settings.decay);
+    let sustain = to_f64(settings.sustain);
+    let release = to_f64(settings.release);
+    let level = |t: f64| {
+        if t < attack {
+            t / attack
+        } else if t < attack + decay {
+            1.0 - (1.0 - sustain) * (t - attack) / decay
+        } else {
+            sustain
+        }
+    };
+    if t < duration {
+        level(t)
+    } else if t < duration + release {
+        level(duration) * (1.0 - (t - duration) / release)
+    } else {
+        0.0
+    }
+}
+
+/// Return the value of a band-limited waveform at `phase`, measured in cycles, using only
+/// harmonics below `max_freq`.
+fn oscillator(waveform: Waveform, phase: f64, freq: f64, max_freq: f64) -> f64 {
+    let angle = 2.0 * PI * phase;
+    if waveform == Waveform::Sine {
+        return angle.sin();
+    }
+    let mut result = 0.0;
+    for k in 1..=MAX_HARMONICS {
+        let kf = f64::from(k);
+        if freq * kf >= max_freq {
+            break;
+        }
+        let harmonic = (angle * kf).sin();
+        result += match waveform {
+            Waveform::Sine => 0.0,
+            Waveform::Square if k % 2 == 1 => harmonic / kf,
+            Waveform::Triangle if k % 2 == 1 => {
+                let sign = if k % 4 == 1 { 1.0 } else { -1.0 };
+                sign * harmonic / (kf * kf)
+            }
+            Waveform::Sawtooth => {
+                let sign = if k % 2 == 1 { 1.0 } else { -1.0 };
+                sign * harmonic / kf
+            }
+            Waveform::Square | Waveform::Triangle => 0.0,
+        };
+    }
+    match waveform {
+        Waveform::Sine => result,
+        Waveform::Square => result * 4.0 / PI,
+        Waveform::Triangle => result * 8.0 / (PI * PI),
+        Waveform::Sawtooth => result * 2.0 / PI,
+    }
+}
+
+struct WavGenerator<'s> {
+    timeline: &'s Timeline<'s>,
+    sample_rate: u32,
+    notes: Vec<Note<'s>>,
+    dynamics: BTreeMap<&'s str, Dynamics>,
+    /// Number of distinct note numbers in each part, used for polyphonic attenuation
+    note_counts: BTreeMap<&'s str, usize>,
+}
+
+impl<'s> WavGenerator<'s> {
+    fn new(timeline: &'s Timeline<'s>, sample_rate: u32) -> Self {
+        let tempo = TempoMap::new(timeline);
+        let seconds = |r: Ratio<u32>| tempo.seconds(to_f64(r));
+        let mut notes = Vec::new();
+        let mut dynamics: BTreeMap<&str, Dynamics> = BTreeMap::new();
+        let mut note_numbers: BTreeMap<&str, BTreeSet<u32>> = BTreeMap::new();
+        for event in &timeline.events {
+            match &event.data {
+                TimelineData::Dynamic(e) => {
+                    let start = seconds(event.time);
+                    let start_value = f64::from(e.start_level) / 127.0;
+                    let ramp = match &e.end_level {
+                        None => Ramp {
+                            start,
+                            start_value,
+                            end: start,
+                            end_value: start_value,
+                        },
+                        Some(end) => Ramp {
+                            start,
+                            start_value,
+                            end: seconds(end.time),
+                            end_value: f64::from(end.item) / 127.0,
+                        },
+                    };
+                    dynamics.entry(e.part).or_default().ramps.push(ramp);
+                }
+                TimelineData::Note(e) => {
+                    if e.value.velocity == 0 {
+                        continue;
+                    }
+                    let part = e.part_note.part;
+                    note_numbers
+                        .entry(part)
+                        .or_default()
+                        .insert(e.part_note.note_number);
+                    let pitches: Vec<PitchSegment> = e
+                        .value
+                        .pitches
+                        .iter()
+                        .map(|pc| {
+                            let start_freq = pc.start_pitch.as_float();
+                            PitchSegment {
+                                start: seconds(pc.start_time),
+                                end: seconds(pc.end_time),
+                                start_freq,
+                                end_freq: pc
+                                    .end_pitch
+                                    .as_ref()
+                                    .map_or(start_freq, |p| p.as_float()),
+                            }
+                        })
+                        .collect();
+                    let Some(last) = pitches.last() else {
+                        continue;
+                    };
+                    notes.push(Note {
+                        part,
+                        start: seconds(event.time),
+                        end: last.end,
+                        velocity: f64::from(e.value.velocity.min(127)) / 127.0,
+                        pitches,
+                    });
+                }
+                TimelineData::Tempo(_)
+                | TimelineData::CsoundParam(_)
+                | TimelineData::Mark(_)
+                | TimelineData::RepeatStart(_)
+                | TimelineData::RepeatEnd(_)
+                | TimelineData::BarLine => {}
+            }
+        }
+        let note_counts = note_numbers
+            .into_iter()
+            .map(|(part, numbers)| (part, numbers.len()))
+            .collect();
+        Self {
+            timeline,
+            sample_rate,
+            notes,
+            dynamics,
+            note_counts,
+        }
+    }
+
+    fn instrument_for_part(&self, part: &str) -> WavInstrumentSettings {
+        self.timeline
+            .wav_instruments
+            .get(part)
+            .or_else(|| self.timeline.wav_instruments.get(""))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Number of samples needed to play every note through the end of its release
+    fn sample_count(&self) -> f64 {
+        let total = self
+            .notes
+            .iter()
+            .map(|n| n.end + to_f64(self.instrument_for_part(n.part).release))
+            .fold(0.0, f64::max);
+        (total * f64::from(self.sample_rate)).ceil()
+    }
+
+    fn render(&self, sample_count: usize) -> Vec<i16> {
+        let rate = f64::from(self.sample_rate);
+        let nyquist = rate / 2.0;
+        let mut samples = vec![0.0f64; sample_count];
+        let default_dynamics = Dynamics::default();
+        for note in &self.notes {
+            let settings = self.instrument_for_part(note.part);
+            let dynamics = self.dynamics.get(note.part).unwrap_or(&default_dynamics);
+            let note_count = self.note_counts.get(note.part).copied().unwrap_or(1).max(1);
+            let gain = note.velocity / (note_count as f64).sqrt();
+            // One-pole low-pass filter
+            let alpha = 1.0 - (-2.0 * PI * f64::from(settings.cutoff) / rate).exp();
+            let duration = note.end - note.start;
+            let release = to_f64(settings.release);
+            let first = (note.start * rate).ceil() as usize;
+            let last = (((note.end + release) * rate).ceil() as usize).min(samples.len());
+            let mut phase = 0.0;
+            let mut filtered = 0.0;
+            let mut pitch_idx = 0;
+            for (i, sample) in samples.iter_mut().enumerate().take(last).skip(first) {
+                let t = i as f64 / rate;
+                let freq = note.frequency(t, &mut pitch_idx);
+                let raw = oscillator(settings.waveform, phase, freq, nyquist);
+                filtered += alpha * (raw - filtered);
+                let amp = dynamics.value(t) * gain * envelope(&settings, t - note.start, duration);
+                *sample += filtered * amp;
+                phase = (phase + freq / rate).fract();
+            }
+        }
+        // Scale down only if the result would clip.
+        let peak = samples.iter().fold(0.0, |m: f64, x| m.max(x.abs()));
+        let scale = if peak > 1.0 { 1.0 / peak } else { 1.0 };
+        samples
+            .into_iter()
+            .map(|x| (x * scale * f64::from(i16::MAX)).round() as i16)
+            .collect()
+    }
+
+    fn generate(&self) -> anyhow::Result<Vec<u8>> {
+        // The RIFF header stores sizes as 32-bit numbers. Check this before allocating anything
+        // for the samples.
+        let sample_count = self.sample_count();
+        if sample_count * 2.0 > f64::from(u32::MAX - 36) {
+            bail!("the score is too long to write as a WAV file");
+        }
+        let samples = self.render(sample_count as usize);
+        let data_len = (samples.len() * 2) as u32;
+        let mut out = Vec::with_capacity(44 + data_len as usize);
+        out.extend_from_slice(b"RIFF");
+        out.extend_from_slice(&(36 + data_len).to_le_bytes());
+        out.extend_from_slice(b"WAVE");
+        out.extend_from_slice(b"fmt ");
+        out.extend_from_slice(&16u32.to_le_bytes());
+        // PCM, mono
+        out.extend_from_slice(&1u16.to_le_bytes());
+        out.extend_from_slice(&1u16.to_le_bytes());
+        out.extend_from_slice(&self.sample_rate.to_le_bytes());
+        // Bytes per second, bytes per sample frame, and bits per sample
+        out.extend_from_slice(&(self.sample_rate * 2).to_le_bytes());
+        out.extend_from_slice(&2u16.to_le_bytes());
+        out.extend_from_slice(&16u16.to_le_bytes());
+        out.extend_from_slice(b"data");
+        out.extend_from_slice(&data_len.to_le_bytes());
+        for s in samples {
+            out.extend_from_slice(&s.to_le_bytes());
+        }
+        Ok(out)
+    }
+}
+
+pub(crate) fn generate(
+    timeline: &Timeline,
+    sample_rate: u32,
+    out: impl AsRef<Path>,
+) -> anyhow::Result<()> {
+    let wav = WavGenerator::new(timeline, sample_rate).generate()?;
+    fs::write(&out, wav)?;
+    println!("WAV output written to {}", out.as_ref().display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syntoniq_common::parsing;
+
+    #[test]
+    fn test_envelope() {
+        let s = WavInstrumentSettings::default();
+        assert!((envelope(&s, 0.025, 1.0) - 0.5).abs() < 1e-9);
+        assert!((envelope(&s, 0.5, 1.0) - 0.9).abs() < 1e-9);
+        // Half way through the release
+        assert!((envelope(&s, 1.075, 1.0) - 0.45).abs() < 1e-9);
+        assert!(envelope(&s, 1.2, 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_too_long() {
+        // At one beat per minute, this note needs more samples than a RIFF header can describe.
+        let src = "\
+syntoniq(version=1)
+tempo(bpm=1)
+[p1.0] 2000:c
+";
+        let timeline = parsing::timeline("test", src, &Default::default()).unwrap();
+        let generator = WavGenerator::new(&timeline, 44100);
+        assert!(generator.sample_count() > f64::from(u32::MAX));
+        assert_eq!(
+            generator.generate().unwrap_err().to_string(),
+            "the score is too long to write as a WAV file"
+        );
+    }
+}
@@ -0,0 +1,849 @@
+use crate::calc::ScaleDegrees;
+use anyhow::{anyhow, bail};
+use clap::Parser;
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use num_rational::Ratio;
+use num_traits::ToPrimitive;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+use syntoniq_common::parsing;
+
+// MIDI import creates a starting point for a score from a MIDI file, such as an improvisation
+// recorded in a Digital Audio Workstation. It is not intended to round-trip every detail.
+//
+// - Each track with notes becomes a part. If the file has a single track and doesn't use MPE,
+//   each channel becomes a part instead.
+// - Pitches are computed from the note number and the channel's pitch bend when the note starts.
+//   Pitch bend sensitivity is tracked from registered parameter messages, and MPE configuration
+//   sets the member channels to the MPE default of 48 semitones. Pitch bend changes while a note
+//   is sounding are not imported.
+// - Each pitch is written as the nearest note of the chosen scale, assuming the default base
+//   pitch. Deviations are reported.
+// - Note starts and ends are rounded to a grid. Overlapping notes within a part are assigned to
+//   separate note lines, and notes are split with ties at bar checks and score block boundaries.
+// - Dynamics come from channel volume (CC 7) when a part has any. Otherwise, they come from note
+//   velocity. When channel volume is used, high velocities become accents.
+
+/// Semitones of pitch bend sensitivity for MPE member channels, given by the MPE specification
+const MPE_BEND_RANGE: f64 = 48.0;
+
+/// Default pitch bend sensitivity in semitones
+const DEFAULT_BEND_RANGE: f64 = 2.0;
+
+/// MIDI's default tempo when a file has no tempo events
+const DEFAULT_MICROS_PER_BEAT: u32 = 500_000;
+
+/// When dynamics come from velocity, a new dynamic is written only when the velocity changes by
+/// at least this much.
+const VELOCITY_THRESHOLD: u8 = 8;
+
+/// Dynamics more than this many beats apart are never combined into a crescendo or diminuendo
+const MAX_RAMP_STEP: u32 = 1;
+
+#[derive(Parser)]
+pub struct ImportMidiOptions {
+    /// MIDI file to import
+    input: PathBuf,
+    /// Scale whose note names are used for the imported pitches: a built-in scale, a scale
+    /// defined in the score given with `--score`, or N-EDO for N equal divisions of the octave
+    #[arg(long)]
+    scale: String,
+    /// Score from which to take scale definitions
+    #[arg(long)]
+    score: Option<PathBuf>,
+    /// File to write the score to; if omitted, the score is written to standard output and the
+    /// report of pitch deviations to standard error
+    #[arg(long)]
+    output: Option<PathBuf>,
+    /// Grid, in beats, to which the starts and ends of notes are rounded
+    #[arg(long, default_value = "1/4")]
+    quantize: Ratio<u32>,
+    /// Number of beats between bar checks
+    #[arg(long, default_value = "4")]
+    bar_beats: Ratio<u32>,
+    /// Number of bars in each score block
+    #[arg(long, default_value_t = 4)]
+    block_bars: u32,
+}
+
+/// The scale used for note names, along with any directive needed to define it in the output
+struct ImportScale {
+    degrees: ScaleDegrees,
+    definition: Option<String>,
+}
+
+impl ImportScale {
+    fn load(score: Option<&PathBuf>, name: &str) -> anyhow::Result<Self> {
+        let builtin = parsing::timeline(
+            "built-in scales",
+            "syntoniq(version=1)\n",
+            &parsing::Options::default(),
+        )?;
+        if let Some(scale) = builtin.scales.get(name) {
+            return Ok(Self {
+                degrees: ScaleDegrees::from_scale(scale),
+                definition: None,
+            });
+        }
+        if let Some(path) = score {
+            let src = fs::read_to_string(path)?;
+            let timeline =
+                parsing::timeline(&path.display().to_string(), &src, &Default::default())?;
+            if let Some(scale) = timeline.scales.get(name) {
+                if scale.pitches.is_empty() {
+                    bail!("scale {name} has no notes");
+                }
+                // Define the scale in the output so that the imported score stands alone.
+                let mut definition = format!("define_scale(scale=\"{name}\"");
+                if scale.definition.cycle != Ratio::from_integer(2) {
+                    let _ = write!(definition, " cycle_ratio={}", scale.definition.cycle);
+                }
+                definition.push_str(") <<\n");
+                for (pitch, note) in scale.pitches.iter().zip(&scale.primary_names) {
+                    let _ = writeln!(definition, "{pitch} {note}");
+                }
+                definition.push_str(">>");
+                return Ok(Self {
+                    degrees: ScaleDegrees::from_scale(scale),
+                    definition: Some(definition),
+                });
+            }
+        }
+        let degrees = ScaleDegrees::inline_edo(name)
+            .ok_or_else(|| anyhow!("{name} is not a known scale or N-EDO"))?;
+        let definition = format!(
+            "define_generated_scale(scale=\"{name}\" divisions={})",
+            degrees.cents.len()
+        );
+        Ok(Self {
+            degrees,
+            definition: Some(definition),
+        })
+    }
+
+    /// Return the note name nearest to a pitch given in cents relative to the base pitch, along
+    /// with how far the pitch is above the note.
+    fn note(&self, cents: f64) -> (String, f64) {
+        let d = &self.degrees;
+        let cycle = (cents / d.cycle_cents).floor();
+        let reduced = cents - cycle * d.cycle_cents;
+        let (idx, delta) = d.nearest(reduced);
+        // The nearest degree may be in an adjacent cycle.
+        let adjust = ((reduced + delta - d.cents[idx]) / d.cycle_cents).round();
+        let cycle = (cycle + adjust) as i32;
+        let marks = match cycle {
+            0 => String::new(),
+            1 => "'".to_string(),
+            -1 => ",".to_string(),
+            n if n > 0 => format!("'{n}"),
+            n => format!(",{}", -n),
+        };
+        (format!("{}{marks}", d.notes[idx]), -delta)
+    }
+}
+
+#[derive(Default)]
+struct ChannelState {
+    /// Pitch bend from -8192 to 8191
+    bend: i16,
+    bend_range: Option<f64>,
+    /// Currently selected registered parameter number as (MSB, LSB)
+    rpn: Option<(u8, u8)>,
+}
+
+/// A note as read from the file, with times in ticks
+struct RawNote {
+    part: usize,
+    start: u32,
+    end: u32,
+    /// Pitch in semitones using MIDI note numbers
+    semitones: f64,
+    velocity: u8,
+}
+
+/// A note in beats after quantizing
+struct Note {
+    start: Ratio<u32>,
+    end: Ratio<u32>,
+    name: String,
+    accent: &'static str,
+}
+
+struct Part {
+    name: String,
+    voices: Vec<Vec<Note>>,
+    /// Dynamics in beats, marked at the start of a crescendo or diminuendo
+    dynamics: BTreeMap<Ratio<u32>, (u8, Option<bool>)>,
+}
+
+/// How a part is identified in the file: by track, and by channel if channels are parts
+type PartKey = (usize, Option<u8>);
+
+fn format_ratio(r: Ratio<u32>) -> String {
+    if r.is_integer() {
+        r.numer().to_string()
+    } else {
+        format!("{}/{}", r.numer(), r.denom())
+    }
+}
+
+/// Replace runs of at least three evenly spaced values that steadily increase or decrease, such as
+/// those written for a crescendo or a tempo change, with a gradual change from the first to the
+/// last. The first value of each run is marked with whether the values increase.
+fn ramps<V: Copy + PartialOrd>(
+    levels: &BTreeMap<Ratio<u32>, V>,
+) -> BTreeMap<Ratio<u32>, (V, Option<bool>)> {
+    let points: Vec<(Ratio<u32>, V)> = levels.iter().map(|(&t, &v)| (t, v)).collect();
+    let mut result = BTreeMap::new();
+    let mut i = 0;
+    while i < points.len() {
+        let mut j = i;
+        if let Some(&(next_t, next_v)) = points.get(i + 1) {
+            let louder = next_v > points[i].1;
+            let step = next_t - points[i].0;
+            while let Some(&(t, v)) = points.get(j + 1) {
+                let (prev_t, prev_v) = points[j];
+                if (v > prev_v) != louder
+                    || t - prev_t != step
+                    || step > Ratio::from_integer(MAX_RAMP_STEP)
+                {
+                    break;
+                }
+                j += 1;
+            }
+            if j - i >= 2 {
+                result.insert(points[i].0, (points[i].1, Some(louder)));
+                // The end of this run may start another.
+                i = j;
+                continue;
+            }
+        }
+        result.insert(points[i].0, (points[i].1, None));
+        i += 1;
+    }
+    result
+}
+
+fn is_identifier(s: &str) -> bool {
+    s.chars().next().is_some_and(char::is_alphabetic)
+        && s.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+struct Importer {
+    options: ImportMidiOptions,
+    scale: ImportScale,
+    ticks_per_beat: u32,
+    raw_notes: Vec<RawNote>,
+    part_names: Vec<String>,
+    /// Channel volume changes by part as (tick, value)
+    volumes: HashMap<usize, Vec<(u32, u8)>>,
+    /// Tempo changes as (tick, microseconds per beat)
+    tempos: Vec<(u32, u32)>,
+    /// Distinct imported pitches in cents with note names, deviations, and counts
+    pitches: BTreeMap<i64, (String, f64, usize)>,
+}
+
+impl Importer {
+    fn new(options: ImportMidiOptions, smf: &Smf) -> anyhow::Result<Self> {
+        let Timing::Metrical(ticks_per_beat) = smf.header.timing else {
+            bail!("only MIDI files with metrical timing can be imported");
+        };
+        let scale = ImportScale::load(options.score.as_ref(), &options.scale)?;
+        let mut importer = Self {
+            options,
+            scale,
+            ticks_per_beat: u32::from(ticks_per_beat.as_int()),
+            raw_notes: Default::default(),
+            part_names: Default::default(),
+            volumes: Default::default(),
+            tempos: Default::default(),
+            pitches: Default::default(),
+        };
+        importer.read(smf);
+        Ok(importer)
+    }
+
+    fn read(&mut self, smf: &Smf) {
+        // Merge the tracks into a single list of events in time order, keeping the track number
+        // and the track's port.
+        let mut events = Vec::new();
+        for (track_idx, track) in smf.tracks.iter().enumerate() {
+            let mut tick = 0u32;
+            let mut port = 0u8;
+            for event in track {
+                tick += event.delta.as_int();
+                if let TrackEventKind::Meta(MetaMessage::MidiPort(p)) = event.kind {
+                    port = p.as_int();
+                }
+                events.push((tick, track_idx, port, event.kind));
+            }
+        }
+        events.sort_by_key(|&(tick, track, _, _)| (tick, track));
+        // Decide whether channels are parts. MPE configuration is registered parameter 6.
+        let mpe = events.windows(2).any(|w| {
+            matches!(
+                (w[0].3, w[1].3),
+                (
+                    TrackEventKind::Midi {
+                        message: MidiMessage::Controller { controller: c1, value: v1 },
+                        ..
+                    },
+                    TrackEventKind::Midi {
+                        message: MidiMessage::Controller { controller: c2, value: v2 },
+                        ..
+                    },
+                ) if c1 == 101 && v1 == 0 && c2 == 100 && v2 == 6
+            )
+        });
+        let channel_parts = smf.tracks.len() == 1 && !mpe;
+        let mut track_names: HashMap<usize, String> = HashMap::new();
+        let mut channels: HashMap<(u8, u8), ChannelState> = HashMap::new();
+        let mut parts: HashMap<PartKey, usize> = HashMap::new();
+        let mut volumes: HashMap<PartKey, Vec<(u32, u8)>> = HashMap::new();
+        // Sounding notes by (port, channel, key), giving the index in raw_notes
+        let mut sounding: HashMap<(u8, u8, u8), usize> = HashMap::new();
+        let mut last_tick = 0;
+        for (tick, track, port, kind) in events {
+            last_tick = tick;
+            match kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(t)) => {
+                    self.tempos.push((tick, t.as_int()));
+                }
+                TrackEventKind::Meta(MetaMessage::TrackName(name)) => {
+                    if let Ok(name) = str::from_utf8(name) {
+                        track_names.insert(track, name.trim().to_string());
+                    }
+                }
+                TrackEventKind::Midi { channel, message } => {
+                    let channel = channel.as_int();
+                    let key: PartKey = (track, channel_parts.then_some(channel));
+                    match message {
+                        MidiMessage::NoteOn { key: note, vel } if vel > 0 => {
+                            if let Some(idx) = sounding.remove(&(port, channel, note.as_int())) {
+                                self.raw_notes[idx].end = tick;
+                            }
+                            let next_part = parts.len();
+                            let part = *parts.entry(key).or_insert(next_part);
+                            let state = channels.entry((port, channel)).or_default();
+                            let range = state.bend_range.unwrap_or(DEFAULT_BEND_RANGE);
+                            let semitones =
+                                f64::from(note.as_int()) + f64::from(state.bend) / 8192.0 * range;
+                            sounding.insert((port, channel, note.as_int()), self.raw_notes.len());
+                            self.raw_notes.push(RawNote {
+                                part,
+                                start: tick,
+                                end: tick,
+                                semitones,
+                                velocity: vel.as_int(),
+                            });
+                        }
+                        MidiMessage::NoteOn { key: note, .. }
+                        | MidiMessage::NoteOff { key: note, .. } => {
+                            if let Some(idx) = sounding.remove(&(port, channel, note.as_int())) {
+                                self.raw_notes[idx].end = tick;
+                            }
+                        }
+                        MidiMessage::PitchBend { bend } => {
+                            channels.entry((port, channel)).or_default().bend = bend.as_int();
+                        }
+                        MidiMessage::Controller { controller, value } => {
+                            let value = value.as_int();
+                            Self::controller(
+                                &mut channels,
+                                port,
+                                channel,
+                                controller.as_int(),
+                                value,
+                            );
+                            if controller == 7 {
+                                volumes.entry(key).or_default().push((tick, value));
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+        for idx in sounding.into_values() {
+            self.raw_notes[idx].end = last_tick;
+        }
+        // Name parts in order of their first notes.
+        let mut keys: Vec<(PartKey, usize)> = parts.into_iter().collect();
+        keys.sort_by_key(|&(_, part)| part);
+        for (key, part) in keys {
+            let name = track_names
+                .get(&key.0)
+                .filter(|name| {
+                    key.1.is_none() && is_identifier(name) && !self.part_names.contains(name)
+                })
+                .cloned()
+                .unwrap_or_else(|| format!("p{}", part + 1));
+            self.part_names.push(name);
+            if let Some(v) = volumes.remove(&key) {
+                self.volumes.insert(part, v);
+            }
+        }
+    }
+
+    fn controller(
+        channels: &mut HashMap<(u8, u8), ChannelState>,
+        port: u8,
+        channel: u8,
+        controller: u8,
+        value: u8,
+    ) {
+        let state = channels.entry((port, channel)).or_default();
+        match controller {
+            101 => state.rpn = Some((value, state.rpn.map_or(127, |x| x.1))),
+            100 => state.rpn = Some((state.rpn.map_or(127, |x| x.0), value)),
+            6 => match state.rpn {
+                Some((0, 0)) => {
+                    state.bend_range = Some(f64::from(value));
+                }
+                Some((0, 6)) if channel == 0 || channel == 15 => {
+                    // MPE configuration: the zone's member channels use the MPE default pitch
+                    // bend sensitivity.
+                    let members: Vec<u8> = if channel == 0 {
+                        (1..=value.min(15)).collect()
+                    } else {
+                        (15 - value.min(15)..15).collect()
+                    };
+                    for ch in members {
+                        channels.entry((port, ch)).or_default().bend_range = Some(MPE_BEND_RANGE);
+                    }
+                }
+                _ => {}
+            },
+            38 if state.rpn == Some((0, 0)) => {
+                // Data entry LSB for pitch bend sensitivity is in cents.
+                let semitones = state.bend_range.unwrap_or_default().floor();
+                state.bend_range = Some(semitones + f64::from(value) / 100.0);
+            }
+            _ => {}
+        }
+    }
+
+    fn beats(&self, tick: u32) -> Ratio<u32> {
+        Ratio::new(tick, self.ticks_per_beat)
+    }
+
+    fn quantize(&self, tick: u32) -> Ratio<u32> {
+        let grid = self.options.quantize;
+        (self.beats(tick) / grid).round() * grid
+    }
+
+    fn parts(&mut self) -> Vec<Part> {
+        let mut parts: Vec<Part> = self
+            .part_names
+            .iter()
+            .map(|name| Part {
+                name: name.clone(),
+                voices: Vec::new(),
+                dynamics: BTreeMap::new(),
+            })
+            .collect();
+        let mut raw_notes = std::mem::take(&mut self.raw_notes);
+        raw_notes.sort_by(|a, b| {
+            (a.start, a.semitones)
+                .partial_cmp(&(b.start, b.semitones))
+                .unwrap()
+        });
+        let mut onset_velocities: BTreeMap<(usize, Ratio<u32>), u8> = BTreeMap::new();
+        for raw in &raw_notes {
+            let start = self.quantize(raw.start);
+            let end = self.quantize(raw.end).max(start + self.options.quantize);
+            let cents = (raw.semitones - 60.0) * 100.0;
+            let (name, deviation) = self.scale.note(cents);
+            let entry = self
+                .pitches
+                .entry((cents * 10.0).round() as i64)
+                .or_insert((name.clone(), deviation, 0));
+            entry.2 += 1;
+            let has_volume = self.volumes.contains_key(&raw.part);
+            let accent = match raw.velocity {
+                _ if !has_volume => "",
+                108.. => "^",
+                96.. => ">",
+                _ => "",
+            };
+            let v = onset_velocities.entry((raw.part, start)).or_default();
+            *v = (*v).max(raw.velocity);
+            let part = &mut parts[raw.part];
+            let note = Note {
+                start,
+                end,
+                name,
+                accent,
+            };
+            match part
+                .voices
+                .iter_mut()
+                .find(|v| v.last().is_none_or(|n| n.end <= start))
+            {
+                Some(voice) => voice.push(note),
+                None => part.voices.push(vec![note]),
+            }
+        }
+        for (idx, part) in parts.iter_mut().enumerate() {
+            let mut levels = BTreeMap::new();
+            match self.volumes.get(&idx) {
+                Some(volumes) => {
+                    for &(tick, value) in volumes {
+                        levels.insert(self.quantize(tick), value);
+                    }
+                }
+                None => {
+                    let mut current: Option<u8> = None;
+                    for (&(_, time), &velocity) in onset_velocities
+                        .range((idx, Ratio::from_integer(0))..(idx + 1, Ratio::from_integer(0)))
+                    {
+                        if current.is_none_or(|c| c.abs_diff(velocity) >= VELOCITY_THRESHOLD) {
+                            levels.insert(time, velocity);
+                            current = Some(velocity);
+                        }
+                    }
+                }
+            }
+            // Drop dynamics that don't change the level.
+            let mut current = None;
+            levels.retain(|_, level| {
+                let keep = current != Some(*level);
+                current = Some(*level);
+                keep
+            });
+            part.dynamics = ramps(&levels);
+        }
+        parts
+    }
+
+    fn tempos(&self) -> BTreeMap<Ratio<u32>, (Ratio<u32>, Option<bool>)> {
+        let mut result = BTreeMap::new();
+        let mut tempos = self.tempos.clone();
+        if tempos.first().is_none_or(|&(tick, _)| tick > 0) {
+            tempos.insert(0, (0, DEFAULT_MICROS_PER_BEAT));
+        }
+        for (tick, micros) in tempos {
+            let bpm = 60_000_000.0 / f64::from(micros.max(1));
+            result.insert(
+                self.quantize(tick),
+                Ratio::new((bpm * 100.0).round() as u32, 100),
+            );
+        }
+        let mut current = None;
+        result.retain(|_, bpm| {
+            let keep = current != Some(*bpm);
+            current = Some(*bpm);
+            keep
+        });
+        ramps(&result)
+    }
+
+    /// Write a note line for the notes of a voice that fall in the block starting at `start`
+    /// with `bars` bars.
+    fn note_line(&self, voice: &[Note], start: Ratio<u32>, bars: u32) -> String {
+        let bar = self.options.bar_beats;
+        let end = start + bar * bars;
+        // Each item is (start, end, note, tie) where note is None for a rest.
+        let mut items = Vec::new();
+        let mut cursor = start;
+        for note in voice.iter().filter(|n| n.end > start && n.start < end) {
+            if note.start > cursor {
+                items.push((cursor, note.start, None, false));
+            }
+            let piece_start = note.start.max(start);
+            let piece_end = note.end.min(end);
+            items.push((piece_start, piece_end, Some(note), note.end > end));
+            cursor = piece_end;
+        }
+        if cursor < end {
+            items.push((cursor, end, None, false));
+        }
+        let mut line = String::new();
+        let mut last_duration = None;
+        let mut next_bar = start + bar;
+        for (item_start, item_end, note, tie) in items {
+            let mut piece_start = item_start;
+            while piece_start < item_end {
+                if piece_start == next_bar {
+                    line.push_str(" |");
+                    next_bar += bar;
+                }
+                let piece_end = item_end.min(next_bar);
+                let duration = piece_end - piece_start;
+                line.push(' ');
+                if last_duration != Some(duration) {
+                    let _ = write!(line, "{}:", format_ratio(duration));
+                    last_duration = Some(duration);
+                }
+                match note {
+                    None => line.push('~'),
+                    Some(note) => {
+                        line.push_str(&note.name);
+                        // Only the start of the note is accented, and all but the last piece
+                        // are tied to the next.
+                        let mut modifiers = "";
+                        if piece_start == note.start {
+                            modifiers = note.accent;
+                        }
+                        let tied = tie || piece_end < item_end;
+                        if !modifiers.is_empty() || tied {
+                            let _ = write!(line, ":{modifiers}{}", if tied { "~" } else { "" });
+                        }
+                    }
+                }
+                piece_start = piece_end;
+            }
+        }
+        line
+    }
+
+    fn dynamic_line(&self, part: &Part, start: Ratio<u32>, bars: u32) -> Option<String> {
+        let bar = self.options.bar_beats;
+        let end = start + bar * bars;
+        let mut events = part.dynamics.range(start..end).peekable();
+        events.peek()?;
+        let mut line = String::new();
+        for b in 0..bars {
+            let bar_start = start + bar * b;
+            if b > 0 {
+                line.push_str(" |");
+            }
+            while let Some((&time, &(level, change))) =
+                events.next_if(|&(&t, _)| t < bar_start + bar)
+            {
+                let change = match change {
+                    Some(true) => "<",
+                    Some(false) => ">",
+                    None => "",
+                };
+                let _ = write!(line, " {level}@{}{change}", format_ratio(time - bar_start));
+            }
+        }
+        Some(line)
+    }
+
+    fn generate(&mut self) -> String {
+        let parts = self.parts();
+        let tempos = self.tempos();
+        let bar = self.options.bar_beats;
+        let end = parts
+            .iter()
+            .flat_map(|p| p.voices.iter().flatten())
+            .map(|n| n.end)
+            .max()
+            .unwrap_or_default();
+        let total_bars = ((end / bar).ceil().to_integer()).max(1);
+        let mut out = String::new();
+        out.push_str("syntoniq(version=1)\n\n");
+        let _ = writeln!(
+            out,
+            "; Imported from {} with syntoniq import-midi",
+            self.options.input.display()
+        );
+        if let Some(definition) = &self.scale.definition {
+            let _ = writeln!(out, "{definition}");
+        }
+        let _ = writeln!(out, "use_scale(scale=\"{}\")", self.options.scale);
+        let mut block_start_bar = 0;
+        while block_start_bar < total_bars {
+            let bars = self.options.block_bars.min(total_bars - block_start_bar);
+            let start = bar * block_start_bar;
+            let end = start + bar * bars;
+            out.push('\n');
+            for (&time, &(bpm, change)) in tempos.range(start..end) {
+                // The end of a gradual change is written with its start.
+                if tempos
+                    .range(..time)
+                    .next_back()
+                    .is_some_and(|(_, (_, prev))| prev.is_some())
+                    && change.is_none()
+                {
+                    continue;
+                }
+                let mut directive = format!("tempo(bpm={}", format_ratio(bpm));
+                if change.is_some()
+                    && let Some((&end_time, &(end_bpm, _))) = tempos.range(time..).nth(1)
+                {
+                    let _ = write!(
+                        directive,
+                        " end_bpm={} duration={}",
+                        format_ratio(end_bpm),
+                        format_ratio(end_time - time)
+                    );
+                }
+                if time > start {
+                    let _ = write!(directive, " start_time={}", format_ratio(time - start));
+                }
+                let _ = writeln!(out, "{directive})");
+            }
+            let mut lines = Vec::new();
+            for part in &parts {
+                let mut part_lines = Vec::new();
+                for (v, voice) in part.voices.iter().enumerate() {
+                    if voice.iter().any(|n| n.end > start && n.start < end) {
+                        let line = self.note_line(voice, start, bars);
+                        part_lines.push(format!("[{}.{v}]{line}", part.name));
+                    }
+                }
+                if let Some(line) = self.dynamic_line(part, start, bars) {
+                    if part_lines.is_empty() {
+                        // Dynamics need a note line in the same score block.
+                        let line = self.note_line(&[], start, bars);
+                        part_lines.push(format!("[{}.0]{line}", part.name));
+                    }
+                    part_lines.push(format!("[{}]{line}", part.name));
+                }
+                lines.append(&mut part_lines);
+            }
+            if lines.is_empty() {
+                // Keep time moving through silence.
+                let name = parts.first().map_or("p1", |p| p.name.as_str());
+                lines.push(format!("[{name}.0]{}", self.note_line(&[], start, bars)));
+            }
+            for line in lines {
+                let _ = writeln!(out, "{line}");
+            }
+            block_start_bar += bars;
+        }
+        out
+    }
+
+    fn report(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{} notes imported into scale {}",
+            self.pitches.values().map(|x| x.2).sum::<usize>(),
+            self.options.scale
+        );
+        let _ = writeln!(
+            out,
+            "{:>10}  {:<8}  {:>9}  {:>5}",
+            "cents", "note", "deviation", "count"
+        );
+        for (&cents, (name, deviation, count)) in &self.pitches {
+            let cents = cents.to_f64().unwrap() / 10.0;
+            // Avoid showing -0.0 for exact matches.
+            let deviation = if deviation.abs() < 0.05 {
+                0.0
+            } else {
+                *deviation
+            };
+            let _ = writeln!(
+                out,
+                "{cents:>10.1}  {name:<8}  {deviation:>+8.1}¢  {count:>5}"
+            );
+        }
+        out
+    }
+}
+
+fn import(options: ImportMidiOptions) -> anyhow::Result<(String, String)> {
+    if options.quantize == Ratio::from_integer(0) {
+        bail!("--quantize must be greater than 0");
+    }
+    if options.bar_beats == Ratio::from_integer(0) {
+        bail!("--bar-beats must be greater than 0");
+    }
+    if options.block_bars == 0 {
+        bail!("--block-bars must be greater than 0");
+    }
+    let data = fs::read(&options.input)?;
+    let smf = Smf::parse(&data)?;
+    let mut importer = Importer::new(options, &smf)?;
+    let score = importer.generate();
+    Ok((score, importer.report()))
+}
+
+pub fn run(options: ImportMidiOptions) -> anyhow::Result<()> {
+    let output = options.output.clone();
+    let (score, report) = import(options)?;
+    match output {
+        Some(path) => {
+            fs::write(&path, score)?;
+            print!("{report}");
+            println!("Score written to {}", path.display());
+        }
+        None => {
+            print!("{score}");
+            eprint!("{report}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_ramps() {
+        let levels: BTreeMap<Ratio<u32>, u8> =
+            [(0, 32), (1, 48), (2, 64), (3, 96), (4, 64), (6, 64)]
+                .into_iter()
+                .map(|(t, v)| (Ratio::from_integer(t), v))
+                .collect();
+        let r: Vec<(u32, u8, Option<bool>)> = ramps(&levels)
+            .into_iter()
+            .map(|(t, (v, c))| (t.to_integer(), v, c))
+            .collect();
+        assert_eq!(
+            r,
+            [
+                (0, 32, Some(true)),
+                (3, 96, None),
+                (4, 64, None),
+                (6, 64, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_import() -> anyhow::Result<()> {
+        // Import MIDI files created from test scores and compare with the expected score and
+        // report in test-data/import. Mismatches are saved in test-data/actual/import. Every
+        // imported score must be valid.
+        let cases = [
+            ("test05-dynamics", "JI1", true),
+            ("test07-tempo", "31-EDO", false),
+            ("test26-musicxml", "19-EDO", false),
+        ];
+        let mut errors = Vec::new();
+        for (base, scale, use_score) in cases {
+            let options = ImportMidiOptions {
+                input: format!("test-data/{base}.midi").into(),
+                scale: scale.to_string(),
+                score: use_score.then(|| format!("test-data/{base}.stq").into()),
+                output: None,
+                quantize: Ratio::new(1, 4),
+                bar_beats: Ratio::from_integer(4),
+                block_bars: 4,
+            };
+            let (score, report) = import(options)?;
+            if let Err(e) = parsing::timeline(base, &score, &Default::default()) {
+                errors.push(format!("{base}: imported score is invalid: {e}"));
+            }
+            for (suf, actual) in [("stq", score), ("txt", report)] {
+                let exp_file = format!("test-data/import/{base}.{suf}");
+                let exp = fs::read_to_string(&exp_file).unwrap_or_default();
+                if actual != exp {
+                    let save = format!("test-data/actual/import/{base}.{suf}");
+                    fs::create_dir_all(Path::new(&save).parent().unwrap())?;
+                    fs::write(save, actual)?;
+                    errors.push(format!("{base}: import {suf} FAILED; saved in actual"));
+                }
+            }
+        }
+        if !errors.is_empty() {
+            for e in errors {
+                eprintln!("ERROR: {e}");
+            }
+            panic!("there were errors");
+        }
+        Ok(())
+    }
+}
@@ -1,2 +1,5 @@
 pub mod calc;
 pub mod generator;
+pub mod import_midi;
+#[cfg(feature = "play")]
+pub mod play;
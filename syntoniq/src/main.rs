@@ -4,9 +4,12 @@ use clap_complete::Shell;
 use log::LevelFilter;
 use std::{env, process};
 use syntoniq::generator::GenerateOptions;
-use syntoniq::{calc, generator};
+use syntoniq::import_midi::ImportMidiOptions;
+#[cfg(feature = "play")]
+use syntoniq::play::{self, PlayOptions};
+use syntoniq::{calc, generator, import_midi};
 use syntoniq_common::parsing;
-use syntoniq_common::parsing::score;
+use syntoniq_common::parsing::{score, timeline_json};
 
 /// Logging is controlled with RUST_LOG; see docs for the env_logger crate.
 /// If RUST_LOG is not set, the log level defaults to Info.
@@ -25,6 +28,13 @@ enum Commands {
     /// Generate Csound and/or MIDI output. If no output is specified, this just parses the score
     /// and reports errors, if any.
     Generate(GenerateOptions),
+    /// Play a score in real time through a virtual MIDI port or the keyboard's Csound instrument,
+    /// with commands for pausing and jumping to marks
+    #[cfg(feature = "play")]
+    Play(PlayOptions),
+    /// Create a score from a MIDI file, such as a recorded improvisation. Pitches are written as
+    /// the nearest notes of a scale, and the deviations are reported.
+    ImportMidi(ImportMidiOptions),
     /// Show built-in documentation
     Doc,
     /// Perform various calculations about pitches and scales; see also calc --help and the
@@ -32,6 +42,9 @@ enum Commands {
     Calc {
         #[command(subcommand)]
         command: calc::Commands,
+        /// Output format
+        #[arg(long, global = true, value_enum, default_value_t)]
+        format: calc::OutputFormat,
     },
     /// Generate shell completion
     Completion {
@@ -39,9 +52,15 @@ enum Commands {
         shell: Shell,
     },
     /// Write built-in Csound template to standard output
-    CsoundTemplate,
+    CsoundTemplate {
+        /// Write only the orchestra, for use with `generate --csound-orc` or `--csound-sco`
+        #[arg(long)]
+        orc: bool,
+    },
     /// Write built-in scales in syntoniq format to standard output
     BuiltInScales,
+    /// Write the JSON schema for timelines written by `generate --json` to standard output
+    TimelineSchema,
 }
 
 fn run() -> anyhow::Result<()> {
@@ -58,17 +77,28 @@ fn run() -> anyhow::Result<()> {
             syntoniq_common::cli_completions(shell, &mut cmd);
             Ok(())
         }
-        Commands::CsoundTemplate => {
-            print!("{}", generator::CSOUND_TEMPLATE);
+        Commands::CsoundTemplate { orc } => {
+            if orc {
+                print!("{}", generator::csound_orchestra()?);
+            } else {
+                print!("{}", generator::CSOUND_TEMPLATE);
+            }
             Ok(())
         }
         Commands::BuiltInScales => {
             print!("{}", score::BUILTIN_SCALES);
             Ok(())
         }
+        Commands::TimelineSchema => {
+            println!("{}", timeline_json::schema());
+            Ok(())
+        }
         Commands::Generate(options) => generator::run(options),
+        #[cfg(feature = "play")]
+        Commands::Play(options) => play::run(options),
+        Commands::ImportMidi(options) => import_midi::run(options),
         Commands::Doc => parsing::show_help(),
-        Commands::Calc { command } => calc::run(command),
+        Commands::Calc { command, format } => calc::run(command, format),
     }
 }
 
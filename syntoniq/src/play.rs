@@ -0,0 +1,422 @@
+use crate::generator::tempo::{TempoMap, to_f64};
+use anyhow::bail;
+use clap::Parser;
+use num_rational::Ratio;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{fs, io, thread};
+use syntoniq_common::parsing;
+use syntoniq_common::parsing::{DynamicEvent, Timeline, TimelineData};
+use syntoniq_common::pitch::Pitch;
+use syntoniq_kbd::engine;
+use syntoniq_kbd::engine::SoundType;
+use syntoniq_kbd::events;
+use syntoniq_kbd::events::{Event, Events, PlayNoteEvent};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+// Live playback sends the score's notes to the keyboard's sound engine in real time, so a score
+// can be auditioned without writing a file and opening it in another program. With `--midi`,
+// notes go to the keyboard's virtual MIDI port using MPE. Otherwise, they go to the keyboard's
+// embedded Csound instrument.
+//
+// - Each pitch of a note is played from its start time to its end time. The sound engine can't
+//   bend a sounding note, so glides are played at their starting pitch, and a warning is given.
+// - The keyboard's sound engine plays every note at the same volume, so dynamics are applied by
+//   scaling each note's velocity by its part's level when the note starts. This only affects
+//   MIDI output.
+// - Playback is controlled by commands typed at the terminal. Pausing or jumping turns off any
+//   notes that are sounding, and notes that started before the point where playback resumes are
+//   not played.
+
+pub const HELP: &str = r"** Commands **
+?               -- show this help and the score's marks
+Enter           -- start, pause, or resume playback
+mark            -- play from the first occurrence of the given mark
+<               -- play from the beginning
+q               -- stop and exit
+Exit with CTRL-C or CTRL-D.
+";
+
+/// Time to let the sound engine handle note-off events before shutting it down
+const SHUTDOWN_DELAY: Duration = Duration::from_millis(200);
+
+#[derive(Parser)]
+pub struct PlayOptions {
+    #[arg(long)]
+    score: PathBuf,
+    /// Send notes to a virtual MIDI output port named "Syntoniq Keyboard" using MPE instead of
+    /// playing them with Csound
+    #[arg(long)]
+    midi: bool,
+    /// Additional option to pass to csound, e.g. --csound-arg=-odac1; repeatable
+    #[arg(long, conflicts_with = "midi")]
+    csound_arg: Vec<String>,
+    /// Csound file containing the instrument; start with `syntoniq-kbd csound-text` and modify
+    /// based on the comments.
+    #[arg(long, conflicts_with = "midi")]
+    csound_file: Option<PathBuf>,
+    #[command(flatten)]
+    parse_options: parsing::Options,
+}
+
+/// Turn a pitch on or off at a time in seconds from the start of the score
+#[derive(Debug, PartialEq)]
+struct Action {
+    seconds: f64,
+    pitch: Pitch,
+    /// Zero turns the pitch off.
+    velocity: u8,
+}
+
+enum Command {
+    Help,
+    Toggle,
+    Jump(String),
+    Restart,
+    Quit,
+}
+
+/// Return the level of a part's dynamic at the given time
+fn level_at(dynamics: &[(Ratio<u32>, &DynamicEvent)], time: Ratio<u32>) -> u8 {
+    let idx = dynamics.partition_point(|(t, _)| *t <= time);
+    let Some((start, e)) = idx.checked_sub(1).map(|i| dynamics[i]) else {
+        // This matches the initial channel volume in MIDI output.
+        return 127;
+    };
+    match &e.end_level {
+        Some(end) if time < end.time => {
+            let fraction = to_f64((time - start) / (end.time - start));
+            let start_level = f64::from(e.start_level);
+            let level = start_level + fraction * (f64::from(end.item) - start_level);
+            level.round() as u8
+        }
+        Some(end) => end.item,
+        None => e.start_level,
+    }
+}
+
+fn actions(timeline: &Timeline) -> Vec<Action> {
+    let tempo = TempoMap::new(timeline);
+    let seconds = |time: Ratio<u32>| tempo.seconds(to_f64(time));
+    let mut dynamics: HashMap<&str, Vec<(Ratio<u32>, &DynamicEvent)>> = HashMap::new();
+    for event in &timeline.events {
+        if let TimelineData::Dynamic(e) = &event.data {
+            dynamics.entry(e.part).or_default().push((event.time, e));
+        }
+    }
+    let mut actions = Vec::new();
+    for event in &timeline.events {
+        let TimelineData::Note(e) = &event.data else {
+            continue;
+        };
+        let level = dynamics
+            .get(e.part_note.part)
+            .map_or(127, |d| level_at(d, event.time));
+        let velocity = (u32::from(e.value.velocity) * u32::from(level) / 127).clamp(1, 127) as u8;
+        for p in &e.value.pitches {
+            actions.push(Action {
+                seconds: seconds(p.start_time),
+                pitch: p.start_pitch.clone(),
+                velocity,
+            });
+            actions.push(Action {
+                seconds: seconds(p.end_time),
+                pitch: p.start_pitch.clone(),
+                velocity: 0,
+            });
+        }
+    }
+    // Turn pitches off before turning pitches on at the same time so that repeated pitches are
+    // heard.
+    actions.sort_by(|a, b| {
+        a.seconds
+            .total_cmp(&b.seconds)
+            .then((a.velocity > 0).cmp(&(b.velocity > 0)))
+    });
+    actions
+}
+
+/// Return the number of pitches that glide, which are played at their starting pitch
+fn glides(timeline: &Timeline) -> usize {
+    timeline
+        .events
+        .iter()
+        .filter_map(|event| match &event.data {
+            TimelineData::Note(e) => Some(&e.value.pitches),
+            _ => None,
+        })
+        .flatten()
+        .filter(|p| p.end_pitch.is_some())
+        .count()
+}
+
+/// Return the time in seconds of the first occurrence of each mark
+fn marks(timeline: &Timeline) -> BTreeMap<String, f64> {
+    let tempo = TempoMap::new(timeline);
+    let mut marks = BTreeMap::new();
+    for event in &timeline.events {
+        if let TimelineData::Mark(e) = &event.data {
+            marks
+                .entry(e.label.to_string())
+                .or_insert_with(|| tempo.seconds(to_f64(event.time)));
+        }
+    }
+    marks
+}
+
+struct Player {
+    tx: events::WeakSender,
+    sounding: HashMap<Pitch, usize>,
+}
+
+impl Player {
+    fn send(&self, event: Event) {
+        if let Some(tx) = self.tx.upgrade() {
+            _ = tx.send(event);
+        }
+    }
+
+    fn play(&mut self, pitch: &Pitch, velocity: u8) {
+        if velocity == 0 {
+            let Some(count) = self.sounding.get_mut(pitch) else {
+                return;
+            };
+            *count -= 1;
+            if *count == 0 {
+                self.sounding.remove(pitch);
+            }
+        } else {
+            *self.sounding.entry(pitch.clone()).or_default() += 1;
+        }
+        self.send(Event::PlayNote(PlayNoteEvent {
+            pitch: pitch.clone(),
+            velocity,
+            note: None,
+        }));
+    }
+
+    fn silence(&mut self) {
+        for (pitch, count) in std::mem::take(&mut self.sounding) {
+            for _ in 0..count {
+                self.send(Event::PlayNote(PlayNoteEvent {
+                    pitch: pitch.clone(),
+                    velocity: 0,
+                    note: None,
+                }));
+            }
+        }
+    }
+}
+
+fn show_help(marks: &BTreeMap<String, f64>) {
+    print!("{HELP}");
+    if !marks.is_empty() {
+        let names: Vec<_> = marks.keys().map(String::as_str).collect();
+        println!("marks: {}", names.join(", "));
+    }
+}
+
+fn read_commands(tx: mpsc::Sender<Command>) {
+    for line in io::stdin().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let command = match line.trim() {
+            "?" => Command::Help,
+            "" => Command::Toggle,
+            "<" => Command::Restart,
+            "q" => Command::Quit,
+            mark => Command::Jump(mark.to_string()),
+        };
+        if tx.blocking_send(command).is_err() {
+            return;
+        }
+    }
+    _ = tx.blocking_send(Command::Quit);
+}
+
+async fn play(
+    actions: Vec<Action>,
+    marks: BTreeMap<String, f64>,
+    tx: events::WeakSender,
+    mut commands: mpsc::Receiver<Command>,
+) {
+    let mut player = Player {
+        tx,
+        sounding: Default::default(),
+    };
+    // `offset` is the time in the score, in seconds, when playback started or resumed at
+    // `started`. When paused, `started` is None.
+    let mut idx = 0;
+    let mut offset = 0.0;
+    let mut started: Option<Instant> = None;
+    loop {
+        if started.is_some() && idx == actions.len() {
+            println!("end of score; press Enter to play again");
+            started = None;
+            idx = 0;
+            offset = 0.0;
+        }
+        let next = started
+            .map(|start| start + Duration::from_secs_f64((actions[idx].seconds - offset).max(0.0)));
+        let command = tokio::select! {
+            () = tokio::time::sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {
+                let action = &actions[idx];
+                player.play(&action.pitch, action.velocity);
+                idx += 1;
+                continue;
+            }
+            _ = tokio::signal::ctrl_c() => Command::Quit,
+            command = commands.recv() => command.unwrap_or(Command::Quit),
+        };
+        let jump_to = match command {
+            Command::Quit => break,
+            Command::Help => {
+                show_help(&marks);
+                continue;
+            }
+            Command::Toggle => match started {
+                Some(start) => {
+                    player.silence();
+                    offset += start.elapsed().as_secs_f64();
+                    started = None;
+                    println!("paused at {offset:.1} seconds");
+                    continue;
+                }
+                None => offset,
+            },
+            Command::Restart => 0.0,
+            Command::Jump(mark) => match marks.get(&mark) {
+                Some(seconds) => *seconds,
+                None => {
+                    println!("unknown mark '{mark}'; enter ? for help");
+                    continue;
+                }
+            },
+        };
+        player.silence();
+        idx = actions.partition_point(|a| a.seconds < jump_to);
+        offset = jump_to;
+        started = Some(Instant::now());
+        println!("playing from {offset:.1} seconds");
+    }
+    player.silence();
+    tokio::time::sleep(SHUTDOWN_DELAY).await;
+}
+
+pub fn run(options: PlayOptions) -> anyhow::Result<()> {
+    let data = fs::read(&options.score)?;
+    let src = str::from_utf8(&data)?;
+    let timeline = parsing::timeline(
+        &options.score.display().to_string(),
+        src,
+        &options.parse_options,
+    )?;
+    let actions = actions(&timeline);
+    if actions.is_empty() {
+        bail!("{} has no notes to play", options.score.display());
+    }
+    let glides = glides(&timeline);
+    if glides > 0 {
+        log::warn!(
+            "glides can't be played live; {glides} glide(s) will be played at their starting pitch"
+        );
+    }
+    let marks = marks(&timeline);
+    let sound_type = if options.midi {
+        SoundType::Midi
+    } else {
+        #[cfg(feature = "csound")]
+        {
+            SoundType::Csound {
+                file: options.csound_file,
+                args: options.csound_arg,
+            }
+        }
+        #[cfg(not(feature = "csound"))]
+        bail!("MIDI not requested and csound not available");
+    };
+    tokio::runtime::Runtime::new()?.block_on(async move {
+        let events = Events::new();
+        let events_tx = events.sender().await;
+        engine::start_sound(sound_type, events_tx.clone(), events.receiver());
+        let (command_tx, command_rx) = mpsc::channel(10);
+        thread::spawn(move || read_commands(command_tx));
+        show_help(&marks);
+        println!("press Enter to start");
+        play(actions, marks, events_tx, command_rx).await;
+        events.shutdown().await;
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_actions() {
+        let src = "\
+syntoniq(version=1)
+tempo(bpm=60)
+[p1.0] 1:c c 2:e
+[p1] 64@0< 127@4
+mark(label=\"x\")
+[p1.0] 1:g
+";
+        let timeline = parsing::timeline("test", src, &Default::default()).unwrap();
+        let actions: Vec<_> = actions(&timeline)
+            .into_iter()
+            .map(|a| (a.seconds, a.pitch.to_string(), a.velocity))
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                (0.0, "220*^1|4".to_string(), 36),
+                (1.0, "220*^1|4".to_string(), 0),
+                (1.0, "220*^1|4".to_string(), 45),
+                (2.0, "220*^1|4".to_string(), 0),
+                (2.0, "220*^7|12".to_string(), 54),
+                (4.0, "220*^7|12".to_string(), 0),
+                (4.0, "220*^5|6".to_string(), 72),
+                (5.0, "220*^5|6".to_string(), 0),
+            ]
+        );
+        assert_eq!(marks(&timeline), [("x".to_string(), 4.0)].into());
+        assert_eq!(glides(&timeline), 0);
+    }
+
+    #[test]
+    fn test_glides() {
+        let src = "\
+syntoniq(version=1)
+tempo(bpm=60)
+[p1.0] 1:c:& e 2:g
+";
+        let timeline = parsing::timeline("test", src, &Default::default()).unwrap();
+        assert_eq!(glides(&timeline), 1);
+        // The glide sounds at its starting pitch until the note ends.
+        let actions: Vec<_> = actions(&timeline)
+            .into_iter()
+            .take(2)
+            .map(|a| (a.seconds, a.pitch.to_string()))
+            .collect();
+        assert_eq!(
+            actions,
+            [(0.0, "220*^1|4".to_string()), (1.0, "220*^1|4".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_options() {
+        let parse = |args: &[&str]| {
+            PlayOptions::try_parse_from(["play", "--score", "a.stq"].iter().chain(args))
+        };
+        assert!(parse(&["--midi"]).is_ok());
+        assert!(parse(&["--csound-file", "a.csd", "--csound-arg=-odac1"]).is_ok());
+        assert!(parse(&["--midi", "--csound-file", "a.csd"]).is_err());
+        assert!(parse(&["--midi", "--csound-arg=-odac1"]).is_err());
+    }
+}